@@ -0,0 +1,504 @@
+use crate::database::Value;
+use crate::executor::{ExecError, format_value};
+use crate::expr::{
+    Collation, column_position, compare_values, eval_arithmetic, eval_expr, expr_collation,
+    walk_expr_mut,
+};
+use sqlparser::ast::{
+    BinaryOperator, ColumnDef, DataType, DuplicateTreatment, Expr, Function, FunctionArg,
+    FunctionArgExpr, FunctionArguments, GroupByExpr, Ident, Select,
+};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AggregateFunction {
+    Count,
+    Sum,
+    Min,
+    Max,
+}
+
+/// One aggregate call in a select list, e.g. `COUNT(*)` or `MAX(level)`
+struct AggregateCall {
+    function: AggregateFunction,
+    /// `None` for `COUNT(*)`
+    arg: Option<Expr>,
+    /// How MIN and MAX compare text
+    collation: Collation,
+}
+
+/// Recognize an aggregate function call. Returns `None` for any other function.
+fn aggregate_call(
+    func: &Function,
+    schema: &[ColumnDef],
+) -> Result<Option<AggregateCall>, ExecError> {
+    let function = match func.name.to_string().to_uppercase().as_str() {
+        "COUNT" => AggregateFunction::Count,
+        "SUM" => AggregateFunction::Sum,
+        "MIN" => AggregateFunction::Min,
+        "MAX" => AggregateFunction::Max,
+        _ => return Ok(None),
+    };
+
+    let FunctionArguments::List(list) = &func.args else {
+        return Err(ExecError::Type(format!("{} takes one argument", func.name)));
+    };
+    if list.duplicate_treatment == Some(DuplicateTreatment::Distinct) {
+        return Err(ExecError::Unsupported(format!(
+            "DISTINCT in aggregates is not supported: {}",
+            func
+        )));
+    }
+    let arg = match list.args.as_slice() {
+        [FunctionArg::Unnamed(FunctionArgExpr::Wildcard)]
+            if function == AggregateFunction::Count =>
+        {
+            None
+        }
+        [FunctionArg::Unnamed(FunctionArgExpr::Expr(arg))] => Some(arg.clone()),
+        _ => return Err(ExecError::Type(format!("{} takes one argument", func.name))),
+    };
+
+    if arg.as_ref().is_some_and(contains_aggregate) {
+        return Err(ExecError::Unsupported(format!(
+            "Aggregate calls can't be nested: {}",
+            func
+        )));
+    }
+
+    let collation = match &arg {
+        Some(arg) => expr_collation(arg, schema)?,
+        None => Collation::Binary,
+    };
+    Ok(Some(AggregateCall {
+        function,
+        arg,
+        collation,
+    }))
+}
+
+/// Whether a function call is to an aggregate function
+pub fn is_aggregate(func: &Function) -> bool {
+    matches!(
+        func.name.to_string().to_uppercase().as_str(),
+        "COUNT" | "SUM" | "MIN" | "MAX"
+    )
+}
+
+/// Whether an expression contains an aggregate call anywhere
+pub fn contains_aggregate(expr: &Expr) -> bool {
+    let mut found = false;
+    walk_expr_mut(&mut expr.clone(), &mut |expr| {
+        if let Expr::Function(func) = expr
+            && is_aggregate(func)
+        {
+            found = true;
+        }
+        !found
+    });
+    found
+}
+
+/// Swap each aggregate call in `expr` for a reference to the group row
+/// column its value will be in, appending the calls to `calls`
+fn extract_aggregates(
+    expr: &Expr,
+    schema: &[ColumnDef],
+    calls: &mut Vec<AggregateCall>,
+) -> Result<Expr, ExecError> {
+    let mut expr = expr.clone();
+    let mut error = None;
+    walk_expr_mut(&mut expr, &mut |expr| {
+        let Expr::Function(func) = expr else {
+            return true;
+        };
+        match aggregate_call(func, schema) {
+            Ok(Some(call)) => {
+                *expr = Expr::Identifier(Ident::new(format!("#agg{}", calls.len())));
+                calls.push(call);
+                false
+            }
+            Ok(None) => true,
+            Err(e) => {
+                error = Some(e);
+                false
+            }
+        }
+    });
+    match error {
+        Some(e) => Err(e),
+        None => Ok(expr),
+    }
+}
+
+/// How a SELECT with GROUP BY or aggregate calls turns its source rows into
+/// one row per group.
+///
+/// A group's row is laid out by `schema`: the values of the group's first
+/// source row, followed by one value per aggregate call. The select list and
+/// HAVING are rewritten to read each aggregate from its column, so they're
+/// evaluated on group rows like any other expression.
+pub struct Grouping {
+    /// Positions of the GROUP BY columns in the source schema
+    keys: Vec<usize>,
+    calls: Vec<AggregateCall>,
+    source_len: usize,
+    pub schema: Vec<ColumnDef>,
+    pub columns: Vec<(String, Expr)>,
+    /// Filters the groups, once their aggregates are computed
+    pub having: Option<Expr>,
+}
+
+impl Grouping {
+    /// The grouping for a SELECT, or `None` if it neither groups nor aggregates
+    pub fn new(
+        select: &Select,
+        columns: &[(String, Expr)],
+        schema: &[ColumnDef],
+    ) -> Result<Option<Self>, ExecError> {
+        let group_by = match &select.group_by {
+            GroupByExpr::Expressions(exprs, modifiers) if modifiers.is_empty() => exprs,
+            _ => {
+                return Err(ExecError::Unsupported(format!(
+                    "Unsupported GROUP BY: {}",
+                    select.group_by
+                )));
+            }
+        };
+        let keys = group_by
+            .iter()
+            .map(|expr| {
+                column_position(schema, expr).unwrap_or_else(|| {
+                    Err(ExecError::Unsupported(
+                        "GROUP BY supports column names".to_string(),
+                    ))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // WHERE picks the rows that are grouped, so it can't depend on the groups
+        if let Some(selection) = &select.selection
+            && contains_aggregate(selection)
+        {
+            return Err(ExecError::Unsupported(format!(
+                "Aggregate functions are not allowed in WHERE; use HAVING: {}",
+                selection
+            )));
+        }
+
+        let mut calls = Vec::new();
+        let mut rewritten = Vec::new();
+        for (name, expr) in columns {
+            rewritten.push((name.clone(), extract_aggregates(expr, schema, &mut calls)?));
+        }
+        let having = select
+            .having
+            .as_ref()
+            .map(|having| extract_aggregates(having, schema, &mut calls))
+            .transpose()?;
+
+        // HAVING alone still makes the whole table one group
+        if keys.is_empty() && calls.is_empty() && having.is_none() {
+            return Ok(None);
+        }
+
+        let mut grouped_schema = schema.to_vec();
+        grouped_schema.extend((0..calls.len()).map(|idx| ColumnDef {
+            name: Ident::new(format!("#agg{}", idx)),
+            data_type: DataType::Unspecified,
+            options: vec![],
+        }));
+
+        let grouping = Grouping {
+            keys,
+            calls,
+            source_len: schema.len(),
+            schema: grouped_schema,
+            columns: rewritten,
+            having,
+        };
+
+        // Outside of aggregates, only grouped columns have one value per group
+        let exprs = grouping.columns.iter().map(|(_, expr)| expr);
+        for expr in exprs.chain(&grouping.having) {
+            grouping.check_grouped_expr(expr)?;
+        }
+
+        Ok(Some(grouping))
+    }
+
+    /// `check_grouped` for every column an expression over group rows uses
+    pub fn check_grouped_expr(&self, expr: &Expr) -> Result<(), ExecError> {
+        let mut error = None;
+        walk_expr_mut(&mut expr.clone(), &mut |expr| {
+            if let Some(Ok(idx)) = column_position(&self.schema, expr)
+                && let Err(e) = self.check_grouped(idx)
+            {
+                error.get_or_insert(e);
+            }
+            error.is_none()
+        });
+        error.map_or(Ok(()), Err)
+    }
+
+    /// Make sure a column of a group row has a single value per group: it's
+    /// an aggregate, or a source column listed in GROUP BY
+    pub fn check_grouped(&self, idx: usize) -> Result<(), ExecError> {
+        if idx >= self.source_len || self.keys.contains(&idx) {
+            return Ok(());
+        }
+        Err(ExecError::NotFound(format!(
+            "Column '{}' must appear in GROUP BY or be used in an aggregate",
+            self.schema[idx].name.value
+        )))
+    }
+
+    pub fn groups(&self) -> Groups<'_> {
+        Groups {
+            grouping: self,
+            groups: BTreeMap::new(),
+        }
+    }
+}
+
+/// The groups found so far, keyed by their GROUP BY values
+pub struct Groups<'a> {
+    grouping: &'a Grouping,
+    groups: BTreeMap<Vec<Value>, (Vec<Value>, Vec<Accumulator>)>,
+}
+
+impl Groups<'_> {
+    /// Add a source row to its group
+    pub fn add(&mut self, row: &[Value]) -> Result<(), ExecError> {
+        let grouping = self.grouping;
+        let key: Vec<Value> = grouping.keys.iter().map(|&idx| row[idx].clone()).collect();
+        let (_, accumulators) = self.groups.entry(key).or_insert_with(|| {
+            (
+                row.to_vec(),
+                grouping.calls.iter().map(Accumulator::new).collect(),
+            )
+        });
+
+        let source_schema = &grouping.schema[..grouping.source_len];
+        for (call, accumulator) in grouping.calls.iter().zip(accumulators) {
+            let value = match &call.arg {
+                Some(arg) => eval_expr(arg, source_schema, row)?,
+                None => Value::Bool(true),
+            };
+            if let (AggregateFunction::Sum, Some(arg)) = (call.function, &call.arg)
+                && !matches!(value, Value::Null | Value::Long(_) | Value::Decimal(_))
+            {
+                return Err(ExecError::Type(format!(
+                    "SUM expects numbers, but {} is {}",
+                    arg,
+                    format_value(&value)
+                )));
+            }
+            accumulator.add(value, call.collation)?;
+        }
+        Ok(())
+    }
+
+    /// One row per group, in order of the GROUP BY values. Without GROUP BY
+    /// there is always exactly one group, even when no rows were added.
+    pub fn finish(mut self) -> Vec<Vec<Value>> {
+        let grouping = self.grouping;
+        if grouping.keys.is_empty() && self.groups.is_empty() {
+            self.groups.insert(
+                Vec::new(),
+                (
+                    vec![Value::Null; grouping.source_len],
+                    grouping.calls.iter().map(Accumulator::new).collect(),
+                ),
+            );
+        }
+
+        self.groups
+            .into_values()
+            .map(|(mut row, accumulators)| {
+                row.extend(accumulators.into_iter().map(Accumulator::finish));
+                row
+            })
+            .collect()
+    }
+}
+
+/// The running value of one aggregate call within one group
+enum Accumulator {
+    Count(i64),
+    Sum(Option<Value>),
+    Min(Option<Value>),
+    Max(Option<Value>),
+}
+
+impl Accumulator {
+    fn new(call: &AggregateCall) -> Self {
+        match call.function {
+            AggregateFunction::Count => Accumulator::Count(0),
+            AggregateFunction::Sum => Accumulator::Sum(None),
+            AggregateFunction::Min => Accumulator::Min(None),
+            AggregateFunction::Max => Accumulator::Max(None),
+        }
+    }
+
+    /// Fold in one value; NULLs are skipped by every aggregate
+    fn add(&mut self, value: Value, collation: Collation) -> Result<(), ExecError> {
+        if value == Value::Null {
+            return Ok(());
+        }
+        match self {
+            Accumulator::Count(n) => *n += 1,
+            Accumulator::Sum(sum) => {
+                *sum = Some(match sum.take() {
+                    Some(total) => eval_arithmetic(&BinaryOperator::Plus, &total, &value)?,
+                    None => value,
+                });
+            }
+            Accumulator::Min(best) => keep_if(best, value, collation, Ordering::Less)?,
+            Accumulator::Max(best) => keep_if(best, value, collation, Ordering::Greater)?,
+        }
+        Ok(())
+    }
+
+    /// The aggregate's result. SUM, MIN and MAX of no values are NULL.
+    fn finish(self) -> Value {
+        match self {
+            Accumulator::Count(n) => Value::Long(n),
+            Accumulator::Sum(sum) => sum.unwrap_or(Value::Null),
+            Accumulator::Min(best) | Accumulator::Max(best) => best.unwrap_or(Value::Null),
+        }
+    }
+}
+
+/// Replace `best` with `value` if it's unset or `value` compares as `wanted` to it
+fn keep_if(
+    best: &mut Option<Value>,
+    value: Value,
+    collation: Collation,
+    wanted: Ordering,
+) -> Result<(), ExecError> {
+    let replace = match best {
+        Some(current) => compare_values(&value, current, collation)? == wanted,
+        None => true,
+    };
+    if replace {
+        *best = Some(value);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dialect::MatiDialect;
+    use sqlparser::parser::Parser;
+
+    fn grouping(sql: &str, schema: &[ColumnDef]) -> Result<Option<Grouping>, ExecError> {
+        let stmt = Parser::parse_sql(&MatiDialect {}, sql).unwrap().remove(0);
+        let sqlparser::ast::Statement::Query(query) = stmt else {
+            panic!("Expected a query");
+        };
+        let sqlparser::ast::SetExpr::Select(select) = *query.body else {
+            panic!("Expected a SELECT");
+        };
+        let columns: Vec<(String, Expr)> = select
+            .projection
+            .iter()
+            .map(|item| match item {
+                sqlparser::ast::SelectItem::UnnamedExpr(expr) => (expr.to_string(), expr.clone()),
+                _ => panic!("Expected plain expressions"),
+            })
+            .collect();
+        Grouping::new(&select, &columns, schema)
+    }
+
+    fn schema() -> Vec<ColumnDef> {
+        ["dept", "level", "name"]
+            .into_iter()
+            .map(|name| ColumnDef {
+                name: Ident::new(name),
+                data_type: DataType::Unspecified,
+                options: vec![],
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_group_rows() {
+        let schema = schema();
+        let grouping = grouping(
+            "SELECT dept, COUNT(*), COUNT(name), SUM(level), MIN(name), MAX(level) \
+             FROM t GROUP BY dept",
+            &schema,
+        )
+        .unwrap()
+        .unwrap();
+
+        let text = |s: &str| Value::Text(s.to_string());
+        let mut groups = grouping.groups();
+        for (dept, level, name) in [
+            ("ops", 2, Some("b")),
+            ("eng", 1, Some("c")),
+            ("eng", 5, None),
+            ("eng", 3, Some("a")),
+        ] {
+            let name = name.map_or(Value::Null, text);
+            groups.add(&[text(dept), Value::Long(level), name]).unwrap();
+        }
+
+        let rows = groups.finish();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(
+            rows[0][3..],
+            [
+                Value::Long(3),
+                Value::Long(2),
+                Value::Long(9),
+                text("a"),
+                Value::Long(5),
+            ]
+        );
+        assert_eq!(rows[1][0], text("ops"));
+    }
+
+    #[test]
+    fn test_aggregate_without_rows() {
+        let schema = schema();
+        let aggregates = grouping("SELECT COUNT(*), SUM(level) FROM t", &schema)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            aggregates.groups().finish(),
+            vec![vec![
+                Value::Null,
+                Value::Null,
+                Value::Null,
+                Value::Long(0),
+                Value::Null,
+            ]]
+        );
+
+        assert!(grouping("SELECT dept FROM t", &schema).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_invalid_grouping() {
+        let schema = schema();
+        let err = |sql: &str| grouping(sql, &schema).err().unwrap().to_string();
+
+        assert!(
+            err("SELECT name, COUNT(*) FROM t GROUP BY dept").contains("must appear in GROUP BY")
+        );
+        assert!(err("SELECT level FROM t GROUP BY dept").contains("must appear in GROUP BY"));
+        assert!(err("SELECT MAX(COUNT(*)) FROM t").contains("can't be nested"));
+        assert!(err("SELECT SUM(*) FROM t").contains("takes one argument"));
+        assert!(err("SELECT COUNT(DISTINCT dept) FROM t").contains("DISTINCT"));
+        assert!(err("SELECT SUM(level + COUNT(*)) FROM t").contains("can't be nested"));
+        assert!(err("SELECT dept FROM t WHERE MAX(level) > 1").contains("not allowed in WHERE"));
+        assert!(
+            err("SELECT dept FROM t GROUP BY dept HAVING level > 1")
+                .contains("must appear in GROUP BY")
+        );
+    }
+}
@@ -1,11 +1,16 @@
 use rustyline::DefaultEditor;
 use rustyline::error::ReadlineError;
 use std::env;
-use std::io::{BufReader, BufWriter, Write};
-use std::net::TcpStream;
+use std::time::Duration;
 
 // We need to include the protocol module
-use matidb::protocol::Response;
+use matidb::client::Connection;
+use matidb::protocol::{END_OF_DATA, Response, is_copy_from_stdin};
+
+/// How many times to try reopening a dropped connection, and the wait
+/// before the second attempt (it doubles after that)
+const RECONNECT_ATTEMPTS: u32 = 5;
+const RECONNECT_BACKOFF: Duration = Duration::from_millis(200);
 
 fn main() -> std::io::Result<()> {
     let args: Vec<String> = env::args().collect();
@@ -18,16 +23,13 @@ fn main() -> std::io::Result<()> {
 
     println!("Connecting to MatiDB server at {}...", addr);
 
-    let stream = TcpStream::connect(addr)?;
+    let mut connection = Connection::connect(addr)?;
     println!("Connected!\n");
 
-    let mut reader = BufReader::new(stream.try_clone()?);
-    let mut writer = BufWriter::new(stream);
-
     let mut rl = DefaultEditor::new().map_err(std::io::Error::other)?;
 
     println!("MatiDB Client v0.2.0");
-    println!("Type 'exit' to quit\n");
+    println!("Type 'exit' to quit, '\\reconnect' to reopen the connection\n");
 
     loop {
         let readline = rl.readline("matidb> ");
@@ -42,12 +44,28 @@ fn main() -> std::io::Result<()> {
                 rl.add_history_entry(query)
                     .map_err(std::io::Error::other)?;
 
-                // Send query to server
-                writeln!(writer, "{}", query)?;
-                writer.flush()?;
+                if query == "\\reconnect" {
+                    if !reconnect(&mut connection) {
+                        break;
+                    }
+                    continue;
+                }
 
-                // Read response
-                match Response::from_reader(&mut reader) {
+                // Send query to server and read the response. A COPY FROM
+                // STDIN sends its rows too, read one per line up to `\.`
+                let response = if is_copy_from_stdin(query) {
+                    let mut lines = Vec::new();
+                    while let Ok(line) = rl.readline("") {
+                        if line == END_OF_DATA {
+                            break;
+                        }
+                        lines.push(line);
+                    }
+                    connection.copy_in(query, &lines)
+                } else {
+                    connection.send(query)
+                };
+                match response {
                     Ok(Response::Ok(msg)) => {
                         println!("{}", msg);
 
@@ -56,12 +74,15 @@ fn main() -> std::io::Result<()> {
                             break;
                         }
                     }
-                    Ok(Response::Error(msg)) => {
-                        eprintln!("Error: {}", msg);
+                    Ok(Response::Error { code, message }) => {
+                        eprintln!("Error [{}]: {}", code.as_str(), message);
                     }
                     Err(e) => {
+                        // The query may or may not have run, so it isn't resent
                         eprintln!("Connection error: {}", e);
-                        break;
+                        if !reconnect(&mut connection) {
+                            break;
+                        }
                     }
                 }
             }
@@ -81,3 +102,21 @@ fn main() -> std::io::Result<()> {
 
     Ok(())
 }
+
+/// Reopen the connection, reporting the outcome. Returns false after giving up.
+fn reconnect(connection: &mut Connection) -> bool {
+    println!("Reconnecting...");
+    match connection.reconnect(RECONNECT_ATTEMPTS, RECONNECT_BACKOFF) {
+        Ok(()) => {
+            println!("Reconnected. Session settings and any open transaction were lost.");
+            true
+        }
+        Err(e) => {
+            eprintln!(
+                "Could not reconnect after {} attempts: {}",
+                RECONNECT_ATTEMPTS, e
+            );
+            false
+        }
+    }
+}
@@ -1,21 +1,36 @@
-use crate::storage::{DiskManager, Page, PageId};
+use crate::storage::{Page, PageId, Storage};
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::io::Result;
 use std::rc::Rc;
 
 pub struct BufferPool {
-    disk: DiskManager,
-    pages: HashMap<PageId, Rc<RefCell<Page>>>,
+    disk: Box<dyn Storage>,
+    /// Cached pages in page order, so eviction and flushing happen in the
+    /// same order on every run
+    pages: BTreeMap<PageId, Rc<RefCell<Page>>>,
     capacity: usize,
+    stats: BufferStats,
+}
+
+/// Counters for how well the cache is doing, kept since the pool was created
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BufferStats {
+    /// Fetches served from the cache
+    pub hits: u64,
+    /// Fetches that had to read the page from disk
+    pub misses: u64,
+    /// Pages written out and dropped to make room
+    pub evictions: u64,
 }
 
 impl BufferPool {
-    pub fn new(disk: DiskManager, capacity: usize) -> Self {
+    pub fn new(disk: impl Storage + 'static, capacity: usize) -> Self {
         Self {
-            disk,
-            pages: HashMap::new(),
+            disk: Box::new(disk),
+            pages: BTreeMap::new(),
             capacity,
+            stats: BufferStats::default(),
         }
     }
 
@@ -23,11 +38,13 @@ impl BufferPool {
     pub fn fetch_page(&mut self, page_id: PageId) -> Result<Rc<RefCell<Page>>> {
         // Cache hit
         if let Some(page) = self.pages.get(&page_id) {
+            self.stats.hits += 1;
             return Ok(Rc::clone(page));
         }
 
         // Cache miss - need to load from disk
-        self.evict_if_needed();
+        self.stats.misses += 1;
+        self.evict_if_needed()?;
 
         let page = self.disk.read_page(page_id)?;
         let page_rc = Rc::new(RefCell::new(page));
@@ -39,9 +56,9 @@ impl BufferPool {
 
     /// Create a new page in the buffer pool
     pub fn create_page(&mut self) -> Result<(PageId, Rc<RefCell<Page>>)> {
-        self.evict_if_needed();
+        self.evict_if_needed()?;
 
-        let page_id = self.disk.allocate_page();
+        let page_id = self.disk.allocate_page()?;
         let page = Page::new();
         let page_rc = Rc::new(RefCell::new(page));
 
@@ -51,41 +68,97 @@ impl BufferPool {
     }
 
     /// Evict a page if we're at capacity
-    fn evict_if_needed(&mut self) {
+    fn evict_if_needed(&mut self) -> Result<()> {
         if self.pages.len() >= self.capacity {
-            // Simple eviction: remove the first page we find
+            // Simple eviction: remove the lowest-numbered page
             // A real database would use LRU or Clock algorithm
             if let Some(&page_id) = self.pages.keys().next() {
-                self.evict_page(page_id);
+                self.evict_page(page_id)?;
+                self.stats.evictions += 1;
             }
         }
+        Ok(())
     }
 
-    /// Evict a specific page, writing it to disk first
-    fn evict_page(&mut self, page_id: PageId) {
-        if let Some(page_rc) = self.pages.remove(&page_id) {
-            // Write to disk before evicting
+    /// Evict a specific page, writing it to disk first.
+    /// If the write fails the page stays cached, so its contents aren't lost.
+    fn evict_page(&mut self, page_id: PageId) -> Result<()> {
+        if let Some(page_rc) = self.pages.get(&page_id) {
             // In a real DB, we'd check if it's dirty first
-            let page = page_rc.borrow();
-            let _ = self.disk.write_page(page_id, &page);
+            let page = page_rc
+                .try_borrow()
+                .map_err(|_| being_modified(&[page_id]))?;
+            self.disk.write_page(page_id, &page)?;
+            drop(page);
+            self.pages.remove(&page_id);
         }
+        Ok(())
     }
 
-    /// Flush all pages to disk
+    /// Flush all pages to disk: write them in page order, then sync once,
+    /// so everything is durable by the time this returns.
+    ///
+    /// A page that's borrowed mutably can't be read, so it's skipped: the
+    /// other pages are still written and synced, and then the skipped ones
+    /// are reported as an error.
     pub fn flush_all(&mut self) -> Result<()> {
-        for (&page_id, page_rc) in &self.pages {
-            let page = page_rc.borrow();
-            self.disk.write_page(page_id, &page)?;
+        let mut borrowed = Vec::new();
+        let mut skipped = Vec::new();
+        for (&page_id, page) in &self.pages {
+            match page.try_borrow() {
+                Ok(page) => borrowed.push((page_id, page)),
+                Err(_) => skipped.push(page_id),
+            }
+        }
+        let pages: Vec<(PageId, &Page)> = borrowed
+            .iter()
+            .map(|(page_id, page)| (*page_id, &**page))
+            .collect();
+        self.disk.write_pages(&pages)?;
+        self.disk.sync()?;
+
+        if skipped.is_empty() {
+            Ok(())
+        } else {
+            Err(being_modified(&skipped))
         }
-        Ok(())
     }
 
+    /// Number of pages allocated in the underlying file
+    pub fn num_pages(&self) -> PageId {
+        self.disk.num_pages()
+    }
 
+    pub fn stats(&self) -> BufferStats {
+        self.stats
+    }
+
+    /// Number of pages currently cached, and the most the cache holds
+    pub fn cached_pages(&self) -> (usize, usize) {
+        (self.pages.len(), self.capacity)
+    }
+
+    /// Drop every page at or beyond `num_pages`, both from the cache and the file.
+    /// Cached copies are discarded without being written back.
+    pub fn truncate(&mut self, num_pages: PageId) -> Result<()> {
+        self.pages.retain(|&page_id, _| page_id < num_pages);
+        self.disk.truncate(num_pages)
+    }
+}
+
+/// The error for pages that couldn't be written because they're borrowed mutably
+fn being_modified(page_ids: &[PageId]) -> std::io::Error {
+    let ids: Vec<String> = page_ids.iter().map(|id| id.to_string()).collect();
+    std::io::Error::other(format!(
+        "Page(s) {} are being modified and weren't written to disk",
+        ids.join(", ")
+    ))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::storage::{DiskManager, MemoryStorage};
     use std::fs;
 
     fn with_test_file<F>(name: &str, f: F)
@@ -218,6 +291,212 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_stats() {
+        let mut pool = BufferPool::new(MemoryStorage::new(), 1);
+        let (id0, _) = pool.create_page().unwrap();
+        pool.fetch_page(id0).unwrap();
+        assert_eq!(pool.stats().hits, 1);
+
+        // A second page pushes the first out, so fetching it reads it back
+        pool.create_page().unwrap();
+        assert_eq!(pool.stats().evictions, 1);
+        assert_eq!(pool.cached_pages(), (1, 1));
+        pool.fetch_page(id0).unwrap();
+        pool.fetch_page(id0).unwrap();
+
+        assert_eq!(
+            pool.stats(),
+            BufferStats {
+                hits: 2,
+                misses: 1,
+                evictions: 2,
+            }
+        );
+    }
+
+    /// Storage whose writes fail while `fail_writes` is set
+    struct FailingStorage {
+        inner: MemoryStorage,
+        fail_writes: Rc<std::cell::Cell<bool>>,
+    }
+
+    impl Storage for FailingStorage {
+        fn read_page(&mut self, page_id: PageId) -> Result<Page> {
+            self.inner.read_page(page_id)
+        }
+
+        fn write_page(&mut self, page_id: PageId, page: &Page) -> Result<()> {
+            if self.fail_writes.get() {
+                return Err(std::io::Error::other("disk full"));
+            }
+            self.inner.write_page(page_id, page)
+        }
+
+        fn sync(&mut self) -> Result<()> {
+            self.inner.sync()
+        }
+
+        fn allocate_page(&mut self) -> Result<PageId> {
+            self.inner.allocate_page()
+        }
+
+        fn num_pages(&self) -> PageId {
+            self.inner.num_pages()
+        }
+
+        fn truncate(&mut self, num_pages: PageId) -> Result<()> {
+            self.inner.truncate(num_pages)
+        }
+    }
+
+    #[test]
+    fn test_eviction_write_failure() {
+        let fail_writes = Rc::new(std::cell::Cell::new(false));
+        let disk = FailingStorage {
+            inner: MemoryStorage::new(),
+            fail_writes: Rc::clone(&fail_writes),
+        };
+        let mut pool = BufferPool::new(disk, 2);
+
+        for i in 0..2 {
+            let (_, page_rc) = pool.create_page().unwrap();
+            page_rc.borrow_mut().data[0] = i + 1;
+        }
+
+        fail_writes.set(true);
+        let err = pool.create_page().unwrap_err();
+        assert_eq!(err.to_string(), "disk full");
+        assert!(pool.fetch_page(5).is_err());
+
+        // Nothing was allocated or dropped from the cache
+        assert_eq!(pool.num_pages(), 2);
+        assert_eq!(pool.pages.len(), 2);
+
+        fail_writes.set(false);
+        pool.create_page().unwrap();
+        pool.flush_all().unwrap();
+        for i in 0..2 {
+            assert_eq!(pool.fetch_page(i).unwrap().borrow().data[0], i as u8 + 1);
+        }
+    }
+
+    /// Storage that records each write and sync made to it
+    struct RecordingStorage<S> {
+        inner: S,
+        calls: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl<S: Storage> Storage for RecordingStorage<S> {
+        fn read_page(&mut self, page_id: PageId) -> Result<Page> {
+            self.inner.read_page(page_id)
+        }
+
+        fn write_page(&mut self, page_id: PageId, page: &Page) -> Result<()> {
+            self.calls.borrow_mut().push(format!("write {}", page_id));
+            self.inner.write_page(page_id, page)
+        }
+
+        fn sync(&mut self) -> Result<()> {
+            self.calls.borrow_mut().push("sync".to_string());
+            self.inner.sync()
+        }
+
+        fn allocate_page(&mut self) -> Result<PageId> {
+            self.inner.allocate_page()
+        }
+
+        fn num_pages(&self) -> PageId {
+            self.inner.num_pages()
+        }
+
+        fn truncate(&mut self, num_pages: PageId) -> Result<()> {
+            self.inner.truncate(num_pages)
+        }
+    }
+
+    #[test]
+    fn test_flush_all_syncs_once() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let disk = RecordingStorage {
+            inner: MemoryStorage::new(),
+            calls: Rc::clone(&calls),
+        };
+        let mut pool = BufferPool::new(disk, 10);
+        for _ in 0..5 {
+            pool.create_page().unwrap();
+        }
+
+        pool.flush_all().unwrap();
+        assert_eq!(
+            *calls.borrow(),
+            vec![
+                "write 0", "write 1", "write 2", "write 3", "write 4", "sync"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_flush_all_with_page_borrowed_mutably() {
+        let mut pool = BufferPool::new(MemoryStorage::new(), 10);
+        for i in 0..3u8 {
+            let (_, page_rc) = pool.create_page().unwrap();
+            page_rc.borrow_mut().data[0] = i + 1;
+        }
+
+        let busy = pool.fetch_page(1).unwrap();
+        let mut page = busy.borrow_mut();
+        page.data[0] = 20;
+        let err = pool.flush_all().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Page(s) 1 are being modified and weren't written to disk"
+        );
+        drop(page);
+
+        // The other pages were written, and the skipped one is once it's released
+        pool.pages.clear();
+        assert_eq!(pool.fetch_page(0).unwrap().borrow().data[0], 1);
+        assert_eq!(pool.fetch_page(1).unwrap().borrow().data[0], 0);
+        assert_eq!(pool.fetch_page(2).unwrap().borrow().data[0], 3);
+        pool.pages.insert(1, busy);
+        pool.flush_all().unwrap();
+        pool.pages.clear();
+        assert_eq!(pool.fetch_page(1).unwrap().borrow().data[0], 20);
+    }
+
+    #[test]
+    fn test_same_operations_give_same_writes() {
+        // Evictions write pages out along the way, so with a small cache the
+        // order of writes depends on which pages the pool picks
+        let run = |path: &str| {
+            let calls = Rc::new(RefCell::new(Vec::new()));
+            let disk = RecordingStorage {
+                inner: DiskManager::open(path).unwrap(),
+                calls: Rc::clone(&calls),
+            };
+            let mut pool = BufferPool::new(disk, 3);
+            for i in 0..6u8 {
+                let (_, page_rc) = pool.create_page().unwrap();
+                page_rc.borrow_mut().data[0] = i;
+            }
+            for page_id in [0, 4, 2, 5, 1] {
+                pool.fetch_page(page_id).unwrap().borrow_mut().data[1] = 7;
+            }
+            pool.flush_all().unwrap();
+            calls.take()
+        };
+
+        with_test_file("deterministic_a", |path_a| {
+            with_test_file("deterministic_b", |path_b| {
+                let calls = run(path_a);
+                assert_eq!(run(path_b), calls);
+                assert_eq!(fs::read(path_a).unwrap(), fs::read(path_b).unwrap());
+                assert!(calls.len() > 7, "{:?}", calls);
+            });
+        });
+    }
+
     #[test]
     fn test_multiple_pages() {
         with_test_file("multiple", |path| {
@@ -242,7 +521,23 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_memory_storage_backend() {
+        // Capacity 2 forces pages out to the storage and back in
+        let mut pool = BufferPool::new(MemoryStorage::new(), 2);
+
+        for i in 0..5 {
+            let (page_id, page_rc) = pool.create_page().unwrap();
+            assert_eq!(page_id, i);
+            page_rc.borrow_mut().data[0] = i as u8 + 10;
+        }
+        pool.flush_all().unwrap();
 
+        for i in 0..5 {
+            let page_rc = pool.fetch_page(i).unwrap();
+            assert_eq!(page_rc.borrow().data[0], i as u8 + 10);
+        }
+    }
 
     #[test]
     fn test_shared_references() {
@@ -0,0 +1,365 @@
+use crate::protocol::{
+    END_OF_DATA, PONG, PROTOCOL_VERSION, Response, handshake, parse_handshake_reply,
+};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// The writing half of a connection, shared with the thread that answers pings
+type Writer = Arc<Mutex<BufWriter<Box<dyn Write + Send>>>>;
+
+/// A client's connection to a MatiDB server.
+///
+/// When the connection drops it can be opened again with `reconnect`. The
+/// server treats that as a new client: session settings are back to their
+/// defaults and a transaction that was open has been rolled back.
+///
+/// A thread reads everything the server sends, so the server's pings are
+/// answered even while no query is being sent.
+pub struct Connection {
+    addr: String,
+    writer: Writer,
+    /// Responses passed on by the reading thread, or the error that ended it
+    responses: Receiver<std::io::Result<Response>>,
+    /// Closes the connection, which also ends the reading thread
+    close: Box<dyn Fn()>,
+    /// Protocol version agreed with the server
+    protocol_version: u32,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        (self.close)();
+    }
+}
+
+impl Connection {
+    /// Connect and agree on a protocol version with the server. Fails if the
+    /// server doesn't speak a version this client does.
+    ///
+    /// `addr` is a TCP address such as `127.0.0.1:5432`, or `unix:<path>`
+    /// for a server listening on a Unix domain socket.
+    pub fn connect(addr: &str) -> std::io::Result<Self> {
+        let (reader, writer, close) = open_stream(addr)?;
+        let writer: Writer = Arc::new(Mutex::new(BufWriter::new(writer)));
+        let (sender, responses) = mpsc::channel();
+        let pong_writer = Arc::clone(&writer);
+        thread::spawn(move || read_frames(BufReader::new(reader), pong_writer, sender));
+        let mut connection = Self {
+            addr: addr.to_string(),
+            writer,
+            responses,
+            close,
+            protocol_version: PROTOCOL_VERSION,
+        };
+
+        let invalid =
+            |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+        connection.protocol_version = match connection.send(&handshake(PROTOCOL_VERSION))? {
+            Response::Ok(reply) => match parse_handshake_reply(&reply) {
+                Some(version) if version <= PROTOCOL_VERSION => version,
+                _ => return Err(invalid(format!("Unexpected handshake reply: {}", reply))),
+            },
+            Response::Error { message, .. } => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    message,
+                ));
+            }
+        };
+        Ok(connection)
+    }
+
+    #[allow(dead_code)]
+    pub fn protocol_version(&self) -> u32 {
+        self.protocol_version
+    }
+
+    /// Send one query and wait for its response. An error means the
+    /// connection is no longer usable.
+    pub fn send(&mut self, query: &str) -> std::io::Result<Response> {
+        self.write_lines(query, &[])
+    }
+
+    /// Send a `COPY ... FROM STDIN` query followed by its data lines, and
+    /// wait for the one response to the whole copy
+    pub fn copy_in(&mut self, query: &str, lines: &[String]) -> std::io::Result<Response> {
+        let mut data = lines.to_vec();
+        data.push(END_OF_DATA.to_string());
+        self.write_lines(query, &data)
+    }
+
+    /// Write a query and the lines that go with it, all at once so a `PONG`
+    /// can't come between them, and wait for the response
+    fn write_lines(&mut self, query: &str, lines: &[String]) -> std::io::Result<Response> {
+        {
+            let mut writer = self.writer.lock().map_err(|_| closed())?;
+            writeln!(writer, "{}", query)?;
+            for line in lines {
+                writeln!(writer, "{}", line)?;
+            }
+            writer.flush()?;
+        }
+        // The thread has stopped once it has passed on the error that ended it
+        self.responses.recv().unwrap_or_else(|_| Err(closed()))
+    }
+
+    /// Replace the connection with a new one to the same address, trying up
+    /// to `attempts` times. The wait between attempts starts at `backoff` and
+    /// doubles after each failure. Returns the last error if every attempt fails.
+    ///
+    /// The current connection is closed first: the server serves one client
+    /// at a time, so it wouldn't answer the new one while the old is open.
+    pub fn reconnect(&mut self, attempts: u32, backoff: Duration) -> std::io::Result<()> {
+        let mut delay = backoff;
+        let mut attempt = 1;
+        loop {
+            (self.close)();
+            match Connection::connect(&self.addr) {
+                Ok(connection) => {
+                    *self = connection;
+                    return Ok(());
+                }
+                Err(e) if attempt >= attempts => return Err(e),
+                Err(_) => {
+                    thread::sleep(delay);
+                    delay *= 2;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+fn closed() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Connection closed")
+}
+
+/// Read what the server sends until the connection ends, answering pings and
+/// passing responses on. The error that ends it is passed on too.
+fn read_frames(
+    mut reader: BufReader<Box<dyn Read + Send>>,
+    writer: Writer,
+    responses: Sender<std::io::Result<Response>>,
+) {
+    loop {
+        let result = match Response::read_frame(&mut reader) {
+            Ok(Some(response)) => Ok(response),
+            Ok(None) => match answer_ping(&writer) {
+                Ok(()) => continue,
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        let failed = result.is_err();
+        if responses.send(result).is_err() || failed {
+            return;
+        }
+    }
+}
+
+fn answer_ping(writer: &Writer) -> std::io::Result<()> {
+    let mut writer = writer.lock().map_err(|_| closed())?;
+    writeln!(writer, "{}", PONG)?;
+    writer.flush()
+}
+
+/// The halves of a connection to read from and write to, and a function
+/// that closes it
+type Stream = (Box<dyn Read + Send>, Box<dyn Write + Send>, Box<dyn Fn()>);
+
+/// Open a connection to `addr`
+fn open_stream(addr: &str) -> std::io::Result<Stream> {
+    let Some(path) = addr.strip_prefix("unix:") else {
+        let stream = TcpStream::connect(addr)?;
+        let closer = stream.try_clone()?;
+        return Ok((
+            Box::new(stream.try_clone()?),
+            Box::new(stream),
+            Box::new(move || {
+                let _ = closer.shutdown(Shutdown::Both);
+            }),
+        ));
+    };
+
+    #[cfg(unix)]
+    {
+        let stream = std::os::unix::net::UnixStream::connect(path)?;
+        let closer = stream.try_clone()?;
+        Ok((
+            Box::new(stream.try_clone()?),
+            Box::new(stream),
+            Box::new(move || {
+                let _ = closer.shutdown(Shutdown::Both);
+            }),
+        ))
+    }
+    #[cfg(not(unix))]
+    {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!("Unix domain sockets aren't available here: {}", path),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{
+        ErrorCode, negotiate_version, parse_handshake, read_query, write_response,
+    };
+    use std::net::TcpListener;
+
+    /// Accept a connection and answer its handshake, as the server would
+    fn accept(listener: &TcpListener) -> TcpStream {
+        let (stream, _) = listener.accept().unwrap();
+        answer_handshake(&stream);
+        stream
+    }
+
+    fn answer_handshake<S>(stream: &S)
+    where
+        for<'a> &'a S: Read + Write,
+    {
+        let line = read_query(&mut BufReader::new(stream)).unwrap().unwrap();
+        let version = parse_handshake(&line).unwrap().unwrap();
+        let response = match negotiate_version(version) {
+            Ok(version) => Response::Ok(format!("PROTOCOL {}", version)),
+            Err(message) => Response::error(ErrorCode::Unsupported, message),
+        };
+        write_response(&mut &*stream, &response).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_connect_to_unix_socket() {
+        use std::os::unix::net::UnixListener;
+
+        let path = std::env::temp_dir().join(format!("matidb-client-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            answer_handshake(&stream);
+            let query = read_query(&mut BufReader::new(&stream)).unwrap().unwrap();
+            write_response(&mut &stream, &Response::Ok(format!("got {}", query))).unwrap();
+        });
+
+        let mut connection = Connection::connect(&format!("unix:{}", path.display())).unwrap();
+        match connection.send("SELECT 1").unwrap() {
+            Response::Ok(msg) => assert_eq!(msg, "got SELECT 1"),
+            other => panic!("Expected Ok response, got {:?}", other),
+        }
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        // Nothing listens there any more
+        assert!(Connection::connect(&format!("unix:{}", path.display())).is_err());
+    }
+
+    #[test]
+    fn test_reconnect_after_drop() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let server = thread::spawn(move || {
+            // The first connection is dropped without answering
+            let stream = accept(&listener);
+            read_query(&mut BufReader::new(&stream)).unwrap();
+            drop(stream);
+
+            let stream = accept(&listener);
+            let query = read_query(&mut BufReader::new(&stream)).unwrap().unwrap();
+            write_response(&mut &stream, &Response::Ok(format!("got {}", query))).unwrap();
+        });
+
+        let mut connection = Connection::connect(&addr).unwrap();
+        assert!(connection.send("SELECT 1").is_err());
+
+        connection.reconnect(3, Duration::from_millis(10)).unwrap();
+        match connection.send("SELECT 2").unwrap() {
+            Response::Ok(msg) => assert_eq!(msg, "got SELECT 2"),
+            other => panic!("Expected Ok response, got {:?}", other),
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_reconnect_while_connected() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        // Like the server, take the next client only once the last has gone
+        let server = thread::spawn(move || {
+            for client in 1..=2 {
+                let stream = accept(&listener);
+                let mut reader = BufReader::new(&stream);
+                while let Some(query) = read_query(&mut reader).unwrap() {
+                    let response = Response::Ok(format!("client {} got {}", client, query));
+                    write_response(&mut &stream, &response).unwrap();
+                }
+            }
+        });
+
+        let mut connection = Connection::connect(&addr).unwrap();
+        match connection.send("SELECT 1").unwrap() {
+            Response::Ok(msg) => assert_eq!(msg, "client 1 got SELECT 1"),
+            other => panic!("Expected Ok response, got {:?}", other),
+        }
+        connection.reconnect(3, Duration::from_millis(10)).unwrap();
+        match connection.send("SELECT 2").unwrap() {
+            Response::Ok(msg) => assert_eq!(msg, "client 2 got SELECT 2"),
+            other => panic!("Expected Ok response, got {:?}", other),
+        }
+        drop(connection);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_answers_pings() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (answered_tx, answered_rx) = mpsc::channel();
+        let server = thread::spawn(move || {
+            let stream = accept(&listener);
+            let mut reader = BufReader::new(&stream);
+
+            // Pinged while idle, and again between a query and its response
+            writeln!(&stream, "PING").unwrap();
+            assert_eq!(read_query(&mut reader).unwrap().unwrap(), "PONG");
+            answered_tx.send(()).unwrap();
+            let query = read_query(&mut reader).unwrap().unwrap();
+            writeln!(&stream, "PING").unwrap();
+            assert_eq!(read_query(&mut reader).unwrap().unwrap(), "PONG");
+            write_response(&mut &stream, &Response::Ok(format!("got {}", query))).unwrap();
+        });
+
+        let mut connection = Connection::connect(&addr).unwrap();
+        answered_rx.recv().unwrap();
+        match connection.send("SELECT 1").unwrap() {
+            Response::Ok(msg) => assert_eq!(msg, "got SELECT 1"),
+            other => panic!("Expected Ok response, got {:?}", other),
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_reconnect_gives_up() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            accept(&listener);
+            listener
+        });
+        let mut connection = Connection::connect(&addr).unwrap();
+        let listener = server.join().unwrap();
+
+        // Nothing listens on the address any more
+        drop(listener);
+        assert!(connection.reconnect(2, Duration::from_millis(1)).is_err());
+    }
+}
@@ -0,0 +1,337 @@
+use crate::database::{Database, Value};
+use crate::dialect::MatiDialect;
+use crate::executor::{
+    ExecError, decode_hex, execute, format_value, is_system_column, query, value_to_expr,
+};
+use crate::expr::{cast_value, column_index};
+use sqlparser::ast::{
+    CopyLegacyCsvOption, CopyLegacyOption, CopyOption, CopySource, CopyTarget, DataType, SetExpr,
+    Statement,
+};
+use sqlparser::parser::Parser;
+
+/// What a `COPY` statement asks for, once it's known to be one MatiDB runs
+struct Copy {
+    source: CopySource,
+    /// The first line holds the column names
+    header: bool,
+}
+
+impl Copy {
+    /// Check a `COPY` is to STDOUT or from STDIN (whichever `to` says) in CSV
+    fn new(stmt: Statement, to: bool) -> Result<Copy, ExecError> {
+        let Statement::Copy {
+            source,
+            to: is_to,
+            target,
+            options,
+            legacy_options,
+            ..
+        } = stmt
+        else {
+            return Err(ExecError::Unsupported("Not a COPY statement".to_string()));
+        };
+        match (is_to, &target) {
+            (true, CopyTarget::Stdout) | (false, CopyTarget::Stdin) if is_to == to => {}
+            (true, CopyTarget::Stdout) | (false, CopyTarget::Stdin) => {
+                return Err(ExecError::Unsupported(
+                    "COPY FROM STDIN needs its rows to follow it, ended by a line holding \\."
+                        .to_string(),
+                ));
+            }
+            _ => {
+                return Err(ExecError::Unsupported(format!(
+                    "COPY only supports TO STDOUT and FROM STDIN, not {}",
+                    target
+                )));
+            }
+        }
+
+        let mut header = false;
+        let unsupported = |option: &dyn std::fmt::Display| {
+            ExecError::Unsupported(format!("Unsupported COPY option: {}", option))
+        };
+        for option in &options {
+            match option {
+                CopyOption::Format(format) if format.value.eq_ignore_ascii_case("csv") => {}
+                CopyOption::Header(on) => header = *on,
+                other => return Err(unsupported(other)),
+            }
+        }
+        for option in &legacy_options {
+            let CopyLegacyOption::Csv(csv_options) = option else {
+                return Err(unsupported(option));
+            };
+            for csv_option in csv_options {
+                match csv_option {
+                    CopyLegacyCsvOption::Header => header = true,
+                    other => return Err(unsupported(other)),
+                }
+            }
+        }
+
+        Ok(Copy { source, header })
+    }
+}
+
+/// Run a `COPY ... TO STDOUT`, returning the rows as CSV: a table's columns
+/// (all but the system columns unless they're named) or a query's result
+pub fn copy_to_stdout(db: &Database, stmt: Statement) -> Result<String, ExecError> {
+    let copy = Copy::new(stmt, true)?;
+    let select = match copy.source {
+        CopySource::Query(query) => Statement::Query(query),
+        CopySource::Table {
+            table_name,
+            columns,
+        } => {
+            let columns = if columns.is_empty() {
+                "*".to_string()
+            } else {
+                columns
+                    .iter()
+                    .map(|c| c.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            parse(&format!("SELECT {} FROM {}", columns, table_name))?
+        }
+    };
+    let result = query(db, select)?;
+
+    let mut lines = Vec::new();
+    if copy.header {
+        lines.push(
+            result
+                .columns
+                .iter()
+                .map(|c| csv_field(c))
+                .collect::<Vec<_>>()
+                .join(","),
+        );
+    }
+    for row in &result.rows {
+        let fields: Vec<String> = row
+            .iter()
+            .map(|value| match value {
+                // An empty string is quoted to tell it from NULL
+                Value::Null => String::new(),
+                value => csv_field(&format_value(value)),
+            })
+            .collect();
+        lines.push(fields.join(","));
+    }
+    Ok(lines.join("\n"))
+}
+
+/// Run a `COPY table FROM STDIN` with `data`, the CSV lines that followed it.
+/// The rows go in as one INSERT, so any bad row leaves the table unchanged.
+pub fn copy_from_stdin(db: &mut Database, query: &str, data: &str) -> Result<String, ExecError> {
+    // The parser expects rows after FROM STDIN unless the statement ends
+    let stmt = parse(&format!("{};", query.trim().trim_end_matches(';')))?;
+    let copy = Copy::new(stmt, false)?;
+    let CopySource::Table {
+        table_name,
+        columns,
+    } = copy.source
+    else {
+        return Err(ExecError::Unsupported(
+            "COPY FROM STDIN copies into a table, not a query".to_string(),
+        ));
+    };
+
+    let table_name = table_name.to_string();
+    let metadata = db
+        .tables
+        .get(&table_name)
+        .ok_or_else(|| ExecError::NotFound(format!("Table '{}' does not exist", table_name)))?;
+    let targets: Vec<usize> = if columns.is_empty() {
+        (0..metadata.schema.len())
+            .filter(|&idx| !is_system_column(&metadata.schema[idx].name.value))
+            .collect()
+    } else {
+        columns
+            .iter()
+            .map(|c| column_index(&metadata.schema, &c.value))
+            .collect::<Result<_, _>>()?
+    };
+    let types: Vec<&DataType> = targets
+        .iter()
+        .map(|&idx| &metadata.schema[idx].data_type)
+        .collect();
+
+    let mut rows = Vec::new();
+    for (line, fields) in parse_csv(data)?
+        .into_iter()
+        .enumerate()
+        .skip(copy.header as usize)
+    {
+        if fields.len() != targets.len() {
+            return Err(ExecError::Type(format!(
+                "COPY row {} has {} field(s), expected {}",
+                line + 1,
+                fields.len(),
+                targets.len()
+            )));
+        }
+        let row = fields
+            .into_iter()
+            .zip(&types)
+            .map(|(field, data_type)| {
+                field_value(field, data_type)
+                    .map(|value| value_to_expr(&value))
+                    .map_err(|e| ExecError::Type(format!("COPY row {}: {}", line + 1, e)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        rows.push(row);
+    }
+    if rows.is_empty() {
+        return Ok("Copied 0 row(s)".to_string());
+    }
+
+    let names: Vec<String> = targets
+        .iter()
+        .map(|&idx| metadata.schema[idx].name.to_string())
+        .collect();
+    let count = rows.len();
+    let mut insert = parse(&format!(
+        "INSERT INTO {} ({}) VALUES (NULL)",
+        table_name,
+        names.join(", ")
+    ))?;
+    if let Statement::Insert(insert) = &mut insert
+        && let Some(source) = &mut insert.source
+        && let SetExpr::Values(values) = &mut *source.body
+    {
+        values.rows = rows;
+    }
+    execute(db, insert)?;
+    Ok(format!("Copied {} row(s)", count))
+}
+
+fn parse(sql: &str) -> Result<Statement, ExecError> {
+    Parser::parse_sql(&MatiDialect {}, sql)
+        .map_err(|e| ExecError::Unsupported(format!("Parse error: {}", e)))?
+        .pop()
+        .ok_or_else(|| ExecError::Unsupported("Empty statement".to_string()))
+}
+
+/// A value as a CSV field, quoted if it has to be: when it holds a comma,
+/// quote or line break, when it's empty (an empty field is NULL), or when
+/// it's `\.`, which would end the data of a `COPY FROM STDIN`
+fn csv_field(text: &str) -> String {
+    if text.is_empty() || text == "\\." || text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+/// The value a CSV field gives a column of `data_type`. Blobs are written as
+/// `\x` and hex digits, as they're displayed.
+fn field_value(field: Option<String>, data_type: &DataType) -> Result<Value, ExecError> {
+    let Some(field) = field else {
+        return Ok(Value::Null);
+    };
+    if matches!(data_type, DataType::Blob(_) | DataType::Bytea)
+        && let Some(hex) = field.strip_prefix("\\x")
+    {
+        return decode_hex(hex).map(Value::Blob);
+    }
+    cast_value(Value::Text(field), data_type)
+}
+
+/// Split CSV data into rows of fields. An empty unquoted field is NULL
+/// (`None`), while `""` is an empty string. Quoted fields may hold commas,
+/// doubled quotes and line breaks.
+fn parse_csv(data: &str) -> Result<Vec<Vec<Option<String>>>, ExecError> {
+    let mut rows = Vec::new();
+    if data.is_empty() {
+        return Ok(rows);
+    }
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = data.chars().peekable();
+    let mut line = 1;
+    loop {
+        let Some(c) = chars.next() else {
+            row.push((quoted || !field.is_empty()).then_some(field));
+            rows.push(row);
+            return Ok(rows);
+        };
+        match c {
+            '"' if field.is_empty() && !quoted => {
+                quoted = true;
+                loop {
+                    match chars.next() {
+                        Some('"') if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            field.push('"');
+                        }
+                        Some('"') => break,
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            field.push(c);
+                        }
+                        None => {
+                            return Err(ExecError::Type(format!(
+                                "Unterminated quoted CSV field on line {}",
+                                line
+                            )));
+                        }
+                    }
+                }
+                if !matches!(chars.peek(), None | Some(',' | '\n' | '\r')) {
+                    return Err(ExecError::Type(format!(
+                        "Unexpected text after a quoted CSV field on line {}",
+                        line
+                    )));
+                }
+            }
+            ',' => {
+                row.push((quoted || !field.is_empty()).then(|| std::mem::take(&mut field)));
+                quoted = false;
+            }
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                row.push((quoted || !field.is_empty()).then(|| std::mem::take(&mut field)));
+                rows.push(std::mem::take(&mut row));
+                quoted = false;
+                line += 1;
+            }
+            c => field.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_csv() {
+        let rows = parse_csv("1,\"a, \"\"b\"\"\",\n2,\"\",\"two\nlines\"\r\n3,x,y").unwrap();
+        let text = |s: &str| Some(s.to_string());
+        assert_eq!(
+            rows,
+            vec![
+                vec![text("1"), text("a, \"b\""), None],
+                vec![text("2"), text(""), text("two\nlines")],
+                vec![text("3"), text("x"), text("y")],
+            ]
+        );
+        assert!(parse_csv("\"open").is_err());
+        assert!(parse_csv("\"a\"b").is_err());
+        assert!(parse_csv("").unwrap().is_empty());
+
+        for text in ["plain", "", "a,b", "say \"hi\"", "\\.", "x\ny"] {
+            let field = csv_field(text);
+            assert_eq!(
+                parse_csv(&field).unwrap(),
+                vec![vec![Some(text.to_string())]]
+            );
+        }
+    }
+}
@@ -1,17 +1,53 @@
 use crate::buffer::BufferPool;
-use crate::storage::{DiskManager, PageId};
-use sqlparser::ast::{ColumnDef, DataType};
+use crate::decimal::Decimal;
+use crate::dialect::MatiDialect;
+use crate::executor::QueryStats;
+use crate::index::Index;
+use crate::logging::{LogLevel, log};
+use crate::slotted_page::{NO_NEXT_PAGE, SlottedPage};
+use crate::sort::DEFAULT_SORT_BUFFER_ROWS;
+use crate::stats::TableStats;
+use crate::storage::{DiskManager, MemoryStorage, Page, PageId, Storage, VerifyingStorage};
+use sqlparser::ast::{ColumnDef, ColumnOptionDef, DataType, ReferentialAction, TableConstraint};
+use sqlparser::parser::Parser;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
 use std::fs::{File, OpenOptions};
 use std::io::{BufRead, BufReader, Write};
+use std::ops::ControlFlow;
 use std::rc::Rc;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Value {
     Long(i64),
+    Decimal(Decimal),
     Text(String),
     Bool(bool),
+    Blob(Vec<u8>),
+    /// Sorts after every other value
+    Null,
+}
+
+impl Value {
+    /// The kind of value, as error messages name it
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Long(_) => "integer",
+            Value::Decimal(_) => "decimal",
+            Value::Text(_) => "text",
+            Value::Bool(_) => "boolean",
+            Value::Blob(_) => "blob",
+            Value::Null => "null",
+        }
+    }
+}
+
+/// Physical location of a tuple: the page holding it and its slot on that page
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RowId {
+    pub page_id: PageId,
+    pub slot_id: u16,
 }
 
 #[derive(Debug)]
@@ -19,6 +55,125 @@ pub struct Row {
     pub values: Vec<Value>,
 }
 
+/// How a table lays its rows out in tuples, chosen when the table is created
+/// and kept in the catalog
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RowFormat {
+    /// Each value is preceded by a type tag, so any value fits any column
+    #[default]
+    Tagged,
+    /// A NULL bitmap, then each non-NULL value as its column's type with no
+    /// tag. Integers, booleans and decimals take a fixed width.
+    Compact,
+}
+
+impl RowFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            RowFormat::Tagged => "tagged",
+            RowFormat::Compact => "compact",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "tagged" => Some(RowFormat::Tagged),
+            "compact" => Some(RowFormat::Compact),
+            _ => None,
+        }
+    }
+
+    /// Make sure every column of `schema` can be stored in this format
+    pub fn check_schema(self, schema: &[ColumnDef]) -> Result<(), String> {
+        if self == RowFormat::Tagged {
+            return Ok(());
+        }
+        match schema
+            .iter()
+            .find(|col| compact_kind(&col.data_type).is_none())
+        {
+            Some(col) => Err(format!(
+                "Column '{}' of type {} can't be stored in compact rows",
+                col.name, col.data_type
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// What a compact row stores for a column, going by its declared type
+#[derive(Clone, Copy)]
+enum CompactKind {
+    Long,
+    Bool,
+    Decimal,
+    Text,
+    Blob,
+}
+
+fn compact_kind(data_type: &DataType) -> Option<CompactKind> {
+    match data_type {
+        DataType::Int(_) | DataType::Integer(_) | DataType::BigInt(_) | DataType::SmallInt(_) => {
+            Some(CompactKind::Long)
+        }
+        DataType::Boolean | DataType::Bool => Some(CompactKind::Bool),
+        DataType::Decimal(_) | DataType::Numeric(_) | DataType::Dec(_) => {
+            Some(CompactKind::Decimal)
+        }
+        DataType::Text | DataType::Varchar(_) | DataType::Char(_) | DataType::String(_) => {
+            Some(CompactKind::Text)
+        }
+        DataType::Blob(_) | DataType::Bytea => Some(CompactKind::Blob),
+        _ => None,
+    }
+}
+
+/// A column type for recovered values: the type they all share, ignoring
+/// NULLs, or a DECIMAL wide enough for a mix of integers and decimals. Any
+/// other mix, or only NULLs, gives TEXT.
+pub(crate) fn recovered_type<'a>(values: impl Iterator<Item = &'a Value>) -> DataType {
+    let mut kinds = HashSet::new();
+    let mut scale = 0;
+    for value in values {
+        if let Value::Decimal(d) = value {
+            scale = scale.max(d.scale());
+        }
+        if *value != Value::Null {
+            kinds.insert(value.type_name());
+        }
+    }
+    let mut kinds: Vec<_> = kinds.into_iter().collect();
+    kinds.sort_unstable();
+    match kinds.as_slice() {
+        ["integer"] => DataType::Int(None),
+        ["decimal"] | ["decimal", "integer"] => {
+            DataType::Decimal(sqlparser::ast::ExactNumberInfo::PrecisionAndScale(
+                crate::decimal::MAX_PRECISION as u64,
+                i64::from(scale),
+            ))
+        }
+        ["boolean"] => DataType::Boolean,
+        ["blob"] => DataType::Blob(None),
+        _ => DataType::Text,
+    }
+}
+
+/// The next `len` bytes of a tuple, advancing `offset` past them
+fn take<'a>(
+    bytes: &'a [u8],
+    offset: &mut usize,
+    len: usize,
+    what: &str,
+) -> Result<&'a [u8], String> {
+    let end = offset
+        .checked_add(len)
+        .filter(|&end| end <= bytes.len())
+        .ok_or_else(|| format!("Unexpected end of data for {}", what))?;
+    let taken = &bytes[*offset..end];
+    *offset = end;
+    Ok(taken)
+}
+
 impl Row {
     /// Serialize a Row to bytes (self-describing with type tags)
     pub fn to_bytes(&self) -> Vec<u8> {
@@ -40,6 +195,17 @@ impl Row {
                     bytes.push(2);
                     bytes.push(if *b { 1 } else { 0 });
                 }
+                Value::Blob(data) => {
+                    bytes.push(3);
+                    bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                    bytes.extend_from_slice(data);
+                }
+                Value::Null => bytes.push(4),
+                Value::Decimal(d) => {
+                    bytes.push(5);
+                    bytes.extend_from_slice(&d.mantissa().to_le_bytes());
+                    bytes.push(d.scale());
+                }
             }
         }
 
@@ -48,10 +214,152 @@ impl Row {
 
     /// Deserialize bytes back to a Row (self-describing format with type tags)
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let (values, _) = Self::decode(bytes, usize::MAX)?;
+        Ok(Row { values })
+    }
+
+    /// Deserialize a row that must hold exactly `column_count` values.
+    ///
+    /// Unlike `from_bytes`, which reads values until the bytes run out, this
+    /// fails on a missing value or on bytes left over after the last one,
+    /// either of which means the tuple doesn't match the table's schema.
+    pub fn from_bytes_strict(bytes: &[u8], column_count: usize) -> Result<Self, String> {
+        let (values, consumed) = Self::decode(bytes, column_count)?;
+        if values.len() < column_count {
+            return Err(format!(
+                "Expected {} values, found {}",
+                column_count,
+                values.len()
+            ));
+        }
+        if consumed < bytes.len() {
+            return Err(format!(
+                "{} trailing byte(s) after the last value",
+                bytes.len() - consumed
+            ));
+        }
+        Ok(Row { values })
+    }
+
+    /// Serialize in a table's row format. A compact row can only hold values
+    /// of its columns' types.
+    pub fn encode(&self, format: RowFormat, schema: &[ColumnDef]) -> Result<Vec<u8>, String> {
+        if format == RowFormat::Tagged {
+            return Ok(self.to_bytes());
+        }
+        if self.values.len() != schema.len() {
+            return Err(format!(
+                "Expected {} values, found {}",
+                schema.len(),
+                self.values.len()
+            ));
+        }
+
+        let mut bytes = vec![0u8; schema.len().div_ceil(8)];
+        for (idx, (col, value)) in schema.iter().zip(&self.values).enumerate() {
+            match (compact_kind(&col.data_type), value) {
+                (_, Value::Null) => bytes[idx / 8] |= 1 << (idx % 8),
+                (Some(CompactKind::Long), Value::Long(n)) => {
+                    bytes.extend_from_slice(&n.to_le_bytes())
+                }
+                (Some(CompactKind::Bool), Value::Bool(b)) => bytes.push(*b as u8),
+                (Some(CompactKind::Decimal), Value::Decimal(d)) => {
+                    bytes.extend_from_slice(&d.mantissa().to_le_bytes());
+                    bytes.push(d.scale());
+                }
+                (Some(CompactKind::Text), Value::Text(s)) => {
+                    bytes.extend_from_slice(&(s.len() as u32).to_le_bytes());
+                    bytes.extend_from_slice(s.as_bytes());
+                }
+                (Some(CompactKind::Blob), Value::Blob(data)) => {
+                    bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                    bytes.extend_from_slice(data);
+                }
+                _ => {
+                    return Err(format!(
+                        "Column '{}' is {} and can't hold {:?} in a compact row",
+                        col.name, col.data_type, value
+                    ));
+                }
+            }
+        }
+
+        Ok(bytes)
+    }
+
+    /// Deserialize a tuple written by `encode` with the same format and schema.
+    /// A compact tuple must hold exactly one value per column.
+    pub fn decode_as(
+        bytes: &[u8],
+        format: RowFormat,
+        schema: &[ColumnDef],
+    ) -> Result<Self, String> {
+        if format == RowFormat::Tagged {
+            return Self::from_bytes(bytes);
+        }
+
+        let mut offset = 0;
+        let bitmap = take(bytes, &mut offset, schema.len().div_ceil(8), "NULL bitmap")?;
+        let mut values = Vec::with_capacity(schema.len());
+        for (idx, col) in schema.iter().enumerate() {
+            if bitmap[idx / 8] & (1 << (idx % 8)) != 0 {
+                values.push(Value::Null);
+                continue;
+            }
+            let kind = compact_kind(&col.data_type).ok_or_else(|| {
+                format!(
+                    "Column '{}' of type {} has no compact layout",
+                    col.name, col.data_type
+                )
+            })?;
+            let value = match kind {
+                CompactKind::Long => {
+                    let n = take(bytes, &mut offset, 8, "Long")?;
+                    Value::Long(i64::from_le_bytes(n.try_into().unwrap()))
+                }
+                CompactKind::Bool => Value::Bool(take(bytes, &mut offset, 1, "Bool")?[0] != 0),
+                CompactKind::Decimal => {
+                    let d = take(bytes, &mut offset, 17, "Decimal")?;
+                    let mantissa = i128::from_le_bytes(d[..16].try_into().unwrap());
+                    Value::Decimal(Decimal::new(mantissa, d[16]))
+                }
+                CompactKind::Text | CompactKind::Blob => {
+                    let len = take(bytes, &mut offset, 4, "length")?;
+                    let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+                    let start = offset;
+                    let data = take(bytes, &mut offset, len, "content")?.to_vec();
+                    if let CompactKind::Blob = kind {
+                        Value::Blob(data)
+                    } else {
+                        Value::Text(String::from_utf8(data).map_err(|e| {
+                            format!(
+                                "Text in column {} at byte {} is not valid UTF-8: {}",
+                                idx + 1,
+                                start,
+                                e
+                            )
+                        })?)
+                    }
+                }
+            };
+            values.push(value);
+        }
+
+        if offset < bytes.len() {
+            return Err(format!(
+                "{} trailing byte(s) after the last value",
+                bytes.len() - offset
+            ));
+        }
+        Ok(Row { values })
+    }
+
+    /// Decode up to `limit` values, returning them with the number of bytes read
+    fn decode(bytes: &[u8], limit: usize) -> Result<(Vec<Value>, usize), String> {
         let mut values = Vec::new();
         let mut offset = 0;
 
-        while offset < bytes.len() {
+        while offset < bytes.len() && values.len() < limit {
             let type_tag = bytes[offset];
             offset += 1;
 
@@ -75,8 +383,15 @@ impl Row {
                     if offset + len > bytes.len() {
                         return Err("Unexpected end of data for Text content".to_string());
                     }
-                    let s = String::from_utf8(bytes[offset..offset + len].to_vec())
-                        .map_err(|e| e.to_string())?;
+                    let s =
+                        String::from_utf8(bytes[offset..offset + len].to_vec()).map_err(|e| {
+                            format!(
+                                "Text in column {} at byte {} is not valid UTF-8: {}",
+                                values.len() + 1,
+                                offset,
+                                e
+                            )
+                        })?;
                     offset += len;
                     values.push(Value::Text(s));
                 }
@@ -88,13 +403,38 @@ impl Row {
                     offset += 1;
                     values.push(Value::Bool(b));
                 }
+                3 => {
+                    if offset + 4 > bytes.len() {
+                        return Err("Unexpected end of data for Blob length".to_string());
+                    }
+                    let len =
+                        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize;
+                    offset += 4;
+
+                    if offset + len > bytes.len() {
+                        return Err("Unexpected end of data for Blob content".to_string());
+                    }
+                    values.push(Value::Blob(bytes[offset..offset + len].to_vec()));
+                    offset += len;
+                }
+                4 => values.push(Value::Null),
+                5 => {
+                    if offset + 17 > bytes.len() {
+                        return Err("Unexpected end of data for Decimal".to_string());
+                    }
+                    let mantissa =
+                        i128::from_le_bytes(bytes[offset..offset + 16].try_into().unwrap());
+                    let scale = bytes[offset + 16];
+                    offset += 17;
+                    values.push(Value::Decimal(Decimal::new(mantissa, scale)));
+                }
                 _ => {
                     return Err(format!("Unknown type tag: {}", type_tag));
                 }
             }
         }
 
-        Ok(Row { values })
+        Ok((values, offset))
     }
 }
 
@@ -104,12 +444,100 @@ pub struct TableMetadata {
     pub schema: Vec<ColumnDef>,
     pub first_page_id: PageId,
     pub last_page_id: PageId, // Optimization: track last page for faster inserts
+    pub auto_increment: i64,  // Last value handed out to an AUTOINCREMENT column
+    /// Table-level CHECK constraints; column-level ones stay in the column options
+    pub checks: Vec<TableConstraint>,
+    /// Columns declared with `REFERENCES`, whether on the column or as a
+    /// table-level `FOREIGN KEY`
+    pub foreign_keys: Vec<ForeignKey>,
+    /// Planner statistics from the last ANALYZE, if any
+    pub stats: Option<TableStats>,
+    pub format: RowFormat,
+    /// Percent of each page that inserts fill, leaving the rest for rows an
+    /// UPDATE makes longer
+    pub fill_factor: u8,
+    /// Set by `COMMENT ON TABLE`; column comments are kept as a column option
+    pub comment: Option<String>,
+}
+
+/// A foreign key: every non-NULL value of `column` has to be a value of
+/// `referred_column` in `foreign_table`, a PRIMARY KEY or UNIQUE column
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForeignKey {
+    pub column: String,
+    pub foreign_table: String,
+    pub referred_column: String,
+    /// What deleting a referenced row does to the rows referencing it
+    pub on_delete: ReferentialAction,
+}
+
+impl ForeignKey {
+    /// The foreign key a single-column table-level `FOREIGN KEY` constraint
+    /// with its referenced column spelled out declares
+    pub fn from_constraint(constraint: &TableConstraint) -> Option<ForeignKey> {
+        let TableConstraint::ForeignKey {
+            columns,
+            foreign_table,
+            referred_columns,
+            on_delete,
+            ..
+        } = constraint
+        else {
+            return None;
+        };
+        let ([column], [referred_column]) = (columns.as_slice(), referred_columns.as_slice())
+        else {
+            return None;
+        };
+        Some(ForeignKey {
+            column: column.value.clone(),
+            foreign_table: foreign_table.to_string(),
+            referred_column: referred_column.value.clone(),
+            on_delete: on_delete.unwrap_or(ReferentialAction::NoAction),
+        })
+    }
+}
+
+impl fmt::Display for ForeignKey {
+    /// As a table-level constraint, the way the catalog and dumps record it
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "FOREIGN KEY ({}) REFERENCES {} ({}) ON DELETE {}",
+            self.column, self.foreign_table, self.referred_column, self.on_delete
+        )
+    }
+}
+
+/// Tables fill their pages completely unless created with a fill factor
+pub const DEFAULT_FILL_FACTOR: u8 = 100;
+/// The smallest fill factor a table can have, as in PostgreSQL
+pub const MIN_FILL_FACTOR: u8 = 10;
+
+/// What ROLLBACK needs to put the database back as it was at BEGIN.
+///
+/// Undo works at page granularity: the first time a page that existed at
+/// BEGIN is modified, its whole original image is saved. Pages allocated
+/// during the transaction are simply truncated away.
+struct Transaction {
+    undo_pages: HashMap<PageId, Page>,
+    num_pages: PageId,
+    tables: HashMap<String, TableMetadata>,
+    // Definition of each index; entries are rebuilt on rollback
+    indexes: Vec<Index>,
+    /// Tables written to with DEFERRABLE INITIALLY DEFERRED unique columns,
+    /// checked at COMMIT
+    deferred_checks: BTreeSet<String>,
 }
 
 pub struct Database {
     pub buffer_pool: Rc<RefCell<BufferPool>>,
     pub tables: HashMap<String, TableMetadata>,
+    pub indexes: HashMap<String, Index>,
+    /// Rows an ORDER BY sorts in memory before spilling sorted runs to disk
+    pub sort_buffer_rows: usize,
     catalog_path: String,
+    transaction: Option<Transaction>,
 }
 
 impl Drop for Database {
@@ -122,37 +550,591 @@ impl Drop for Database {
 
 impl Database {
     pub fn new(db_path: &str) -> std::io::Result<Self> {
-        let disk = DiskManager::open(db_path)?;
+        Self::open(db_path, DiskManager::open(db_path)?)
+    }
+
+    /// Like `new`, but every page written to the file is read back and
+    /// compared, so a write the disk got wrong fails immediately
+    pub fn new_verifying_writes(db_path: &str) -> std::io::Result<Self> {
+        Self::open(db_path, VerifyingStorage::new(DiskManager::open(db_path)?))
+    }
+
+    fn open(db_path: &str, disk: impl Storage + 'static) -> std::io::Result<Self> {
         let buffer_pool = BufferPool::new(disk, 100); // 100 page capacity
         let catalog_path = format!("{}.catalog", db_path);
 
         let mut db = Self {
             buffer_pool: Rc::new(RefCell::new(buffer_pool)),
             tables: HashMap::new(),
+            indexes: HashMap::new(),
+            sort_buffer_rows: DEFAULT_SORT_BUFFER_ROWS,
             catalog_path: catalog_path.clone(),
+            transaction: None,
         };
 
         // Load catalog if it exists
-        if let Err(e) = db.load_catalog() {
-            eprintln!("Warning: Could not load catalog: {}", e);
+        match db.load_catalog() {
+            Ok(warnings) => {
+                for warning in warnings {
+                    log(LogLevel::Warn, format_args!("{}", warning));
+                }
+            }
+            Err(e) => log(
+                LogLevel::Warn,
+                format_args!("Could not load catalog: {}", e),
+            ),
+        }
+
+        // The catalog can reference pages that never reached the file (e.g. a crash before flush)
+        match db.recover_page_references() {
+            Ok(repairs) => {
+                for repair in repairs {
+                    log(LogLevel::Warn, format_args!("{}", repair));
+                }
+            }
+            Err(e) => log(
+                LogLevel::Warn,
+                format_args!("Could not check table pages: {}", e),
+            ),
+        }
+
+        // Index entries aren't persisted, so repopulate them from the table data
+        if let Err(e) = crate::executor::rebuild_indexes(&mut db) {
+            log(
+                LogLevel::Warn,
+                format_args!("Could not rebuild indexes: {}", e),
+            );
         }
 
         Ok(db)
     }
 
-    #[cfg(test)]
+    /// Open an empty database at `db_path`, first deleting the data file and
+    /// catalog if they exist. This destroys whatever data was there; it's
+    /// meant for tests and throwaway runs that need a clean slate.
+    #[allow(dead_code)]
+    pub fn create_fresh(db_path: &str) -> std::io::Result<Self> {
+        for path in [db_path.to_string(), format!("{}.catalog", db_path)] {
+            match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Self::new(db_path)
+    }
+
+    /// Creates a database that lives entirely in memory.
+    /// Nothing is written to disk and all data is lost when it's dropped.
+    pub fn in_memory() -> Self {
+        let buffer_pool = BufferPool::new(MemoryStorage::new(), 100);
+        Self::with_buffer_pool(Rc::new(RefCell::new(buffer_pool)))
+    }
+
     pub fn with_buffer_pool(buffer_pool: Rc<RefCell<BufferPool>>) -> Self {
         Self {
             buffer_pool,
             tables: HashMap::new(),
-            catalog_path: String::new(), // Tests don't use catalog persistence
+            indexes: HashMap::new(),
+            sort_buffer_rows: DEFAULT_SORT_BUFFER_ROWS,
+            catalog_path: String::new(), // No catalog persistence
+            transaction: None,
         }
     }
 
+    pub fn in_transaction(&self) -> bool {
+        self.transaction.is_some()
+    }
+
+    /// Start recording undo information. Callers check that no transaction is active.
+    pub fn begin_transaction(&mut self) {
+        debug_assert!(self.transaction.is_none());
+        self.transaction = Some(Transaction {
+            undo_pages: HashMap::new(),
+            num_pages: self.buffer_pool.borrow().num_pages(),
+            tables: self.tables.clone(),
+            indexes: self.indexes.values().map(Index::definition).collect(),
+            deferred_checks: BTreeSet::new(),
+        });
+    }
+
+    /// Note that a table's deferred unique columns need checking at COMMIT
+    pub fn defer_unique_check(&mut self, table_name: &str) {
+        if let Some(transaction) = &mut self.transaction {
+            transaction.deferred_checks.insert(table_name.to_string());
+        }
+    }
+
+    /// Carry a table's pending deferred checks over to its new name
+    pub fn rename_deferred_checks(&mut self, old_name: &str, new_name: &str) {
+        if let Some(transaction) = &mut self.transaction
+            && transaction.deferred_checks.remove(old_name)
+        {
+            transaction.deferred_checks.insert(new_name.to_string());
+        }
+    }
+
+    /// Tables whose deferred unique columns need checking before COMMIT
+    pub fn deferred_checks(&self) -> Vec<String> {
+        self.transaction
+            .as_ref()
+            .map(|t| t.deferred_checks.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Keep every change made since BEGIN
+    pub fn commit_transaction(&mut self) {
+        self.transaction = None;
+    }
+
+    /// Undo every change made since BEGIN: restore the original image of each
+    /// modified page, drop pages allocated since, and restore the catalog
+    pub fn rollback_transaction(&mut self) -> std::io::Result<()> {
+        let Some(transaction) = self.transaction.take() else {
+            return Ok(());
+        };
+
+        {
+            let mut buffer_pool = self.buffer_pool.borrow_mut();
+            buffer_pool.truncate(transaction.num_pages)?;
+            for (page_id, original) in transaction.undo_pages {
+                *buffer_pool.fetch_page(page_id)?.borrow_mut() = original;
+            }
+            buffer_pool.flush_all()?;
+        }
+
+        self.tables = transaction.tables;
+        self.indexes = transaction
+            .indexes
+            .into_iter()
+            .map(|index| (index.name.clone(), index))
+            .collect();
+        crate::executor::rebuild_indexes(self).map_err(std::io::Error::other)?;
+
+        self.save_catalog()
+    }
+
+    /// Fetch a page that is about to be modified. Inside a transaction, the
+    /// page's current image is saved first so ROLLBACK can put it back.
+    pub fn fetch_page_for_write(&mut self, page_id: PageId) -> std::io::Result<Rc<RefCell<Page>>> {
+        let page_rc = self.buffer_pool.borrow_mut().fetch_page(page_id)?;
+
+        if let Some(transaction) = &mut self.transaction
+            && page_id < transaction.num_pages
+        {
+            transaction
+                .undo_pages
+                .entry(page_id)
+                .or_insert_with(|| *page_rc.borrow());
+        }
+
+        Ok(page_rc)
+    }
+
+    /// Page ids of a table's page chain, in chain order
+    pub fn table_pages(&self, first_page_id: PageId) -> std::io::Result<Vec<PageId>> {
+        let mut pages = vec![first_page_id];
+        let mut current_page_id = first_page_id;
+
+        loop {
+            let page_rc = self.buffer_pool.borrow_mut().fetch_page(current_page_id)?;
+            let mut page = page_rc.borrow_mut();
+            let next = SlottedPage::new(&mut page).next_page_id();
+
+            if next == NO_NEXT_PAGE {
+                break;
+            }
+            pages.push(next);
+            current_page_id = next;
+        }
+
+        Ok(pages)
+    }
+
+    /// Pass each row of a table to `f` in storage order, without building a
+    /// result. `f` returns `ControlFlow::Break(())` to stop the scan early.
+    /// Rows hold every column in schema order, system columns included.
+    #[allow(dead_code)]
+    pub fn scan_table(
+        &self,
+        name: &str,
+        mut f: impl FnMut(&Row) -> ControlFlow<()>,
+    ) -> std::io::Result<()> {
+        let metadata = self.tables.get(name).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Table '{}' does not exist", name),
+            )
+        })?;
+        crate::executor::scan_table(self, metadata, &mut QueryStats::default(), |_, row| {
+            Ok(f(&row).is_continue())
+        })
+        .map_err(std::io::Error::other)
+    }
+
+    /// Pages in the file that no table's chain uses, in page order. VACUUM
+    /// gives back the ones at the end of the file.
+    pub fn free_pages(&self) -> std::io::Result<Vec<PageId>> {
+        let mut used = HashSet::new();
+        for metadata in self.tables.values() {
+            used.extend(self.table_pages(metadata.first_page_id)?);
+        }
+        let num_pages = self.buffer_pool.borrow().num_pages();
+        Ok((0..num_pages)
+            .filter(|page_id| !used.contains(page_id))
+            .collect())
+    }
+
+    /// The table whose page chain includes a page, if any
+    pub fn page_owner(&self, page_id: PageId) -> std::io::Result<Option<&str>> {
+        for (table_name, metadata) in &self.tables {
+            if self.table_pages(metadata.first_page_id)?.contains(&page_id) {
+                return Ok(Some(table_name));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Describe a page's slotted-page header and slots, and which table's
+    /// chain it belongs to, for debugging storage problems
+    pub fn inspect_page(&self, page_id: PageId) -> std::io::Result<String> {
+        let num_pages = self.buffer_pool.borrow().num_pages();
+        if page_id >= num_pages {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Page {} does not exist ({} pages)", page_id, num_pages),
+            ));
+        }
+
+        let owner = match self.page_owner(page_id)? {
+            Some(table_name) => format!("table {}", table_name),
+            None => "no table".to_string(),
+        };
+
+        let page_rc = self.buffer_pool.borrow_mut().fetch_page(page_id)?;
+        let mut page = *page_rc.borrow();
+        Ok(format!(
+            "Page {} ({})\n{}",
+            page_id,
+            owner,
+            SlottedPage::new(&mut page).describe()
+        ))
+    }
+
+    /// Repair tables whose catalog entry points past the end of the data file.
+    ///
+    /// A table whose first page is missing gets a fresh empty page (its rows
+    /// are lost); a page chain that links to a missing page is cut at the last
+    /// page that exists. Returns a description of each repair.
+    fn recover_page_references(&mut self) -> std::io::Result<Vec<String>> {
+        let mut repairs = Vec::new();
+
+        // Pages created below to replace a table's lost first page are past
+        // the end of the file as it was, so they don't count as another
+        // table's missing pages turning up
+        let num_pages = self.buffer_pool.borrow().num_pages();
+        let mut table_names: Vec<String> = self.tables.keys().cloned().collect();
+        table_names.sort();
+        for table_name in table_names {
+            let metadata = &self.tables[&table_name];
+            let (first_page_id, last_page_id) = (metadata.first_page_id, metadata.last_page_id);
+
+            if first_page_id >= num_pages {
+                let (page_id, page_rc) = self.buffer_pool.borrow_mut().create_page()?;
+                SlottedPage::new(&mut page_rc.borrow_mut()).init();
+
+                let metadata = self.tables.get_mut(&table_name).unwrap();
+                metadata.first_page_id = page_id;
+                metadata.last_page_id = page_id;
+                repairs.push(format!(
+                    "Table '{}': first page {} is beyond the end of the file ({} pages); \
+                     replaced with an empty page {}",
+                    table_name, first_page_id, num_pages, page_id
+                ));
+                continue;
+            }
+
+            // Walk the chain, cutting it where it links past the end of the file
+            let mut current_page_id = first_page_id;
+            loop {
+                let page_rc = self.buffer_pool.borrow_mut().fetch_page(current_page_id)?;
+                let mut page = page_rc.borrow_mut();
+                let mut slotted = SlottedPage::new(&mut page);
+                let next = slotted.next_page_id();
+
+                if next == NO_NEXT_PAGE {
+                    break;
+                }
+                if next >= num_pages {
+                    slotted.set_next_page_id(NO_NEXT_PAGE);
+                    repairs.push(format!(
+                        "Table '{}': page {} links to missing page {}; chain cut at page {}",
+                        table_name, current_page_id, next, current_page_id
+                    ));
+                    break;
+                }
+                current_page_id = next;
+            }
+
+            if last_page_id != current_page_id {
+                self.tables.get_mut(&table_name).unwrap().last_page_id = current_page_id;
+                repairs.push(format!(
+                    "Table '{}': last page {} corrected to {}",
+                    table_name, last_page_id, current_page_id
+                ));
+            }
+        }
+
+        if !repairs.is_empty() {
+            self.buffer_pool.borrow_mut().flush_all()?;
+            self.save_catalog()?;
+        }
+
+        Ok(repairs)
+    }
+
+    /// Validate the layout of every page in every table, and that each tuple
+    /// decodes to a row of the table's schema.
+    /// Returns one message per corrupt page or tuple; an empty list means all is well.
+    pub fn check_pages(&self) -> std::io::Result<Vec<String>> {
+        let mut problems = Vec::new();
+
+        let mut table_names: Vec<&String> = self.tables.keys().collect();
+        table_names.sort();
+        for table_name in table_names {
+            for page_id in self.table_pages(self.tables[table_name].first_page_id)? {
+                let page_rc = self.buffer_pool.borrow_mut().fetch_page(page_id)?;
+                let mut page = *page_rc.borrow();
+                let slotted = SlottedPage::new(&mut page);
+                if let Err(e) = slotted.validate() {
+                    problems.push(format!("{} page {}: {}", table_name, page_id, e));
+                    continue;
+                }
+
+                // Every tuple must decode to exactly one value per column
+                let metadata = &self.tables[table_name];
+                for slot_id in 0..slotted.num_slots() {
+                    let Some(bytes) = slotted.get_tuple_ref(slot_id) else {
+                        continue;
+                    };
+                    let decoded = match metadata.format {
+                        RowFormat::Tagged => Row::from_bytes_strict(bytes, metadata.schema.len()),
+                        format => Row::decode_as(bytes, format, &metadata.schema),
+                    };
+                    if let Err(e) = decoded {
+                        problems.push(format!(
+                            "{} page {} slot {}: {}",
+                            table_name, page_id, slot_id, e
+                        ));
+                    }
+                }
+            }
+        }
+
+        Ok(problems)
+    }
+
+    /// Rebuild catalog entries for page chains no table owns, as a last resort
+    /// when the catalog is lost or damaged but the data file survives.
+    ///
+    /// A chain starts at a valid page that no other unowned page links to.
+    /// Tagged rows describe their own types, so each chain becomes a table
+    /// `recovered_<first page>` with columns `col1`, `col2`, ... typed from
+    /// the values found. Empty chains are left alone, and so are chains whose
+    /// rows don't decode without a schema, as most compact-format rows don't;
+    /// a compact row that happens to decode as a tagged one comes back with
+    /// the wrong values. Pages of dropped tables that were never reused come
+    /// back too. A recovered chain's last page is unlinked from whatever page
+    /// it linked to beyond the chain. The caller saves the catalog. Returns a
+    /// description of each chain found.
+    pub fn repair(&mut self) -> std::io::Result<Vec<String>> {
+        let mut report = Vec::new();
+
+        // Valid unowned pages and where each links to
+        let mut links = HashMap::new();
+        for page_id in self.free_pages()? {
+            let page_rc = self.buffer_pool.borrow_mut().fetch_page(page_id)?;
+            let mut page = *page_rc.borrow();
+            let slotted = SlottedPage::new(&mut page);
+            if slotted.validate().is_ok() {
+                links.insert(page_id, slotted.next_page_id());
+            }
+        }
+        let linked: HashSet<PageId> = links.values().copied().collect();
+        let mut heads: Vec<PageId> = links
+            .keys()
+            .copied()
+            .filter(|page_id| !linked.contains(page_id))
+            .collect();
+        heads.sort_unstable();
+
+        for first_page_id in heads {
+            let mut pages = vec![first_page_id];
+            while let Some(&next) = links.get(&pages[pages.len() - 1])
+                && links.contains_key(&next)
+                && !pages.contains(&next)
+            {
+                pages.push(next);
+            }
+            let last_page_id = pages[pages.len() - 1];
+            let span = match pages.len() {
+                1 => format!("Page {}", first_page_id),
+                n => format!("Pages {}..{} ({} pages)", first_page_id, last_page_id, n),
+            };
+
+            let rows = match self.recover_rows(&pages)? {
+                Ok(rows) => rows,
+                Err(e) => {
+                    report.push(format!("{}: skipped, {}", span, e));
+                    continue;
+                }
+            };
+            let column_count = rows.first().map_or(0, |row| row.len());
+            if column_count == 0 {
+                report.push(format!("{}: skipped, no rows", span));
+                continue;
+            }
+            if rows.iter().any(|row| row.len() != column_count) {
+                report.push(format!(
+                    "{}: skipped, rows have differing numbers of values",
+                    span
+                ));
+                continue;
+            }
+
+            let schema = (0..column_count)
+                .map(|idx| ColumnDef {
+                    name: sqlparser::ast::Ident::new(format!("col{}", idx + 1)),
+                    data_type: recovered_type(rows.iter().map(|row| &row[idx])),
+                    options: vec![],
+                })
+                .collect();
+            let table_name = format!("recovered_{}", first_page_id);
+            let mut line = format!(
+                "{}: recovered {} row(s) of {} column(s) as table '{}'",
+                span,
+                rows.len(),
+                column_count,
+                table_name
+            );
+            // The walk stops at a link to a page that's owned, invalid or
+            // already in the chain. The link goes, so scans of the new table
+            // don't follow it into another table's rows or round a loop.
+            let page_rc = self.buffer_pool.borrow_mut().fetch_page(last_page_id)?;
+            let mut page = page_rc.borrow_mut();
+            let mut slotted = SlottedPage::new(&mut page);
+            let next = slotted.next_page_id();
+            if next != NO_NEXT_PAGE {
+                slotted.set_next_page_id(NO_NEXT_PAGE);
+                line.push_str(&format!(
+                    "; its link from page {} to page {} was cut",
+                    last_page_id, next
+                ));
+            }
+            report.push(line);
+            self.tables.insert(
+                table_name,
+                TableMetadata {
+                    schema,
+                    first_page_id,
+                    last_page_id,
+                    auto_increment: 0,
+                    checks: vec![],
+                    foreign_keys: vec![],
+                    stats: None,
+                    format: RowFormat::Tagged,
+                    fill_factor: DEFAULT_FILL_FACTOR,
+                    comment: None,
+                },
+            );
+        }
+
+        Ok(report)
+    }
+
+    /// Decode every tuple on `pages` as a tagged row. The inner error says
+    /// why a tuple couldn't be read.
+    fn recover_rows(&self, pages: &[PageId]) -> std::io::Result<Result<Vec<Vec<Value>>, String>> {
+        let mut rows = Vec::new();
+        for &page_id in pages {
+            let page_rc = self.buffer_pool.borrow_mut().fetch_page(page_id)?;
+            let mut page = *page_rc.borrow();
+            let slotted = SlottedPage::new(&mut page);
+            for slot_id in 0..slotted.num_slots() {
+                let Some(bytes) = slotted.get_tuple_ref(slot_id) else {
+                    continue;
+                };
+                match Row::from_bytes(bytes) {
+                    Ok(row) => rows.push(row.values),
+                    Err(e) => {
+                        return Ok(Err(format!(
+                            "page {} slot {} isn't a self-describing row ({}); \
+                             recreate the table with its schema and restore the rows by hand",
+                            page_id, slot_id, e
+                        )));
+                    }
+                }
+            }
+        }
+        Ok(Ok(rows))
+    }
+
+    /// Reclaim disk space held by trailing pages no table uses.
+    ///
+    /// Empty pages at the end of each table's chain are unlinked first, then
+    /// the file is truncated right after the highest page still referenced by
+    /// a table. Returns the number of pages removed from the file.
+    pub fn trim_trailing_pages(&mut self) -> std::io::Result<PageId> {
+        let mut keep: PageId = 0;
+
+        let table_names: Vec<String> = self.tables.keys().cloned().collect();
+        for table_name in table_names {
+            let first_page_id = self.tables[&table_name].first_page_id;
+            let mut pages = self.table_pages(first_page_id)?;
+
+            // The first page is always kept, even when empty
+            let mut unlinked = false;
+            while pages.len() > 1 && self.page_is_empty(pages[pages.len() - 1])? {
+                pages.pop();
+                unlinked = true;
+            }
+
+            let last_page_id = pages[pages.len() - 1];
+            if unlinked {
+                let page_rc = self.buffer_pool.borrow_mut().fetch_page(last_page_id)?;
+                let mut page = page_rc.borrow_mut();
+                SlottedPage::new(&mut page).set_next_page_id(NO_NEXT_PAGE);
+            }
+
+            if let Some(meta) = self.tables.get_mut(&table_name) {
+                meta.last_page_id = last_page_id;
+            }
+
+            let highest = pages.iter().copied().max().unwrap_or(first_page_id);
+            keep = keep.max(highest + 1);
+        }
+
+        let removed = {
+            let mut pool = self.buffer_pool.borrow_mut();
+            pool.flush_all()?;
+            let removed = pool.num_pages().saturating_sub(keep);
+            pool.truncate(keep)?;
+            removed
+        };
+
+        self.save_catalog()?;
+        Ok(removed)
+    }
+
+    fn page_is_empty(&self, page_id: PageId) -> std::io::Result<bool> {
+        let page_rc = self.buffer_pool.borrow_mut().fetch_page(page_id)?;
+        let mut page = page_rc.borrow_mut();
+        Ok(SlottedPage::new(&mut page).num_slots() == 0)
+    }
+
     /// Save the catalog to disk
     pub fn save_catalog(&self) -> std::io::Result<()> {
         if self.catalog_path.is_empty() {
-            return Ok(()); // Skip for test databases
+            return Ok(()); // Skip for in-memory and test databases
         }
 
         let mut file = OpenOptions::new()
@@ -161,37 +1143,82 @@ impl Database {
             .truncate(true)
             .open(&self.catalog_path)?;
 
-        for (table_name, metadata) in &self.tables {
-            // Format: table_name|first_page_id|last_page_id|column_count
+        // Sorted, so the same catalog always saves to the same bytes
+        let mut tables: Vec<_> = self.tables.iter().collect();
+        tables.sort_by_key(|(name, _)| *name);
+        for (table_name, metadata) in tables {
+            // Format: table_name|first_page_id|last_page_id|column_count|auto_increment|fingerprint|row_format|fill_factor
             writeln!(
                 file,
-                "{}|{}|{}|{}",
+                "{}|{}|{}|{}|{}|{:016x}|{}|{}",
                 table_name,
                 metadata.first_page_id,
                 metadata.last_page_id,
-                metadata.schema.len()
+                metadata.schema.len(),
+                metadata.auto_increment,
+                Self::schema_fingerprint(&metadata.schema),
+                metadata.format.name(),
+                metadata.fill_factor
             )?;
 
-            // Write each column: name|type
+            // Write each column: name|type|options (options as SQL, e.g. `NOT NULL DEFAULT 0`)
             for col in &metadata.schema {
                 let type_str = Self::datatype_to_string(&col.data_type);
-                writeln!(file, "  {}|{}", col.name, type_str)?;
+                let options: Vec<String> = col.options.iter().map(|o| o.to_string()).collect();
+                writeln!(file, "  {}|{}|{}", col.name, type_str, options.join(" "))?;
+            }
+
+            for check in &metadata.checks {
+                // Format: @check|table_name|constraint SQL
+                writeln!(file, "@check|{}|{}", table_name, check)?;
+            }
+
+            for foreign_key in &metadata.foreign_keys {
+                // Format: @foreign_key|table_name|constraint SQL
+                writeln!(file, "@foreign_key|{}|{}", table_name, foreign_key)?;
+            }
+
+            if let Some(stats) = &metadata.stats {
+                // Format: @stats|table_name|row_count|distinct:min:max,...
+                writeln!(file, "@stats|{}|{}", table_name, stats.to_catalog())?;
+            }
+
+            if let Some(comment) = &metadata.comment {
+                // Format: @comment|table_name|comment text
+                writeln!(file, "@comment|{}|{}", table_name, comment)?;
             }
         }
 
+        let mut indexes: Vec<_> = self.indexes.values().collect();
+        indexes.sort_by_key(|index| &index.name);
+        for index in indexes {
+            // Format: @index|index_name|table_name|col1,col2,...[|unique]
+            let unique = if index.unique { "|unique" } else { "" };
+            writeln!(
+                file,
+                "@index|{}|{}|{}{}",
+                index.name,
+                index.table,
+                index.columns.join(","),
+                unique
+            )?;
+        }
+
         file.flush()?;
         Ok(())
     }
 
-    /// Load the catalog from disk
-    fn load_catalog(&mut self) -> std::io::Result<()> {
+    /// Load the catalog from disk, returning a warning for each table whose
+    /// columns don't match the fingerprint saved with them
+    fn load_catalog(&mut self) -> std::io::Result<Vec<String>> {
+        let mut warnings = Vec::new();
         if self.catalog_path.is_empty() {
-            return Ok(()); // Skip for test databases
+            return Ok(warnings); // Skip for in-memory and test databases
         }
 
         let file = match File::open(&self.catalog_path) {
             Ok(f) => f,
-            Err(_) => return Ok(()), // Catalog doesn't exist yet, that's ok
+            Err(_) => return Ok(warnings), // Catalog doesn't exist yet, that's ok
         };
 
         let reader = BufReader::new(file);
@@ -202,9 +1229,62 @@ impl Database {
                 continue;
             }
 
+            // Index definitions; entries are rebuilt after loading
+            if let Some(def) = line.strip_prefix("@index|") {
+                let parts: Vec<&str> = def.split('|').collect();
+                if parts.len() == 3 || parts.len() == 4 {
+                    let columns = parts[2].split(',').map(|c| c.to_string()).collect();
+                    let mut index = Index::new(parts[0].to_string(), parts[1].to_string(), columns);
+                    index.unique = parts.get(3) == Some(&"unique");
+                    self.indexes.insert(parts[0].to_string(), index);
+                }
+                continue;
+            }
+
+            // Table-level CHECK constraints follow their table's columns
+            if let Some(def) = line.strip_prefix("@check|") {
+                if let Some((table, sql)) = def.split_once('|')
+                    && let Some(metadata) = self.tables.get_mut(table)
+                    && let Some(check) = Self::parse_table_constraint(sql)
+                {
+                    metadata.checks.push(check);
+                }
+                continue;
+            }
+
+            if let Some(def) = line.strip_prefix("@foreign_key|") {
+                if let Some((table, sql)) = def.split_once('|')
+                    && let Some(metadata) = self.tables.get_mut(table)
+                    && let Some(foreign_key) = Self::parse_table_constraint(sql)
+                        .as_ref()
+                        .and_then(ForeignKey::from_constraint)
+                {
+                    metadata.foreign_keys.push(foreign_key);
+                }
+                continue;
+            }
+
+            if let Some(def) = line.strip_prefix("@comment|") {
+                if let Some((table, comment)) = def.split_once('|')
+                    && let Some(metadata) = self.tables.get_mut(table)
+                {
+                    metadata.comment = Some(comment.to_string());
+                }
+                continue;
+            }
+
+            if let Some(def) = line.strip_prefix("@stats|") {
+                if let Some((table, stats)) = def.split_once('|')
+                    && let Some(metadata) = self.tables.get_mut(table)
+                {
+                    metadata.stats = TableStats::from_catalog(stats);
+                }
+                continue;
+            }
+
             // Parse table metadata line
             let parts: Vec<&str> = line.split('|').collect();
-            if parts.len() != 4 {
+            if parts.len() < 4 {
                 continue;
             }
 
@@ -212,36 +1292,126 @@ impl Database {
             let first_page_id: PageId = parts[1].parse().unwrap_or(0);
             let last_page_id: PageId = parts[2].parse().unwrap_or(0);
             let column_count: usize = parts[3].parse().unwrap_or(0);
+            let auto_increment: i64 = parts.get(4).and_then(|p| p.parse().ok()).unwrap_or(0);
+            // Catalogs written before fingerprints were added don't have one
+            let fingerprint = parts.get(5).and_then(|p| u64::from_str_radix(p, 16).ok());
+            // Nor a row format; their rows are all tagged
+            let format = parts
+                .get(6)
+                .and_then(|p| RowFormat::from_name(p))
+                .unwrap_or_default();
+            let fill_factor = parts
+                .get(7)
+                .and_then(|p| p.parse().ok())
+                .unwrap_or(DEFAULT_FILL_FACTOR);
 
             // Parse columns
             let mut schema = Vec::new();
             for _ in 0..column_count {
                 if let Some(Ok(col_line)) = lines.next() {
                     let col_line = col_line.trim();
-                    let col_parts: Vec<&str> = col_line.split('|').collect();
-                    if col_parts.len() == 2 {
+                    let col_parts: Vec<&str> = col_line.splitn(3, '|').collect();
+                    if col_parts.len() >= 2 {
                         let col_name = sqlparser::ast::Ident::new(col_parts[0]);
                         let data_type = Self::string_to_datatype(col_parts[1]);
+                        let options = col_parts
+                            .get(2)
+                            .map(|sql| Self::parse_column_options(sql))
+                            .unwrap_or_default();
                         schema.push(ColumnDef {
                             name: col_name,
                             data_type,
-                            options: vec![],
+                            options,
                         });
                     }
                 }
             }
 
+            if fingerprint.is_some_and(|f| f != Self::schema_fingerprint(&schema)) {
+                warnings.push(format!(
+                    "Table '{}': columns don't match the schema fingerprint saved with them \
+                     (was the catalog edited?); its rows may be misread",
+                    table_name
+                ));
+            }
+
             self.tables.insert(
                 table_name,
                 TableMetadata {
                     schema,
                     first_page_id,
                     last_page_id,
+                    auto_increment,
+                    checks: Vec::new(),
+                    foreign_keys: Vec::new(),
+                    stats: None,
+                    format,
+                    fill_factor,
+                    comment: None,
                 },
             );
         }
 
-        Ok(())
+        Ok(warnings)
+    }
+
+    /// Hash of a table's column names and types as the catalog stores them.
+    /// FNV-1a, since unlike `DefaultHasher` it's the same in every build.
+    pub fn schema_fingerprint(schema: &[ColumnDef]) -> u64 {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for col in schema {
+            let entry = format!("{}:{};", col.name, Self::datatype_to_string(&col.data_type));
+            for byte in entry.bytes() {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        }
+        hash
+    }
+
+    /// Re-parse column options saved in the catalog as SQL text
+    fn parse_column_options(sql: &str) -> Vec<ColumnOptionDef> {
+        if sql.trim().is_empty() {
+            return vec![];
+        }
+
+        // Wrap the options in a throwaway column definition so the parser accepts them
+        Parser::new(&MatiDialect {})
+            .try_with_sql(&format!("c INT {}", sql))
+            .and_then(|mut parser| parser.parse_column_def())
+            .map(|def| def.options)
+            .unwrap_or_default()
+    }
+
+    /// Re-parse a table constraint saved in the catalog as SQL text
+    fn parse_table_constraint(sql: &str) -> Option<TableConstraint> {
+        Parser::new(&MatiDialect {})
+            .try_with_sql(sql)
+            .and_then(|mut parser| parser.parse_optional_table_constraint())
+            .ok()
+            .flatten()
+    }
+
+    /// The form of a column type that the catalog records, or `None` for a
+    /// type MatiDB doesn't support. Tables keep their declared types in this
+    /// form, so they read the same before and after a restart.
+    pub fn canonical_type(dt: &DataType) -> Option<DataType> {
+        match dt {
+            DataType::Int(_) | DataType::Integer(_) => Some(DataType::Int(None)),
+            DataType::BigInt(_) => Some(DataType::BigInt(None)),
+            DataType::SmallInt(_) => Some(DataType::SmallInt(None)),
+            DataType::Text => Some(DataType::Text),
+            DataType::Varchar(_) => Some(DataType::Varchar(None)),
+            DataType::Char(_) => Some(DataType::Char(None)),
+            DataType::String(_) => Some(DataType::String(None)),
+            DataType::Boolean | DataType::Bool => Some(DataType::Boolean),
+            DataType::Blob(_) => Some(DataType::Blob(None)),
+            DataType::Bytea => Some(DataType::Bytea),
+            DataType::Decimal(info) | DataType::Numeric(info) | DataType::Dec(info) => {
+                Some(DataType::Decimal(*info))
+            }
+            _ => None,
+        }
     }
 
     fn datatype_to_string(dt: &DataType) -> String {
@@ -253,7 +1423,10 @@ impl Database {
             DataType::Varchar(_) => "VARCHAR".to_string(),
             DataType::Char(_) => "CHAR".to_string(),
             DataType::String(_) => "STRING".to_string(),
-            DataType::Boolean => "BOOLEAN".to_string(),
+            DataType::Boolean | DataType::Bool => "BOOLEAN".to_string(),
+            DataType::Blob(_) => "BLOB".to_string(),
+            DataType::Bytea => "BYTEA".to_string(),
+            DataType::Decimal(_) | DataType::Numeric(_) | DataType::Dec(_) => dt.to_string(),
             _ => "TEXT".to_string(), // Default fallback
         }
     }
@@ -269,7 +1442,15 @@ impl Database {
             "CHAR" => DataType::Char(None),
             "STRING" => DataType::String(None),
             "BOOLEAN" => DataType::Boolean,
-            _ => DataType::Text, // Default fallback
+            "BLOB" => DataType::Blob(None),
+            "BYTEA" => DataType::Bytea,
+            // Types with parameters, like DECIMAL(10,2)
+            _ => Parser::new(&MatiDialect {})
+                .try_with_sql(s)
+                .and_then(|mut parser| parser.parse_data_type())
+                .ok()
+                .filter(|dt| matches!(dt, DataType::Decimal(_) | DataType::Numeric(_)))
+                .unwrap_or(DataType::Text), // Default fallback
         }
     }
 }
@@ -278,6 +1459,22 @@ impl Database {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_serialize_null() {
+        let row = Row {
+            values: vec![Value::Null, Value::Long(7), Value::Null],
+        };
+
+        let bytes = row.to_bytes();
+        assert_eq!(bytes.len(), 11);
+        let restored = Row::from_bytes(&bytes).unwrap();
+
+        assert_eq!(
+            restored.values,
+            vec![Value::Null, Value::Long(7), Value::Null]
+        );
+    }
+
     #[test]
     fn test_serialize_long() {
         let row = Row {
@@ -290,6 +1487,88 @@ mod tests {
         assert!(matches!(restored.values[0], Value::Long(42)));
     }
 
+    #[test]
+    fn test_serialize_decimal() {
+        let row = Row {
+            values: vec![Value::Decimal(Decimal::new(-1250, 3))],
+        };
+
+        let bytes = row.to_bytes();
+        assert_eq!(bytes.len(), 18);
+        let restored = Row::from_bytes(&bytes).unwrap();
+
+        // The scale is kept, not just the value
+        assert!(matches!(&restored.values[0], Value::Decimal(d) if d.to_string() == "-1.250"));
+        assert!(Row::from_bytes(&bytes[..10]).is_err());
+    }
+
+    fn compact_schema(types: &[DataType]) -> Vec<ColumnDef> {
+        types
+            .iter()
+            .enumerate()
+            .map(|(idx, data_type)| ColumnDef {
+                name: sqlparser::ast::Ident::new(format!("c{}", idx)),
+                data_type: data_type.clone(),
+                options: vec![],
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_compact_row_round_trip() {
+        // Ten columns, so the NULL bitmap takes two bytes
+        let mut types = vec![DataType::BigInt(None); 6];
+        types.extend([
+            DataType::Boolean,
+            DataType::Decimal(sqlparser::ast::ExactNumberInfo::PrecisionAndScale(10, 2)),
+            DataType::Text,
+            DataType::Bytea,
+        ]);
+        let schema = compact_schema(&types);
+        let mut values: Vec<Value> = (0..6).map(Value::Long).collect();
+        values.extend([
+            Value::Bool(true),
+            Value::Decimal(Decimal::new(1250, 2)),
+            Value::Text("héllo".to_string()),
+            Value::Null,
+        ]);
+        values[1] = Value::Null;
+        let row = Row { values };
+
+        let compact = row.encode(RowFormat::Compact, &schema).unwrap();
+        let restored = Row::decode_as(&compact, RowFormat::Compact, &schema).unwrap();
+        assert_eq!(restored.values, row.values);
+        assert_eq!(compact[..2], [0b0000_0010, 0b0000_0010]);
+
+        // No type tags: 2 bitmap bytes instead of one tag per value
+        let tagged = row.encode(RowFormat::Tagged, &schema).unwrap();
+        assert_eq!(tagged.len() - compact.len(), 10 - 2);
+
+        assert!(
+            Row::decode_as(&compact[..compact.len() - 1], RowFormat::Compact, &schema).is_err()
+        );
+        let mut padded = compact.clone();
+        padded.push(0);
+        let err = Row::decode_as(&padded, RowFormat::Compact, &schema).unwrap_err();
+        assert!(err.contains("trailing"));
+    }
+
+    #[test]
+    fn test_compact_row_needs_column_types() {
+        let schema = compact_schema(&[DataType::Int(None), DataType::Text]);
+        let row = Row {
+            values: vec![Value::Text("one".to_string()), Value::Null],
+        };
+        let err = row.encode(RowFormat::Compact, &schema).unwrap_err();
+        assert!(err.contains("Column 'c0' is INT"));
+        // The tagged format takes anything
+        assert!(row.encode(RowFormat::Tagged, &schema).is_ok());
+
+        let schema = compact_schema(&[DataType::Float(sqlparser::ast::ExactNumberInfo::None)]);
+        assert!(RowFormat::Compact.check_schema(&schema).is_err());
+        assert!(RowFormat::Tagged.check_schema(&schema).is_ok());
+    }
+
     #[test]
     fn test_serialize_text() {
         let row = Row {
@@ -315,6 +1594,23 @@ mod tests {
         assert!(matches!(restored.values[1], Value::Bool(false)));
     }
 
+    #[test]
+    fn test_serialize_blob() {
+        let data = vec![0xde, 0x00, 0xad, 0x00, 0x00];
+        let row = Row {
+            values: vec![Value::Blob(data.clone()), Value::Blob(vec![])],
+        };
+
+        let bytes = row.to_bytes();
+        let restored = Row::from_bytes(&bytes).unwrap();
+
+        assert_eq!(
+            restored.values,
+            vec![Value::Blob(data), Value::Blob(vec![])]
+        );
+        assert!(Row::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
     #[test]
     fn test_serialize_mixed() {
         let row = Row {
@@ -359,6 +1655,31 @@ mod tests {
         assert!(matches!(restored.values[1], Value::Long(n) if n == i64::MIN));
     }
 
+    #[test]
+    fn test_from_bytes_strict() {
+        let row = Row {
+            values: vec![Value::Long(7), Value::Text("Alice".to_string())],
+        };
+        let bytes = row.to_bytes();
+        assert_eq!(
+            Row::from_bytes_strict(&bytes, 2).unwrap().values,
+            row.values
+        );
+
+        // Extra bytes that happen to decode as another value
+        let mut padded = bytes.clone();
+        padded.extend_from_slice(&[2, 1]);
+        assert_eq!(Row::from_bytes(&padded).unwrap().values.len(), 3);
+        let err = Row::from_bytes_strict(&padded, 2).unwrap_err();
+        assert!(err.contains("2 trailing byte(s)"), "{}", err);
+
+        assert!(
+            Row::from_bytes_strict(&bytes, 3)
+                .unwrap_err()
+                .contains("Expected 3 values, found 2")
+        );
+    }
+
     #[test]
     fn test_from_bytes_invalid_tag() {
         let bytes = vec![99, 0, 0, 0, 0];
@@ -383,4 +1704,532 @@ mod tests {
 
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_scan_table() {
+        use crate::executor::execute;
+
+        let mut db = Database::in_memory();
+        let mut values = Vec::new();
+        for n in 1..=300 {
+            values.push(format!("({}, {})", n, n * 2));
+        }
+        for sql in [
+            "CREATE TABLE numbers (id INT, doubled INT)".to_string(),
+            format!("INSERT INTO numbers VALUES {}", values.join(", ")),
+        ] {
+            let stmt = Parser::parse_sql(&MatiDialect {}, &sql).unwrap().remove(0);
+            execute(&mut db, stmt).unwrap();
+        }
+        assert!(
+            db.table_pages(db.tables["numbers"].first_page_id)
+                .unwrap()
+                .len()
+                > 1
+        );
+
+        // Every row, across all the table's pages
+        let mut sum = 0;
+        db.scan_table("numbers", |row| {
+            if let Value::Long(n) = row.values[1] {
+                sum += n;
+            }
+            ControlFlow::Continue(())
+        })
+        .unwrap();
+        assert_eq!(sum, 300 * 301);
+
+        // Breaking stops the scan after that row
+        let mut ids = Vec::new();
+        db.scan_table("numbers", |row| {
+            ids.push(row.values[0].clone());
+            if ids.len() == 5 {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        })
+        .unwrap();
+        assert_eq!(ids, (1..=5).map(Value::Long).collect::<Vec<_>>());
+
+        let missing = db.scan_table("nope", |_| ControlFlow::Continue(()));
+        assert_eq!(missing.unwrap_err().kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_trim_trailing_pages() {
+        use crate::dialect::MatiDialect;
+        use crate::executor::execute;
+        use crate::storage::PAGE_SIZE;
+        use sqlparser::parser::Parser;
+        use std::fs;
+
+        let path = "test_database_trim.db";
+        let catalog = format!("{}.catalog", path);
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+
+        {
+            let mut db = Database::new(path).unwrap();
+            for sql in [
+                "CREATE TABLE users (id INT, name TEXT)",
+                "INSERT INTO users VALUES (1, 'Alice')",
+            ] {
+                let stmt = Parser::parse_sql(&MatiDialect {}, sql).unwrap().remove(0);
+                execute(&mut db, stmt).unwrap();
+            }
+
+            // Allocate a tail of pages that no table references
+            for _ in 0..3 {
+                db.buffer_pool.borrow_mut().create_page().unwrap();
+            }
+            db.buffer_pool.borrow_mut().flush_all().unwrap();
+            assert_eq!(fs::metadata(path).unwrap().len(), 4 * PAGE_SIZE as u64);
+
+            assert_eq!(db.trim_trailing_pages().unwrap(), 3);
+            assert_eq!(fs::metadata(path).unwrap().len(), PAGE_SIZE as u64);
+            assert_eq!(db.buffer_pool.borrow().num_pages(), 1);
+
+            // An empty page linked at the end of the table's chain is unlinked and dropped
+            let (page_id, page_rc) = db.buffer_pool.borrow_mut().create_page().unwrap();
+            SlottedPage::new(&mut page_rc.borrow_mut()).init();
+            let first_rc = db.buffer_pool.borrow_mut().fetch_page(0).unwrap();
+            SlottedPage::new(&mut first_rc.borrow_mut()).set_next_page_id(page_id);
+            db.tables.get_mut("users").unwrap().last_page_id = page_id;
+
+            assert_eq!(db.trim_trailing_pages().unwrap(), 1);
+            assert_eq!(db.tables["users"].last_page_id, 0);
+            assert_eq!(db.table_pages(0).unwrap(), vec![0]);
+        }
+
+        // The table's data survives the trim
+        {
+            let mut db = Database::new(path).unwrap();
+            let stmt = Parser::parse_sql(&MatiDialect {}, "SELECT * FROM users")
+                .unwrap()
+                .remove(0);
+            let result = execute(&mut db, stmt).unwrap();
+            assert!(result.contains("Alice"));
+        }
+
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+    }
+
+    #[test]
+    fn test_in_memory_database() {
+        use crate::dialect::MatiDialect;
+        use crate::executor::execute;
+        use sqlparser::parser::Parser;
+
+        let mut db = Database::in_memory();
+        let mut run = |sql: &str| {
+            let stmt = Parser::parse_sql(&MatiDialect {}, sql).unwrap().remove(0);
+            execute(&mut db, stmt).unwrap()
+        };
+
+        run("CREATE TABLE users (id INT, name TEXT)");
+        for i in 0..300 {
+            run(&format!("INSERT INTO users VALUES ({}, 'User{}')", i, i));
+        }
+
+        let result = run("SELECT * FROM users");
+        assert!(result.contains("User0"));
+        assert!(result.contains("User299"));
+        assert!(result.contains("(300 rows)"));
+    }
+
+    #[test]
+    fn test_inspect_page() {
+        use crate::dialect::MatiDialect;
+        use crate::executor::execute;
+        use sqlparser::parser::Parser;
+
+        let mut db = Database::in_memory();
+        let mut run = |sql: &str| {
+            let stmt = Parser::parse_sql(&MatiDialect {}, sql).unwrap().remove(0);
+            execute(&mut db, stmt).unwrap()
+        };
+
+        run("CREATE TABLE users (id INT, name TEXT)");
+        let values: Vec<String> = (0..300).map(|i| format!("({}, 'User{}')", i, i)).collect();
+        run(&format!("INSERT INTO users VALUES {}", values.join(", ")));
+
+        let pages = db.table_pages(0).unwrap();
+        let first_page_count = {
+            let page_rc = db.buffer_pool.borrow_mut().fetch_page(0).unwrap();
+            let mut page = *page_rc.borrow();
+            SlottedPage::new(&mut page).num_slots()
+        };
+
+        let description = db.inspect_page(0).unwrap();
+        assert!(description.starts_with(&format!(
+            "Page 0 (table users)\nnum_slots: {}\n",
+            first_page_count
+        )));
+        assert!(description.contains(&format!("\nnext_page_id: {}\n", pages[1])));
+        assert!(description.contains("\nslot 0: offset "));
+
+        let last = db.inspect_page(*pages.last().unwrap()).unwrap();
+        assert!(last.contains("\nnext_page_id: none\n"));
+
+        let err = db.inspect_page(pages.len() as PageId).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_recover_missing_pages() {
+        use crate::dialect::MatiDialect;
+        use crate::executor::execute;
+        use crate::storage::PAGE_SIZE;
+        use sqlparser::parser::Parser;
+        use std::fs;
+
+        let path = "test_database_recover.db";
+        let catalog = format!("{}.catalog", path);
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+
+        let run = |db: &mut Database, sql: &str| {
+            let stmt = Parser::parse_sql(&MatiDialect {}, sql).unwrap().remove(0);
+            execute(db, stmt).unwrap()
+        };
+
+        {
+            let mut db = Database::new(path).unwrap();
+            run(&mut db, "CREATE TABLE users (id INT, name TEXT)");
+            run(&mut db, "CREATE TABLE notes (body TEXT)");
+            let values: Vec<String> = (0..300).map(|i| format!("({}, 'User{}')", i, i)).collect();
+            run(
+                &mut db,
+                &format!("INSERT INTO users VALUES {}", values.join(", ")),
+            );
+
+            assert_eq!(db.tables["notes"].first_page_id, 1);
+            assert!(db.table_pages(0).unwrap().len() > 1);
+        }
+
+        // Lose every page but the first, as if they were never flushed
+        fs::OpenOptions::new()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_len(PAGE_SIZE as u64)
+            .unwrap();
+
+        {
+            let mut db = Database::new(path).unwrap();
+
+            // The users chain is cut after its first page, which kept its rows
+            assert_eq!(db.table_pages(0).unwrap(), vec![0]);
+            assert_eq!(db.tables["users"].last_page_id, 0);
+            let users = run(&mut db, "SELECT * FROM users");
+            assert!(users.contains("User0"));
+            assert!(!users.contains("User299"));
+
+            // notes lost its only page and starts over empty
+            assert!(run(&mut db, "SELECT * FROM notes").ends_with("(0 rows)"));
+            run(&mut db, "INSERT INTO notes VALUES ('hello')");
+            assert!(run(&mut db, "SELECT * FROM notes").ends_with("(1 rows)"));
+
+            assert!(db.recover_page_references().unwrap().is_empty());
+        }
+
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+    }
+
+    #[test]
+    fn test_recover_shared_missing_page() {
+        use crate::dialect::MatiDialect;
+        use crate::executor::execute;
+        use crate::storage::PAGE_SIZE;
+        use sqlparser::parser::Parser;
+        use std::fs;
+
+        let path = "test_database_recover_shared.db";
+        let catalog = format!("{}.catalog", path);
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+
+        let run = |db: &mut Database, sql: &str| {
+            let stmt = Parser::parse_sql(&MatiDialect {}, sql).unwrap().remove(0);
+            execute(db, stmt).unwrap()
+        };
+
+        {
+            let mut db = Database::new(path).unwrap();
+            // users fills pages 0 and 1, and notes starts on page 2
+            run(&mut db, "CREATE TABLE users (id INT, name TEXT)");
+            let values: Vec<String> = (0..200).map(|i| format!("({}, 'User{}')", i, i)).collect();
+            run(
+                &mut db,
+                &format!("INSERT INTO users VALUES {}", values.join(", ")),
+            );
+            assert_eq!(db.table_pages(0).unwrap(), vec![0, 1]);
+            run(&mut db, "CREATE TABLE notes (body TEXT)");
+            assert_eq!(db.tables["notes"].first_page_id, 2);
+        }
+
+        fs::OpenOptions::new()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_len(PAGE_SIZE as u64)
+            .unwrap();
+
+        {
+            let mut db = Database::new(path).unwrap();
+
+            // notes is repaired first and gets a new page 1, which users'
+            // chain linked to before it was lost
+            assert_eq!(db.tables["notes"].first_page_id, 1);
+            assert_eq!(db.table_pages(0).unwrap(), vec![0]);
+            run(&mut db, "INSERT INTO notes VALUES ('hello')");
+            assert!(!run(&mut db, "SELECT * FROM users").contains("hello"));
+            assert!(run(&mut db, "SELECT * FROM notes").ends_with("(1 rows)"));
+        }
+
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+    }
+
+    #[test]
+    fn test_schema_fingerprint_mismatch() {
+        use crate::executor::execute;
+        use std::fs;
+
+        let path = "test_database_fingerprint.db";
+        let catalog = format!("{}.catalog", path);
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+
+        {
+            let mut db = Database::new(path).unwrap();
+            let stmt = Parser::parse_sql(
+                &MatiDialect {},
+                "CREATE TABLE users (id INT, \"Name\" VARCHAR(20), balance DECIMAL(8, 2))",
+            )
+            .unwrap()
+            .remove(0);
+            execute(&mut db, stmt).unwrap();
+        }
+
+        // An untouched catalog matches its fingerprints
+        let mut db = Database::new(path).unwrap();
+        assert_eq!(db.load_catalog().unwrap(), Vec::<String>::new());
+        drop(db);
+
+        // Retype a column behind the data's back
+        let text = fs::read_to_string(&catalog).unwrap();
+        assert!(text.contains("  id|INT|"));
+        fs::write(&catalog, text.replace("  id|INT|", "  id|TEXT|")).unwrap();
+
+        let mut db = Database::new(path).unwrap();
+        let warnings = db.load_catalog().unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("Table 'users'"), "{}", warnings[0]);
+        drop(db);
+
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+    }
+
+    #[test]
+    fn test_catalog_is_deterministic() {
+        use crate::executor::execute;
+        use std::fs;
+
+        let path = "test_database_deterministic.db";
+        let catalog = format!("{}.catalog", path);
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+
+        {
+            let mut db = Database::new(path).unwrap();
+            for sql in [
+                "CREATE TABLE orders (id INT, user_id INT)",
+                "CREATE TABLE users (id INT, name TEXT)",
+                "CREATE TABLE audit (what TEXT)",
+                "CREATE TABLE items (sku TEXT, price DECIMAL(8, 2))",
+                "CREATE INDEX by_user ON orders (user_id)",
+                "CREATE INDEX by_name ON users (name)",
+            ] {
+                let stmt = Parser::parse_sql(&MatiDialect {}, sql).unwrap().remove(0);
+                execute(&mut db, stmt).unwrap();
+            }
+        }
+        let first = fs::read(&catalog).unwrap();
+
+        // Reopening builds new hash maps, which iterate in a different order
+        for _ in 0..3 {
+            Database::new(path).unwrap().save_catalog().unwrap();
+            assert_eq!(fs::read(&catalog).unwrap(), first);
+        }
+
+        let text = String::from_utf8(first).unwrap();
+        let tables: Vec<&str> = text
+            .lines()
+            .filter(|line| !line.starts_with([' ', '@']))
+            .map(|line| line.split('|').next().unwrap())
+            .collect();
+        assert_eq!(tables, vec!["audit", "items", "orders", "users"]);
+
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+    }
+
+    #[test]
+    fn test_repair_recovers_tables_without_catalog() {
+        use crate::executor::execute;
+        use std::fs;
+
+        let path = "test_database_repair.db";
+        let catalog = format!("{}.catalog", path);
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+
+        let run = |db: &mut Database, sql: &str| {
+            let stmt = Parser::parse_sql(&MatiDialect {}, sql).unwrap().remove(0);
+            execute(db, stmt).unwrap()
+        };
+        {
+            let mut db = Database::new(path).unwrap();
+            run(
+                &mut db,
+                "CREATE TABLE users (id INT, name TEXT, balance DECIMAL(8, 2))",
+            );
+            let values: Vec<String> = (0..300)
+                .map(|i| format!("({}, 'user{}', {}.50)", i, i, i))
+                .collect();
+            run(
+                &mut db,
+                &format!("INSERT INTO users VALUES {}", values.join(", ")),
+            );
+            run(&mut db, "DELETE FROM users WHERE id = 7");
+            run(&mut db, "CREATE TABLE empty (id INT)");
+            run(
+                &mut db,
+                "CREATE TABLE packed (id INT, name TEXT) WITH (row_format = 'compact')",
+            );
+            run(&mut db, "INSERT INTO packed VALUES (1, 'x')");
+            db.buffer_pool.borrow_mut().flush_all().unwrap();
+        }
+        fs::remove_file(&catalog).unwrap();
+
+        let mut db = Database::new(path).unwrap();
+        assert!(db.tables.is_empty());
+        let report = db.repair().unwrap();
+        assert_eq!(report.len(), 3, "{:?}", report);
+        assert!(
+            report[0].ends_with("recovered 299 row(s) of 3 column(s) as table 'recovered_0'"),
+            "{}",
+            report[0]
+        );
+        assert!(report[1].ends_with("skipped, no rows"), "{}", report[1]);
+        assert!(
+            report[2].contains("isn't a self-describing row"),
+            "{}",
+            report[2]
+        );
+        db.save_catalog().unwrap();
+        drop(db);
+
+        let mut db = Database::new(path).unwrap();
+        let table = &db.tables["recovered_0"];
+        assert!(db.table_pages(table.first_page_id).unwrap().len() > 1);
+        let types: Vec<String> = table
+            .schema
+            .iter()
+            .map(|col| format!("{} {}", col.name, col.data_type))
+            .collect();
+        assert_eq!(types, vec!["col1 INT", "col2 TEXT", "col3 DECIMAL(38,2)"]);
+        let result = run(
+            &mut db,
+            "SELECT col2, col3 FROM recovered_0 WHERE col1 = 299",
+        );
+        assert!(
+            result.ends_with("\nuser299\t299.50\n(1 rows)"),
+            "{}",
+            result
+        );
+
+        // Chains the catalog already knows about are left alone
+        assert!(
+            db.repair()
+                .unwrap()
+                .iter()
+                .all(|line| !line.contains("recovered"))
+        );
+
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+    }
+
+    #[test]
+    fn test_repair_cuts_links_out_of_the_chain() {
+        use crate::executor::execute;
+
+        let run = |db: &mut Database, sql: &str| {
+            let stmt = Parser::parse_sql(&MatiDialect {}, sql).unwrap().remove(0);
+            execute(db, stmt).unwrap()
+        };
+        let mut db = Database::in_memory();
+        run(&mut db, "CREATE TABLE lost (id INT)");
+        run(&mut db, "INSERT INTO lost VALUES (1), (2)");
+        run(&mut db, "CREATE TABLE kept (id INT)");
+        run(&mut db, "INSERT INTO kept VALUES (3)");
+        let (lost, kept) = (
+            db.tables["lost"].first_page_id,
+            db.tables["kept"].first_page_id,
+        );
+
+        // lost's catalog entry is gone, and its page links into kept's
+        db.tables.remove("lost");
+        let page_rc = db.buffer_pool.borrow_mut().fetch_page(lost).unwrap();
+        SlottedPage::new(&mut page_rc.borrow_mut()).set_next_page_id(kept);
+
+        let report = db.repair().unwrap();
+        assert_eq!(
+            report,
+            vec![format!(
+                "Page {}: recovered 2 row(s) of 1 column(s) as table 'recovered_{}'; \
+                 its link from page {} to page {} was cut",
+                lost, lost, lost, kept
+            )]
+        );
+        assert_eq!(db.table_pages(lost).unwrap(), vec![lost]);
+        let result = run(&mut db, &format!("SELECT col1 FROM recovered_{}", lost));
+        assert!(result.ends_with("\n1\n2\n(2 rows)"), "{}", result);
+        assert!(run(&mut db, "SELECT * FROM kept").ends_with("\n3\n(1 rows)"));
+    }
+
+    #[test]
+    fn test_create_fresh() {
+        use crate::executor::execute;
+        use std::fs;
+
+        let path = "test_database_fresh.db";
+        let catalog = format!("{}.catalog", path);
+
+        {
+            let mut db = Database::create_fresh(path).unwrap();
+            for sql in [
+                "CREATE TABLE users (id INT)",
+                "INSERT INTO users VALUES (1)",
+            ] {
+                let stmt = Parser::parse_sql(&MatiDialect {}, sql).unwrap().remove(0);
+                execute(&mut db, stmt).unwrap();
+            }
+        }
+        assert!(Database::new(path).unwrap().tables.contains_key("users"));
+
+        let db = Database::create_fresh(path).unwrap();
+        assert!(db.tables.is_empty());
+        assert_eq!(db.buffer_pool.borrow().num_pages(), 0);
+        drop(db);
+
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+    }
 }
@@ -0,0 +1,266 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+/// Most digits a decimal can hold, as in `DECIMAL(38, s)`
+pub const MAX_PRECISION: u8 = 38;
+
+/// An exact fixed-point number, `mantissa * 10^-scale`: `12.50` is 1250
+/// with scale 2.
+///
+/// The scale is kept as written so the value prints with the same number of
+/// fractional digits, but it doesn't take part in comparisons: `1.5 = 1.50`.
+#[derive(Debug, Clone, Copy)]
+pub struct Decimal {
+    mantissa: i128,
+    scale: u8,
+}
+
+impl Decimal {
+    pub fn new(mantissa: i128, scale: u8) -> Self {
+        Self { mantissa, scale }
+    }
+
+    pub fn from_i64(n: i64) -> Self {
+        Self::new(n as i128, 0)
+    }
+
+    pub fn mantissa(&self) -> i128 {
+        self.mantissa
+    }
+
+    pub fn scale(&self) -> u8 {
+        self.scale
+    }
+
+    /// Parse a numeric literal such as `12.50`, `-1_000.5` or `2.5e-3`.
+    /// Returns `None` if it isn't one or needs more than `MAX_PRECISION`
+    /// digits.
+    pub fn parse(literal: &str) -> Option<Self> {
+        let (negative, literal) = match literal.strip_prefix('-') {
+            Some(unsigned) => (true, unsigned),
+            None => (false, literal),
+        };
+        let digits_only = literal.replace('_', "");
+        let (mantissa, exponent) = match digits_only.split_once(['e', 'E']) {
+            Some((mantissa, exponent)) => (mantissa, exponent.parse::<i64>().ok()?),
+            None => (digits_only.as_str(), 0),
+        };
+        let (whole, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+        let digits = format!("{}{}", whole, fraction);
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+
+        let mut value: i128 = digits.parse().ok()?;
+        let mut scale = (fraction.len() as i64).checked_sub(exponent)?;
+        if scale < 0 {
+            value = value.checked_mul(10i128.checked_pow(u32::try_from(-scale).ok()?)?)?;
+            scale = 0;
+        }
+        while scale > MAX_PRECISION as i64 && value % 10 == 0 {
+            value /= 10;
+            scale -= 1;
+        }
+        if negative {
+            value = -value;
+        }
+        let decimal = Self::new(value, u8::try_from(scale).ok()?);
+        (scale <= MAX_PRECISION as i64 && decimal.precision() <= MAX_PRECISION).then_some(decimal)
+    }
+
+    /// Number of digits in the mantissa, so `12.50` has four. Leading zeros
+    /// after the point count too: `0.05` has two.
+    pub fn precision(&self) -> u8 {
+        let digits = self
+            .mantissa
+            .unsigned_abs()
+            .checked_ilog10()
+            .map_or(1, |n| n + 1);
+        (digits as u8).max(self.scale)
+    }
+
+    /// The same value with `scale` fractional digits, rounding half away
+    /// from zero if that drops digits. `None` if it doesn't fit.
+    pub fn rescale(self, scale: u8) -> Option<Self> {
+        match scale.cmp(&self.scale) {
+            Ordering::Equal => Some(self),
+            Ordering::Greater => {
+                let factor = 10i128.checked_pow((scale - self.scale) as u32)?;
+                Some(Self::new(self.mantissa.checked_mul(factor)?, scale))
+            }
+            Ordering::Less => {
+                let divisor = 10i128.pow((self.scale - scale) as u32);
+                let mut mantissa = self.mantissa / divisor;
+                let remainder = (self.mantissa % divisor).unsigned_abs();
+                if remainder * 2 >= divisor.unsigned_abs() {
+                    mantissa += self.mantissa.signum();
+                }
+                Some(Self::new(mantissa, scale))
+            }
+        }
+    }
+
+    /// Exact sum, with as many fractional digits as the more precise operand
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        let scale = self.scale.max(other.scale);
+        let (a, b) = (self.rescale(scale)?, other.rescale(scale)?);
+        Some(Self::new(a.mantissa.checked_add(b.mantissa)?, scale))
+    }
+
+    /// Exact difference, with as many fractional digits as the more precise
+    /// operand
+    pub fn checked_sub(self, other: Self) -> Option<Self> {
+        self.checked_add(other.checked_neg()?)
+    }
+
+    /// The same value with the opposite sign
+    pub fn checked_neg(self) -> Option<Self> {
+        Some(Self::new(self.mantissa.checked_neg()?, self.scale))
+    }
+
+    /// Exact product, with the fractional digits of both operands together,
+    /// rounded to `MAX_PRECISION` of them if that's more
+    pub fn checked_mul(self, other: Self) -> Option<Self> {
+        let mantissa = self.mantissa.checked_mul(other.mantissa)?;
+        let product = Self::new(mantissa, self.scale + other.scale);
+        product.rescale(product.scale.min(MAX_PRECISION))
+    }
+
+    /// The value with trailing fractional zeros removed, so equal values
+    /// have equal representations
+    fn normalized(self) -> (i128, u8) {
+        let (mut mantissa, mut scale) = (self.mantissa, self.scale);
+        while scale > 0 && mantissa % 10 == 0 {
+            mantissa /= 10;
+            scale -= 1;
+        }
+        (mantissa, scale)
+    }
+}
+
+impl PartialEq for Decimal {
+    fn eq(&self, other: &Self) -> bool {
+        self.normalized() == other.normalized()
+    }
+}
+
+impl Eq for Decimal {}
+
+impl Hash for Decimal {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.normalized().hash(state);
+    }
+}
+
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Decimal {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let scale = self.scale.max(other.scale);
+        match (self.rescale(scale), other.rescale(scale)) {
+            (Some(a), Some(b)) => a.mantissa.cmp(&b.mantissa),
+            // Only a value too large to scale up can fail, and that one is
+            // further from zero than the other
+            (None, _) if self.mantissa < 0 => Ordering::Less,
+            (None, _) => Ordering::Greater,
+            (_, None) if other.mantissa < 0 => Ordering::Greater,
+            (_, None) => Ordering::Less,
+        }
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scale = self.scale as usize;
+        let digits = format!(
+            "{:0>width$}",
+            self.mantissa.unsigned_abs(),
+            width = scale + 1
+        );
+        let (whole, fraction) = digits.split_at(digits.len() - scale);
+        let sign = if self.mantissa < 0 { "-" } else { "" };
+
+        if fraction.is_empty() {
+            write!(f, "{}{}", sign, whole)
+        } else {
+            write!(f, "{}{}.{}", sign, whole, fraction)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dec(literal: &str) -> Decimal {
+        Decimal::parse(literal).unwrap()
+    }
+
+    #[test]
+    fn test_parse_and_display() {
+        assert_eq!(dec("12.50").to_string(), "12.50");
+        assert_eq!(dec("0.05").to_string(), "0.05");
+        assert_eq!(dec("1_000.5").to_string(), "1000.5");
+        assert_eq!(dec("2.5e-3").to_string(), "0.0025");
+        assert_eq!(dec("2.5e3").to_string(), "2500");
+        assert_eq!(Decimal::new(-5, 3).to_string(), "-0.005");
+        assert_eq!(dec("-12.50"), Decimal::new(-1250, 2));
+        assert_eq!(dec("0.05").precision(), 2);
+        assert_eq!(dec("12.50").precision(), 4);
+
+        assert_eq!(Decimal::parse("1.2.3"), None);
+        assert_eq!(Decimal::parse("."), None);
+        assert_eq!(Decimal::parse("--1"), None);
+        assert_eq!(Decimal::parse("1e100"), None);
+    }
+
+    #[test]
+    fn test_exact_addition() {
+        // 0.1 + 0.2 is 0.30000000000000004 in binary floating point
+        assert_ne!(0.1f64 + 0.2f64, 0.3f64);
+        let sum = dec("0.1").checked_add(dec("0.2")).unwrap();
+        assert_eq!(sum, dec("0.3"));
+        assert_eq!(sum.to_string(), "0.3");
+
+        assert_eq!(
+            dec("1.25").checked_add(dec("2")).unwrap().to_string(),
+            "3.25"
+        );
+        assert_eq!(
+            dec("1").checked_sub(dec("1.005")).unwrap().to_string(),
+            "-0.005"
+        );
+        assert_eq!(Decimal::new(i128::MAX, 0).checked_add(dec("1")), None);
+
+        assert_eq!(
+            dec("19.99").checked_mul(dec("3")).unwrap().to_string(),
+            "59.97"
+        );
+        assert_eq!(
+            dec("-0.5").checked_mul(dec("0.25")).unwrap().to_string(),
+            "-0.125"
+        );
+        assert_eq!(Decimal::new(i128::MAX, 0).checked_mul(dec("2")), None);
+    }
+
+    #[test]
+    fn test_rescale_and_ordering() {
+        assert_eq!(dec("1.5").rescale(3).unwrap().to_string(), "1.500");
+        assert_eq!(dec("1.005").rescale(2).unwrap().to_string(), "1.01");
+        assert_eq!(dec("1.004").rescale(2).unwrap().to_string(), "1.00");
+        assert_eq!(
+            Decimal::new(-1005, 3).rescale(2).unwrap().to_string(),
+            "-1.01"
+        );
+
+        assert_eq!(dec("1.5"), dec("1.50"));
+        assert!(dec("0.3") < dec("0.31"));
+        assert!(Decimal::new(-1, 0) < dec("0.001"));
+        assert!(Decimal::new(i128::MAX, 0) > dec("0.5"));
+    }
+}
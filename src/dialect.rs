@@ -0,0 +1,118 @@
+use sqlparser::ast::{Ident, Statement, Value};
+use sqlparser::dialect::{Dialect, GenericDialect};
+use sqlparser::keywords::Keyword;
+use sqlparser::parser::{Parser, ParserError};
+use sqlparser::tokenizer::Token;
+use std::any::TypeId;
+
+/// The SQL dialect MatiDB parses: sqlparser's generic dialect, plus `_`
+/// between the digits of a number (`1_000_000`) and a bare table name in
+/// `PRAGMA table_pages(users)`. The parser's checks for the generic dialect
+/// match this one too.
+#[derive(Debug, Default)]
+pub struct MatiDialect;
+
+/// Forward methods that take no arguments to the generic dialect
+macro_rules! generic_flags {
+    ($($name:ident),* $(,)?) => {
+        $(
+            fn $name(&self) -> bool {
+                GenericDialect {}.$name()
+            }
+        )*
+    };
+}
+
+impl Dialect for MatiDialect {
+    fn dialect(&self) -> TypeId {
+        TypeId::of::<GenericDialect>()
+    }
+
+    fn supports_numeric_literal_underscores(&self) -> bool {
+        true
+    }
+
+    fn is_delimited_identifier_start(&self, ch: char) -> bool {
+        GenericDialect {}.is_delimited_identifier_start(ch)
+    }
+
+    fn is_identifier_start(&self, ch: char) -> bool {
+        GenericDialect {}.is_identifier_start(ch)
+    }
+
+    fn is_identifier_part(&self, ch: char) -> bool {
+        GenericDialect {}.is_identifier_part(ch)
+    }
+
+    /// sqlparser only takes a literal inside `PRAGMA name(...)`, so a bare
+    /// name there is read as if it were quoted. Anything else is left to the
+    /// generic parser.
+    fn parse_statement(&self, parser: &mut Parser) -> Option<Result<Statement, ParserError>> {
+        let tokens: Vec<Token> = (0..5).map(|n| parser.peek_nth_token(n).token).collect();
+        let [
+            Token::Word(pragma),
+            Token::Word(name),
+            Token::LParen,
+            Token::Word(argument),
+            Token::RParen,
+        ] = tokens.as_slice()
+        else {
+            return None;
+        };
+        if pragma.keyword != Keyword::PRAGMA {
+            return None;
+        }
+
+        let statement = Statement::Pragma {
+            name: vec![Ident::new(&name.value)].into(),
+            value: Some(Value::SingleQuotedString(argument.value.clone())),
+            is_eq: false,
+        };
+        for _ in 0..tokens.len() {
+            parser.advance_token();
+        }
+        Some(Ok(statement))
+    }
+
+    generic_flags!(
+        supports_unicode_string_literal,
+        supports_group_by_expr,
+        supports_group_by_with_modifier,
+        supports_left_associative_joins_without_parens,
+        supports_connect_by,
+        supports_match_recognize,
+        supports_pipe_operator,
+        supports_start_transaction_modifier,
+        supports_window_function_null_treatment_arg,
+        supports_dictionary_syntax,
+        supports_window_clause_named_window_reference,
+        supports_parenthesized_set_variables,
+        supports_select_wildcard_except,
+        support_map_literal_syntax,
+        allow_extract_custom,
+        allow_extract_single_quotes,
+        supports_create_index_with_clause,
+        supports_explain_with_utility_options,
+        supports_limit_comma,
+        supports_from_first_select,
+        supports_projection_trailing_commas,
+        supports_asc_desc_in_column_definition,
+        supports_try_convert,
+        supports_comment_on,
+        supports_load_extension,
+        supports_named_fn_args_with_assignment_operator,
+        supports_struct_literal,
+        supports_empty_projections,
+        supports_nested_comments,
+        supports_user_host_grantee,
+        supports_string_escape_constant,
+        supports_array_typedef_with_brackets,
+        supports_match_against,
+        supports_set_names,
+        supports_comma_separated_set_assignments,
+        supports_filter_during_aggregation,
+        supports_select_wildcard_exclude,
+        supports_data_type_signed_suffix,
+        supports_interval_options,
+    );
+}
@@ -0,0 +1,273 @@
+use crate::database::{DEFAULT_FILL_FACTOR, Database, RowFormat, Value};
+use crate::dialect::MatiDialect;
+use crate::executor::{ExecError, QueryStats, execute, is_system_column, scan_table};
+use sqlparser::parser::Parser;
+
+/// Rows per INSERT statement in a dump
+const INSERT_BATCH: usize = 100;
+
+/// Write the whole database out as SQL: a `CREATE TABLE` and `INSERT`s for
+/// every table, then its foreign keys and indexes. Foreign keys come after
+/// all the rows, so tables can be restored in any order.
+///
+/// This is a logical backup. It doesn't depend on the page layout, so it can
+/// be replayed with `restore` into a database written by a newer version.
+pub fn dump(db: &Database) -> Result<String, ExecError> {
+    let mut output = String::new();
+
+    let mut table_names: Vec<&String> = db.tables.keys().collect();
+    table_names.sort();
+
+    for &table_name in &table_names {
+        let metadata = &db.tables[table_name];
+
+        // A `_created_at` column is recreated by the table option, and
+        // restored rows are stamped with the time they're restored
+        let columns: Vec<usize> = (0..metadata.schema.len())
+            .filter(|&idx| !is_system_column(&metadata.schema[idx].name.value))
+            .collect();
+        let mut elements: Vec<String> = columns
+            .iter()
+            .map(|&idx| metadata.schema[idx].to_string())
+            .collect();
+        elements.extend(metadata.checks.iter().map(|c| c.to_string()));
+        let mut options = Vec::new();
+        if metadata.format != RowFormat::Tagged {
+            options.push(format!("row_format = '{}'", metadata.format.name()));
+        }
+        if columns.len() < metadata.schema.len() {
+            options.push("created_at = true".to_string());
+        }
+        if metadata.fill_factor != DEFAULT_FILL_FACTOR {
+            options.push(format!("fillfactor = {}", metadata.fill_factor));
+        }
+        let options = if options.is_empty() {
+            String::new()
+        } else {
+            format!(" WITH ({})", options.join(", "))
+        };
+        output.push_str(&format!(
+            "CREATE TABLE {} ({}){};\n",
+            table_name,
+            elements.join(", "),
+            options
+        ));
+        // Column comments are column options, so they're in the CREATE TABLE
+        if let Some(comment) = &metadata.comment {
+            output.push_str(&format!(
+                "COMMENT ON TABLE {} IS {};\n",
+                table_name,
+                sql_literal(&Value::Text(comment.clone()))
+            ));
+        }
+
+        let mut batch = Vec::new();
+        let mut stats = QueryStats::default();
+        scan_table(db, metadata, &mut stats, |_, row| {
+            let values: Vec<String> = columns
+                .iter()
+                .map(|&idx| {
+                    row.values
+                        .get(idx)
+                        .map_or_else(|| "NULL".to_string(), sql_literal)
+                })
+                .collect();
+            batch.push(format!("({})", values.join(", ")));
+            if batch.len() == INSERT_BATCH {
+                push_insert(&mut output, table_name, &mut batch);
+            }
+            Ok(true)
+        })?;
+        push_insert(&mut output, table_name, &mut batch);
+    }
+
+    for table_name in table_names {
+        for foreign_key in &db.tables[table_name].foreign_keys {
+            output.push_str(&format!(
+                "ALTER TABLE {} ADD {};\n",
+                table_name, foreign_key
+            ));
+        }
+    }
+
+    let mut indexes: Vec<_> = db.indexes.values().collect();
+    indexes.sort_by(|a, b| a.name.cmp(&b.name));
+    for index in indexes {
+        output.push_str(&format!(
+            "CREATE {}INDEX {} ON {} ({});\n",
+            if index.unique { "UNIQUE " } else { "" },
+            index.name,
+            index.table,
+            index.columns.join(", ")
+        ));
+    }
+
+    Ok(output)
+}
+
+/// Replay a dump into `db`, returning the number of statements run.
+/// Runs in a transaction, so a failing statement leaves `db` unchanged.
+pub fn restore(db: &mut Database, sql: &str) -> Result<usize, String> {
+    let statements = Parser::parse_sql(&MatiDialect {}, sql).map_err(|e| e.to_string())?;
+    let count = statements.len();
+
+    if db.in_transaction() {
+        return Err("Cannot restore inside a transaction".to_string());
+    }
+    db.begin_transaction();
+    for stmt in statements {
+        let description = stmt.to_string();
+        if let Err(e) = execute(db, stmt) {
+            db.rollback_transaction().map_err(|e| e.to_string())?;
+            return Err(format!("{} (in: {})", e, summarize(&description)));
+        }
+    }
+    db.commit_transaction();
+
+    Ok(count)
+}
+
+fn push_insert(output: &mut String, table_name: &str, batch: &mut Vec<String>) {
+    if !batch.is_empty() {
+        output.push_str(&format!(
+            "INSERT INTO {} VALUES {};\n",
+            table_name,
+            batch.join(", ")
+        ));
+        batch.clear();
+    }
+}
+
+/// Render a value as a SQL literal that parses back to the same value
+fn sql_literal(value: &Value) -> String {
+    match value {
+        Value::Long(n) => n.to_string(),
+        Value::Decimal(d) => d.to_string(),
+        Value::Text(s) => format!("'{}'", s.replace('\'', "''")),
+        Value::Bool(b) => b.to_string().to_uppercase(),
+        Value::Blob(data) => {
+            let hex: String = data.iter().map(|b| format!("{:02X}", b)).collect();
+            format!("X'{}'", hex)
+        }
+        Value::Null => "NULL".to_string(),
+    }
+}
+
+/// The start of a statement, for error messages
+fn summarize(sql: &str) -> String {
+    match sql.char_indices().nth(60) {
+        Some((end, _)) => format!("{}...", &sql[..end]),
+        None => sql.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(sql: &str) -> sqlparser::ast::Statement {
+        Parser::parse_sql(&MatiDialect {}, sql).unwrap().remove(0)
+    }
+
+    fn run(db: &mut Database, sql: &str) -> String {
+        execute(db, parse(sql)).unwrap()
+    }
+
+    #[test]
+    fn test_dump_and_restore() {
+        let mut db = Database::in_memory();
+        run(
+            &mut db,
+            "CREATE TABLE users (id INT AUTOINCREMENT PRIMARY KEY, name TEXT DEFAULT 'anon', active BOOLEAN)",
+        );
+        run(&mut db, "COMMENT ON TABLE users IS 'Everyone''s accounts'");
+        run(&mut db, "COMMENT ON COLUMN users.name IS 'display name'");
+        run(
+            &mut db,
+            "CREATE TABLE files (name TEXT, body BLOB) WITH (row_format = 'compact', created_at = true, fillfactor = 70)",
+        );
+        run(&mut db, "CREATE INDEX by_name ON users (name)");
+        for i in 0..250 {
+            run(
+                &mut db,
+                &format!(
+                    "INSERT INTO users (name, active) VALUES ('O''Brien {}', {})",
+                    i,
+                    i % 2 == 0
+                ),
+            );
+        }
+        run(&mut db, "INSERT INTO files VALUES ('a\nb', X'00FF10')");
+
+        let sql = dump(&db).unwrap();
+        // The hidden creation time is left out of the rows
+        assert!(sql.starts_with(
+            "CREATE TABLE files (name TEXT, body BLOB) WITH (row_format = 'compact', created_at = true, fillfactor = 70);\n"
+        ));
+        assert!(sql.contains("INSERT INTO files VALUES ('a\nb', X'00FF10');\n"));
+
+        let mut restored = Database::in_memory();
+        let count = restore(&mut restored, &sql).unwrap();
+        // Two tables, a comment, three user batches, one file batch and one index
+        assert_eq!(count, 8);
+
+        for query in [
+            "SELECT * FROM users",
+            "SELECT * FROM files",
+            "SELECT id FROM users WHERE name = 'O''Brien 42'",
+        ] {
+            assert_eq!(run(&mut restored, query), run(&mut db, query));
+        }
+        assert_eq!(dump(&restored).unwrap(), sql);
+        assert_eq!(restored.tables["files"].format, RowFormat::Compact);
+        assert_eq!(
+            run(&mut restored, "DESCRIBE users"),
+            run(&mut db, "DESCRIBE users")
+        );
+
+        // Column options come back too
+        let result = run(
+            &mut restored,
+            "INSERT INTO users (active) VALUES (TRUE) RETURNING id, name",
+        );
+        assert!(result.contains("251\tanon"));
+    }
+
+    #[test]
+    fn test_dump_foreign_keys() {
+        let mut db = Database::in_memory();
+        run(
+            &mut db,
+            "CREATE TABLE staff (id INT PRIMARY KEY, boss INT REFERENCES staff ON DELETE SET NULL)",
+        );
+        run(
+            &mut db,
+            "CREATE TABLE desks (owner INT REFERENCES staff (id))",
+        );
+        // Rows refer to rows that come after them
+        run(&mut db, "INSERT INTO staff VALUES (2, 1), (1, NULL)");
+        run(&mut db, "INSERT INTO desks VALUES (2)");
+
+        let sql = dump(&db).unwrap();
+        assert!(sql.contains(
+            "ALTER TABLE staff ADD FOREIGN KEY (boss) REFERENCES staff (id) ON DELETE SET NULL;\n"
+        ));
+        let mut restored = Database::in_memory();
+        restore(&mut restored, &sql).unwrap();
+        assert_eq!(dump(&restored).unwrap(), sql);
+        assert!(execute(&mut restored, parse("INSERT INTO desks VALUES (3)")).is_err());
+    }
+
+    #[test]
+    fn test_failed_restore_leaves_database_unchanged() {
+        let mut db = Database::in_memory();
+        run(&mut db, "CREATE TABLE users (id INT)");
+
+        let sql = "CREATE TABLE notes (body TEXT); INSERT INTO notes VALUES ('x'); CREATE TABLE users (id INT);";
+        let err = restore(&mut db, sql).unwrap_err();
+
+        assert!(err.contains("already exists"));
+        assert!(!db.tables.contains_key("notes"));
+        assert!(restore(&mut db, "NOT SQL").is_err());
+    }
+}
@@ -1,36 +1,321 @@
-use crate::database::{Database, Row, TableMetadata, Value};
+use crate::aggregate::{Grouping, contains_aggregate, is_aggregate};
+use crate::copy;
+use crate::database::{
+    DEFAULT_FILL_FACTOR, Database, ForeignKey, MIN_FILL_FACTOR, Row, RowFormat, RowId,
+    TableMetadata, Value, recovered_type,
+};
+use crate::decimal::{Decimal, MAX_PRECISION};
+use crate::expr::{
+    Collation, column_collation, column_index, column_name, column_position, compare_values,
+    eval_expr, eval_predicate, expr_collation, function_args, negate, rename_column_refs,
+    unqualified, walk_expr_mut,
+};
+use crate::index::Index;
+use crate::result::QueryResult;
+use crate::sample::{SampleSize, Sampler};
 use crate::slotted_page::{NO_NEXT_PAGE, SlottedPage};
+use crate::sort::{SortRow, SortRuns};
+use crate::stats::{StatsCollector, TableStats};
 use crate::storage::PageId;
-use sqlparser::ast::{ColumnDef, Expr, ObjectName, SetExpr, Statement, Values};
+use sqlparser::ast::{
+    AlterTableOperation, Assignment, AssignmentTarget, BinaryOperator, ColumnDef, ColumnOption,
+    ColumnOptionDef, CommentObject, ConflictTarget, CreateIndex, CreateTableOptions, DataType,
+    DeferrableInitial, Delete, ExactNumberInfo, Expr, FromTable, FunctionArg, FunctionArgExpr,
+    FunctionArguments, GroupByExpr, Ident, Insert, JoinConstraint, JoinOperator, LimitClause,
+    ObjectName, OnConflictAction, OnInsert, OrderByKind, ReferentialAction, RenameTableNameKind,
+    Select, SelectItem, SetExpr, SetOperator, SetQuantifier, SqlOption, Statement, TableConstraint,
+    TableFactor, TableSampleKind, TableSampleMethod, TableSampleUnit, TableWithJoins,
+    UnaryOperator, Values, With,
+};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
-pub fn execute(db: &mut Database, stmt: Statement) -> Result<String, String> {
+/// Errors produced while executing a statement.
+///
+/// Each variant corresponds to an error category that is reported to
+/// clients alongside the message, so they can branch on the kind of
+/// failure instead of parsing the text.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExecError {
+    /// A referenced table or column does not exist
+    NotFound(String),
+    /// An object with the same name already exists
+    AlreadyExists(String),
+    /// A row would violate a PRIMARY KEY, UNIQUE, NOT NULL or CHECK constraint
+    Constraint(String),
+    /// A value could not be interpreted as the expected type
+    Type(String),
+    /// The statement uses a feature MatiDB doesn't implement
+    Unsupported(String),
+    /// Reading or writing pages/catalog failed, or stored data is corrupt
+    Storage(String),
+}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecError::NotFound(msg)
+            | ExecError::AlreadyExists(msg)
+            | ExecError::Constraint(msg)
+            | ExecError::Type(msg)
+            | ExecError::Unsupported(msg)
+            | ExecError::Storage(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ExecError {}
+
+impl From<std::io::Error> for ExecError {
+    fn from(e: std::io::Error) -> Self {
+        ExecError::Storage(e.to_string())
+    }
+}
+
+/// Diagnostics collected while running a query, to judge how selective it was
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueryStats {
+    /// Tuples read from pages, whether or not they matched
+    pub rows_scanned: usize,
+    /// Rows that made it into the result
+    pub rows_returned: usize,
+    /// Rows read that the WHERE clause rejected
+    pub rows_filtered: usize,
+    /// Pages fetched from the buffer pool
+    pub pages_read: usize,
+}
+
+impl fmt::Display for QueryStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "rows scanned: {}, rows returned: {}, pages read: {}",
+            self.rows_scanned, self.rows_returned, self.pages_read
+        )
+    }
+}
+
+pub fn execute(db: &mut Database, stmt: Statement) -> Result<String, ExecError> {
+    execute_with_stats(db, stmt).map(|(output, _)| output)
+}
+
+/// Run a SELECT and return its rows as values rather than formatted text
+#[allow(dead_code)]
+pub fn query(db: &Database, stmt: Statement) -> Result<QueryResult, ExecError> {
     match stmt {
-        Statement::CreateTable(create_table) => {
-            handle_create_table(db, create_table.name, create_table.columns)
+        Statement::Query(query) => {
+            select_rows(db, *query, &CommonTables::new(), &mut QueryStats::default())
         }
-        Statement::Insert(insert) => handle_insert(db, insert.table, insert.source),
-        Statement::Query(query) => handle_query(db, *query),
-        _ => Err("Unsupported statement".to_string()),
+        _ => Err(ExecError::Unsupported(
+            "Only a SELECT returns a QueryResult".to_string(),
+        )),
     }
 }
 
+/// Execute a statement, also returning scan diagnostics.
+/// Statements that don't read table data report zeroed stats.
+pub fn execute_with_stats(
+    db: &mut Database,
+    stmt: Statement,
+) -> Result<(String, QueryStats), ExecError> {
+    let mut stats = QueryStats::default();
+    let output = match stmt {
+        Statement::CreateTable(create_table) => handle_create_table(
+            db,
+            create_table.name,
+            create_table.columns,
+            create_table.constraints,
+            create_table.table_options,
+        ),
+        Statement::CreateIndex(create_index) => handle_create_index(db, create_index),
+        Statement::Insert(insert) => handle_insert(db, insert),
+        copy @ Statement::Copy { .. } => copy::copy_to_stdout(db, copy),
+        Statement::Query(query) => handle_query(db, *query, &mut stats),
+        Statement::Explain {
+            statement, analyze, ..
+        } => handle_explain(db, *statement, analyze, &mut stats),
+        Statement::Update {
+            table,
+            assignments,
+            from,
+            selection,
+            returning,
+            ..
+        } => {
+            if from.is_some() {
+                return Err(ExecError::Unsupported(
+                    "UPDATE ... FROM is not supported".to_string(),
+                ));
+            }
+            handle_update(db, table, assignments, selection, returning)
+        }
+        Statement::Delete(delete) => handle_delete(db, delete),
+        Statement::StartTransaction { .. } => {
+            if db.in_transaction() {
+                return Err(ExecError::AlreadyExists(
+                    "A transaction is already active".to_string(),
+                ));
+            }
+            db.begin_transaction();
+            Ok("BEGIN".to_string())
+        }
+        Statement::Commit { .. } => {
+            if !db.in_transaction() {
+                return Err(ExecError::NotFound("No transaction is active".to_string()));
+            }
+            if let Err(err) = check_deferred_unique(db) {
+                db.rollback_transaction()?;
+                return Err(ExecError::Constraint(format!(
+                    "{}; the transaction was rolled back",
+                    err
+                )));
+            }
+            db.commit_transaction();
+            Ok("COMMIT".to_string())
+        }
+        Statement::Rollback { savepoint, .. } => {
+            if savepoint.is_some() {
+                return Err(ExecError::Unsupported(
+                    "Savepoints are not supported".to_string(),
+                ));
+            }
+            if !db.in_transaction() {
+                return Err(ExecError::NotFound("No transaction is active".to_string()));
+            }
+            db.rollback_transaction()?;
+            Ok("ROLLBACK".to_string())
+        }
+        Statement::Vacuum(_) => {
+            // Truncating the file would discard pages ROLLBACK may need
+            if db.in_transaction() {
+                return Err(ExecError::Unsupported(
+                    "VACUUM cannot run inside a transaction".to_string(),
+                ));
+            }
+            let removed = db.trim_trailing_pages()?;
+            Ok(format!("Reclaimed {} page(s)", removed))
+        }
+        Statement::Analyze { table_name, .. } => handle_analyze(db, table_name, &mut stats),
+        Statement::AlterTable {
+            name, operations, ..
+        } => handle_alter_table(db, name, operations),
+        Statement::RenameTable(renames) => renames
+            .into_iter()
+            .map(|r| rename_table(db, &r.old_name.to_string(), r.new_name.to_string()))
+            .collect::<Result<Vec<_>, _>>()
+            .map(|messages| messages.join("\n")),
+        Statement::Pragma { name, value, is_eq } => handle_pragma(db, &name, value.as_ref(), is_eq),
+        Statement::Comment {
+            object_type,
+            object_name,
+            comment,
+            if_exists,
+        } => handle_comment(db, object_type, &object_name, comment, if_exists),
+        Statement::ExplainTable { table_name, .. } => handle_describe(db, &table_name.to_string()),
+        // Cursors live in a server connection's session
+        Statement::Declare { .. } | Statement::Fetch { .. } | Statement::Close { .. } => Err(
+            ExecError::Unsupported("Cursors are only available to server clients".to_string()),
+        ),
+        _ => Err(ExecError::Unsupported("Unsupported statement".to_string())),
+    }?;
+
+    Ok((output, stats))
+}
+
 fn handle_create_table(
     db: &mut Database,
     name: ObjectName,
-    columns: Vec<ColumnDef>,
-) -> Result<String, String> {
+    mut columns: Vec<ColumnDef>,
+    constraints: Vec<TableConstraint>,
+    options: CreateTableOptions,
+) -> Result<String, ExecError> {
     let table_name = name.to_string();
 
     if db.tables.contains_key(&table_name) {
-        return Err(format!("Table '{}' already exists", table_name));
+        return Err(ExecError::AlreadyExists(format!(
+            "Table '{}' already exists",
+            table_name
+        )));
+    }
+
+    // Declared types are kept the way the catalog will record them
+    for col in &mut columns {
+        if is_system_column(&col.name.value) {
+            return Err(ExecError::Unsupported(format!(
+                "Column name '{}' is reserved for a system column",
+                col.name.value
+            )));
+        }
+        col.data_type = Database::canonical_type(&col.data_type).ok_or_else(|| {
+            ExecError::Unsupported(format!("unsupported column type '{}'", col.data_type))
+        })?;
+    }
+
+    // A column's REFERENCES is kept with the table-level FOREIGN KEYs
+    let mut references = Vec::new();
+    for col in &mut columns {
+        col.options.retain(|o| match &o.option {
+            ColumnOption::ForeignKey {
+                foreign_table,
+                referred_columns,
+                on_delete,
+                on_update,
+                characteristics,
+            } => {
+                references.push(TableConstraint::ForeignKey {
+                    name: None,
+                    index_name: None,
+                    columns: vec![col.name.clone()],
+                    foreign_table: foreign_table.clone(),
+                    referred_columns: referred_columns.clone(),
+                    on_delete: *on_delete,
+                    on_update: *on_update,
+                    characteristics: *characteristics,
+                });
+                false
+            }
+            _ => true,
+        });
+    }
+
+    // Only CHECK and FOREIGN KEY are kept from the table-level constraints
+    let (foreign_keys, checks): (Vec<TableConstraint>, Vec<TableConstraint>) = constraints
+        .into_iter()
+        .filter(|c| {
+            matches!(
+                c,
+                TableConstraint::Check { .. } | TableConstraint::ForeignKey { .. }
+            )
+        })
+        .partition(|c| matches!(c, TableConstraint::ForeignKey { .. }));
+    let foreign_keys = references
+        .iter()
+        .chain(&foreign_keys)
+        .map(|constraint| foreign_key(db, &table_name, &columns, constraint))
+        .collect::<Result<Vec<_>, _>>()?;
+    for (_, expr) in check_expressions(&columns, &checks) {
+        validate_columns(expr, &columns)?;
+    }
+    for col in &columns {
+        column_collation(col)?;
+        decimal_type(&col.data_type)?;
     }
+    let TableOptions {
+        format,
+        created_at,
+        fill_factor,
+    } = table_options(&options)?;
+    if created_at {
+        columns.push(system_column(CREATED_AT_COLUMN));
+    }
+    format
+        .check_schema(&columns)
+        .map_err(ExecError::Unsupported)?;
 
     // Create the first page for this table
-    let (page_id, page_rc) = db
-        .buffer_pool
-        .borrow_mut()
-        .create_page()
-        .map_err(|e| e.to_string())?;
+    let (page_id, page_rc) = db.buffer_pool.borrow_mut().create_page()?;
 
     // Initialize the page as a slotted page
     {
@@ -43,413 +328,7884 @@ fn handle_create_table(
         schema: columns,
         first_page_id: page_id,
         last_page_id: page_id,
+        auto_increment: 0,
+        checks,
+        foreign_keys,
+        stats: None,
+        format,
+        fill_factor,
+        comment: None,
     };
 
     db.tables.insert(table_name.clone(), metadata);
-    db.buffer_pool.borrow_mut().flush_all().map_err(|e| e.to_string())?;
-    db.save_catalog().map_err(|e| e.to_string())?;
+    db.buffer_pool.borrow_mut().flush_all()?;
+    db.save_catalog()?;
 
     Ok(format!("Table '{}' created", table_name))
 }
 
-fn handle_insert(
-    db: &mut Database,
-    table: sqlparser::ast::TableObject,
-    source: Option<Box<sqlparser::ast::Query>>,
-) -> Result<String, String> {
-    let table_name_str = table.to_string();
+/// What a `CREATE TABLE ... WITH (...)` asked for
+struct TableOptions {
+    /// `row_format = 'compact'`
+    format: RowFormat,
+    /// `created_at = true`: add a `_created_at` column
+    created_at: bool,
+    /// `fillfactor = 80`: how full inserts fill each page, in percent
+    fill_factor: u8,
+}
+
+fn table_options(options: &CreateTableOptions) -> Result<TableOptions, ExecError> {
+    let mut table_options = TableOptions {
+        format: RowFormat::default(),
+        created_at: false,
+        fill_factor: DEFAULT_FILL_FACTOR,
+    };
+    let options = match options {
+        CreateTableOptions::None => return Ok(table_options),
+        CreateTableOptions::With(options) => options,
+        other => {
+            return Err(ExecError::Unsupported(format!(
+                "Unsupported table options: {}",
+                other
+            )));
+        }
+    };
+
+    for option in options {
+        let unsupported =
+            || ExecError::Unsupported(format!("Unsupported table option: {}", option));
+        let SqlOption::KeyValue { key, value } = option else {
+            return Err(unsupported());
+        };
+        let setting = match value {
+            Expr::Identifier(ident) => Some(ident.value.clone()),
+            Expr::Value(v) => match &v.value {
+                sqlparser::ast::Value::Boolean(b) => Some(b.to_string()),
+                sqlparser::ast::Value::Number(n, _) => Some(n.clone()),
+                other => other.clone().into_string(),
+            },
+            _ => None,
+        };
+        match key.value.to_lowercase().as_str() {
+            "row_format" => {
+                table_options.format = setting
+                    .as_deref()
+                    .and_then(RowFormat::from_name)
+                    .ok_or_else(|| {
+                        ExecError::Unsupported(format!(
+                            "Unknown row format in {}; expected 'tagged' or 'compact'",
+                            option
+                        ))
+                    })?;
+            }
+            "created_at" => {
+                table_options.created_at = match setting.map(|s| s.to_lowercase()).as_deref() {
+                    Some("true" | "on") => true,
+                    Some("false" | "off") => false,
+                    _ => {
+                        return Err(ExecError::Type(format!(
+                            "created_at must be true or false, got {}",
+                            value
+                        )));
+                    }
+                };
+            }
+            "fillfactor" => {
+                table_options.fill_factor = setting
+                    .and_then(|s| s.parse().ok())
+                    .filter(|percent| (MIN_FILL_FACTOR..=100).contains(percent))
+                    .ok_or_else(|| {
+                        ExecError::Type(format!(
+                            "fillfactor must be a whole number from {} to 100, got {}",
+                            MIN_FILL_FACTOR, value
+                        ))
+                    })?;
+            }
+            _ => return Err(unsupported()),
+        }
+    }
+    Ok(table_options)
+}
+
+fn handle_create_index(db: &mut Database, create_index: CreateIndex) -> Result<String, ExecError> {
+    let table_name = create_index.table_name.to_string();
+
+    let metadata = db
+        .tables
+        .get(&table_name)
+        .ok_or_else(|| ExecError::NotFound(format!("Table '{}' does not exist", table_name)))?
+        .clone();
+
+    let index_name = create_index
+        .name
+        .map(|n| n.to_string())
+        .ok_or_else(|| ExecError::Unsupported("CREATE INDEX requires an index name".to_string()))?;
+
+    if db.indexes.contains_key(&index_name) {
+        if create_index.if_not_exists {
+            return Ok(format!("Index '{}' already exists", index_name));
+        }
+        return Err(ExecError::AlreadyExists(format!(
+            "Index '{}' already exists",
+            index_name
+        )));
+    }
+
+    let mut columns = Vec::new();
+    for index_column in &create_index.columns {
+        let name = column_name(&index_column.column.expr).ok_or_else(|| {
+            ExecError::Unsupported("Only plain columns can be indexed".to_string())
+        })?;
+        let idx = column_index(&metadata.schema, name)?;
+        columns.push(metadata.schema[idx].name.value.clone());
+    }
+
+    let mut index = Index::new(index_name.clone(), table_name, columns);
+    index.unique = create_index.unique;
+    populate_index(db, &metadata, &mut index)?;
+    if index.unique
+        && let Some(key) = duplicate_key(&index, &metadata.schema)?
+    {
+        return Err(ExecError::Constraint(format!(
+            "Cannot create unique index '{}': duplicate value {}",
+            index_name,
+            format_key(key)
+        )));
+    }
+
+    db.indexes.insert(index_name.clone(), index);
+    db.save_catalog()?;
+
+    Ok(format!("Index '{}' created", index_name))
+}
+
+/// Build the key for a row in the given index
+fn index_key(
+    index: &Index,
+    schema: &[ColumnDef],
+    values: &[Value],
+) -> Result<Vec<Value>, ExecError> {
+    index
+        .columns
+        .iter()
+        .map(|name| {
+            let idx = column_index(schema, name)?;
+            values.get(idx).cloned().ok_or_else(|| {
+                ExecError::Storage(format!("Row has no value for column '{}'", name))
+            })
+        })
+        .collect()
+}
+
+/// The collation of each column of an index
+fn index_collations(index: &Index, schema: &[ColumnDef]) -> Result<Vec<Collation>, ExecError> {
+    index
+        .columns
+        .iter()
+        .map(|name| column_collation(&schema[column_index(schema, name)?]))
+        .collect()
+}
+
+/// An index key as its columns' collations compare it
+fn collated_key(collations: &[Collation], key: &[Value]) -> Vec<Value> {
+    collations.iter().zip(key).map(|(c, v)| c.key(v)).collect()
+}
+
+/// The first key of a unique index that more than one row shares. The index
+/// itself is in binary order, so keys that differ only by case are compared
+/// by their columns' collations here.
+fn duplicate_key<'a>(
+    index: &'a Index,
+    schema: &[ColumnDef],
+) -> Result<Option<&'a [Value]>, ExecError> {
+    let collations = index_collations(index, schema)?;
+    if let Some(key) = index.duplicate_key() {
+        return Ok(Some(key));
+    }
+    let mut seen = HashSet::new();
+    Ok(index
+        .keys()
+        .filter(|key| !key.contains(&Value::Null))
+        .find(|key| !seen.insert(collated_key(&collations, key))))
+}
+
+/// Add every existing row of the indexed table to the index
+fn populate_index(
+    db: &Database,
+    metadata: &TableMetadata,
+    index: &mut Index,
+) -> Result<(), ExecError> {
+    let mut stats = QueryStats::default();
+    scan_table(db, metadata, &mut stats, |row_id, row| {
+        let key = index_key(index, &metadata.schema, &row.values)?;
+        index.insert(key, row_id);
+        Ok(true)
+    })
+}
+
+/// Repopulate every index from its table's data (index entries live only in memory)
+pub(crate) fn rebuild_indexes(db: &mut Database) -> Result<(), ExecError> {
+    reindex(db, None).map(|_| ())
+}
+
+/// Throw away the entries of the indexes on `table`, or of every index, and
+/// build them again with a scan of the table. Returns how many indexes and
+/// entries were rebuilt.
+fn reindex(db: &mut Database, table: Option<&str>) -> Result<(usize, usize), ExecError> {
+    let mut indexes = std::mem::take(&mut db.indexes);
+
+    let mut rebuilt = (0, 0);
+    let result = indexes
+        .values_mut()
+        .filter(|index| table.is_none_or(|table| index.table == table))
+        .try_for_each(|index| {
+            let metadata = db.tables.get(&index.table).ok_or_else(|| {
+                ExecError::NotFound(format!(
+                    "Table '{}' for index '{}' does not exist",
+                    index.table, index.name
+                ))
+            })?;
+            *index = index.definition();
+            populate_index(db, metadata, index)?;
+            rebuilt.0 += 1;
+            rebuilt.1 += index.entry_count();
+            Ok(())
+        });
+
+    db.indexes = indexes;
+    result.map(|()| rebuilt)
+}
+
+/// Run `query` if it's `REINDEX` or `REINDEX TABLE name`, returning `None`
+/// if it's something else. sqlparser doesn't know REINDEX, so callers try
+/// this before parsing a query.
+pub fn execute_reindex(db: &mut Database, query: &str) -> Option<Result<String, ExecError>> {
+    let words: Vec<&str> = query
+        .trim()
+        .trim_end_matches(';')
+        .split_whitespace()
+        .collect();
+    let (command, target) = words.split_first()?;
+    if !command.eq_ignore_ascii_case("reindex") {
+        return None;
+    }
+    let table = match target {
+        [] => None,
+        [keyword] if keyword.eq_ignore_ascii_case("database") => None,
+        [keyword, table] if keyword.eq_ignore_ascii_case("table") => Some(*table),
+        _ => {
+            return Some(Err(ExecError::Unsupported(
+                "Usage: REINDEX or REINDEX TABLE <name>".to_string(),
+            )));
+        }
+    };
+    let result = table
+        .map_or(Ok(()), |table| table_metadata(db, table).map(|_| ()))
+        .and_then(|()| reindex(db, table))
+        .map(|(indexes, entries)| {
+            format!("Rebuilt {} index(es) with {} entries", indexes, entries)
+        });
+    Some(result)
+}
+
+fn handle_insert(db: &mut Database, insert: Insert) -> Result<String, ExecError> {
+    let table_name_str = insert.table.to_string();
 
     // Get table metadata (clone to avoid borrow issues)
     let metadata = db
         .tables
         .get(&table_name_str)
-        .ok_or_else(|| format!("Table '{}' does not exist", table_name_str))?
+        .ok_or_else(|| ExecError::NotFound(format!("Table '{}' does not exist", table_name_str)))?
         .clone();
 
-    let source = source.ok_or("INSERT requires VALUES")?;
+    let source = insert
+        .source
+        .ok_or_else(|| ExecError::Unsupported("INSERT requires VALUES".to_string()))?;
 
     let rows = match *source.body {
         SetExpr::Values(Values { rows, .. }) => rows,
-        _ => return Err("Only INSERT ... VALUES is supported".to_string()),
+        _ => {
+            return Err(ExecError::Unsupported(
+                "Only INSERT ... VALUES is supported".to_string(),
+            ));
+        }
     };
 
-    let mut inserted_count = 0;
-    let mut last_page_id = metadata.last_page_id;
+    // Map each listed column to its position in the table
+    let mut targets = Vec::new();
+    for column in &insert.columns {
+        let idx = writable_column(&metadata.schema, &column.value)?;
+        if targets.contains(&idx) {
+            return Err(ExecError::AlreadyExists(format!(
+                "Column '{}' specified more than once",
+                column.value
+            )));
+        }
+        targets.push(idx);
+    }
 
-    for row_exprs in rows {
-        let values: Vec<Value> = row_exprs
-            .into_iter()
-            .map(expr_to_value)
+    // Resolve RETURNING before writing anything, so a bad column fails the whole statement
+    let returning = insert
+        .returning
+        .as_deref()
+        .map(|items| projection_columns(items, &metadata.schema))
+        .transpose()?;
+
+    let qualifier = insert
+        .table_alias
+        .as_ref()
+        .map_or_else(|| table_name_str.clone(), |alias| alias.value.clone());
+    let upsert = insert
+        .on
+        .as_ref()
+        .map(|on| on_conflict(on, &metadata, &qualifier))
+        .transpose()?;
+
+    let mut auto_increment = metadata.auto_increment;
+    let mut new_rows = Vec::new();
+    let created_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as i64);
+
+    // Without a column list, values are taken positionally for every column
+    // but the hidden creation time
+    let expected = if targets.is_empty() {
+        created_at_position(&metadata.schema).unwrap_or(metadata.schema.len())
+    } else {
+        targets.len()
+    };
+
+    for (row_number, row_exprs) in rows.into_iter().enumerate() {
+        // Checked before any row is written, so a ragged VALUES list inserts nothing
+        if row_exprs.len() != expected {
+            let columns = if targets.is_empty() {
+                format!("table '{}' has {} column(s)", table_name_str, expected)
+            } else {
+                format!("INSERT lists {} column(s)", expected)
+            };
+            return Err(ExecError::Type(format!(
+                "VALUES row {} has {} value(s) but {}",
+                row_number + 1,
+                row_exprs.len(),
+                columns
+            )));
+        }
+
+        // Values are constant expressions, so there are no columns to resolve
+        let mut provided = row_exprs
+            .iter()
+            .map(|expr| eval_expr(expr, &[], &[]))
             .collect::<Result<Vec<_>, _>>()?;
 
-        let row = Row { values };
-        let bytes = row.to_bytes();
+        if !targets.is_empty() {
+            let mut slots: Vec<Option<Value>> = vec![None; metadata.schema.len()];
+            for (idx, value) in targets.iter().zip(provided) {
+                slots[*idx] = Some(value);
+            }
+            provided = fill_omitted_columns(&metadata.schema, slots, &mut auto_increment)?;
+        }
+
+        // The creation time is always stamped here, never taken from the INSERT
+        if let Some(idx) = created_at_position(&metadata.schema) {
+            provided.truncate(idx);
+            provided.push(Value::Long(created_at));
+        }
+
+        let provided = coerce_row(&metadata.schema, provided)?;
+
+        // Explicit values in an AUTOINCREMENT column move the counter past them
+        for (col, value) in metadata.schema.iter().zip(&provided) {
+            if is_auto_increment(col)
+                && let Value::Long(n) = value
+            {
+                auto_increment = auto_increment.max(*n);
+            }
+        }
+
+        // Defaults are filled in by now, so they count towards NOT NULL
+        check_not_null(&metadata.schema, &provided)?;
+        check_constraints(&metadata, &provided)?;
+        new_rows.push(Row { values: provided });
+    }
+
+    let (new_rows, updates) = match &upsert {
+        Some(upsert) => resolve_conflicts(db, &metadata, upsert, new_rows)?,
+        None => (new_rows, Vec::new()),
+    };
+
+    // Check every row before writing any, so a violation leaves the table untouched
+    let changed: HashSet<RowId> = updates.iter().map(|(row_id, _, _)| *row_id).collect();
+    let checked: Vec<Row> = new_rows
+        .iter()
+        .chain(updates.iter().map(|(_, _, new)| new))
+        .map(|row| Row {
+            values: row.values.clone(),
+        })
+        .collect();
+    check_unique_excluding(db, &table_name_str, &metadata, &checked, &changed)?;
+    check_foreign_keys(db, &table_name_str, &metadata, &checked)?;
+    check_referenced_updates(db, &table_name_str, &metadata, &updates)?;
+    let tuples = new_rows
+        .iter()
+        .map(|row| encode_row(&metadata, row))
+        .collect::<Result<Vec<_>, _>>()?;
 
+    let mut inserted_count = 0;
+    let mut last_page_id = write_updates(db, &metadata, &table_name_str, &updates)?;
+    let mut returned_rows = Vec::new();
+
+    for (row, bytes) in new_rows.into_iter().zip(tuples) {
         // Find a page with space and insert
-        last_page_id = insert_tuple(db, metadata.first_page_id, last_page_id, &bytes)?;
+        let row_id = insert_tuple(db, &metadata, last_page_id, &bytes)?;
+        last_page_id = row_id.page_id;
         inserted_count += 1;
+
+        for index in db.indexes.values_mut() {
+            if index.table == table_name_str {
+                let key = index_key(index, &metadata.schema, &row.values)?;
+                index.insert(key, row_id);
+            }
+        }
+
+        if let Some(columns) = &returning {
+            let values = columns
+                .iter()
+                .map(|(_, expr)| eval_expr(expr, &metadata.schema, &row.values))
+                .collect::<Result<Vec<_>, _>>()?;
+            returned_rows.push(values);
+        }
+    }
+
+    // Rows changed by ON CONFLICT DO UPDATE are returned after the inserted ones
+    if let Some(columns) = &returning {
+        for (_, _, row) in &updates {
+            let values = columns
+                .iter()
+                .map(|(_, expr)| eval_expr(expr, &metadata.schema, &row.values))
+                .collect::<Result<Vec<_>, _>>()?;
+            returned_rows.push(values);
+        }
     }
 
-    // Update last_page_id in metadata
+    // Update last_page_id and the AUTOINCREMENT counter in metadata
     if let Some(meta) = db.tables.get_mut(&table_name_str) {
         meta.last_page_id = last_page_id;
+        meta.auto_increment = auto_increment;
     }
 
-    db.buffer_pool.borrow_mut().flush_all().map_err(|e| e.to_string())?;
+    db.buffer_pool.borrow_mut().flush_all()?;
 
     // Save catalog to disk (last_page_id may have changed)
-    db.save_catalog().map_err(|e| e.to_string())?;
+    db.save_catalog()?;
 
-    Ok(format!("Inserted {} row(s)", inserted_count))
+    match returning {
+        Some(columns) => {
+            let headers: Vec<String> = columns.into_iter().map(|(name, _)| name).collect();
+            Ok(format_result(&headers, &returned_rows))
+        }
+        None if !updates.is_empty() => Ok(format!(
+            "Inserted {} row(s), updated {} row(s)",
+            inserted_count,
+            updates.len()
+        )),
+        None => Ok(format!("Inserted {} row(s)", inserted_count)),
+    }
 }
 
-fn insert_tuple(
-    db: &mut Database,
-    _first_page_id: PageId,
-    last_page_id: PageId,
-    bytes: &[u8],
-) -> Result<PageId, String> {
-    // Start from the last known page (optimization)
-    let mut current_page_id = last_page_id;
+/// What `INSERT ... ON CONFLICT` does with a row that would duplicate a
+/// value in one of `columns`
+struct Upsert {
+    columns: Vec<usize>,
+    action: ConflictAction,
+    /// The table's columns qualified with its name or alias, followed by
+    /// the proposed row's as `excluded.<column>`
+    schema: Vec<ColumnDef>,
+}
 
-    loop {
-        let page_rc = db
-            .buffer_pool
-            .borrow_mut()
-            .fetch_page(current_page_id)
-            .map_err(|e| e.to_string())?;
+enum ConflictAction {
+    DoNothing,
+    /// Assign to the existing row, if the condition holds for it
+    DoUpdate(Vec<(usize, Expr)>, Option<Box<Expr>>),
+}
 
-        let mut page = page_rc.borrow_mut();
-        let mut slotted = SlottedPage::new(&mut page);
+/// Resolve an `ON CONFLICT` clause against the table being inserted into.
+/// `DO UPDATE` needs a single PRIMARY KEY or UNIQUE conflict column; `DO
+/// NOTHING` without one skips rows that collide on any unique column.
+fn on_conflict(
+    on: &OnInsert,
+    metadata: &TableMetadata,
+    qualifier: &str,
+) -> Result<Upsert, ExecError> {
+    let OnInsert::OnConflict(conflict) = on else {
+        return Err(ExecError::Unsupported(format!(
+            "Unsupported INSERT clause: {}",
+            on
+        )));
+    };
 
-        // Try to add the tuple
-        match slotted.add_tuple(bytes) {
-            Ok(_) => {
-                return Ok(current_page_id);
+    let columns = match &conflict.conflict_target {
+        None => (0..metadata.schema.len())
+            .filter(|&idx| is_unique(&metadata.schema[idx]))
+            .collect(),
+        Some(ConflictTarget::Columns(target)) => {
+            let [column] = target.as_slice() else {
+                return Err(ExecError::Unsupported(
+                    "ON CONFLICT supports a single conflict column".to_string(),
+                ));
+            };
+            let idx = column_index(&metadata.schema, &column.value)?;
+            if !is_unique(&metadata.schema[idx]) {
+                return Err(ExecError::Constraint(format!(
+                    "ON CONFLICT column '{}' is not a PRIMARY KEY or UNIQUE column",
+                    column.value
+                )));
             }
-            Err(_) => {
-                // Page is full, check for next page
-                let next = slotted.next_page_id();
+            vec![idx]
+        }
+        Some(ConflictTarget::OnConstraint(_)) => {
+            return Err(ExecError::Unsupported(
+                "ON CONFLICT ON CONSTRAINT is not supported; name the column instead".to_string(),
+            ));
+        }
+    };
 
-                if next == NO_NEXT_PAGE {
-                    // Need to allocate a new page
-                    drop(page); // Release borrow before creating new page
+    let schema: Vec<ColumnDef> = [qualifier, "excluded"]
+        .iter()
+        .flat_map(|qualifier| {
+            metadata.schema.iter().map(move |c| ColumnDef {
+                name: Ident::new(format!("{}.{}", qualifier, c.name.value)),
+                ..c.clone()
+            })
+        })
+        .collect();
 
-                    let (new_page_id, new_page_rc) = db
-                        .buffer_pool
-                        .borrow_mut()
-                        .create_page()
-                        .map_err(|e| e.to_string())?;
+    let action = match &conflict.action {
+        OnConflictAction::DoNothing => ConflictAction::DoNothing,
+        OnConflictAction::DoUpdate(update) => {
+            if conflict.conflict_target.is_none() {
+                return Err(ExecError::Unsupported(
+                    "ON CONFLICT DO UPDATE needs a conflict column".to_string(),
+                ));
+            }
+            let mut targets = Vec::new();
+            for (idx, expr) in assignment_targets(&metadata.schema, &update.assignments)? {
+                validate_columns(expr, &schema)?;
+                targets.push((idx, expr.clone()));
+            }
+            if let Some(selection) = &update.selection {
+                validate_columns(selection, &schema)?;
+            }
+            ConflictAction::DoUpdate(targets, update.selection.clone().map(Box::new))
+        }
+    };
 
-                    // Initialize the new page
-                    {
-                        let mut new_page = new_page_rc.borrow_mut();
-                        let mut new_slotted = SlottedPage::new(&mut new_page);
-                        new_slotted.init();
+    Ok(Upsert {
+        columns,
+        action,
+        schema,
+    })
+}
 
-                        // Add the tuple to the new page
-                        new_slotted
-                            .add_tuple(bytes)
-                            .map_err(|e| format!("Tuple too large for page: {}", e))?;
-                    }
+/// Split the rows of an `INSERT ... ON CONFLICT` into the ones to insert and
+/// updates to the existing rows others collide with.
+/// Skipped rows are dropped. A row can only be updated once per statement.
+fn resolve_conflicts(
+    db: &Database,
+    metadata: &TableMetadata,
+    upsert: &Upsert,
+    rows: Vec<Row>,
+) -> Result<(Vec<Row>, Vec<RowUpdate>), ExecError> {
+    let existing = matching_rows(db, metadata, None)?;
+    // Position in `existing` of the row holding each value of each conflict column
+    let collations = upsert
+        .columns
+        .iter()
+        .map(|&idx| column_collation(&metadata.schema[idx]))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut taken: Vec<HashMap<Value, usize>> = vec![HashMap::new(); upsert.columns.len()];
+    for (pos, (_, row)) in existing.iter().enumerate() {
+        for ((map, &idx), collation) in taken.iter_mut().zip(&upsert.columns).zip(&collations) {
+            // NULLs never collide with each other
+            if row.values[idx] != Value::Null {
+                map.insert(collation.key(&row.values[idx]), pos);
+            }
+        }
+    }
 
-                    // Link the old page to the new page
-                    {
-                        let page_rc = db
-                            .buffer_pool
-                            .borrow_mut()
-                            .fetch_page(current_page_id)
-                            .map_err(|e| e.to_string())?;
+    let mut inserts = Vec::new();
+    let mut updates = Vec::new();
+    let mut pending: Vec<HashSet<Value>> = vec![HashSet::new(); upsert.columns.len()];
+    let mut updated = HashSet::new();
 
-                        let mut page = page_rc.borrow_mut();
-                        let mut slotted = SlottedPage::new(&mut page);
-                        slotted.set_next_page_id(new_page_id);
+    for row in rows {
+        let conflict = upsert
+            .columns
+            .iter()
+            .zip(&collations)
+            .zip(&taken)
+            .find_map(|((&idx, c), map)| map.get(&c.key(&row.values[idx])).copied());
+        let repeated = upsert
+            .columns
+            .iter()
+            .zip(&collations)
+            .zip(&pending)
+            .any(|((&idx, c), set)| set.contains(&c.key(&row.values[idx])));
+
+        match &upsert.action {
+            _ if conflict.is_none() && !repeated => {
+                for ((set, &idx), c) in pending.iter_mut().zip(&upsert.columns).zip(&collations) {
+                    if row.values[idx] != Value::Null {
+                        set.insert(c.key(&row.values[idx]));
                     }
+                }
+                inserts.push(row);
+            }
+            ConflictAction::DoNothing => {}
+            ConflictAction::DoUpdate(targets, selection) => {
+                let pos = conflict
+                    .filter(|pos| !repeated && updated.insert(*pos))
+                    .ok_or_else(|| {
+                        ExecError::Constraint(
+                            "ON CONFLICT DO UPDATE cannot change the same row twice".to_string(),
+                        )
+                    })?;
+                let (row_id, old) = &existing[pos];
+                let values: Vec<Value> = old.values.iter().chain(&row.values).cloned().collect();
+                if let Some(selection) = selection
+                    && !eval_predicate(selection, &upsert.schema, &values)?
+                {
+                    continue;
+                }
 
-                    return Ok(new_page_id);
-                } else {
-                    // Move to the next page
-                    current_page_id = next;
+                let mut new_values = old.values.clone();
+                for (idx, expr) in targets {
+                    new_values[*idx] = eval_expr(expr, &upsert.schema, &values)?;
                 }
+                let new_values = coerce_row(&metadata.schema, new_values)?;
+                check_not_null(&metadata.schema, &new_values)?;
+                check_constraints(metadata, &new_values)?;
+                let old = Row {
+                    values: old.values.clone(),
+                };
+                updates.push((*row_id, old, Row { values: new_values }));
             }
         }
     }
+
+    Ok((inserts, updates))
 }
 
-fn handle_query(db: &Database, query: sqlparser::ast::Query) -> Result<String, String> {
-    let select = match *query.body {
-        SetExpr::Select(select) => select,
-        _ => return Err("Only SELECT is supported".to_string()),
-    };
+/// Reject rows that would duplicate a value in a PRIMARY KEY or UNIQUE column,
+/// or a key of a unique index, either against existing rows or against each
+/// other. Existing rows in `replaced` are ignored, since the new rows are about
+/// to overwrite them. Inside a transaction, columns declared `DEFERRABLE
+/// INITIALLY DEFERRED` are left for COMMIT to check.
+fn check_unique_excluding(
+    db: &mut Database,
+    table_name: &str,
+    metadata: &TableMetadata,
+    rows: &[Row],
+    replaced: &HashSet<RowId>,
+) -> Result<(), ExecError> {
+    check_unique_indexes(db, table_name, metadata, rows, replaced)?;
 
-    if select.from.is_empty() {
-        return Err("SELECT requires a FROM clause".to_string());
+    let deferring = db.in_transaction();
+    let (deferred, unique): (Vec<usize>, Vec<usize>) = (0..metadata.schema.len())
+        .filter(|&idx| is_unique(&metadata.schema[idx]))
+        .partition(|&idx| deferring && is_deferred(&metadata.schema[idx]));
+    if !deferred.is_empty() && !rows.is_empty() {
+        db.defer_unique_check(table_name);
     }
-
-    let table_name = match &select.from[0].relation {
-        sqlparser::ast::TableFactor::Table { name, .. } => name.to_string(),
-        _ => return Err("Only simple table references are supported".to_string()),
-    };
-
-    let metadata = db
-        .tables
-        .get(&table_name)
-        .ok_or_else(|| format!("Table '{}' does not exist", table_name))?;
-
-    let is_select_star = select.projection.len() == 1
-        && matches!(
-            select.projection[0],
-            sqlparser::ast::SelectItem::Wildcard(_)
-        );
-
-    if !is_select_star {
-        return Err("Only SELECT * is supported for now".to_string());
+    if unique.is_empty() || rows.is_empty() {
+        return Ok(());
     }
 
-    // Scan all pages in the linked list
-    let mut output = String::new();
+    // Values are compared by the column's collation, so a NOCASE column
+    // can't hold both 'A' and 'a'
+    let collations = unique
+        .iter()
+        .map(|&idx| column_collation(&metadata.schema[idx]))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut seen: Vec<HashSet<Value>> = vec![HashSet::new(); unique.len()];
+    let mut stats = QueryStats::default();
+    scan_table(db, metadata, &mut stats, |row_id, row| {
+        if replaced.contains(&row_id) {
+            return Ok(true);
+        }
+        for ((set, &idx), collation) in seen.iter_mut().zip(&unique).zip(&collations) {
+            if let Some(value) = row.values.get(idx) {
+                set.insert(collation.key(value));
+            }
+        }
+        Ok(true)
+    })?;
 
-    // Print column headers if schema exists
-    if !metadata.schema.is_empty() {
-        let headers: Vec<String> = metadata.schema.iter().map(|c| c.name.to_string()).collect();
-        output.push_str(&headers.join("\t"));
-        output.push('\n');
-        output.push_str(&"-".repeat(headers.len() * 10));
-        output.push('\n');
+    for row in rows {
+        for ((set, &idx), collation) in seen.iter_mut().zip(&unique).zip(&collations) {
+            // NULLs never collide with each other
+            if let Some(value) = row.values.get(idx)
+                && *value != Value::Null
+                && !set.insert(collation.key(value))
+            {
+                return Err(ExecError::Constraint(format!(
+                    "Duplicate value {} for unique column '{}'",
+                    format_value(value),
+                    metadata.schema[idx].name
+                )));
+            }
+        }
     }
 
-    let mut row_count = 0;
-    let mut current_page_id = metadata.first_page_id;
-
-    loop {
-        let page_rc = db
-            .buffer_pool
-            .borrow_mut()
-            .fetch_page(current_page_id)
-            .map_err(|e| e.to_string())?;
-
-        let page = page_rc.borrow();
-        // Need to create a mutable copy for SlottedPage
-        let mut page_copy = *page;
-        let slotted = SlottedPage::new(&mut page_copy);
+    Ok(())
+}
 
-        // Read all tuples from this page
-        for slot_id in 0..slotted.num_slots() {
-            if let Some(bytes) = slotted.get_tuple(slot_id) {
-                match Row::from_bytes(&bytes) {
-                    Ok(row) => {
-                        let formatted: Vec<String> =
-                            row.values.iter().map(format_value).collect();
-                        output.push_str(&formatted.join("\t"));
-                        output.push('\n');
-                        row_count += 1;
-                    }
-                    Err(e) => {
-                        return Err(format!("Failed to deserialize row: {}", e));
-                    }
+/// Check the deferred unique columns of every table the transaction wrote
+/// to, now that it's about to commit
+fn check_deferred_unique(db: &Database) -> Result<(), ExecError> {
+    for table_name in db.deferred_checks() {
+        let metadata = table_metadata(db, &table_name)?;
+        let deferred: Vec<usize> = (0..metadata.schema.len())
+            .filter(|&idx| is_deferred(&metadata.schema[idx]))
+            .collect();
+        let collations = deferred
+            .iter()
+            .map(|&idx| column_collation(&metadata.schema[idx]))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut seen: Vec<HashSet<Value>> = vec![HashSet::new(); deferred.len()];
+        let mut duplicate = None;
+        let mut stats = QueryStats::default();
+        scan_table(db, metadata, &mut stats, |_, row| {
+            for ((set, &idx), collation) in seen.iter_mut().zip(&deferred).zip(&collations) {
+                if let Some(value) = row.values.get(idx)
+                    && *value != Value::Null
+                    && !set.insert(collation.key(value))
+                {
+                    duplicate = Some((value.clone(), idx));
+                    return Ok(false);
                 }
             }
+            Ok(true)
+        })?;
+        if let Some((value, idx)) = duplicate {
+            return Err(ExecError::Constraint(format!(
+                "Duplicate value {} for unique column '{}'",
+                format_value(&value),
+                metadata.schema[idx].name
+            )));
         }
+    }
+    Ok(())
+}
 
-        // Move to next page
-        let next = slotted.next_page_id();
-        if next == NO_NEXT_PAGE {
-            break;
+/// Unique indexes already hold every existing key, so new rows are looked up
+/// in them rather than scanning the table. An index over a NOCASE column is in
+/// binary order and can't be looked up that way, so its keys are collated and
+/// compared in full instead.
+fn check_unique_indexes(
+    db: &Database,
+    table_name: &str,
+    metadata: &TableMetadata,
+    rows: &[Row],
+    replaced: &HashSet<RowId>,
+) -> Result<(), ExecError> {
+    for index in db.indexes.values() {
+        if !index.unique || index.table != table_name {
+            continue;
+        }
+        let collations = index_collations(index, &metadata.schema)?;
+        let binary = collations.iter().all(|c| *c == Collation::Binary);
+        let existing: HashSet<Vec<Value>> = if binary {
+            HashSet::new()
+        } else {
+            index
+                .keys()
+                .filter(|key| index.lookup(key).iter().any(|r| !replaced.contains(r)))
+                .map(|key| collated_key(&collations, key))
+                .collect()
+        };
+        let mut seen = HashSet::new();
+        for row in rows {
+            let key = index_key(index, &metadata.schema, &row.values)?;
+            if key.contains(&Value::Null) {
+                continue;
+            }
+            let collated = collated_key(&collations, &key);
+            let taken = if binary {
+                index
+                    .lookup(&key)
+                    .iter()
+                    .any(|row_id| !replaced.contains(row_id))
+            } else {
+                existing.contains(&collated)
+            };
+            if taken || !seen.insert(collated) {
+                return Err(ExecError::Constraint(format!(
+                    "Duplicate value {} for unique index '{}'",
+                    format_key(&key),
+                    index.name
+                )));
+            }
         }
-        current_page_id = next;
     }
-
-    output.push_str(&format!("({} rows)", row_count));
-
-    Ok(output)
+    Ok(())
 }
 
-fn expr_to_value(expr: Expr) -> Result<Value, String> {
-    match expr {
-        Expr::Value(v) => match v.value {
-            sqlparser::ast::Value::Number(n, _) => n
-                .parse::<i64>()
-                .map(Value::Long)
-                .map_err(|_| format!("Invalid number: {}", n)),
-            sqlparser::ast::Value::SingleQuotedString(s)
-            | sqlparser::ast::Value::DoubleQuotedString(s) => Ok(Value::Text(s)),
-            sqlparser::ast::Value::Boolean(b) => Ok(Value::Bool(b)),
-            sqlparser::ast::Value::Null => Err("NULL not supported yet".to_string()),
-            _ => Err(format!("Unsupported value type: {:?}", v)),
-        },
-        _ => Err(format!("Unsupported expression: {:?}", expr)),
+/// An index key as it appears in error messages: the value itself, or a
+/// parenthesized list for a composite key
+fn format_key(key: &[Value]) -> String {
+    match key {
+        [value] => format_value(value),
+        _ => format!(
+            "({})",
+            key.iter().map(format_value).collect::<Vec<_>>().join(", ")
+        ),
     }
 }
 
-fn format_value(value: &Value) -> String {
-    match value {
-        Value::Long(n) => n.to_string(),
-        Value::Text(s) => s.clone(),
-        Value::Bool(b) => b.to_string(),
+/// The foreign key a `REFERENCES` or `FOREIGN KEY` constraint declares on
+/// `table_name`, whose columns are `schema`. The referenced column defaults to
+/// the referenced table's PRIMARY KEY; a table may refer to itself.
+fn foreign_key(
+    db: &Database,
+    table_name: &str,
+    schema: &[ColumnDef],
+    constraint: &TableConstraint,
+) -> Result<ForeignKey, ExecError> {
+    let TableConstraint::ForeignKey {
+        columns,
+        foreign_table,
+        referred_columns,
+        on_delete,
+        on_update,
+        characteristics,
+        ..
+    } = constraint
+    else {
+        return Err(ExecError::Unsupported(format!(
+            "Unsupported constraint: {}",
+            constraint
+        )));
+    };
+    let multi_column =
+        || ExecError::Unsupported("Foreign keys on more than one column are not supported".into());
+    let [column] = columns.as_slice() else {
+        return Err(multi_column());
+    };
+    let column = &schema[column_index(schema, &column.value)?];
+    match on_delete {
+        Some(ReferentialAction::SetDefault) => {
+            return Err(ExecError::Unsupported(
+                "ON DELETE SET DEFAULT is not supported".to_string(),
+            ));
+        }
+        Some(ReferentialAction::SetNull) if is_not_null(column) => {
+            return Err(ExecError::Constraint(format!(
+                "ON DELETE SET NULL needs column '{}' to allow NULL",
+                column.name.value
+            )));
+        }
+        _ => {}
+    }
+    if let Some(action) = on_update
+        && !matches!(
+            action,
+            ReferentialAction::NoAction | ReferentialAction::Restrict
+        )
+    {
+        return Err(ExecError::Unsupported(format!(
+            "ON UPDATE {} is not supported",
+            action
+        )));
+    }
+    if characteristics.is_some_and(|c| c.initially == Some(DeferrableInitial::Deferred)) {
+        return Err(ExecError::Unsupported(
+            "Deferred foreign keys are not supported".to_string(),
+        ));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::buffer::BufferPool;
-    use crate::storage::DiskManager;
-    use sqlparser::dialect::GenericDialect;
-    use sqlparser::parser::Parser;
-    use std::cell::RefCell;
-    use std::fs;
-    use std::rc::Rc;
+    let foreign_table = foreign_table.to_string();
+    let referred_schema = if foreign_table == table_name {
+        schema
+    } else {
+        &table_metadata(db, &foreign_table)?.schema
+    };
+    let referred = match referred_columns.as_slice() {
+        [] => referred_schema
+            .iter()
+            .find(|col| is_unique(col) && is_not_null(col) && !is_deferred(col))
+            .ok_or_else(|| {
+                ExecError::Constraint(format!(
+                    "Table '{}' has no PRIMARY KEY to reference",
+                    foreign_table
+                ))
+            })?,
+        [referred] => &referred_schema[column_index(referred_schema, &referred.value)?],
+        _ => return Err(multi_column()),
+    };
+    // A deferred column can hold duplicates until COMMIT
+    if !is_unique(referred) || is_deferred(referred) {
+        return Err(ExecError::Constraint(format!(
+            "Foreign key '{}' must reference a PRIMARY KEY or UNIQUE column, \
+             and '{}.{}' is neither",
+            column.name.value, foreign_table, referred.name.value
+        )));
+    }
 
-    fn with_test_db<F>(name: &str, f: F)
-    where
-        F: FnOnce(&mut Database),
+    Ok(ForeignKey {
+        column: column.name.value.clone(),
+        foreign_table,
+        referred_column: referred.name.value.clone(),
+        on_delete: on_delete.unwrap_or(ReferentialAction::NoAction),
+    })
+}
+
+/// Reject rows whose foreign key columns hold a value the referenced table
+/// doesn't have
+fn check_foreign_keys(
+    db: &Database,
+    table_name: &str,
+    metadata: &TableMetadata,
+    rows: &[Row],
+) -> Result<(), ExecError> {
+    for foreign_key in &metadata.foreign_keys {
+        check_foreign_key(db, table_name, &metadata.schema, foreign_key, rows)?;
+    }
+    Ok(())
+}
+
+/// Reject rows whose `foreign_key` column holds a value the referenced table
+/// doesn't have. In a table that refers to itself, a row may refer to one
+/// written alongside it.
+fn check_foreign_key(
+    db: &Database,
+    table_name: &str,
+    schema: &[ColumnDef],
+    foreign_key: &ForeignKey,
+    rows: &[Row],
+) -> Result<(), ExecError> {
+    let idx = column_index(schema, &foreign_key.column)?;
+    let values: Vec<Value> = rows
+        .iter()
+        .filter_map(|row| row.values.get(idx))
+        .filter(|value| **value != Value::Null)
+        .cloned()
+        .collect();
+    if values.is_empty() {
+        return Ok(());
+    }
+
+    let mut present = present_values(
+        db,
+        &foreign_key.foreign_table,
+        &foreign_key.referred_column,
+        &values,
+    )?;
+    if foreign_key.foreign_table == table_name {
+        let referred = column_index(schema, &foreign_key.referred_column)?;
+        present.extend(
+            rows.iter()
+                .filter_map(|row| row.values.get(referred).cloned()),
+        );
+    }
+    match values.iter().find(|value| !present.contains(value)) {
+        Some(missing) => Err(ExecError::Constraint(format!(
+            "Value {} for '{}.{}' is not present in '{}.{}'",
+            format_value(missing),
+            table_name,
+            foreign_key.column,
+            foreign_key.foreign_table,
+            foreign_key.referred_column
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Which of `values` a table's column holds: looked up in an index on just
+/// that column if there is one, and found by a scan otherwise
+fn present_values(
+    db: &Database,
+    table_name: &str,
+    column: &str,
+    values: &[Value],
+) -> Result<HashSet<Value>, ExecError> {
+    if let Some(index) = db.indexes.values().find(|index| {
+        index.table == table_name && matches!(index.columns.as_slice(), [c] if c == column)
+    }) {
+        return Ok(values
+            .iter()
+            .filter(|value| !index.lookup(std::slice::from_ref(value)).is_empty())
+            .cloned()
+            .collect());
+    }
+
+    let metadata = table_metadata(db, table_name)?;
+    let idx = column_index(&metadata.schema, column)?;
+    let wanted: HashSet<&Value> = values.iter().collect();
+    let mut present = HashSet::new();
+    let mut stats = QueryStats::default();
+    scan_table(db, metadata, &mut stats, |_, row| {
+        if let Some(value) = row.values.get(idx)
+            && wanted.contains(value)
+        {
+            present.insert(value.clone());
+        }
+        Ok(true)
+    })?;
+    Ok(present)
+}
+
+/// The foreign keys that refer to `table_name`, each with the table it's on
+fn referring_keys(db: &Database, table_name: &str) -> Vec<(String, ForeignKey)> {
+    let mut keys: Vec<(String, ForeignKey)> = db
+        .tables
+        .iter()
+        .flat_map(|(name, metadata)| {
+            metadata
+                .foreign_keys
+                .iter()
+                .filter(|fk| fk.foreign_table == table_name)
+                .map(move |fk| (name.clone(), fk.clone()))
+        })
+        .collect();
+    // So the same violation is reported every time
+    keys.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.column.cmp(&b.1.column)));
+    keys
+}
+
+/// Reject updates that change a referenced value some row still refers to.
+/// Foreign keys are all `ON UPDATE NO ACTION`.
+fn check_referenced_updates(
+    db: &Database,
+    table_name: &str,
+    metadata: &TableMetadata,
+    updates: &[RowUpdate],
+) -> Result<(), ExecError> {
+    for (child_table, foreign_key) in referring_keys(db, table_name) {
+        let idx = column_index(&metadata.schema, &foreign_key.referred_column)?;
+        // A value one row gives up and another takes is still there
+        let kept: HashSet<&Value> = updates.iter().map(|(_, _, new)| &new.values[idx]).collect();
+        let changed: HashSet<&Value> = updates
+            .iter()
+            .map(|(_, old, _)| &old.values[idx])
+            .filter(|value| **value != Value::Null && !kept.contains(value))
+            .collect();
+        if changed.is_empty() {
+            continue;
+        }
+
+        // A table referring to itself is checked as the update leaves it
+        let updated: HashMap<RowId, &Row> = if child_table == table_name {
+            updates
+                .iter()
+                .map(|(row_id, _, new)| (*row_id, new))
+                .collect()
+        } else {
+            HashMap::new()
+        };
+        let child = table_metadata(db, &child_table)?;
+        let child_idx = column_index(&child.schema, &foreign_key.column)?;
+        let mut referenced = None;
+        let mut stats = QueryStats::default();
+        scan_table(db, child, &mut stats, |row_id, row| {
+            let values = updated.get(&row_id).map_or(&row.values, |new| &new.values);
+            match values.get(child_idx) {
+                Some(value) if changed.contains(value) => {
+                    referenced = Some(value.clone());
+                    Ok(false)
+                }
+                _ => Ok(true),
+            }
+        })?;
+        if let Some(value) = referenced {
+            return Err(ExecError::Constraint(format!(
+                "Cannot change '{}.{}' = {}: '{}.{}' still refers to it",
+                table_name,
+                foreign_key.referred_column,
+                format_value(&value),
+                child_table,
+                foreign_key.column
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// What deleting some rows does, once foreign keys have had their say: the
+/// rows to delete from each table and the rows `ON DELETE SET NULL` rewrites
+#[derive(Default)]
+struct DeletePlan {
+    deleted: Vec<(String, Vec<(RowId, Row)>)>,
+    set_null: Vec<(String, Vec<RowUpdate>)>,
+}
+
+/// Follow the foreign keys that refer to rows being deleted from
+/// `table_name`: `CASCADE` deletes the referring rows too, `SET NULL` clears
+/// their column, and `NO ACTION` or `RESTRICT` rejects the whole delete if a
+/// referring row would be left behind.
+fn plan_delete(
+    db: &Database,
+    table_name: &str,
+    rows: Vec<(RowId, Row)>,
+) -> Result<DeletePlan, ExecError> {
+    let mut deleted_ids: HashSet<(String, RowId)> = rows
+        .iter()
+        .map(|(row_id, _)| (table_name.to_string(), *row_id))
+        .collect();
+    let mut plan = DeletePlan {
+        deleted: vec![(table_name.to_string(), rows)],
+        set_null: Vec::new(),
+    };
+    // (table, row, column) to clear, and the rows NO ACTION keys hold on to
+    let mut nulls: Vec<(String, RowId, Row, usize)> = Vec::new();
+    let mut restricted: Vec<(String, RowId, ForeignKey, Value)> = Vec::new();
+
+    let mut next = 0;
+    while next < plan.deleted.len() {
+        let parent_table = plan.deleted[next].0.clone();
+        let parent = table_metadata(db, &parent_table)?;
+        for (child_table, foreign_key) in referring_keys(db, &parent_table) {
+            let idx = column_index(&parent.schema, &foreign_key.referred_column)?;
+            let values: HashSet<&Value> = plan.deleted[next]
+                .1
+                .iter()
+                .filter_map(|(_, row)| row.values.get(idx))
+                .filter(|value| **value != Value::Null)
+                .collect();
+            if values.is_empty() {
+                continue;
+            }
+
+            let child = table_metadata(db, &child_table)?;
+            let child_idx = column_index(&child.schema, &foreign_key.column)?;
+            let mut referring = Vec::new();
+            let mut stats = QueryStats::default();
+            scan_table(db, child, &mut stats, |row_id, row| {
+                if row
+                    .values
+                    .get(child_idx)
+                    .is_some_and(|v| values.contains(v))
+                    && !deleted_ids.contains(&(child_table.clone(), row_id))
+                {
+                    referring.push((row_id, row));
+                }
+                Ok(true)
+            })?;
+            if referring.is_empty() {
+                continue;
+            }
+
+            match foreign_key.on_delete {
+                ReferentialAction::Cascade => {
+                    deleted_ids.extend(
+                        referring
+                            .iter()
+                            .map(|(row_id, _)| (child_table.clone(), *row_id)),
+                    );
+                    plan.deleted.push((child_table, referring));
+                }
+                ReferentialAction::SetNull => {
+                    nulls.extend(
+                        referring
+                            .into_iter()
+                            .map(|(row_id, row)| (child_table.clone(), row_id, row, child_idx)),
+                    );
+                }
+                _ => {
+                    restricted.extend(referring.into_iter().map(|(row_id, row)| {
+                        let value = row.values[child_idx].clone();
+                        (child_table.clone(), row_id, foreign_key.clone(), value)
+                    }));
+                }
+            }
+        }
+        next += 1;
+    }
+
+    // A referring row a cascade deletes anyway doesn't hold the delete up
+    for (child_table, row_id, foreign_key, value) in restricted {
+        if !deleted_ids.contains(&(child_table.clone(), row_id)) {
+            return Err(ExecError::Constraint(format!(
+                "Cannot delete '{}.{}' = {}: '{}.{}' still refers to it",
+                foreign_key.foreign_table,
+                foreign_key.referred_column,
+                format_value(&value),
+                child_table,
+                foreign_key.column
+            )));
+        }
+    }
+
+    // Clearing several columns of one row is one update
+    let mut cleared: HashMap<(String, RowId), (Row, Vec<usize>)> = HashMap::new();
+    for (child_table, row_id, row, idx) in nulls {
+        if !deleted_ids.contains(&(child_table.clone(), row_id)) {
+            cleared
+                .entry((child_table, row_id))
+                .or_insert_with(|| (row, Vec::new()))
+                .1
+                .push(idx);
+        }
+    }
+    let mut by_table: HashMap<String, Vec<RowUpdate>> = HashMap::new();
+    for ((child_table, row_id), (old, columns)) in cleared {
+        let mut values = old.values.clone();
+        for idx in columns {
+            values[idx] = Value::Null;
+        }
+        by_table
+            .entry(child_table)
+            .or_default()
+            .push((row_id, old, Row { values }));
+    }
+    plan.set_null = by_table.into_iter().collect();
+
+    Ok(plan)
+}
+
+/// Precision and scale of a DECIMAL or NUMERIC column type, `None` for any
+/// other type. A plain `DECIMAL` holds whole numbers of up to 38 digits.
+pub(crate) fn decimal_type(data_type: &DataType) -> Result<Option<(u8, u8)>, ExecError> {
+    let info = match data_type {
+        DataType::Decimal(info) | DataType::Numeric(info) | DataType::Dec(info) => info,
+        _ => return Ok(None),
+    };
+    let (precision, scale) = match *info {
+        ExactNumberInfo::None => (MAX_PRECISION as u64, 0),
+        ExactNumberInfo::Precision(p) => (p, 0),
+        ExactNumberInfo::PrecisionAndScale(p, s) => (p, s),
+    };
+    if !(1..=MAX_PRECISION as u64).contains(&precision) || scale < 0 || scale as u64 > precision {
+        return Err(ExecError::Type(format!(
+            "Invalid type {}: precision must be 1 to {} and scale 0 to the precision",
+            data_type, MAX_PRECISION
+        )));
+    }
+    Ok(Some((precision as u8, scale as u8)))
+}
+
+/// Fit a row's numbers to their columns' types: a DECIMAL(p, s) column
+/// holds decimals with exactly `s` fractional digits, rounding any beyond
+/// that, and an integer column only takes whole numbers. Other values are
+/// stored as they are.
+fn coerce_row(schema: &[ColumnDef], values: Vec<Value>) -> Result<Vec<Value>, ExecError> {
+    values
+        .into_iter()
+        .enumerate()
+        .map(|(idx, value)| match schema.get(idx) {
+            Some(col) => coerce_value(col, value),
+            None => Ok(value),
+        })
+        .collect()
+}
+
+fn coerce_value(col: &ColumnDef, value: Value) -> Result<Value, ExecError> {
+    let mismatch = |value: &Value| {
+        ExecError::Type(format!(
+            "Column '{}' is {}, got {}",
+            col.name,
+            col.data_type,
+            format_value(value)
+        ))
+    };
+
+    if let Some((precision, scale)) = decimal_type(&col.data_type)? {
+        let decimal = match &value {
+            Value::Long(n) => Decimal::from_i64(*n),
+            Value::Decimal(d) => *d,
+            Value::Null => return Ok(value),
+            _ => return Err(mismatch(&value)),
+        };
+        return match decimal.rescale(scale) {
+            Some(d) if d.precision() <= precision => Ok(Value::Decimal(d)),
+            _ => Err(mismatch(&value)),
+        };
+    }
+
+    let integer = matches!(
+        col.data_type,
+        DataType::Int(_) | DataType::Integer(_) | DataType::BigInt(_) | DataType::SmallInt(_)
+    );
+    match value {
+        Value::Decimal(d) if integer => match d.rescale(0) {
+            Some(whole) if whole == d => i64::try_from(whole.mantissa())
+                .map(Value::Long)
+                .map_err(|_| mismatch(&value)),
+            _ => Err(mismatch(&value)),
+        },
+        value => Ok(value),
+    }
+}
+
+/// Reject a row holding NULL in a NOT NULL or PRIMARY KEY column
+fn check_not_null(schema: &[ColumnDef], values: &[Value]) -> Result<(), ExecError> {
+    for (col, value) in schema.iter().zip(values) {
+        if *value == Value::Null && is_not_null(col) {
+            return Err(ExecError::Constraint(format!(
+                "Column '{}' cannot be NULL",
+                col.name
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Reject a row for which a CHECK constraint is false. As in standard SQL,
+/// a check that comes out NULL passes.
+fn check_constraints(metadata: &TableMetadata, values: &[Value]) -> Result<(), ExecError> {
+    for (label, expr) in check_expressions(&metadata.schema, &metadata.checks) {
+        match eval_expr(expr, &metadata.schema, values)? {
+            Value::Bool(true) | Value::Null => {}
+            Value::Bool(false) => {
+                return Err(ExecError::Constraint(format!(
+                    "check constraint violated on '{}'",
+                    label
+                )));
+            }
+            other => {
+                return Err(ExecError::Type(format!(
+                    "CHECK constraint on '{}' gave {}, not a boolean",
+                    label,
+                    format_value(&other)
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Every enforced CHECK on a table, column-level and table-level, labelled
+/// with the column or constraint name (or the expression, if it has no name)
+fn check_expressions<'a>(
+    schema: &'a [ColumnDef],
+    checks: &'a [TableConstraint],
+) -> impl Iterator<Item = (String, &'a Expr)> {
+    let column_checks = schema.iter().flat_map(|col| {
+        col.options.iter().filter_map(move |o| match &o.option {
+            ColumnOption::Check(expr) => Some((col.name.value.clone(), expr)),
+            _ => None,
+        })
+    });
+    let table_checks = checks.iter().filter_map(|c| match c {
+        TableConstraint::Check {
+            name,
+            expr,
+            enforced,
+        } if *enforced != Some(false) => {
+            let label = name
+                .as_ref()
+                .map_or_else(|| expr.to_string(), |n| n.value.clone());
+            Some((label, &**expr))
+        }
+        _ => None,
+    });
+    column_checks.chain(table_checks)
+}
+
+/// Whether the column was declared NOT NULL; a PRIMARY KEY is implicitly
+fn is_not_null(col: &ColumnDef) -> bool {
+    col.options.iter().any(|o| {
+        matches!(
+            o.option,
+            ColumnOption::NotNull
+                | ColumnOption::Unique {
+                    is_primary: true,
+                    ..
+                }
+        )
+    })
+}
+
+/// Whether the column was declared PRIMARY KEY or UNIQUE
+fn is_unique(col: &ColumnDef) -> bool {
+    col.options
+        .iter()
+        .any(|o| matches!(o.option, ColumnOption::Unique { .. }))
+}
+
+/// Whether the column's PRIMARY KEY or UNIQUE constraint is `DEFERRABLE
+/// INITIALLY DEFERRED`, so a transaction only has to satisfy it by COMMIT
+fn is_deferred(col: &ColumnDef) -> bool {
+    col.options.iter().any(|o| {
+        matches!(
+            &o.option,
+            ColumnOption::Unique {
+                characteristics: Some(c),
+                ..
+            } if c.initially == Some(DeferrableInitial::Deferred)
+        )
+    })
+}
+
+/// Build a full row from the values given for some columns, generating
+/// AUTOINCREMENT ids and DEFAULT values for the rest and leaving any other
+/// column NULL
+fn fill_omitted_columns(
+    schema: &[ColumnDef],
+    slots: Vec<Option<Value>>,
+    auto_increment: &mut i64,
+) -> Result<Vec<Value>, ExecError> {
+    schema
+        .iter()
+        .zip(slots)
+        .map(|(col, slot)| {
+            if let Some(value) = slot {
+                return Ok(value);
+            }
+            if is_auto_increment(col) {
+                *auto_increment += 1;
+                return Ok(Value::Long(*auto_increment));
+            }
+            match column_default(col) {
+                Some(expr) => eval_expr(expr, &[], &[]),
+                None => Ok(Value::Null),
+            }
+        })
+        .collect()
+}
+
+/// Whether the column was declared AUTOINCREMENT (or AUTO_INCREMENT)
+fn is_auto_increment(col: &ColumnDef) -> bool {
+    col.options.iter().any(|o| {
+        matches!(&o.option, ColumnOption::DialectSpecific(_))
+            && matches!(
+                o.option.to_string().to_uppercase().as_str(),
+                "AUTOINCREMENT" | "AUTO_INCREMENT"
+            )
+    })
+}
+
+/// The column's DEFAULT expression, if it has one
+fn column_default(col: &ColumnDef) -> Option<&Expr> {
+    col.options.iter().find_map(|o| match &o.option {
+        ColumnOption::Default(expr) => Some(expr),
+        _ => None,
+    })
+}
+
+/// Resolve a projection list into output column names and the expressions producing them
+fn projection_columns(
+    items: &[SelectItem],
+    schema: &[ColumnDef],
+) -> Result<Vec<(String, Expr)>, ExecError> {
+    let mut columns = Vec::new();
+    for item in items {
+        match item {
+            SelectItem::Wildcard(_) => columns.extend(
+                schema
+                    .iter()
+                    .filter(|c| !is_system_column(&c.name.value))
+                    .map(|c| {
+                        (
+                            unqualified(&c.name.value).to_string(),
+                            Expr::Identifier(c.name.clone()),
+                        )
+                    }),
+            ),
+            SelectItem::UnnamedExpr(expr) => {
+                // Plain column references take the declared name as their header
+                let name = match column_position(schema, expr) {
+                    Some(idx) => unqualified(&schema[idx?].name.value).to_string(),
+                    None => expr.to_string(),
+                };
+                columns.push((name, expr.clone()));
+            }
+            SelectItem::ExprWithAlias { expr, alias } => {
+                columns.push((alias.value.clone(), expr.clone()));
+            }
+            SelectItem::QualifiedWildcard(..) => {
+                return Err(ExecError::Unsupported(
+                    "Qualified wildcards are not supported".to_string(),
+                ));
+            }
+        }
+    }
+
+    // Catch unknown columns up front rather than on the first row
+    for (_, expr) in &columns {
+        validate_columns(expr, schema)?;
+    }
+
+    Ok(columns)
+}
+
+/// Check that every column an expression references exists in the schema
+fn validate_columns(expr: &Expr, schema: &[ColumnDef]) -> Result<(), ExecError> {
+    if let Some(idx) = column_position(schema, expr) {
+        return idx.map(|_| ());
+    }
+
+    match expr {
+        Expr::Nested(inner)
+        | Expr::Collate { expr: inner, .. }
+        | Expr::UnaryOp { expr: inner, .. }
+        | Expr::IsNull(inner)
+        | Expr::IsNotNull(inner) => validate_columns(inner, schema),
+        Expr::Between {
+            expr, low, high, ..
+        } => {
+            validate_columns(expr, schema)?;
+            validate_columns(low, schema)?;
+            validate_columns(high, schema)
+        }
+        Expr::BinaryOp { left, right, .. } => {
+            validate_columns(left, schema)?;
+            validate_columns(right, schema)
+        }
+        Expr::Case {
+            operand,
+            conditions,
+            else_result,
+            ..
+        } => operand
+            .iter()
+            .chain(else_result)
+            .map(|expr| &**expr)
+            .chain(conditions.iter().flat_map(|w| [&w.condition, &w.result]))
+            .try_for_each(|expr| validate_columns(expr, schema)),
+        // The argument of COUNT(*) isn't an expression
+        Expr::Function(func) if is_aggregate(func) => match &func.args {
+            FunctionArguments::List(list) => list.args.iter().try_for_each(|arg| match arg {
+                FunctionArg::Unnamed(FunctionArgExpr::Expr(arg)) => validate_columns(arg, schema),
+                _ => Ok(()),
+            }),
+            _ => Ok(()),
+        },
+        Expr::Function(func) => function_args(func)?
+            .into_iter()
+            .try_for_each(|arg| validate_columns(arg, schema)),
+        _ => Ok(()),
+    }
+}
+
+/// Name of the single table a statement targets
+fn target_table(table: &TableWithJoins) -> Result<String, ExecError> {
+    if !table.joins.is_empty() {
+        return Err(ExecError::Unsupported(
+            "Joins are not supported here".to_string(),
+        ));
+    }
+
+    match &table.relation {
+        TableFactor::Table { name, .. } => Ok(name.to_string()),
+        _ => Err(ExecError::Unsupported(
+            "Only simple table references are supported".to_string(),
+        )),
+    }
+}
+
+/// Every row of a table matching an optional WHERE clause, with its location
+fn matching_rows(
+    db: &Database,
+    metadata: &TableMetadata,
+    filter: Option<&Expr>,
+) -> Result<Vec<(RowId, Row)>, ExecError> {
+    let mut rows = Vec::new();
+    let mut stats = QueryStats::default();
+    let schema = query_schema(metadata);
+    let mut filter = filter.cloned();
+    if let Some(filter) = &mut filter {
+        resolve_subqueries(db, filter, &CommonTables::new(), &mut stats)?;
+    }
+    scan_table(db, metadata, &mut stats, |row_id, row| {
+        let mut values = query_row(row_id, row);
+        let matched = match &filter {
+            Some(filter) => eval_predicate(filter, &schema, &values)?,
+            None => true,
+        };
+        if matched {
+            values.truncate(metadata.schema.len());
+            rows.push((row_id, Row { values }));
+        }
+        Ok(true)
+    })?;
+    Ok(rows)
+}
+
+fn handle_update(
+    db: &mut Database,
+    table: TableWithJoins,
+    assignments: Vec<Assignment>,
+    selection: Option<Expr>,
+    returning: Option<Vec<SelectItem>>,
+) -> Result<String, ExecError> {
+    let table_name = target_table(&table)?;
+    let metadata = db
+        .tables
+        .get(&table_name)
+        .ok_or_else(|| ExecError::NotFound(format!("Table '{}' does not exist", table_name)))?
+        .clone();
+
+    let targets = assignment_targets(&metadata.schema, &assignments)?;
+    let returning = returning
+        .as_deref()
+        .map(|items| projection_columns(items, &metadata.schema))
+        .transpose()?;
+
+    // Compute every new row first, so an error leaves the table untouched
+    let mut updates = Vec::new();
+    for (row_id, row) in matching_rows(db, &metadata, selection.as_ref())? {
+        let mut values = row.values.clone();
+        for (idx, expr) in &targets {
+            values[*idx] = eval_expr(expr, &metadata.schema, &row.values)?;
+        }
+        let values = coerce_row(&metadata.schema, values)?;
+        check_not_null(&metadata.schema, &values)?;
+        check_constraints(&metadata, &values)?;
+        updates.push((row_id, row, Row { values }));
+    }
+
+    let changed: HashSet<RowId> = updates.iter().map(|(row_id, _, _)| *row_id).collect();
+    let new_rows: Vec<Row> = updates
+        .iter()
+        .map(|(_, _, new)| Row {
+            values: new.values.clone(),
+        })
+        .collect();
+    check_unique_excluding(db, &table_name, &metadata, &new_rows, &changed)?;
+    check_foreign_keys(db, &table_name, &metadata, &new_rows)?;
+    check_referenced_updates(db, &table_name, &metadata, &updates)?;
+
+    // RETURNING shows each row's new values
+    let returned_rows = match &returning {
+        Some(columns) => new_rows
+            .iter()
+            .map(|row| {
+                columns
+                    .iter()
+                    .map(|(_, expr)| eval_expr(expr, &metadata.schema, &row.values))
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        None => Vec::new(),
+    };
+
+    let last_page_id = write_updates(db, &metadata, &table_name, &updates)?;
+    if let Some(meta) = db.tables.get_mut(&table_name) {
+        meta.last_page_id = last_page_id;
+    }
+    db.buffer_pool.borrow_mut().flush_all()?;
+    db.save_catalog()?;
+
+    match returning {
+        Some(columns) => {
+            let headers: Vec<String> = columns.into_iter().map(|(name, _)| name).collect();
+            Ok(format_result(&headers, &returned_rows))
+        }
+        None => Ok(format!("Updated {} row(s)", updates.len())),
+    }
+}
+
+/// Resolve the column each `SET` assignment writes to
+fn assignment_targets<'a>(
+    schema: &[ColumnDef],
+    assignments: &'a [Assignment],
+) -> Result<Vec<(usize, &'a Expr)>, ExecError> {
+    let mut targets = Vec::new();
+    for assignment in assignments {
+        let column = match &assignment.target {
+            AssignmentTarget::ColumnName(name) => name.0.last().map(|p| p.to_string()),
+            AssignmentTarget::Tuple(_) => None,
+        }
+        .ok_or_else(|| {
+            ExecError::Unsupported("Only single-column assignments are supported".to_string())
+        })?;
+        targets.push((writable_column(schema, &column)?, &assignment.value));
+    }
+    Ok(targets)
+}
+
+/// Position of a column a statement writes to, which can't be a system column
+fn writable_column(schema: &[ColumnDef], name: &str) -> Result<usize, ExecError> {
+    if is_system_column(name) {
+        return Err(ExecError::Unsupported(format!(
+            "Column '{}' is maintained by MatiDB and can't be written",
+            name
+        )));
+    }
+    column_index(schema, name)
+}
+
+/// A row to overwrite: its location, its current values and its new ones
+type RowUpdate = (RowId, Row, Row);
+
+/// Overwrite each row in place, moving it to another
+/// page if the new version doesn't fit, and keep the table's indexes in step.
+/// Returns the table's last page afterwards.
+fn write_updates(
+    db: &mut Database,
+    metadata: &TableMetadata,
+    table_name: &str,
+    updates: &[RowUpdate],
+) -> Result<PageId, ExecError> {
+    // Encode every row first, so a value the format can't hold changes nothing
+    let tuples = updates
+        .iter()
+        .map(|(_, _, new)| encode_row(metadata, new))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut last_page_id = metadata.last_page_id;
+    for ((row_id, old, new), bytes) in updates.iter().zip(tuples) {
+        let updated = {
+            let page_rc = db.fetch_page_for_write(row_id.page_id)?;
+            let mut page = page_rc.borrow_mut();
+            let mut slotted = SlottedPage::new(&mut page);
+            match slotted.update_tuple(row_id.slot_id, &bytes) {
+                Ok(()) => true,
+                Err(_) => {
+                    // No room on this page; the row moves to wherever it fits
+                    slotted
+                        .delete_tuple(row_id.slot_id)
+                        .map_err(ExecError::Storage)?;
+                    slotted.trim_deleted_slots();
+                    false
+                }
+            }
+        };
+        let new_row_id = if updated {
+            *row_id
+        } else {
+            let moved = insert_tuple(db, metadata, last_page_id, &bytes)?;
+            last_page_id = moved.page_id;
+            moved
+        };
+
+        for index in db.indexes.values_mut() {
+            if index.table == table_name {
+                index.remove(&index_key(index, &metadata.schema, &old.values)?, *row_id);
+                let key = index_key(index, &metadata.schema, &new.values)?;
+                index.insert(key, new_row_id);
+            }
+        }
+    }
+    Ok(last_page_id)
+}
+
+fn handle_delete(db: &mut Database, delete: Delete) -> Result<String, ExecError> {
+    let tables = match &delete.from {
+        FromTable::WithFromKeyword(tables) | FromTable::WithoutKeyword(tables) => tables,
+    };
+    if tables.len() != 1 || delete.using.is_some() || delete.returning.is_some() {
+        return Err(ExecError::Unsupported(
+            "DELETE supports a single table without USING or RETURNING".to_string(),
+        ));
+    }
+
+    let table_name = target_table(&tables[0])?;
+    let metadata = db
+        .tables
+        .get(&table_name)
+        .ok_or_else(|| ExecError::NotFound(format!("Table '{}' does not exist", table_name)))?
+        .clone();
+
+    let rows = matching_rows(db, &metadata, delete.selection.as_ref())?;
+    let count = rows.len();
+    // Every referring row is dealt with before anything is written
+    let plan = plan_delete(db, &table_name, rows)?;
+    for (table_name, rows) in &plan.deleted {
+        let metadata = table_metadata(db, table_name)?.clone();
+        delete_rows(db, table_name, &metadata, rows)?;
+    }
+    for (table_name, updates) in &plan.set_null {
+        let metadata = table_metadata(db, table_name)?.clone();
+        let last_page_id = write_updates(db, &metadata, table_name, updates)?;
+        if let Some(meta) = db.tables.get_mut(table_name) {
+            meta.last_page_id = last_page_id;
+        }
+    }
+
+    db.buffer_pool.borrow_mut().flush_all()?;
+    if !plan.set_null.is_empty() {
+        db.save_catalog()?;
+    }
+
+    Ok(format!("Deleted {} row(s)", count))
+}
+
+/// Delete rows from a table's pages and its indexes
+fn delete_rows(
+    db: &mut Database,
+    table_name: &str,
+    metadata: &TableMetadata,
+    rows: &[(RowId, Row)],
+) -> Result<(), ExecError> {
+    for (row_id, row) in rows {
+        {
+            let page_rc = db.fetch_page_for_write(row_id.page_id)?;
+            let mut page = page_rc.borrow_mut();
+            let mut slotted = SlottedPage::new(&mut page);
+            slotted
+                .delete_tuple(row_id.slot_id)
+                .map_err(ExecError::Storage)?;
+            slotted.trim_deleted_slots();
+        }
+
+        for index in db.indexes.values_mut() {
+            if index.table == table_name {
+                index.remove(&index_key(index, &metadata.schema, &row.values)?, *row_id);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn handle_alter_table(
+    db: &mut Database,
+    name: ObjectName,
+    operations: Vec<AlterTableOperation>,
+) -> Result<String, ExecError> {
+    let table_name = name.to_string();
+    let [operation] = operations.as_slice() else {
+        return Err(ExecError::Unsupported(
+            "ALTER TABLE supports one operation at a time".to_string(),
+        ));
+    };
+
+    match operation {
+        AlterTableOperation::RenameTable {
+            table_name: RenameTableNameKind::To(new_name) | RenameTableNameKind::As(new_name),
+        } => rename_table(db, &table_name, new_name.to_string()),
+        AlterTableOperation::RenameColumn {
+            old_column_name,
+            new_column_name,
+        } => rename_column(db, &table_name, &old_column_name.value, new_column_name),
+        AlterTableOperation::AddConstraint {
+            constraint: constraint @ TableConstraint::ForeignKey { .. },
+            ..
+        } => add_foreign_key(db, &table_name, constraint),
+        other => Err(ExecError::Unsupported(format!(
+            "Unsupported ALTER TABLE operation: {}",
+            other
+        ))),
+    }
+}
+
+/// Give a table a new name. Its pages are untouched; only the catalog changes.
+fn rename_table(db: &mut Database, old_name: &str, new_name: String) -> Result<String, ExecError> {
+    if !db.tables.contains_key(old_name) {
+        return Err(ExecError::NotFound(format!(
+            "Table '{}' does not exist",
+            old_name
+        )));
+    }
+    if db.tables.contains_key(&new_name) {
+        return Err(ExecError::AlreadyExists(format!(
+            "Table '{}' already exists",
+            new_name
+        )));
+    }
+
+    let metadata = db.tables.remove(old_name).expect("table exists");
+    db.tables.insert(new_name.clone(), metadata);
+    db.rename_deferred_checks(old_name, &new_name);
+    for index in db.indexes.values_mut() {
+        if index.table == old_name {
+            index.table = new_name.clone();
+        }
+    }
+    for metadata in db.tables.values_mut() {
+        for foreign_key in &mut metadata.foreign_keys {
+            if foreign_key.foreign_table == old_name {
+                foreign_key.foreign_table = new_name.clone();
+            }
+        }
+    }
+    db.save_catalog()?;
+
+    Ok(format!("Table '{}' renamed to '{}'", old_name, new_name))
+}
+
+/// Rename a column, along with the references to it in CHECK constraints,
+/// indexes and foreign keys
+fn rename_column(
+    db: &mut Database,
+    table_name: &str,
+    old_name: &str,
+    new_name: &Ident,
+) -> Result<String, ExecError> {
+    let metadata = db
+        .tables
+        .get_mut(table_name)
+        .ok_or_else(|| ExecError::NotFound(format!("Table '{}' does not exist", table_name)))?;
+
+    let idx = writable_column(&metadata.schema, old_name)?;
+    if is_system_column(&new_name.value) {
+        return Err(ExecError::Unsupported(format!(
+            "Column name '{}' is reserved for a system column",
+            new_name.value
+        )));
+    }
+    if let Ok(existing) = column_index(&metadata.schema, &new_name.value)
+        && existing != idx
     {
-        let path = format!("test_executor_{}.db", name);
-        let _ = fs::remove_file(&path);
+        return Err(ExecError::AlreadyExists(format!(
+            "Column '{}' already exists",
+            new_name.value
+        )));
+    }
+
+    let old_schema = metadata.schema.clone();
+    let declared = std::mem::replace(&mut metadata.schema[idx].name, new_name.clone()).value;
+    for col in &mut metadata.schema {
+        for option in &mut col.options {
+            if let ColumnOption::Check(expr) = &mut option.option {
+                rename_column_refs(expr, &old_schema, idx, new_name);
+            }
+        }
+    }
+    for check in &mut metadata.checks {
+        if let TableConstraint::Check { expr, .. } = check {
+            rename_column_refs(expr, &old_schema, idx, new_name);
+        }
+    }
+    for index in db.indexes.values_mut() {
+        if index.table == table_name {
+            for column in &mut index.columns {
+                if *column == declared {
+                    *column = new_name.value.clone();
+                }
+            }
+        }
+    }
+    for (name, metadata) in db.tables.iter_mut() {
+        for foreign_key in &mut metadata.foreign_keys {
+            if name == table_name && foreign_key.column == declared {
+                foreign_key.column = new_name.value.clone();
+            }
+            if foreign_key.foreign_table == table_name && foreign_key.referred_column == declared {
+                foreign_key.referred_column = new_name.value.clone();
+            }
+        }
+    }
+    db.save_catalog()?;
+
+    Ok(format!(
+        "Column '{}' renamed to '{}'",
+        declared, new_name.value
+    ))
+}
+
+/// `ALTER TABLE t ADD FOREIGN KEY (c) REFERENCES p (k)`. The table's
+/// existing rows have to satisfy it already.
+fn add_foreign_key(
+    db: &mut Database,
+    table_name: &str,
+    constraint: &TableConstraint,
+) -> Result<String, ExecError> {
+    let metadata = table_metadata(db, table_name)?;
+    let foreign_key = foreign_key(db, table_name, &metadata.schema, constraint)?;
+    let rows: Vec<Row> = matching_rows(db, metadata, None)?
+        .into_iter()
+        .map(|(_, row)| row)
+        .collect();
+    check_foreign_key(db, table_name, &metadata.schema, &foreign_key, &rows)?;
+
+    let message = format!(
+        "Foreign key '{}.{}' references '{}.{}'",
+        table_name, foreign_key.column, foreign_key.foreign_table, foreign_key.referred_column
+    );
+    if let Some(metadata) = db.tables.get_mut(table_name) {
+        metadata.foreign_keys.push(foreign_key);
+    }
+    db.save_catalog()?;
+    Ok(message)
+}
+
+/// `COMMENT ON TABLE t IS '...'` or `COMMENT ON COLUMN t.c IS '...'`.
+/// `IS NULL` removes the comment.
+fn handle_comment(
+    db: &mut Database,
+    object_type: CommentObject,
+    object_name: &ObjectName,
+    comment: Option<String>,
+    if_exists: bool,
+) -> Result<String, ExecError> {
+    // The catalog stores a comment on one line
+    if comment.as_ref().is_some_and(|c| c.contains(['\n', '\r'])) {
+        return Err(ExecError::Unsupported(
+            "Comments can't contain line breaks".to_string(),
+        ));
+    }
+
+    let name = object_name.to_string();
+    let (table_name, column) = match object_type {
+        CommentObject::Table => (name.as_str(), None),
+        CommentObject::Column => match name.rsplit_once('.') {
+            Some((table_name, column)) => (table_name, Some(column)),
+            None => {
+                return Err(ExecError::NotFound(format!(
+                    "COMMENT ON COLUMN needs a table-qualified column, got '{}'",
+                    name
+                )));
+            }
+        },
+        other => {
+            return Err(ExecError::Unsupported(format!(
+                "COMMENT ON {} is not supported",
+                other
+            )));
+        }
+    };
+    let Some(metadata) = db.tables.get_mut(table_name) else {
+        if if_exists {
+            return Ok(format!("Table '{}' does not exist", table_name));
+        }
+        return Err(ExecError::NotFound(format!(
+            "Table '{}' does not exist",
+            table_name
+        )));
+    };
+
+    let verb = if comment.is_some() { "set" } else { "removed" };
+    let message = match column {
+        None => {
+            metadata.comment = comment;
+            format!("Comment {} on table '{}'", verb, table_name)
+        }
+        Some(column) => {
+            let idx = writable_column(&metadata.schema, column)?;
+            let col = &mut metadata.schema[idx];
+            col.options
+                .retain(|o| !matches!(o.option, ColumnOption::Comment(_)));
+            if let Some(comment) = comment {
+                col.options.push(ColumnOptionDef {
+                    name: None,
+                    option: ColumnOption::Comment(comment),
+                });
+            }
+            format!("Comment {} on column '{}'", verb, col.name.value)
+        }
+    };
+    db.save_catalog()?;
+
+    Ok(message)
+}
+
+/// `DESCRIBE t`: each column's type, options and comment, after the table's
+/// comment if it has one
+fn handle_describe(db: &Database, table_name: &str) -> Result<String, ExecError> {
+    let metadata = table_metadata(db, table_name)?;
+
+    let rows: Vec<Vec<Value>> = metadata
+        .schema
+        .iter()
+        .filter(|col| !is_system_column(&col.name.value))
+        .map(|col| {
+            let mut options: Vec<String> = col
+                .options
+                .iter()
+                .filter(|o| !matches!(o.option, ColumnOption::Comment(_)))
+                .map(|o| o.to_string())
+                .collect();
+            options.extend(
+                metadata
+                    .foreign_keys
+                    .iter()
+                    .filter(|fk| fk.column == col.name.value)
+                    .map(|fk| {
+                        format!(
+                            "REFERENCES {} ({}) ON DELETE {}",
+                            fk.foreign_table, fk.referred_column, fk.on_delete
+                        )
+                    }),
+            );
+            let comment = col.options.iter().find_map(|o| match &o.option {
+                ColumnOption::Comment(comment) => Some(Value::Text(comment.clone())),
+                _ => None,
+            });
+            vec![
+                Value::Text(col.name.value.clone()),
+                Value::Text(col.data_type.to_string()),
+                Value::Text(options.join(" ")),
+                comment.unwrap_or(Value::Null),
+            ]
+        })
+        .collect();
+    let headers: Vec<String> = ["column", "type", "options", "comment"]
+        .iter()
+        .map(|h| h.to_string())
+        .collect();
+
+    let table = format_result(&headers, &rows);
+    Ok(match &metadata.comment {
+        Some(comment) => format!("Table '{}': {}\n{}", table_name, comment, table),
+        None => table,
+    })
+}
+
+/// Scan a table and record its planner statistics in the catalog
+fn handle_analyze(
+    db: &mut Database,
+    name: ObjectName,
+    stats: &mut QueryStats,
+) -> Result<String, ExecError> {
+    let table_name = name.to_string();
+    let metadata = db
+        .tables
+        .get(&table_name)
+        .ok_or_else(|| ExecError::NotFound(format!("Table '{}' does not exist", table_name)))?;
+
+    let mut collector = StatsCollector::new(metadata.schema.len());
+    scan_table(db, metadata, stats, |_, row| {
+        collector.add(&row.values);
+        Ok(true)
+    })?;
+    let table_stats = collector.finish();
+    let row_count = table_stats.row_count;
+
+    if let Some(meta) = db.tables.get_mut(&table_name) {
+        meta.stats = Some(table_stats);
+    }
+    db.save_catalog()?;
+
+    Ok(format!("Analyzed '{}': {} row(s)", table_name, row_count))
+}
+
+/// Store a tuple on the first page from `last_page_id` on that it fits on
+/// without going past the table's fill factor, adding a page if none does
+fn insert_tuple(
+    db: &mut Database,
+    metadata: &TableMetadata,
+    last_page_id: PageId,
+    bytes: &[u8],
+) -> Result<RowId, ExecError> {
+    // Start from the last known page (optimization)
+    let mut current_page_id = last_page_id;
+
+    loop {
+        let page_rc = db.fetch_page_for_write(current_page_id)?;
+
+        let mut page = page_rc.borrow_mut();
+        let mut slotted = SlottedPage::new(&mut page);
+
+        // Try to add the tuple
+        let added = if slotted.fits_within(bytes.len(), metadata.fill_factor) {
+            slotted.add_tuple(bytes)
+        } else {
+            Err("Page filled to the fill factor".to_string())
+        };
+        match added {
+            Ok(slot_id) => {
+                return Ok(RowId {
+                    page_id: current_page_id,
+                    slot_id,
+                });
+            }
+            Err(_) => {
+                // Page is full, check for next page
+                let next = slotted.next_page_id();
+
+                if next == NO_NEXT_PAGE {
+                    // Need to allocate a new page
+                    drop(page); // Release borrow before creating new page
+
+                    let (new_page_id, new_page_rc) = db.buffer_pool.borrow_mut().create_page()?;
+
+                    // Initialize the new page
+                    let slot_id = {
+                        let mut new_page = new_page_rc.borrow_mut();
+                        let mut new_slotted = SlottedPage::new(&mut new_page);
+                        new_slotted.init();
+
+                        // Add the tuple to the new page
+                        new_slotted.add_tuple(bytes).map_err(|e| {
+                            ExecError::Storage(format!("Tuple too large for page: {}", e))
+                        })?
+                    };
+
+                    // Link the old page to the new page
+                    {
+                        let page_rc = db.fetch_page_for_write(current_page_id)?;
+
+                        let mut page = page_rc.borrow_mut();
+                        let mut slotted = SlottedPage::new(&mut page);
+                        slotted.set_next_page_id(new_page_id);
+                    }
+
+                    return Ok(RowId {
+                        page_id: new_page_id,
+                        slot_id,
+                    });
+                } else {
+                    // Move to the next page
+                    current_page_id = next;
+                }
+            }
+        }
+    }
+}
+
+fn handle_query(
+    db: &Database,
+    query: sqlparser::ast::Query,
+    stats: &mut QueryStats,
+) -> Result<String, ExecError> {
+    let result = select_rows(db, query, &CommonTables::new(), stats)?;
+    Ok(format_result(&result.columns, &result.rows))
+}
+
+/// Run a query. `ctes` are the `WITH` queries it can read like tables, from
+/// the queries it's part of.
+fn select_rows(
+    db: &Database,
+    mut query: sqlparser::ast::Query,
+    ctes: &CommonTables,
+    stats: &mut QueryStats,
+) -> Result<QueryResult, ExecError> {
+    if let Some(with) = query.with.take() {
+        let ctes = common_tables(db, with, ctes, stats)?;
+        return select_rows(db, query, &ctes, stats);
+    }
+    if let SetExpr::SetOperation { .. } = query.body.as_ref() {
+        return union_rows(db, query, ctes, stats);
+    }
+    resolve_where_subqueries(db, &mut query, ctes, stats)?;
+    let select = select_body(&query)?;
+    let tables = from_tables(select)?;
+    let source = row_source(db, &tables, ctes, stats)?;
+    let schema = source.schema();
+
+    let columns = projection_columns(&select.projection, schema)?;
+    let sort_keys = order_by_keys(&query, &columns, schema)?;
+    let (limit, offset) = limit_and_offset(&query)?;
+    // A bare TRUE keeps every row without testing each, and a bare FALSE
+    // means there's nothing to read
+    let constant = select.selection.as_ref().and_then(constant_predicate);
+    let filter = select.selection.as_ref().filter(|_| constant.is_none());
+
+    let grouping = Grouping::new(select, &columns, schema)?;
+    check_lock_clauses(&query, &tables, grouping.is_some())?;
+    if let Some(grouping) = &grouping {
+        for term in &sort_keys {
+            match &term.key {
+                SortKey::Column(idx) => grouping.check_grouped(*idx)?,
+                SortKey::Expr(expr) => grouping.check_grouped_expr(expr)?,
+                SortKey::Output(_) => {}
+            }
+        }
+    }
+
+    // A grouped query's result rows are made from its groups, so WHERE is
+    // applied as the groups are built and HAVING to the groups' rows
+    let mut results = match &grouping {
+        None => ResultRows {
+            schema,
+            filter,
+            columns: &columns,
+            sort_keys: &sort_keys,
+            distinct: distinct_key(select, schema)?,
+            limit,
+            offset,
+            single_match: false,
+            skipped: 0,
+            filtered: 0,
+            seen: HashSet::new(),
+            sort_types: Vec::new(),
+            runs: SortRuns::new(db.sort_buffer_rows),
+            rows: Vec::new(),
+        },
+        Some(grouping) => ResultRows {
+            schema: &grouping.schema,
+            filter: grouping.having.as_ref(),
+            columns: &grouping.columns,
+            sort_keys: &sort_keys,
+            distinct: distinct_key(select, &grouping.schema)?,
+            limit,
+            offset,
+            single_match: false,
+            skipped: 0,
+            filtered: 0,
+            seen: HashSet::new(),
+            sort_types: Vec::new(),
+            runs: SortRuns::new(db.sort_buffer_rows),
+            rows: Vec::new(),
+        },
+    };
+    let mut groups = grouping.as_ref().map(Grouping::groups);
+
+    let plan = match &source {
+        // The sample is drawn from every row, so there's no index to use
+        RowSource::Table {
+            sample: Some(_), ..
+        } => Some(ScanPlan::Full {
+            single_match: false,
+        }),
+        RowSource::Table { name, metadata, .. } => Some(plan_scan(db, name, metadata, filter)),
+        RowSource::Joined { .. } => None,
+    };
+    if let Some(ScanPlan::Full { single_match }) = plan
+        && groups.is_none()
+    {
+        results.single_match = single_match;
+    }
+
+    // Feed one source row in; returns whether to read on
+    let mut grouped_filtered = 0;
+    let mut push = |row: &[Value]| -> Result<bool, ExecError> {
+        match &mut groups {
+            Some(groups) => {
+                let matches = match filter {
+                    Some(filter) => eval_predicate(filter, schema, row)?,
+                    None => true,
+                };
+                if matches {
+                    groups.add(row)?;
+                } else {
+                    grouped_filtered += 1;
+                }
+                Ok(true)
+            }
+            None => results.push(row),
+        }
+    };
+
+    match (&source, plan) {
+        _ if limit == Some(0) || constant == Some(false) => {}
+        (
+            RowSource::Table {
+                metadata,
+                sample: Some(sample),
+                ..
+            },
+            _,
+        ) => {
+            // Rows are sampled before WHERE, as if the table only held the sample
+            let mut sampler = Sampler::new(sample.size, sample.seed);
+            scan_table(db, metadata, stats, |row_id, row| {
+                sampler.offer(query_row(row_id, row));
+                Ok(true)
+            })?;
+            for row in sampler.finish() {
+                if !push(&row)? {
+                    break;
+                }
+            }
+        }
+        (RowSource::Table { metadata, .. }, Some(ScanPlan::Index { index, key })) => {
+            // The index narrows the candidates; the full WHERE is still applied
+            for row_id in index.lookup(&key) {
+                if !push(&query_row(row_id, fetch_row(db, metadata, row_id, stats)?))? {
+                    break;
+                }
+            }
+        }
+        (RowSource::Table { metadata, .. }, _) => {
+            scan_table(db, metadata, stats, |row_id, row| {
+                push(&query_row(row_id, row))
+            })?;
+        }
+        (RowSource::Joined { rows, .. }, _) => {
+            for row in rows {
+                if !push(row)? {
+                    break;
+                }
+            }
+        }
+    }
+
+    // A grouped query's results are filtered by HAVING, not WHERE
+    stats.rows_filtered += match groups {
+        Some(_) => grouped_filtered,
+        None => results.filtered,
+    };
+    if let Some(groups) = groups {
+        for row in groups.finish() {
+            if !results.push(&row)? {
+                break;
+            }
+        }
+    }
+
+    let rows = results.finish()?;
+    stats.rows_returned = rows.len();
+    Ok(QueryResult {
+        columns: columns.into_iter().map(|(name, _)| name).collect(),
+        rows,
+    })
+}
+
+/// Run a `UNION [ALL]`: each side on its own, then the outer query's ORDER BY,
+/// LIMIT and OFFSET over the combined rows. Column names come from the first
+/// side.
+fn union_rows(
+    db: &Database,
+    query: sqlparser::ast::Query,
+    ctes: &CommonTables,
+    stats: &mut QueryStats,
+) -> Result<QueryResult, ExecError> {
+    let combined = set_operation_rows(db, &query, &query.body, ctes, stats)?;
+
+    // The combined rows are read through placeholder columns, since the
+    // result's column names can repeat
+    let schema: Vec<ColumnDef> = (0..combined.columns.len())
+        .map(|idx| ColumnDef {
+            name: Ident::new(format!("#{}", idx)),
+            data_type: DataType::Unspecified,
+            options: vec![],
+        })
+        .collect();
+    let columns: Vec<(String, Expr)> = combined
+        .columns
+        .iter()
+        .zip(&schema)
+        .map(|(name, col)| (name.clone(), Expr::Identifier(col.name.clone())))
+        .collect();
+    let sort_keys = order_by_keys(&query, &columns, &schema)?;
+    let (limit, offset) = limit_and_offset(&query)?;
+
+    let mut results = ResultRows {
+        schema: &schema,
+        filter: None,
+        columns: &columns,
+        sort_keys: &sort_keys,
+        distinct: None,
+        limit,
+        offset,
+        single_match: false,
+        skipped: 0,
+        filtered: 0,
+        seen: HashSet::new(),
+        sort_types: Vec::new(),
+        runs: SortRuns::new(db.sort_buffer_rows),
+        rows: Vec::new(),
+    };
+    for row in &combined.rows {
+        if !results.push(row)? {
+            break;
+        }
+    }
+    let rows = results.finish()?;
+    stats.rows_returned = rows.len();
+    Ok(QueryResult {
+        columns: combined.columns,
+        rows,
+    })
+}
+
+/// The rows of one side of a UNION: a SELECT, a query in parentheses (which
+/// may have its own ORDER BY and LIMIT) or another UNION
+fn set_operation_rows(
+    db: &Database,
+    outer: &sqlparser::ast::Query,
+    body: &SetExpr,
+    ctes: &CommonTables,
+    stats: &mut QueryStats,
+) -> Result<QueryResult, ExecError> {
+    let (quantifier, left, right) = match body {
+        SetExpr::SetOperation {
+            op: SetOperator::Union,
+            set_quantifier,
+            left,
+            right,
+        } => (set_quantifier, left, right),
+        SetExpr::SetOperation { op, .. } => {
+            return Err(ExecError::Unsupported(format!("{} is not supported", op)));
+        }
+        SetExpr::Query(query) => return select_rows(db, (**query).clone(), ctes, stats),
+        body => {
+            let query = sqlparser::ast::Query {
+                body: Box::new(body.clone()),
+                order_by: None,
+                limit_clause: None,
+                fetch: None,
+                locks: vec![],
+                ..outer.clone()
+            };
+            return select_rows(db, query, ctes, stats);
+        }
+    };
+    let distinct = match quantifier {
+        SetQuantifier::None | SetQuantifier::Distinct => true,
+        SetQuantifier::All => false,
+        other => {
+            return Err(ExecError::Unsupported(format!(
+                "UNION {} is not supported",
+                other
+            )));
+        }
+    };
+
+    let mut combined = set_operation_rows(db, outer, left, ctes, stats)?;
+    let other = set_operation_rows(db, outer, right, ctes, stats)?;
+    if other.columns.len() != combined.columns.len() {
+        return Err(ExecError::Type(
+            "each UNION query must have the same number of columns".to_string(),
+        ));
+    }
+    combined.rows.extend(other.rows);
+    unify_column_types(&mut combined.rows, combined.columns.len())?;
+
+    if distinct {
+        let mut seen = HashSet::new();
+        combined.rows.retain(|row| seen.insert(row.clone()));
+    }
+    Ok(combined)
+}
+
+/// Check that each column of a UNION holds one kind of value. Integers and
+/// decimals mix, with the integers widened to decimals so equal values
+/// compare equal.
+fn unify_column_types(rows: &mut [Vec<Value>], column_count: usize) -> Result<(), ExecError> {
+    for idx in 0..column_count {
+        let mut kinds: Vec<&'static str> = Vec::new();
+        for row in rows.iter() {
+            let kind = row[idx].type_name();
+            if row[idx] != Value::Null && !kinds.contains(&kind) {
+                kinds.push(kind);
+            }
+        }
+        let numeric = |kind: &str| kind == "integer" || kind == "decimal";
+        if let [first, rest @ ..] = kinds.as_slice()
+            && let Some(other) = rest.iter().find(|kind| !numeric(first) || !numeric(kind))
+        {
+            return Err(ExecError::Type(format!(
+                "UNION types {} and {} cannot be matched",
+                first, other
+            )));
+        }
+        if kinds.len() > 1 {
+            for row in rows.iter_mut() {
+                if let Value::Long(n) = row[idx] {
+                    row[idx] = Value::Decimal(Decimal::from_i64(n));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Collects a SELECT's result as rows are read: applies WHERE, OFFSET and
+/// LIMIT, and evaluates the select list and sort keys
+struct ResultRows<'a> {
+    schema: &'a [ColumnDef],
+    filter: Option<&'a Expr>,
+    columns: &'a [(String, Expr)],
+    sort_keys: &'a [SortTerm],
+    distinct: Option<DistinctKey<'a>>,
+    limit: Option<usize>,
+    offset: usize,
+    /// At most one row can match, so stop at the first
+    single_match: bool,
+    skipped: usize,
+    /// Rows `filter` rejected
+    filtered: usize,
+    /// DISTINCT keys of the rows kept so far, when duplicates are dropped as
+    /// they arrive
+    seen: HashSet<Vec<Value>>,
+    /// The first non-NULL value of each sort key, which every other value of
+    /// the key must be comparable with
+    sort_types: Vec<Option<Value>>,
+    /// Rows sorted and written out to disk when `rows` gets too long
+    runs: SortRuns,
+    /// Each matching row with its sort key (empty when there's no ORDER BY)
+    /// and DISTINCT ON key (empty without one)
+    rows: Vec<SortRow>,
+}
+
+/// What makes two result rows duplicates under DISTINCT
+enum DistinctKey<'a> {
+    /// `DISTINCT`: every output value is the same
+    Row,
+    /// `DISTINCT ON (...)`: these expressions, evaluated on the source row,
+    /// give the same values. The first row for each is kept.
+    On(&'a [Expr]),
+}
+
+/// How a SELECT's DISTINCT clause, if any, tells duplicate rows apart
+fn distinct_key<'a>(
+    select: &'a Select,
+    schema: &[ColumnDef],
+) -> Result<Option<DistinctKey<'a>>, ExecError> {
+    match &select.distinct {
+        None => Ok(None),
+        Some(sqlparser::ast::Distinct::Distinct) => Ok(Some(DistinctKey::Row)),
+        Some(sqlparser::ast::Distinct::On(exprs)) => {
+            for expr in exprs {
+                validate_columns(expr, schema)?;
+            }
+            Ok(Some(DistinctKey::On(exprs)))
+        }
+    }
+}
+
+impl ResultRows<'_> {
+    /// Whether rows come out in the order they're read, so OFFSET and LIMIT
+    /// can be applied as they arrive. Without ORDER BY, DISTINCT keeps the
+    /// first row of each kind, so duplicates can be dropped as they arrive too.
+    fn streaming(&self) -> bool {
+        self.sort_keys.is_empty()
+    }
+
+    /// Add a row read from the source. Returns whether reading should go on
+    /// to the next row; stopping a table scan means no further pages are fetched.
+    fn push(&mut self, row: &[Value]) -> Result<bool, ExecError> {
+        // WHERE comes first, so only matching rows count towards OFFSET and LIMIT
+        if let Some(filter) = self.filter
+            && !eval_predicate(filter, self.schema, row)?
+        {
+            self.filtered += 1;
+            return Ok(true);
+        }
+
+        // Rows can only be skipped or counted towards LIMIT once they're in
+        // order and duplicates are gone
+        if self.streaming() && self.distinct.is_none() && self.skipped < self.offset {
+            self.skipped += 1;
+            return Ok(true);
+        }
+
+        let values = self
+            .columns
+            .iter()
+            .map(|(_, expr)| eval_expr(expr, self.schema, row))
+            .collect::<Result<Vec<_>, _>>()?;
+        let key = self
+            .sort_keys
+            .iter()
+            .map(|term| match &term.key {
+                SortKey::Output(idx) => Ok(values[*idx].clone()),
+                SortKey::Column(idx) => Ok(row[*idx].clone()),
+                SortKey::Expr(expr) => eval_expr(expr, self.schema, row),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let distinct_key = match self.distinct {
+            Some(DistinctKey::On(exprs)) => exprs
+                .iter()
+                .map(|expr| eval_expr(expr, self.schema, row))
+                .collect::<Result<Vec<_>, _>>()?,
+            _ => Vec::new(),
+        };
+        if !self.streaming() {
+            self.check_sort_types(&key)?;
+        }
+        if self.streaming() && self.distinct.is_some() {
+            let seen_key = match self.distinct {
+                Some(DistinctKey::On(_)) => distinct_key.clone(),
+                _ => values.clone(),
+            };
+            if !self.seen.insert(seen_key) {
+                return Ok(true);
+            }
+            if self.skipped < self.offset {
+                self.skipped += 1;
+                return Ok(true);
+            }
+        }
+        self.rows.push((key, distinct_key, values));
+        if !self.streaming() {
+            let sort_keys = self.sort_keys;
+            self.runs.spill_if_full(&mut self.rows, |a, b| {
+                compare_sort_keys(sort_keys, &a.0, &b.0)
+            })?;
+        }
+
+        Ok(!self.single_match
+            && (!self.streaming() || self.limit.is_none_or(|limit| self.rows.len() < limit)))
+    }
+
+    /// The result rows, sorted, without duplicates, and with OFFSET and
+    /// LIMIT applied
+    fn finish(self) -> Result<Vec<Vec<Value>>, ExecError> {
+        if self.streaming() {
+            return Ok(self.rows.into_iter().map(|(_, _, values)| values).collect());
+        }
+
+        // Stable, so rows with equal keys keep their storage order
+        let sort_keys = self.sort_keys;
+        let sorted = self
+            .runs
+            .merge(self.rows, |a, b| compare_sort_keys(sort_keys, &a.0, &b.0))?;
+        let limit = self.limit.unwrap_or(usize::MAX);
+        let mut seen = HashSet::new();
+        let mut skipped = 0;
+        let mut output = Vec::new();
+        for row in sorted {
+            if output.len() == limit {
+                break;
+            }
+            let (_, distinct_key, values) = row?;
+            let first = match self.distinct {
+                None => true,
+                Some(DistinctKey::Row) => seen.insert(values.clone()),
+                Some(DistinctKey::On(_)) => seen.insert(distinct_key),
+            };
+            if !first {
+                continue;
+            }
+            if skipped < self.offset {
+                skipped += 1;
+                continue;
+            }
+            output.push(values);
+        }
+        Ok(output)
+    }
+
+    /// Make sure each sort key holds one kind of value, since values of
+    /// different types have no order. Integers and decimals mix.
+    fn check_sort_types(&mut self, key: &[Value]) -> Result<(), ExecError> {
+        self.sort_types.resize(key.len(), None);
+        for ((value, first), term) in key.iter().zip(&mut self.sort_types).zip(self.sort_keys) {
+            if *value == Value::Null {
+                continue;
+            }
+            match first {
+                None => *first = Some(value.clone()),
+                Some(first) => {
+                    if compare_values(first, value, term.collation).is_err() {
+                        return Err(ExecError::Type(format!(
+                            "ORDER BY can't sort {} and {} values together",
+                            first.type_name(),
+                            value.type_name()
+                        )));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The order of two rows' sort keys
+fn compare_sort_keys(terms: &[SortTerm], a: &[Value], b: &[Value]) -> Ordering {
+    a.iter()
+        .zip(b)
+        .zip(terms)
+        .map(|((a, b), term)| term.compare(a, b))
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
+
+/// Where a SELECT's rows come from
+/// A SELECT whose rows are read a batch at a time with `FETCH`. Each batch
+/// carries on the table scan from the row after the last one read, so the
+/// table isn't scanned again and only one batch is held in memory.
+///
+/// Cursors read a single table in storage order, so the query can't join,
+/// group or sort. A cursor isn't a snapshot: rows changed between batches are
+/// seen as they are when the scan reaches them.
+pub struct Cursor {
+    query: sqlparser::ast::Query,
+    table: String,
+    /// Where the next batch starts, or `None` once the scan is done
+    next: Option<RowId>,
+    /// Matching rows skipped so far for the query's OFFSET
+    skipped: usize,
+    /// Rows returned so far, which count towards the query's LIMIT
+    returned: usize,
+    /// The last row the last FETCH returned, for `WHERE CURRENT OF`
+    current: Option<RowId>,
+}
+
+impl Cursor {
+    /// Check that a query can be read through a cursor and position it
+    /// before the first row
+    pub fn open(db: &Database, mut query: sqlparser::ast::Query) -> Result<Self, ExecError> {
+        if query.with.is_some() {
+            return Err(ExecError::Unsupported(
+                "A cursor can't read a WITH query".to_string(),
+            ));
+        }
+        let ctes = CommonTables::new();
+        resolve_where_subqueries(db, &mut query, &ctes, &mut QueryStats::default())?;
+        let select = select_body(&query)?;
+        let tables = from_tables(select)?;
+        let [table] = tables.as_slice() else {
+            return Err(ExecError::Unsupported(
+                "A cursor can only read a single table".to_string(),
+            ));
+        };
+        if table.sample.is_some() {
+            return Err(ExecError::Unsupported(
+                "A cursor can't read a TABLESAMPLE".to_string(),
+            ));
+        }
+        let metadata = table_metadata(db, &table.name)?;
+        let schema = query_schema(metadata);
+        let columns = projection_columns(&select.projection, &schema)?;
+        if query.order_by.is_some()
+            || select.distinct.is_some()
+            || Grouping::new(select, &columns, &schema)?.is_some()
+        {
+            return Err(ExecError::Unsupported(
+                "A cursor reads rows in storage order, so its query can't use ORDER BY, DISTINCT, GROUP BY or aggregate functions"
+                    .to_string(),
+            ));
+        }
+        check_lock_clauses(&query, &tables, false)?;
+        limit_and_offset(&query)?;
+
+        let table = table.name.clone();
+        let next = Some(RowId {
+            page_id: metadata.first_page_id,
+            slot_id: 0,
+        });
+        Ok(Cursor {
+            query,
+            table,
+            next,
+            skipped: 0,
+            returned: 0,
+            current: None,
+        })
+    }
+
+    /// The table the cursor reads
+    pub fn table(&self) -> &str {
+        &self.table
+    }
+
+    /// The `_row_id` of the row the last FETCH returned last, or `None`
+    /// before the first FETCH or once one has come back empty
+    pub fn current_row_id(&self) -> Option<i64> {
+        self.current.map(row_id_value)
+    }
+
+    /// Read up to `count` more rows, formatted like a SELECT's result. Once
+    /// the rows run out, each fetch returns none.
+    pub fn fetch(&mut self, db: &Database, count: usize) -> Result<String, ExecError> {
+        let select = select_body(&self.query)?;
+        let metadata = table_metadata(db, &self.table)?;
+        let schema = query_schema(metadata);
+        let columns = projection_columns(&select.projection, &schema)?;
+        let (limit, offset) = limit_and_offset(&self.query)?;
+        let constant = select.selection.as_ref().and_then(constant_predicate);
+        // Reaching the LIMIT or a WHERE that's always false ends the scan
+        let remaining = limit.map(|limit| limit - self.returned);
+        if remaining == Some(0) || constant == Some(false) {
+            self.next = None;
+        }
+        let count = remaining.map_or(count, |remaining| count.min(remaining));
+
+        let mut results = ResultRows {
+            schema: &schema,
+            filter: select.selection.as_ref().filter(|_| constant.is_none()),
+            columns: &columns,
+            sort_keys: &[],
+            distinct: None,
+            limit: Some(count),
+            offset,
+            single_match: false,
+            skipped: self.skipped,
+            filtered: 0,
+            seen: HashSet::new(),
+            sort_types: Vec::new(),
+            runs: SortRuns::new(db.sort_buffer_rows),
+            rows: Vec::new(),
+        };
+        let mut current = None;
+        if count > 0
+            && let Some(start) = self.next
+        {
+            let mut resume = None;
+            scan_table_from(
+                db,
+                metadata,
+                start,
+                &mut QueryStats::default(),
+                |row_id, row| {
+                    let returned = results.rows.len();
+                    let more = results.push(&query_row(row_id, row))?;
+                    if results.rows.len() > returned {
+                        current = Some(row_id);
+                    }
+                    if !more {
+                        resume = Some(RowId {
+                            page_id: row_id.page_id,
+                            slot_id: row_id.slot_id + 1,
+                        });
+                    }
+                    Ok(more)
+                },
+            )?;
+            self.next = resume;
+        }
+        if count > 0 {
+            self.current = current;
+        }
+
+        self.skipped = results.skipped;
+        let rows = results.finish()?;
+        self.returned += rows.len();
+        let headers: Vec<String> = columns.into_iter().map(|(name, _)| name).collect();
+        Ok(format_result(&headers, &rows))
+    }
+}
+
+enum RowSource<'a> {
+    /// A single table, read according to `plan_scan`. Its schema is the
+    /// table's `query_schema`.
+    Table {
+        name: String,
+        metadata: &'a TableMetadata,
+        schema: Vec<ColumnDef>,
+        /// Read only a random sample of the rows, from a full scan
+        sample: Option<TableSampling>,
+    },
+    /// Joined tables, combined up front. Columns are named `table.column`,
+    /// using the table's alias if it has one. Also a lone `WITH` query's
+    /// result, with its columns named as they are.
+    Joined {
+        schema: Vec<ColumnDef>,
+        rows: Vec<Vec<Value>>,
+    },
+}
+
+impl RowSource<'_> {
+    fn schema(&self) -> &[ColumnDef] {
+        match self {
+            RowSource::Table { schema, .. } => schema,
+            RowSource::Joined { schema, .. } => schema,
+        }
+    }
+}
+
+fn row_source<'a>(
+    db: &'a Database,
+    tables: &[JoinedTable],
+    ctes: &CommonTables,
+    stats: &mut QueryStats,
+) -> Result<RowSource<'a>, ExecError> {
+    if tables
+        .iter()
+        .any(|table| table.sample.is_some() && ctes.contains_key(&table.name))
+    {
+        return Err(ExecError::Unsupported(
+            "TABLESAMPLE can't be used on a WITH query".to_string(),
+        ));
+    }
+    if let [table] = tables {
+        if let Some(cte) = ctes.get(&table.name) {
+            return Ok(RowSource::Joined {
+                schema: cte.schema.clone(),
+                rows: cte.rows.clone(),
+            });
+        }
+        let metadata = table_metadata(db, &table.name)?;
+        return Ok(RowSource::Table {
+            name: table.name.clone(),
+            metadata,
+            schema: query_schema(metadata),
+            sample: table.sample,
+        });
+    }
+    if tables.iter().any(|table| table.sample.is_some()) {
+        return Err(ExecError::Unsupported(
+            "TABLESAMPLE is only supported when selecting from a single table".to_string(),
+        ));
+    }
+
+    let (schema, rows) = join_rows(db, tables, ctes, stats)?;
+    Ok(RowSource::Joined { schema, rows })
+}
+
+/// The result of a `WITH name AS (query)`, which the queries after it read
+/// like a table
+struct CommonTable {
+    /// The query's result columns, typed after the values in them
+    schema: Vec<ColumnDef>,
+    rows: Vec<Vec<Value>>,
+}
+
+/// The `WITH` queries in scope, by name
+type CommonTables = HashMap<String, CommonTable>;
+
+/// Run each query of a `WITH` clause once, in order, adding its result to
+/// the ones in `outer`. Each can read those before it, and one named like
+/// a table hides the table.
+fn common_tables(
+    db: &Database,
+    with: With,
+    outer: &CommonTables,
+    stats: &mut QueryStats,
+) -> Result<CommonTables, ExecError> {
+    if with.recursive {
+        return Err(ExecError::Unsupported(
+            "WITH RECURSIVE is not supported".to_string(),
+        ));
+    }
+    let mut ctes: CommonTables = outer
+        .iter()
+        .map(|(name, cte)| {
+            let cte = CommonTable {
+                schema: cte.schema.clone(),
+                rows: cte.rows.clone(),
+            };
+            (name.clone(), cte)
+        })
+        .collect();
+    let mut defined = HashSet::new();
+    for cte in with.cte_tables {
+        let name = cte.alias.name.value;
+        if !defined.insert(name.clone()) {
+            return Err(ExecError::AlreadyExists(format!(
+                "WITH query name '{}' is used more than once",
+                name
+            )));
+        }
+        let result = select_rows(db, *cte.query, &ctes, stats)?;
+        let aliases = &cte.alias.columns;
+        if aliases.len() > result.columns.len() {
+            return Err(ExecError::Type(format!(
+                "WITH query '{}' has {} column(s) but {} name(s) were given",
+                name,
+                result.columns.len(),
+                aliases.len()
+            )));
+        }
+        let schema = result
+            .columns
+            .iter()
+            .enumerate()
+            .map(|(idx, column)| ColumnDef {
+                name: aliases
+                    .get(idx)
+                    .map_or_else(|| Ident::new(column), |alias| alias.name.clone()),
+                data_type: recovered_type(result.rows.iter().map(|row| &row[idx])),
+                options: vec![],
+            })
+            .collect();
+        let table = CommonTable {
+            schema,
+            rows: result.rows,
+        };
+        ctes.insert(name, table);
+    }
+    Ok(ctes)
+}
+
+fn table_metadata<'a>(db: &'a Database, table_name: &str) -> Result<&'a TableMetadata, ExecError> {
+    db.tables
+        .get(table_name)
+        .ok_or_else(|| ExecError::NotFound(format!("Table '{}' does not exist", table_name)))
+}
+
+/// Hidden column every table has: where the row is stored, as
+/// `page_id * 65536 + slot`. It stays the same as long as the row stays put,
+/// but an UPDATE that no longer fits on the page moves the row, and a deleted
+/// row's id can be given to a later one.
+pub(crate) const ROW_ID_COLUMN: &str = "_row_id";
+/// Hidden column of tables created `WITH (created_at = true)`: when the row
+/// was inserted, in seconds since the Unix epoch. It's stored as the table's
+/// last column.
+pub(crate) const CREATED_AT_COLUMN: &str = "_created_at";
+
+/// Whether a column is a hidden system column, which `SELECT *` leaves out
+/// and no statement can write to
+pub(crate) fn is_system_column(name: &str) -> bool {
+    let name = unqualified(name);
+    name.eq_ignore_ascii_case(ROW_ID_COLUMN) || name.eq_ignore_ascii_case(CREATED_AT_COLUMN)
+}
+
+fn system_column(name: &str) -> ColumnDef {
+    ColumnDef {
+        name: Ident::new(name),
+        data_type: DataType::BigInt(None),
+        options: vec![],
+    }
+}
+
+/// Position of a table's `_created_at` column, if it has one
+fn created_at_position(schema: &[ColumnDef]) -> Option<usize> {
+    schema
+        .iter()
+        .position(|c| c.name.value == CREATED_AT_COLUMN)
+}
+
+/// A table's columns as a query reading it sees them: its own, then `_row_id`
+fn query_schema(metadata: &TableMetadata) -> Vec<ColumnDef> {
+    let mut schema = metadata.schema.clone();
+    schema.push(system_column(ROW_ID_COLUMN));
+    schema
+}
+
+/// A stored row's values, lined up with `query_schema`
+fn query_row(row_id: RowId, row: Row) -> Vec<Value> {
+    let mut values = row.values;
+    values.push(Value::Long(row_id_value(row_id)));
+    values
+}
+
+/// A row's `ROW_ID_COLUMN` value
+fn row_id_value(row_id: RowId) -> i64 {
+    ((row_id.page_id as i64) << 16) | row_id.slot_id as i64
+}
+
+/// Join tables with nested loops: every combination of rows for which each
+/// table's ON condition holds, laid out as each table's columns in FROM order
+fn join_rows(
+    db: &Database,
+    tables: &[JoinedTable],
+    ctes: &CommonTables,
+    stats: &mut QueryStats,
+) -> Result<(Vec<ColumnDef>, Vec<Vec<Value>>), ExecError> {
+    let mut schema: Vec<ColumnDef> = Vec::new();
+    let mut rows: Vec<Vec<Value>> = vec![vec![]];
+
+    for table in tables {
+        let (columns, right) = match ctes.get(&table.name) {
+            Some(cte) => (&cte.schema, cte.rows.clone()),
+            None => {
+                let metadata = table_metadata(db, &table.name)?;
+                let mut right = Vec::new();
+                scan_table(db, metadata, stats, |_, row| {
+                    right.push(row.values);
+                    Ok(true)
+                })?;
+                (&metadata.schema, right)
+            }
+        };
+        schema.extend(columns.iter().map(|c| ColumnDef {
+            name: Ident::new(format!("{}.{}", table.qualifier, c.name.value)),
+            ..c.clone()
+        }));
+        // The ON condition sees this table and the ones before it
+        if let Some(on) = table.on {
+            validate_columns(on, &schema)?;
+        }
+
+        let mut joined = Vec::new();
+        for left in &rows {
+            for right in &right {
+                let combined: Vec<Value> = left.iter().chain(right).cloned().collect();
+                let matches = match table.on {
+                    Some(on) => eval_predicate(on, &schema, &combined)?,
+                    None => true,
+                };
+                if matches {
+                    joined.push(combined);
+                }
+            }
+        }
+        rows = joined;
+    }
+
+    Ok((schema, rows))
+}
+
+/// What an ORDER BY term sorts on
+enum SortKey {
+    /// A column of the result, by position
+    Output(usize),
+    /// A table column that isn't necessarily selected
+    Column(usize),
+    /// An expression over the table's columns, such as `salary * 12`
+    Expr(Box<Expr>),
+}
+
+/// A resolved ORDER BY term
+struct SortTerm {
+    key: SortKey,
+    descending: bool,
+    /// Whether NULLs come before every other value, in either direction
+    nulls_first: bool,
+    /// How text values compare
+    collation: Collation,
+}
+
+impl SortTerm {
+    /// The order of two rows' values for this term
+    fn compare(&self, a: &Value, b: &Value) -> Ordering {
+        let nulls = if self.nulls_first {
+            Ordering::Less
+        } else {
+            Ordering::Greater
+        };
+        match (a, b) {
+            (Value::Null, Value::Null) => Ordering::Equal,
+            (Value::Null, _) => nulls,
+            (_, Value::Null) => nulls.reverse(),
+            _ if self.descending => self.collation.sort_order(a, b).reverse(),
+            _ => self.collation.sort_order(a, b),
+        }
+    }
+}
+
+/// Resolve the ORDER BY clause into sort terms.
+///
+/// An integer literal is a 1-based position in the select list (`ORDER BY 2`).
+/// A name matches a result column (including aliases) first, then a table column.
+/// Anything else is an expression evaluated on each row.
+/// A term sorts with its `COLLATE` clause if it has one, else the column's collation.
+/// NULLs sort as if larger than any other value unless `NULLS FIRST` or
+/// `NULLS LAST` says otherwise: last when ascending, first when descending.
+fn order_by_keys(
+    query: &sqlparser::ast::Query,
+    columns: &[(String, Expr)],
+    schema: &[ColumnDef],
+) -> Result<Vec<SortTerm>, ExecError> {
+    let exprs = match query.order_by.as_ref().map(|o| &o.kind) {
+        None => return Ok(vec![]),
+        Some(OrderByKind::Expressions(exprs)) => exprs,
+        Some(OrderByKind::All(_)) => {
+            return Err(ExecError::Unsupported(
+                "ORDER BY ALL is not supported".to_string(),
+            ));
+        }
+    };
+
+    let mut keys = Vec::new();
+    for order_by in exprs {
+        let descending = order_by.options.asc == Some(false);
+        let nulls_first = order_by.options.nulls_first.unwrap_or(descending);
+        let (expr, collation) = match &order_by.expr {
+            Expr::Collate { expr, collation } => (&**expr, Some(Collation::from_name(collation)?)),
+            expr => (expr, None),
+        };
+
+        let key = match expr {
+            Expr::Value(v) if matches!(v.value, sqlparser::ast::Value::Number(..)) => {
+                let position = expr.to_string();
+                match position.parse::<usize>() {
+                    Ok(n) if (1..=columns.len()).contains(&n) => SortKey::Output(n - 1),
+                    _ => {
+                        return Err(ExecError::NotFound(format!(
+                            "ORDER BY position {} is not in the select list",
+                            position
+                        )));
+                    }
+                }
+            }
+            expr => match (column_name(expr), column_position(schema, expr)) {
+                (Some(name), Some(position)) => match columns
+                    .iter()
+                    .position(|(header, _)| header.eq_ignore_ascii_case(name))
+                {
+                    Some(idx) => SortKey::Output(idx),
+                    None => SortKey::Column(position?),
+                },
+                _ => {
+                    validate_columns(expr, schema)?;
+                    SortKey::Expr(Box::new(expr.clone()))
+                }
+            },
+        };
+        let collation = match (collation, &key) {
+            (Some(collation), _) => collation,
+            (None, SortKey::Output(idx)) => expr_collation(&columns[*idx].1, schema)?,
+            (None, SortKey::Column(idx)) => column_collation(&schema[*idx])?,
+            (None, SortKey::Expr(expr)) => expr_collation(expr, schema)?,
+        };
+        keys.push(SortTerm {
+            key,
+            descending,
+            nulls_first,
+            collation,
+        });
+    }
+
+    Ok(keys)
+}
+
+/// The row limit and number of rows to skip, from `LIMIT n [OFFSET m]` or
+/// the standard `[OFFSET m ROWS] FETCH FIRST n ROWS ONLY`
+fn limit_and_offset(query: &sqlparser::ast::Query) -> Result<(Option<usize>, usize), ExecError> {
+    let (mut limit, offset) = match &query.limit_clause {
+        None => (None, None),
+        Some(LimitClause::LimitOffset {
+            limit,
+            offset,
+            limit_by,
+        }) => {
+            if !limit_by.is_empty() {
+                return Err(ExecError::Unsupported(
+                    "LIMIT BY is not supported".to_string(),
+                ));
+            }
+            (limit.as_ref(), offset.as_ref().map(|o| &o.value))
+        }
+        Some(LimitClause::OffsetCommaLimit { offset, limit }) => (Some(limit), Some(offset)),
+    };
+
+    // `FETCH FIRST ROW ONLY` leaves out the count, which is then one
+    let one = Expr::value(sqlparser::ast::Value::Number("1".to_string(), false));
+    if let Some(fetch) = &query.fetch {
+        if fetch.with_ties {
+            return Err(ExecError::Unsupported(
+                "FETCH FIRST ... WITH TIES is not supported".to_string(),
+            ));
+        }
+        if fetch.percent {
+            return Err(ExecError::Unsupported(
+                "FETCH FIRST ... PERCENT is not supported".to_string(),
+            ));
+        }
+        if limit.is_some() {
+            return Err(ExecError::Unsupported(
+                "A query can't have both LIMIT and FETCH FIRST".to_string(),
+            ));
+        }
+        limit = Some(fetch.quantity.as_ref().unwrap_or(&one));
+    }
+
+    let count = |expr: &Expr| match eval_expr(expr, &[], &[])? {
+        Value::Long(n) if n >= 0 => Ok(n as usize),
+        other => Err(ExecError::Type(format!(
+            "LIMIT and OFFSET take a non-negative integer, got {}",
+            format_value(&other)
+        ))),
+    };
+
+    Ok((
+        limit.map(count).transpose()?,
+        offset.map(count).transpose()?.unwrap_or(0),
+    ))
+}
+
+/// The SELECT body of a query
+/// Check a query's `FOR UPDATE` and `FOR SHARE` clauses. They don't lock
+/// anything themselves: the server serves one connection at a time, so a
+/// transaction already has the whole database to itself until it ends.
+fn check_lock_clauses(
+    query: &sqlparser::ast::Query,
+    tables: &[JoinedTable],
+    grouped: bool,
+) -> Result<(), ExecError> {
+    for lock in &query.locks {
+        if grouped {
+            return Err(ExecError::Unsupported(format!(
+                "FOR {} is not allowed with GROUP BY or aggregate functions",
+                lock.lock_type
+            )));
+        }
+        if let Some(of) = &lock.of {
+            let name =
+                of.0.last()
+                    .map_or_else(String::new, |part| part.to_string());
+            if !tables
+                .iter()
+                .any(|t| t.qualifier.eq_ignore_ascii_case(&name))
+            {
+                return Err(ExecError::NotFound(format!(
+                    "Table '{}' in FOR {} OF is not in FROM",
+                    of, lock.lock_type
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn select_body(query: &sqlparser::ast::Query) -> Result<&Select, ExecError> {
+    match query.body.as_ref() {
+        SetExpr::Select(select) => Ok(select),
+        _ => Err(ExecError::Unsupported(
+            "Only SELECT is supported".to_string(),
+        )),
+    }
+}
+
+/// A table in a FROM clause
+struct JoinedTable<'a> {
+    name: String,
+    /// What its columns are qualified with: the alias, or else the table name
+    qualifier: String,
+    /// The condition joining it to the tables before it
+    on: Option<&'a Expr>,
+    sample: Option<TableSampling>,
+}
+
+/// A table's `TABLESAMPLE` clause
+#[derive(Debug, Clone, Copy)]
+struct TableSampling {
+    size: SampleSize,
+    /// From `REPEATABLE (seed)`, which picks the same rows each time
+    seed: Option<u64>,
+}
+
+impl fmt::Display for TableSampling {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.size {
+            SampleSize::Rows(n) => write!(f, "{} ROWS", n)?,
+            SampleSize::Percent(percent) => write!(f, "{} PERCENT", percent)?,
+        }
+        if let Some(seed) = self.seed {
+            write!(f, ", seed {}", seed)?;
+        }
+        Ok(())
+    }
+}
+
+/// The tables a SELECT reads, in join order. Tables separated by commas,
+/// like `CROSS JOIN`, are joined without a condition: every row of one with
+/// every row of the other.
+fn from_tables(select: &Select) -> Result<Vec<JoinedTable<'_>>, ExecError> {
+    if select.from.is_empty() {
+        return Err(ExecError::Unsupported(
+            "SELECT requires a FROM clause".to_string(),
+        ));
+    }
+
+    let mut tables: Vec<JoinedTable> = Vec::new();
+    for from in &select.from {
+        let mut joined = vec![joined_table(&from.relation, None)?];
+        for join in &from.joins {
+            let on = match &join.join_operator {
+                JoinOperator::Join(JoinConstraint::On(expr))
+                | JoinOperator::Inner(JoinConstraint::On(expr)) => Some(expr),
+                JoinOperator::CrossJoin(JoinConstraint::None) => None,
+                _ => {
+                    return Err(ExecError::Unsupported(
+                        "Only [INNER] JOIN ... ON and CROSS JOIN are supported".to_string(),
+                    ));
+                }
+            };
+            joined.push(joined_table(&join.relation, on)?);
+        }
+
+        for table in joined {
+            if tables
+                .iter()
+                .any(|t| t.qualifier.eq_ignore_ascii_case(&table.qualifier))
+            {
+                return Err(ExecError::AlreadyExists(format!(
+                    "Table name '{}' appears more than once in FROM; give one an alias",
+                    table.qualifier
+                )));
+            }
+            tables.push(table);
+        }
+    }
+
+    Ok(tables)
+}
+
+fn joined_table<'a>(
+    relation: &TableFactor,
+    on: Option<&'a Expr>,
+) -> Result<JoinedTable<'a>, ExecError> {
+    match relation {
+        TableFactor::Table {
+            name,
+            alias,
+            sample,
+            ..
+        } => Ok(JoinedTable {
+            name: name.to_string(),
+            qualifier: match alias {
+                Some(alias) => alias.name.value.clone(),
+                None => name
+                    .0
+                    .last()
+                    .map_or_else(String::new, |part| part.to_string()),
+            },
+            on,
+            sample: sample.as_ref().map(table_sampling).transpose()?,
+        }),
+        _ => Err(ExecError::Unsupported(
+            "Only simple table references are supported".to_string(),
+        )),
+    }
+}
+
+/// Resolve `TABLESAMPLE (n ROWS)` or `TABLESAMPLE [BERNOULLI | SYSTEM] (p
+/// [PERCENT])`, optionally followed by `REPEATABLE (seed)`. Every method
+/// picks rows one at a time.
+fn table_sampling(kind: &TableSampleKind) -> Result<TableSampling, ExecError> {
+    let (TableSampleKind::BeforeTableAlias(sample) | TableSampleKind::AfterTableAlias(sample)) =
+        kind;
+    let quantity = match &sample.quantity {
+        Some(quantity)
+            if sample.bucket.is_none()
+                && sample.offset.is_none()
+                && sample.name != Some(TableSampleMethod::Block) =>
+        {
+            quantity
+        }
+        _ => {
+            return Err(ExecError::Unsupported(format!(
+                "Unsupported TABLESAMPLE: {}; use TABLESAMPLE (n ROWS) or TABLESAMPLE (p PERCENT)",
+                sample
+            )));
+        }
+    };
+
+    let value = eval_expr(&quantity.value, &[], &[])?;
+    let size = match (&quantity.unit, &value) {
+        (Some(TableSampleUnit::Rows), Value::Long(n)) if *n >= 0 => SampleSize::Rows(*n as usize),
+        (Some(TableSampleUnit::Rows), _) => {
+            return Err(ExecError::Type(format!(
+                "TABLESAMPLE ROWS takes a non-negative integer, got {}",
+                format_value(&value)
+            )));
+        }
+        (_, Value::Long(_) | Value::Decimal(_))
+            if let Ok(percent) = format_value(&value).parse::<f64>()
+                && (0.0..=100.0).contains(&percent) =>
+        {
+            SampleSize::Percent(percent)
+        }
+        _ => {
+            return Err(ExecError::Type(format!(
+                "TABLESAMPLE PERCENT takes a number from 0 to 100, got {}",
+                format_value(&value)
+            )));
+        }
+    };
+
+    let seed = match &sample.seed {
+        Some(seed) => match expr_to_value(Expr::Value(seed.value.clone().into()))? {
+            Value::Long(n) => Some(n as u64),
+            other => {
+                return Err(ExecError::Type(format!(
+                    "REPEATABLE takes an integer seed, got {}",
+                    format_value(&other)
+                )));
+            }
+        },
+        None => None,
+    };
+    Ok(TableSampling { size, seed })
+}
+
+/// How a SELECT reads its table
+enum ScanPlan<'a> {
+    /// Fetch the rows an index lookup returns
+    Index { index: &'a Index, key: Vec<Value> },
+    /// Walk the page chain; `single_match` stops at the first matching row
+    Full { single_match: bool },
+}
+
+/// Run each `x IN (subquery)` and `EXISTS (subquery)` in a SELECT's WHERE
+/// clause; see `resolve_subqueries`
+fn resolve_where_subqueries(
+    db: &Database,
+    query: &mut sqlparser::ast::Query,
+    ctes: &CommonTables,
+    stats: &mut QueryStats,
+) -> Result<(), ExecError> {
+    if let SetExpr::Select(select) = query.body.as_mut()
+        && let Some(selection) = &mut select.selection
+    {
+        resolve_subqueries(db, selection, ctes, stats)?;
+    }
+    Ok(())
+}
+
+/// Run each `x IN (subquery)` in `expr` once, replacing it with
+/// `x IN (values...)` so every row is tested against the same values. The
+/// subquery can't refer to the outer query's columns. `EXISTS (subquery)` is
+/// run once too; see `resolve_exists`.
+fn resolve_subqueries(
+    db: &Database,
+    expr: &mut Expr,
+    ctes: &CommonTables,
+    stats: &mut QueryStats,
+) -> Result<(), ExecError> {
+    let mut error = None;
+    walk_expr_mut(expr, &mut |expr| {
+        if let Expr::Exists { subquery, negated } = expr {
+            match resolve_exists(db, subquery, *negated, ctes, stats) {
+                Ok(resolved) => *expr = resolved,
+                Err(e) => error = Some(e),
+            }
+            return false;
+        }
+        if let Expr::AnyOp { right, .. } | Expr::AllOp { right, .. } = expr
+            && let Expr::Subquery(subquery) = right.as_mut()
+        {
+            match subquery_values(db, subquery, "ANY and ALL", ctes, stats) {
+                Ok(elem) => **right = Expr::Array(sqlparser::ast::Array { elem, named: false }),
+                Err(e) => error = Some(e),
+            }
+            return false;
+        }
+        let Expr::InSubquery {
+            expr: inner,
+            subquery,
+            negated,
+        } = expr
+        else {
+            return error.is_none();
+        };
+        match subquery_values(db, subquery, "IN", ctes, stats) {
+            Ok(list) => {
+                *expr = Expr::InList {
+                    expr: inner.clone(),
+                    list,
+                    negated: *negated,
+                };
+            }
+            Err(e) => error = Some(e),
+        }
+        false
+    });
+    error.map_or(Ok(()), Err)
+}
+
+/// Run a subquery that must return one column, as literals of its values
+fn subquery_values(
+    db: &Database,
+    subquery: &sqlparser::ast::Query,
+    operator: &str,
+    ctes: &CommonTables,
+    stats: &mut QueryStats,
+) -> Result<Vec<Expr>, ExecError> {
+    let result = select_rows(db, subquery.clone(), ctes, stats)?;
+    if result.columns.len() != 1 {
+        return Err(ExecError::Type(format!(
+            "{} subquery must return one column, got {}",
+            operator,
+            result.columns.len()
+        )));
+    }
+    Ok(result
+        .rows
+        .into_iter()
+        .flat_map(|row| row.into_iter().map(|value| value_to_expr(&value)))
+        .collect())
+}
+
+/// Run an `EXISTS (subquery)` once and return the predicate that replaces it.
+/// A subquery that doesn't refer to the outer query becomes TRUE or FALSE.
+/// One that compares its own rows with an outer column, as in
+/// `o.user_id = u.id`, is run without that comparison to collect the values
+/// it would match, and becomes `u.id IS NOT NULL AND u.id IN (values...)`.
+fn resolve_exists(
+    db: &Database,
+    subquery: &sqlparser::ast::Query,
+    negated: bool,
+    ctes: &CommonTables,
+    stats: &mut QueryStats,
+) -> Result<Expr, ExecError> {
+    let mut subquery = subquery.clone();
+    let outer = match subquery.body.as_mut() {
+        SetExpr::Select(select) => decorrelate(db, select, ctes)?,
+        _ => None,
+    };
+    if outer.is_some() && (subquery.limit_clause.is_some() || subquery.fetch.is_some()) {
+        return Err(ExecError::Unsupported(
+            "Correlated EXISTS subqueries can't use LIMIT or OFFSET".to_string(),
+        ));
+    }
+    let result = select_rows(db, subquery, ctes, stats)?;
+
+    let Some(outer) = outer else {
+        let exists = !result.rows.is_empty();
+        return Ok(Expr::Value(
+            sqlparser::ast::Value::Boolean(exists != negated).into(),
+        ));
+    };
+    // NULL never compares equal, so it's left out; the IS NOT NULL keeps a
+    // NULL outer value from making EXISTS NULL instead of FALSE
+    let values: HashSet<Value> = result
+        .rows
+        .into_iter()
+        .flatten()
+        .filter(|value| *value != Value::Null)
+        .collect();
+    let exists = Expr::BinaryOp {
+        left: Box::new(Expr::IsNotNull(Box::new(outer.clone()))),
+        op: BinaryOperator::And,
+        right: Box::new(Expr::InList {
+            expr: Box::new(outer),
+            list: values.iter().map(value_to_expr).collect(),
+            negated: false,
+        }),
+    };
+    Ok(match negated {
+        false => exists,
+        true => Expr::UnaryOp {
+            op: UnaryOperator::Not,
+            expr: Box::new(Expr::Nested(Box::new(exists))),
+        },
+    })
+}
+
+/// Take the one `inner = outer` comparison out of a correlated EXISTS
+/// subquery's WHERE clause, so the subquery selects the inner side instead,
+/// and return the outer side. A column refers to the outer query if it's
+/// qualified with a name that isn't one of the subquery's tables, or if none
+/// of them has a column by that name. `None` if the subquery doesn't refer to
+/// the outer query at all.
+fn decorrelate(
+    db: &Database,
+    select: &mut Select,
+    ctes: &CommonTables,
+) -> Result<Option<Expr>, ExecError> {
+    let tables: Vec<(String, Vec<ColumnDef>)> = from_tables(select)?
+        .iter()
+        .map(|table| match ctes.get(&table.name) {
+            Some(cte) => Ok((table.qualifier.clone(), cte.schema.clone())),
+            None => {
+                let metadata = table_metadata(db, &table.name)?;
+                Ok((table.qualifier.clone(), query_schema(metadata)))
+            }
+        })
+        .collect::<Result<_, ExecError>>()?;
+    let is_inner = |column: &Expr| match column {
+        Expr::CompoundIdentifier(parts) if parts.len() >= 2 => {
+            let qualifier = &parts[parts.len() - 2].value;
+            tables
+                .iter()
+                .any(|(q, _)| q.eq_ignore_ascii_case(qualifier))
+        }
+        _ => column_name(column).is_some_and(|name| {
+            tables
+                .iter()
+                .any(|(_, schema)| column_index(schema, name).is_ok())
+        }),
+    };
+    // How many of an expression's column references are inner and outer
+    let count_refs = |expr: &Expr| {
+        let refs = column_refs(expr);
+        let inner = refs.iter().filter(|column| is_inner(column)).count();
+        (inner, refs.len() - inner)
+    };
+
+    let Some(selection) = &select.selection else {
+        return Ok(None);
+    };
+    let mut terms = Vec::new();
+    split_conjuncts(selection, &mut terms);
+    let mut kept = Vec::new();
+    let mut correlation = None;
+    for term in terms {
+        if count_refs(term).1 == 0 {
+            kept.push(term.clone());
+            continue;
+        }
+        if correlation.is_none()
+            && let Expr::BinaryOp {
+                left,
+                op: BinaryOperator::Eq,
+                right,
+            } = term
+        {
+            match (count_refs(left), count_refs(right)) {
+                ((_, 0), (0, _)) => correlation = Some(((**left).clone(), (**right).clone())),
+                ((0, _), (_, 0)) => correlation = Some(((**right).clone(), (**left).clone())),
+                _ => {}
+            }
+            if correlation.is_some() {
+                continue;
+            }
+        }
+        return Err(ExecError::Unsupported(format!(
+            "EXISTS subqueries can refer to the outer query in one `column = outer_column` comparison, not in: {}",
+            term
+        )));
+    }
+    let Some((inner, outer)) = correlation else {
+        return Ok(None);
+    };
+
+    let grouped = !matches!(&select.group_by, GroupByExpr::Expressions(exprs, _) if exprs.is_empty())
+        || select.having.is_some()
+        || select.projection.iter().any(|item| match item {
+            SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => {
+                contains_aggregate(expr)
+            }
+            _ => false,
+        });
+    if grouped {
+        return Err(ExecError::Unsupported(
+            "Correlated EXISTS subqueries can't group or aggregate".to_string(),
+        ));
+    }
+    select.projection = vec![SelectItem::UnnamedExpr(inner)];
+    select.selection = kept.into_iter().reduce(|left, right| Expr::BinaryOp {
+        left: Box::new(left),
+        op: BinaryOperator::And,
+        right: Box::new(right),
+    });
+    Ok(Some(outer))
+}
+
+/// The AND-ed terms of a WHERE clause
+fn split_conjuncts<'a>(expr: &'a Expr, out: &mut Vec<&'a Expr>) {
+    match expr {
+        Expr::BinaryOp {
+            left,
+            op: BinaryOperator::And,
+            right,
+        } => {
+            split_conjuncts(left, out);
+            split_conjuncts(right, out);
+        }
+        Expr::Nested(inner) => split_conjuncts(inner, out),
+        _ => out.push(expr),
+    }
+}
+
+/// The column references in an expression
+fn column_refs(expr: &Expr) -> Vec<Expr> {
+    let mut refs = Vec::new();
+    walk_expr_mut(&mut expr.clone(), &mut |expr| match expr {
+        Expr::Identifier(_) | Expr::CompoundIdentifier(_) => {
+            refs.push(expr.clone());
+            false
+        }
+        _ => true,
+    });
+    refs
+}
+
+/// The outcome of a WHERE that's just `TRUE`, `FALSE` or `NULL`, which is the
+/// same for every row. NULL matches nothing, like FALSE.
+fn constant_predicate(expr: &Expr) -> Option<bool> {
+    match expr {
+        Expr::Value(v) => match v.value {
+            sqlparser::ast::Value::Boolean(b) => Some(b),
+            sqlparser::ast::Value::Null => Some(false),
+            _ => None,
+        },
+        Expr::Nested(inner) => constant_predicate(inner),
+        _ => None,
+    }
+}
+
+fn plan_scan<'a>(
+    db: &'a Database,
+    table_name: &str,
+    metadata: &TableMetadata,
+    filter: Option<&Expr>,
+) -> ScanPlan<'a> {
+    match choose_index(db, table_name, filter) {
+        Some((index, key)) => ScanPlan::Index { index, key },
+        // Equality on a unique column matches at most one row, so stop at the first
+        None => ScanPlan::Full {
+            single_match: filter.is_some_and(|f| matches_unique_key(&metadata.schema, f)),
+        },
+    }
+}
+
+/// `PRAGMA name` or `PRAGMA name('argument')`: a read-only look at the
+/// storage engine, returned as a result set
+fn handle_pragma(
+    db: &Database,
+    name: &ObjectName,
+    value: Option<&sqlparser::ast::Value>,
+    is_eq: bool,
+) -> Result<String, ExecError> {
+    let pragma = name.to_string().to_lowercase();
+    if is_eq {
+        return Err(ExecError::Unsupported(format!(
+            "PRAGMA {} can't be set",
+            pragma
+        )));
+    }
+    let argument = value.map(|v| v.clone().into_string().unwrap_or_else(|| v.to_string()));
+    let long = |n: usize| Value::Long(n as i64);
+
+    let (headers, rows) = match (pragma.as_str(), argument) {
+        ("page_count", None) => (
+            vec!["page_count"],
+            vec![vec![long(db.buffer_pool.borrow().num_pages() as usize)]],
+        ),
+        ("free_pages", None) => (vec!["free_pages"], vec![vec![long(db.free_pages()?.len())]]),
+        ("buffer_stats", None) => {
+            let pool = db.buffer_pool.borrow();
+            let (cached, capacity) = pool.cached_pages();
+            let stats = pool.stats();
+            (
+                vec!["cached_pages", "capacity", "hits", "misses", "evictions"],
+                vec![vec![
+                    long(cached),
+                    long(capacity),
+                    Value::Long(stats.hits as i64),
+                    Value::Long(stats.misses as i64),
+                    Value::Long(stats.evictions as i64),
+                ]],
+            )
+        }
+        ("table_pages", Some(table)) => {
+            let metadata = table_metadata(db, &table)?;
+            let mut rows = Vec::new();
+            for page_id in db.table_pages(metadata.first_page_id)? {
+                let page_rc = db.buffer_pool.borrow_mut().fetch_page(page_id)?;
+                let mut page = *page_rc.borrow();
+                let slotted = SlottedPage::new(&mut page);
+                let tuples = (0..slotted.num_slots())
+                    .filter(|&slot_id| slotted.get_tuple_ref(slot_id).is_some())
+                    .count();
+                rows.push(vec![
+                    long(page_id as usize),
+                    long(tuples),
+                    long(slotted.free_space()),
+                ]);
+            }
+            (vec!["page_id", "tuples", "free_space"], rows)
+        }
+        ("table_pages", None) => {
+            return Err(ExecError::Type(
+                "PRAGMA table_pages takes a table name, as in PRAGMA table_pages(users)"
+                    .to_string(),
+            ));
+        }
+        ("page_count" | "free_pages" | "buffer_stats", Some(_)) => {
+            return Err(ExecError::Type(format!(
+                "PRAGMA {} takes no argument",
+                pragma
+            )));
+        }
+        _ => {
+            return Err(ExecError::NotFound(format!("Unknown PRAGMA '{}'", pragma)));
+        }
+    };
+    let headers: Vec<String> = headers.into_iter().map(str::to_string).collect();
+    Ok(format_result(&headers, &rows))
+}
+
+/// EXPLAIN describes how a SELECT would run; EXPLAIN ANALYZE also runs it
+/// and reports the measured row counts and elapsed time
+fn handle_explain(
+    db: &Database,
+    statement: Statement,
+    analyze: bool,
+    stats: &mut QueryStats,
+) -> Result<String, ExecError> {
+    let query = match statement {
+        Statement::Query(query) => query,
+        _ => {
+            return Err(ExecError::Unsupported(
+                "EXPLAIN only supports SELECT".to_string(),
+            ));
+        }
+    };
+    if query.with.is_some() {
+        return Err(ExecError::Unsupported(
+            "EXPLAIN doesn't support WITH queries".to_string(),
+        ));
+    }
+
+    let select = select_body(&query)?;
+    let constant = select.selection.as_ref().and_then(constant_predicate);
+    let filter = select.selection.as_ref().filter(|_| constant.is_none());
+
+    let mut output = match from_tables(select)?.as_slice() {
+        tables if constant == Some(false) => {
+            for table in tables {
+                table_metadata(db, &table.name)?;
+            }
+            "Empty Result (WHERE is always false)".to_string()
+        }
+        [table] => match table.sample {
+            Some(sample) => {
+                table_metadata(db, &table.name)?;
+                format!("Sample Scan on {} ({})", table.name, sample)
+            }
+            None => explain_scan(db, &table.name, filter)?,
+        },
+        tables => {
+            let mut plan = "Nested Loop Join".to_string();
+            for table in tables {
+                table_metadata(db, &table.name)?;
+                plan.push_str(&format!("\n  Seq Scan on {}", table.name));
+                if let Some(on) = table.on {
+                    plan.push_str(&format!("\n    Join Filter: {}", on));
+                }
+            }
+            plan
+        }
+    };
+    if let Some(filter) = filter {
+        output.push_str(&format!("\n  Filter: {}", filter));
+    }
+
+    if analyze {
+        let started = Instant::now();
+        handle_query(db, *query.clone(), stats)?;
+        let elapsed = started.elapsed();
+
+        output.push_str(&format!(
+            "\nRows scanned: {}\nRows filtered: {}\nRows returned: {}\nPages read: {}\nElapsed: {:.3} ms",
+            stats.rows_scanned,
+            stats.rows_filtered,
+            stats.rows_returned,
+            stats.pages_read,
+            elapsed.as_secs_f64() * 1000.0
+        ));
+    }
+
+    Ok(output)
+}
+
+/// The plan for reading a single table, with its estimated row count once
+/// the table has been analyzed
+fn explain_scan(
+    db: &Database,
+    table_name: &str,
+    filter: Option<&Expr>,
+) -> Result<String, ExecError> {
+    let metadata = table_metadata(db, table_name)?;
+
+    let mut output = match plan_scan(db, table_name, metadata, filter) {
+        ScanPlan::Index { index, key } => {
+            let lookup: Vec<String> = index
+                .columns
+                .iter()
+                .zip(&key)
+                .map(|(column, value)| format!("{} = {}", column, format_value(value)))
+                .collect();
+            format!(
+                "Index Lookup on {} using {} ({})",
+                table_name,
+                index.name,
+                lookup.join(", ")
+            )
+        }
+        ScanPlan::Full { single_match } => {
+            let mut plan = format!("Seq Scan on {}", table_name);
+            if single_match {
+                plan.push_str("\n  Stop: first match (unique key)");
+            }
+            plan
+        }
+    };
+    if let Some(stats) = &metadata.stats {
+        let selectivity = filter.map_or(1.0, |f| estimate_selectivity(stats, &metadata.schema, f));
+        output.push_str(&format!(
+            "\n  Estimated rows: {}",
+            stats.estimate_rows(selectivity)
+        ));
+    }
+
+    Ok(output)
+}
+
+/// Render a result set: a header line, a separator, one tab-separated line
+/// per row, and a trailing row count
+fn format_result(headers: &[String], rows: &[Vec<Value>]) -> String {
+    let mut output = String::new();
+
+    if !headers.is_empty() {
+        output.push_str(&headers.join("\t"));
+        output.push('\n');
+        output.push_str(&"-".repeat(headers.len() * 10));
+        output.push('\n');
+    }
+
+    for row in rows {
+        let formatted: Vec<String> = row.iter().map(format_value).collect();
+        output.push_str(&formatted.join("\t"));
+        output.push('\n');
+    }
+
+    output.push_str(&format!("({} rows)", rows.len()));
+    output
+}
+
+/// Visit every row in a table's page chain, in storage order.
+/// The visitor returns `Ok(false)` to stop the scan early.
+pub(crate) fn scan_table<F>(
+    db: &Database,
+    metadata: &TableMetadata,
+    stats: &mut QueryStats,
+    visit: F,
+) -> Result<(), ExecError>
+where
+    F: FnMut(RowId, Row) -> Result<bool, ExecError>,
+{
+    let start = RowId {
+        page_id: metadata.first_page_id,
+        slot_id: 0,
+    };
+    scan_table_from(db, metadata, start, stats, visit)
+}
+
+/// Like `scan_table`, but starting at `start` rather than the table's first row
+fn scan_table_from<F>(
+    db: &Database,
+    metadata: &TableMetadata,
+    start: RowId,
+    stats: &mut QueryStats,
+    mut visit: F,
+) -> Result<(), ExecError>
+where
+    F: FnMut(RowId, Row) -> Result<bool, ExecError>,
+{
+    let mut current_page_id = start.page_id;
+    let mut first_slot = start.slot_id;
+
+    loop {
+        let page_rc = db.buffer_pool.borrow_mut().fetch_page(current_page_id)?;
+        stats.pages_read += 1;
+
+        // Copy the page so the visitor is free to use the buffer pool
+        let mut page_copy = *page_rc.borrow();
+        let slotted = SlottedPage::new(&mut page_copy);
+
+        // Read all tuples from this page
+        for slot_id in first_slot..slotted.num_slots() {
+            if let Some(bytes) = slotted.get_tuple_ref(slot_id) {
+                let row_id = RowId {
+                    page_id: current_page_id,
+                    slot_id,
+                };
+                let row = Row::decode_as(bytes, metadata.format, &metadata.schema)
+                    .map_err(|e| decode_error(db, row_id, e))?;
+                stats.rows_scanned += 1;
+
+                if !visit(row_id, row)? {
+                    return Ok(());
+                }
+            }
+        }
+
+        // Move to next page
+        let next = slotted.next_page_id();
+        if next == NO_NEXT_PAGE {
+            break;
+        }
+        current_page_id = next;
+        first_slot = 0;
+    }
+
+    Ok(())
+}
+
+/// Serialize a row in its table's row format
+fn encode_row(metadata: &TableMetadata, row: &Row) -> Result<Vec<u8>, ExecError> {
+    row.encode(metadata.format, &metadata.schema)
+        .map_err(ExecError::Type)
+}
+
+/// Read a single row of a table by its physical location
+fn fetch_row(
+    db: &Database,
+    metadata: &TableMetadata,
+    row_id: RowId,
+    stats: &mut QueryStats,
+) -> Result<Row, ExecError> {
+    let page_rc = db.buffer_pool.borrow_mut().fetch_page(row_id.page_id)?;
+    stats.pages_read += 1;
+    stats.rows_scanned += 1;
+    let mut page = page_rc.borrow_mut();
+    let slotted = SlottedPage::new(&mut page);
+
+    let bytes = slotted.get_tuple_ref(row_id.slot_id).ok_or_else(|| {
+        ExecError::Storage(format!(
+            "No tuple at page {} slot {}",
+            row_id.page_id, row_id.slot_id
+        ))
+    })?;
+
+    Row::decode_as(bytes, metadata.format, &metadata.schema)
+        .map_err(|e| decode_error(db, row_id, e))
+}
+
+/// A row that failed to decode, with where it's stored so corruption can be
+/// traced to its table, page and slot
+fn decode_error(db: &Database, row_id: RowId, error: String) -> ExecError {
+    let table = match db.page_owner(row_id.page_id) {
+        Ok(Some(table_name)) => format!("table '{}'", table_name),
+        _ => "unknown table".to_string(),
+    };
+    ExecError::Storage(format!(
+        "Failed to deserialize row in {} at page {} slot {}: {}",
+        table, row_id.page_id, row_id.slot_id, error
+    ))
+}
+
+/// Pick the index whose leading columns are covered by the most equality
+/// predicates in the WHERE clause, returning it along with the lookup key.
+fn choose_index<'a>(
+    db: &'a Database,
+    table_name: &str,
+    filter: Option<&Expr>,
+) -> Option<(&'a Index, Vec<Value>)> {
+    let metadata = db.tables.get(table_name)?;
+    let schema = &metadata.schema;
+    let mut equalities = HashMap::new();
+    collect_equalities(filter?, schema, &mut equalities);
+
+    // With statistics, prefer the lookup expected to return the fewest rows
+    let estimate = |index: &Index, key: &[Value]| -> f64 {
+        let Some(stats) = &metadata.stats else {
+            return 0.0;
+        };
+        index
+            .columns
+            .iter()
+            .take(key.len())
+            .filter_map(|c| column_index(schema, c).ok())
+            .map(|idx| stats.equality_selectivity(idx))
+            .product()
+    };
+
+    db.indexes
+        .values()
+        .filter(|index| index.table == table_name)
+        .filter_map(|index| {
+            let key: Vec<Value> = index
+                .columns
+                .iter()
+                .map_while(|c| equalities.get(c.as_str()).cloned())
+                .collect();
+            (!key.is_empty()).then_some((index, key))
+        })
+        // Then the longest usable prefix wins; ties go to the first index by name
+        .min_by(|(a, a_key), (b, b_key)| {
+            estimate(a, a_key)
+                .total_cmp(&estimate(b, b_key))
+                .then_with(|| b_key.len().cmp(&a_key.len()))
+                .then_with(|| a.name.cmp(&b.name))
+        })
+}
+
+/// Estimate the fraction of rows a WHERE clause matches. Equality and integer
+/// range comparisons against literals are estimated from the statistics;
+/// anything else is assumed to match every row.
+fn estimate_selectivity(stats: &TableStats, schema: &[ColumnDef], expr: &Expr) -> f64 {
+    let Expr::BinaryOp { left, op, right } = expr else {
+        return match expr {
+            Expr::Nested(inner) => estimate_selectivity(stats, schema, inner),
+            _ => 1.0,
+        };
+    };
+
+    match op {
+        BinaryOperator::And => {
+            estimate_selectivity(stats, schema, left) * estimate_selectivity(stats, schema, right)
+        }
+        BinaryOperator::Or => {
+            let a = estimate_selectivity(stats, schema, left);
+            let b = estimate_selectivity(stats, schema, right);
+            a + b - a * b
+        }
+        _ => {
+            // Normalize to `column op literal`
+            let (column, op, literal) = match (column_name(left), column_name(right)) {
+                (Some(column), None) => (column, op.clone(), right),
+                (None, Some(column)) => {
+                    let flipped = match op {
+                        BinaryOperator::Lt => BinaryOperator::Gt,
+                        BinaryOperator::LtEq => BinaryOperator::GtEq,
+                        BinaryOperator::Gt => BinaryOperator::Lt,
+                        BinaryOperator::GtEq => BinaryOperator::LtEq,
+                        other => other.clone(),
+                    };
+                    (column, flipped, left)
+                }
+                _ => return 1.0,
+            };
+            let (Ok(idx), Ok(value)) = (
+                column_index(schema, column),
+                expr_to_value((**literal).clone()),
+            ) else {
+                return 1.0;
+            };
+
+            match (op, value) {
+                (BinaryOperator::Eq, _) => stats.equality_selectivity(idx),
+                (BinaryOperator::Lt, Value::Long(n)) => {
+                    stats.range_selectivity(idx, i64::MIN, n.saturating_sub(1))
+                }
+                (BinaryOperator::LtEq, Value::Long(n)) => stats.range_selectivity(idx, i64::MIN, n),
+                (BinaryOperator::Gt, Value::Long(n)) => {
+                    stats.range_selectivity(idx, n.saturating_add(1), i64::MAX)
+                }
+                (BinaryOperator::GtEq, Value::Long(n)) => stats.range_selectivity(idx, n, i64::MAX),
+                _ => 1.0,
+            }
+        }
+    }
+}
+
+/// Whether the WHERE clause pins a PRIMARY KEY or UNIQUE column to a single value
+fn matches_unique_key(schema: &[ColumnDef], filter: &Expr) -> bool {
+    let mut equalities = HashMap::new();
+    collect_equalities(filter, schema, &mut equalities);
+
+    schema
+        .iter()
+        // Mid-transaction, a deferred column may briefly hold duplicates
+        .any(|col| {
+            is_unique(col) && !is_deferred(col) && equalities.contains_key(col.name.value.as_str())
+        })
+}
+
+/// Gather `column = literal` predicates from the AND-ed terms of a WHERE clause,
+/// keyed by the column's declared name
+fn collect_equalities<'a>(expr: &Expr, schema: &'a [ColumnDef], out: &mut HashMap<&'a str, Value>) {
+    match expr {
+        Expr::BinaryOp {
+            left,
+            op: BinaryOperator::And,
+            right,
+        } => {
+            collect_equalities(left, schema, out);
+            collect_equalities(right, schema, out);
+        }
+        Expr::BinaryOp {
+            left,
+            op: BinaryOperator::Eq,
+            right,
+        } => {
+            let term = match (column_name(left), column_name(right)) {
+                (Some(column), None) => Some((column, right)),
+                (None, Some(column)) => Some((column, left)),
+                _ => None,
+            };
+            // Indexes are in binary order, so they can't answer an equality
+            // that ignores case
+            if let Some((column, literal)) = term
+                && let Ok(idx) = column_index(schema, column)
+                && let Ok(Collation::Binary) = column_collation(&schema[idx])
+                && let Ok(value) = expr_to_value((**literal).clone())
+                && value != Value::Null
+            {
+                // A decimal column holds decimals, which an integer literal
+                // has to be turned into to find them
+                let value = match value {
+                    Value::Long(n)
+                        if matches!(decimal_type(&schema[idx].data_type), Ok(Some(_))) =>
+                    {
+                        Value::Decimal(Decimal::from_i64(n))
+                    }
+                    value => value,
+                };
+                out.insert(schema[idx].name.value.as_str(), value);
+            }
+        }
+        Expr::Nested(inner) => collect_equalities(inner, schema, out),
+        _ => {}
+    }
+}
+
+pub(crate) fn expr_to_value(expr: Expr) -> Result<Value, ExecError> {
+    // A signed number is a literal too
+    if let Expr::UnaryOp { op, expr: inner } = &expr
+        && let Expr::Value(v) = &**inner
+        && let sqlparser::ast::Value::Number(n, _) = &v.value
+    {
+        match op {
+            UnaryOperator::Plus => return parse_numeric(n),
+            // Parsed with its sign, since the smallest integer has no
+            // positive counterpart
+            UnaryOperator::Minus => {
+                return match format!("-{}", n).parse::<i64>() {
+                    Ok(n) => Ok(Value::Long(n)),
+                    Err(_) => negate(parse_numeric(n)?),
+                };
+            }
+            _ => {}
+        }
+    }
+
+    match expr {
+        Expr::Value(v) => match v.value {
+            sqlparser::ast::Value::Number(n, _) => parse_numeric(&n),
+            sqlparser::ast::Value::SingleQuotedString(s)
+            | sqlparser::ast::Value::DoubleQuotedString(s) => Ok(Value::Text(s)),
+            sqlparser::ast::Value::Boolean(b) => Ok(Value::Bool(b)),
+            sqlparser::ast::Value::HexStringLiteral(hex) => decode_hex(&hex).map(Value::Blob),
+            sqlparser::ast::Value::Null => Ok(Value::Null),
+            _ => Err(ExecError::Unsupported(format!(
+                "Unsupported value type: {:?}",
+                v
+            ))),
+        },
+        _ => Err(ExecError::Unsupported(format!(
+            "Unsupported expression: {:?}",
+            expr
+        ))),
+    }
+}
+
+/// A literal that evaluates back to `value`
+pub(crate) fn value_to_expr(value: &Value) -> Expr {
+    use sqlparser::ast::Value as Literal;
+    Expr::value(match value {
+        Value::Long(n) => Literal::Number(n.to_string(), false),
+        Value::Decimal(d) => Literal::Number(d.to_string(), false),
+        Value::Text(s) => Literal::SingleQuotedString(s.clone()),
+        Value::Bool(b) => Literal::Boolean(*b),
+        Value::Blob(data) => {
+            Literal::HexStringLiteral(data.iter().map(|b| format!("{:02X}", b)).collect())
+        }
+        Value::Null => Literal::Null,
+    })
+}
+
+pub(crate) fn format_value(value: &Value) -> String {
+    match value {
+        Value::Long(n) => n.to_string(),
+        Value::Decimal(d) => d.to_string(),
+        Value::Text(s) => s.clone(),
+        Value::Bool(b) => b.to_string(),
+        Value::Blob(data) => {
+            let hex: String = data.iter().map(|b| format!("{:02x}", b)).collect();
+            format!("\\x{}", hex)
+        }
+        Value::Null => "NULL".to_string(),
+    }
+}
+
+/// A numeric literal's value: an integer if it's a whole number that fits in
+/// one, otherwise an exact decimal
+fn parse_numeric(literal: &str) -> Result<Value, ExecError> {
+    parse_number(literal)
+        .map(Value::Long)
+        .or_else(|e| Decimal::parse(literal).map(Value::Decimal).ok_or(e))
+}
+
+/// Parse a numeric literal as an integer. Besides plain integers this accepts
+/// `_` between digits, decimals and scientific notation, as long as the value
+/// is a whole number (`1e3`, `2.50e1`, `4.0`); `3.14` is a type error rather
+/// than being rounded.
+fn parse_number(literal: &str) -> Result<i64, ExecError> {
+    if let Ok(n) = literal.parse::<i64>() {
+        return Ok(n);
+    }
+    let invalid = || ExecError::Type(format!("Invalid number: {}", literal));
+
+    let digits_only = literal.replace('_', "");
+    let (mantissa, exponent) = match digits_only.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => (mantissa, exponent.parse::<i64>().map_err(|_| invalid())?),
+        None => (digits_only.as_str(), 0),
+    };
+    let (whole, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    let mut digits = format!("{}{}", whole, fraction);
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid());
+    }
+
+    // The value is `digits * 10^scale`
+    let mut scale = exponent - fraction.len() as i64;
+    while scale < 0 && digits.ends_with('0') {
+        digits.pop();
+        scale += 1;
+    }
+    if scale < 0 {
+        return Err(ExecError::Type(format!(
+            "Fractional numbers are not supported: {}",
+            literal
+        )));
+    }
+
+    let digits = digits.trim_start_matches('0');
+    let out_of_range = || ExecError::Type(format!("Number out of range: {}", literal));
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    if digits.len() as i64 + scale > 19 {
+        return Err(out_of_range());
+    }
+    let value = format!("{}{}", digits, "0".repeat(scale as usize));
+    value.parse::<i64>().map_err(|_| out_of_range())
+}
+
+/// Decode a string of hex digit pairs, as in `X'deadbeef'`
+pub(crate) fn decode_hex(hex: &str) -> Result<Vec<u8>, ExecError> {
+    if !hex.len().is_multiple_of(2) {
+        return Err(ExecError::Type(format!(
+            "Hex string '{}' has an odd number of digits",
+            hex
+        )));
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| ExecError::Type(format!("Invalid hex string '{}'", hex)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer::BufferPool;
+    use crate::dialect::MatiDialect;
+    use crate::storage::{DiskManager, PAGE_SIZE};
+    use sqlparser::parser::Parser;
+    use std::cell::RefCell;
+    use std::fs;
+    use std::rc::Rc;
+
+    fn with_test_db<F>(name: &str, f: F)
+    where
+        F: FnOnce(&mut Database),
+    {
+        let path = format!("test_executor_{}.db", name);
+        let _ = fs::remove_file(&path);
+
+        let disk = DiskManager::open(&path).unwrap();
+        let buffer_pool = Rc::new(RefCell::new(BufferPool::new(disk, 100)));
+        let mut db = Database::with_buffer_pool(buffer_pool);
+
+        f(&mut db);
+
+        let _ = fs::remove_file(&path);
+    }
+
+    /// Like `with_test_db`, with a database that's never written to disk
+    fn with_memory_db<F>(f: F)
+    where
+        F: FnOnce(&mut Database),
+    {
+        let mut db = Database::in_memory();
+        f(&mut db);
+    }
+
+    fn parse_and_execute(db: &mut Database, sql: &str) -> Result<String, String> {
+        let dialect = MatiDialect {};
+        let stmts = Parser::parse_sql(&dialect, sql).map_err(|e| e.to_string())?;
+        execute(db, stmts.into_iter().next().unwrap()).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn test_create_table() {
+        with_test_db("create", |db| {
+            let result = parse_and_execute(db, "CREATE TABLE users (id INT, name TEXT)");
+
+            assert!(result.is_ok());
+            assert_eq!(result.unwrap(), "Table 'users' created");
+            assert!(db.tables.contains_key("users"));
+        });
+    }
+
+    #[test]
+    fn test_create_table_already_exists() {
+        with_test_db("create_exists", |db| {
+            parse_and_execute(db, "CREATE TABLE users (id INT)").unwrap();
+
+            let result = parse_and_execute(db, "CREATE TABLE users (id INT)");
+
+            assert!(result.is_err());
+            assert_eq!(result.unwrap_err(), "Table 'users' already exists");
+        });
+    }
+
+    #[test]
+    fn test_create_table_column_types() {
+        with_memory_db(|db| {
+            let result = parse_and_execute(db, "CREATE TABLE t (id INT, x FOOBAR)");
+            assert_eq!(result.unwrap_err(), "unsupported column type 'FOOBAR'");
+            let result = parse_and_execute(db, "CREATE TABLE t (at TIMESTAMP)");
+            assert_eq!(result.unwrap_err(), "unsupported column type 'TIMESTAMP'");
+            assert!(!db.tables.contains_key("t"));
+
+            // Types are kept the way the catalog records them
+            parse_and_execute(
+                db,
+                "CREATE TABLE t (a INTEGER, b VARCHAR(20), c BOOL, d NUMERIC(6, 2))",
+            )
+            .unwrap();
+            let types: Vec<String> = db.tables["t"]
+                .schema
+                .iter()
+                .map(|col| col.data_type.to_string())
+                .collect();
+            assert_eq!(types, ["INT", "VARCHAR", "BOOLEAN", "DECIMAL(6,2)"]);
+        });
+    }
+
+    #[test]
+    fn test_insert_single_row() {
+        with_test_db("insert_single", |db| {
+            parse_and_execute(db, "CREATE TABLE users (id INT, name TEXT)").unwrap();
+
+            let result = parse_and_execute(db, "INSERT INTO users VALUES (1, 'Alice')");
+
+            assert!(result.is_ok());
+            assert_eq!(result.unwrap(), "Inserted 1 row(s)");
+        });
+    }
+
+    #[test]
+    fn test_insert_multiple_rows() {
+        with_test_db("insert_multiple", |db| {
+            parse_and_execute(db, "CREATE TABLE users (id INT, name TEXT)").unwrap();
+
+            let result = parse_and_execute(db, "INSERT INTO users VALUES (1, 'Alice'), (2, 'Bob')");
+
+            assert!(result.is_ok());
+            assert_eq!(result.unwrap(), "Inserted 2 row(s)");
+        });
+    }
+
+    #[test]
+    fn test_error_categories() {
+        with_memory_db(|db| {
+            let dialect = MatiDialect {};
+            let mut run = |sql: &str| {
+                let stmt = Parser::parse_sql(&dialect, sql).unwrap().remove(0);
+                execute(db, stmt)
+            };
+
+            run("CREATE TABLE users (id INT)").unwrap();
+
+            assert!(matches!(
+                run("CREATE TABLE users (id INT)"),
+                Err(ExecError::AlreadyExists(_))
+            ));
+            assert!(matches!(
+                run("SELECT * FROM missing"),
+                Err(ExecError::NotFound(_))
+            ));
+            assert!(matches!(
+                run("INSERT INTO users VALUES (99999999999999999999)"),
+                Err(ExecError::Type(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn test_insert_into_nonexistent_table() {
+        with_test_db("insert_nonexistent", |db| {
+            let result = parse_and_execute(db, "INSERT INTO users VALUES (1, 'Alice')");
+
+            assert!(result.is_err());
+            assert_eq!(result.unwrap_err(), "Table 'users' does not exist");
+        });
+    }
+
+    #[test]
+    fn test_select_empty_table() {
+        with_test_db("select_empty", |db| {
+            parse_and_execute(db, "CREATE TABLE users (id INT, name TEXT)").unwrap();
+
+            let result = parse_and_execute(db, "SELECT * FROM users");
+
+            assert!(result.is_ok());
+            assert!(result.unwrap().contains("(0 rows)"));
+        });
+    }
+
+    #[test]
+    fn test_select_with_data() {
+        with_test_db("select_data", |db| {
+            parse_and_execute(db, "CREATE TABLE users (id INT, name TEXT)").unwrap();
+            parse_and_execute(db, "INSERT INTO users VALUES (1, 'Alice')").unwrap();
+            parse_and_execute(db, "INSERT INTO users VALUES (2, 'Bob')").unwrap();
+
+            let result = parse_and_execute(db, "SELECT * FROM users").unwrap();
+
+            assert!(result.contains("Alice"));
+            assert!(result.contains("Bob"));
+            assert!(result.contains("(2 rows)"));
+        });
+    }
+
+    #[test]
+    fn test_insert_many_rows_multiple_pages() {
+        with_test_db("insert_many", |db| {
+            parse_and_execute(db, "CREATE TABLE users (id INT, name TEXT)").unwrap();
+
+            // Insert 500 rows - should span multiple pages
+            for i in 0..500 {
+                let sql = format!("INSERT INTO users VALUES ({}, 'User{}')", i, i);
+                parse_and_execute(db, &sql).unwrap();
+            }
+
+            let result = parse_and_execute(db, "SELECT * FROM users").unwrap();
+
+            assert!(result.contains("(500 rows)"));
+            assert!(result.contains("User0"));
+            assert!(result.contains("User499"));
+        });
+    }
+
+    #[test]
+    fn test_insert_5000_rows() {
+        with_test_db("insert_5000", |db| {
+            parse_and_execute(db, "CREATE TABLE users (id INT, name TEXT)").unwrap();
+
+            // Build a batch insert
+            let mut values = Vec::new();
+            for i in 0..100 {
+                values.push(format!("({}, 'User{}')", i, i));
+            }
+
+            // Insert in batches of 100
+            for batch in 0..50 {
+                let mut batch_values = Vec::new();
+                for i in 0..100 {
+                    let id = batch * 100 + i;
+                    batch_values.push(format!("({}, 'User{}')", id, id));
+                }
+                let sql = format!("INSERT INTO users VALUES {}", batch_values.join(", "));
+                parse_and_execute(db, &sql).unwrap();
+            }
+
+            let result = parse_and_execute(db, "SELECT * FROM users").unwrap();
+
+            assert!(result.contains("(5000 rows)"));
+        });
+    }
+
+    #[test]
+    fn test_memory_db_matches_disk() {
+        let values: Vec<String> = (0..500).map(|i| format!("({}, 'User{}')", i, i)).collect();
+        let insert = format!("INSERT INTO users VALUES {}", values.join(", "));
+        let statements = [
+            "CREATE TABLE users (id INT, name TEXT)",
+            &insert,
+            "UPDATE users SET name = 'Renamed' WHERE id % 50 = 0",
+            "DELETE FROM users WHERE id >= 400",
+            "SELECT * FROM users WHERE name = 'Renamed' ORDER BY id DESC",
+            "SELECT COUNT(*), MAX(id) FROM users",
+        ];
+        let run = |db: &mut Database| -> Vec<Result<String, String>> {
+            statements
+                .iter()
+                .map(|sql| parse_and_execute(db, sql))
+                .collect()
+        };
+
+        let mut on_disk = Vec::new();
+        let mut disk_pages = 0;
+        with_test_db("memory_matches_disk", |db| {
+            on_disk = run(db);
+            disk_pages = db.table_pages(0).unwrap().len();
+        });
+        with_memory_db(|db| {
+            assert_eq!(run(db), on_disk);
+            assert_eq!(db.table_pages(0).unwrap().len(), disk_pages);
+        });
+        assert!(disk_pages > 1);
+        assert!(on_disk[5].as_ref().unwrap().contains("\n400\t399\n"));
+    }
+
+    fn parse_expr(sql: &str) -> Expr {
+        Parser::new(&MatiDialect {})
+            .try_with_sql(sql)
+            .unwrap()
+            .parse_expr()
+            .unwrap()
+    }
+
+    fn setup_employees(db: &mut Database) {
+        parse_and_execute(db, "CREATE TABLE employees (id INT, dept TEXT, level INT)").unwrap();
+        parse_and_execute(
+            db,
+            "INSERT INTO employees VALUES (1, 'eng', 1), (2, 'eng', 3), (3, 'ops', 3), (4, 'eng', 3)",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_select_where() {
+        with_memory_db(|db| {
+            setup_employees(db);
+
+            let result = parse_and_execute(
+                db,
+                "SELECT * FROM employees WHERE level = 3 AND dept <> 'ops'",
+            )
+            .unwrap();
+
+            assert!(result.contains("2\teng\t3"));
+            assert!(result.contains("4\teng\t3"));
+            assert!(result.contains("(2 rows)"));
+        });
+    }
+
+    #[test]
+    fn test_multi_column_index_full_key() {
+        with_memory_db(|db| {
+            setup_employees(db);
+            let result = parse_and_execute(db, "CREATE INDEX idx ON employees (dept, level)");
+            assert_eq!(result.unwrap(), "Index 'idx' created");
+
+            let filter = parse_expr("dept = 'eng' AND level = 3");
+            let (index, key) = choose_index(db, "employees", Some(&filter)).unwrap();
+            assert_eq!(index.name, "idx");
+            assert_eq!(key.len(), 2);
+
+            let result = parse_and_execute(
+                db,
+                "SELECT * FROM employees WHERE dept = 'eng' AND level = 3",
+            )
+            .unwrap();
+            assert!(result.contains("2\teng\t3"));
+            assert!(result.contains("4\teng\t3"));
+            assert!(result.contains("(2 rows)"));
+        });
+    }
+
+    #[test]
+    fn test_multi_column_index_prefix() {
+        with_memory_db(|db| {
+            setup_employees(db);
+            parse_and_execute(db, "CREATE INDEX idx ON employees (dept, level)").unwrap();
+
+            // A predicate on the leading column can use the index
+            let filter = parse_expr("dept = 'eng' AND id > 1");
+            let (_, key) = choose_index(db, "employees", Some(&filter)).unwrap();
+            assert_eq!(key, vec![Value::Text("eng".to_string())]);
+
+            // A predicate that skips the leading column cannot
+            let filter = parse_expr("level = 3");
+            assert!(choose_index(db, "employees", Some(&filter)).is_none());
+
+            let result =
+                parse_and_execute(db, "SELECT * FROM employees WHERE dept = 'eng' AND id > 1")
+                    .unwrap();
+            assert!(result.contains("(2 rows)"));
+        });
+    }
+
+    #[test]
+    fn test_index_maintained_on_insert() {
+        with_memory_db(|db| {
+            setup_employees(db);
+            parse_and_execute(db, "CREATE INDEX idx ON employees (dept, level)").unwrap();
+            parse_and_execute(db, "INSERT INTO employees VALUES (5, 'ops', 3)").unwrap();
+
+            let result = parse_and_execute(
+                db,
+                "SELECT * FROM employees WHERE dept = 'ops' AND level = 3",
+            )
+            .unwrap();
+            assert!(result.contains("5\tops\t3"));
+            assert!(result.contains("(2 rows)"));
+        });
+    }
+
+    #[test]
+    fn test_reindex() {
+        with_memory_db(|db| {
+            setup_employees(db);
+            parse_and_execute(db, "CREATE INDEX by_dept ON employees (dept)").unwrap();
+            parse_and_execute(db, "CREATE TABLE teams (name TEXT)").unwrap();
+            parse_and_execute(db, "INSERT INTO teams VALUES ('eng'), ('ops')").unwrap();
+            parse_and_execute(db, "CREATE INDEX by_name ON teams (name)").unwrap();
+
+            // An index that has lost its entries finds nothing
+            let lost = |db: &mut Database, name: &str| {
+                let index = db.indexes.get_mut(name).unwrap();
+                *index = index.definition();
+            };
+            let sql = "SELECT id FROM employees WHERE dept = 'eng'";
+            lost(db, "by_dept");
+            lost(db, "by_name");
+            assert!(parse_and_execute(db, sql).unwrap().ends_with("(0 rows)"));
+
+            let result = execute_reindex(db, "reindex table employees;").unwrap();
+            assert_eq!(result.unwrap(), "Rebuilt 1 index(es) with 4 entries");
+            assert!(parse_and_execute(db, sql).unwrap().ends_with("(3 rows)"));
+            assert_eq!(db.indexes["by_name"].entry_count(), 0);
+
+            let result = execute_reindex(db, "REINDEX").unwrap();
+            assert_eq!(result.unwrap(), "Rebuilt 2 index(es) with 6 entries");
+            let result = parse_and_execute(db, "SELECT name FROM teams WHERE name = 'ops'");
+            assert!(result.unwrap().ends_with("(1 rows)"));
+
+            let result = execute_reindex(db, "REINDEX TABLE missing").unwrap();
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                "Table 'missing' does not exist"
+            );
+            assert!(
+                execute_reindex(db, "REINDEX INDEX by_name")
+                    .unwrap()
+                    .is_err()
+            );
+            assert!(execute_reindex(db, "SELECT 1").is_none());
+        });
+    }
+
+    #[test]
+    fn test_create_index_errors() {
+        with_memory_db(|db| {
+            setup_employees(db);
+
+            let result = parse_and_execute(db, "CREATE INDEX idx ON employees (salary)");
+            assert_eq!(result.unwrap_err(), "Column 'salary' does not exist");
+
+            parse_and_execute(db, "CREATE INDEX idx ON employees (dept)").unwrap();
+            let result = parse_and_execute(db, "CREATE INDEX idx ON employees (level)");
+            assert_eq!(result.unwrap_err(), "Index 'idx' already exists");
+        });
+    }
+
+    #[test]
+    fn test_unique_index() {
+        with_memory_db(|db| {
+            setup_employees(db);
+
+            // Existing duplicates stop the index from being built
+            let result = parse_and_execute(db, "CREATE UNIQUE INDEX by_dept ON employees (dept)");
+            assert_eq!(
+                result.unwrap_err(),
+                "Cannot create unique index 'by_dept': duplicate value eng"
+            );
+            let result = parse_and_execute(
+                db,
+                "CREATE UNIQUE INDEX by_level ON employees (dept, level)",
+            );
+            assert_eq!(
+                result.unwrap_err(),
+                "Cannot create unique index 'by_level': duplicate value (eng, 3)"
+            );
+            assert!(db.indexes.is_empty());
+
+            parse_and_execute(db, "CREATE UNIQUE INDEX by_id ON employees (id)").unwrap();
+            let result = parse_and_execute(db, "INSERT INTO employees VALUES (3, 'hr', 1)");
+            assert_eq!(
+                result.unwrap_err(),
+                "Duplicate value 3 for unique index 'by_id'"
+            );
+            let result = parse_and_execute(
+                db,
+                "INSERT INTO employees VALUES (5, 'hr', 1), (5, 'hr', 2)",
+            );
+            assert!(result.is_err());
+            let result = parse_and_execute(db, "UPDATE employees SET id = 1 WHERE id = 2");
+            assert!(result.is_err());
+            let result = parse_and_execute(db, "SELECT COUNT(*) FROM employees").unwrap();
+            assert!(result.ends_with("\n4\n(1 rows)"), "{}", result);
+
+            // A row may keep its own key, and NULLs never collide
+            parse_and_execute(db, "UPDATE employees SET id = id WHERE id = 2").unwrap();
+            parse_and_execute(db, "UPDATE employees SET id = 5 WHERE id = 2").unwrap();
+            parse_and_execute(db, "INSERT INTO employees VALUES (2, 'hr', 1)").unwrap();
+            parse_and_execute(
+                db,
+                "INSERT INTO employees VALUES (NULL, 'hr', 1), (NULL, 'hr', 2)",
+            )
+            .unwrap();
+        });
+    }
+
+    #[test]
+    fn test_index_survives_reopen() {
+        let path = "test_executor_index_reopen.db";
+        let catalog = format!("{}.catalog", path);
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+
+        {
+            let mut db = Database::new(path).unwrap();
+            setup_employees(&mut db);
+            parse_and_execute(&mut db, "CREATE INDEX idx ON employees (dept, level)").unwrap();
+            parse_and_execute(&mut db, "CREATE UNIQUE INDEX by_id ON employees (id)").unwrap();
+        }
+
+        {
+            let mut db = Database::new(path).unwrap();
+            let index = &db.indexes["idx"];
+            assert_eq!(index.columns, vec!["dept", "level"]);
+            assert!(!index.unique);
+            assert_eq!(index.lookup(&[Value::Text("eng".to_string())]).len(), 3);
+            assert!(db.indexes["by_id"].unique);
+            let result = parse_and_execute(&mut db, "INSERT INTO employees VALUES (1, 'hr', 1)");
+            assert!(result.is_err());
+        }
+
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+    }
+
+    #[test]
+    fn test_comments_survive_reopen() {
+        let path = "test_executor_comments_reopen.db";
+        let catalog = format!("{}.catalog", path);
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+
+        {
+            let mut db = Database::new(path).unwrap();
+            parse_and_execute(
+                &mut db,
+                "CREATE TABLE users (id INT PRIMARY KEY, email TEXT)",
+            )
+            .unwrap();
+            let result = parse_and_execute(
+                &mut db,
+                "COMMENT ON COLUMN users.email IS 'primary contact'",
+            );
+            assert_eq!(result.unwrap(), "Comment set on column 'email'");
+            parse_and_execute(&mut db, "COMMENT ON COLUMN users.id IS 'to be removed'").unwrap();
+            parse_and_execute(&mut db, "COMMENT ON COLUMN users.id IS NULL").unwrap();
+            parse_and_execute(&mut db, "COMMENT ON TABLE users IS 'Accounts | logins'").unwrap();
+        }
+
+        {
+            let mut db = Database::new(path).unwrap();
+            let result = parse_and_execute(&mut db, "DESCRIBE users").unwrap();
+            assert_eq!(
+                result,
+                "Table 'users': Accounts | logins\n\
+                 column\ttype\toptions\tcomment\n\
+                 ----------------------------------------\n\
+                 id\tINT\tPRIMARY KEY\tNULL\n\
+                 email\tTEXT\t\tprimary contact\n\
+                 (2 rows)"
+            );
+
+            parse_and_execute(&mut db, "COMMENT ON TABLE users IS NULL").unwrap();
+            let result = parse_and_execute(&mut db, "DESCRIBE users").unwrap();
+            assert!(result.starts_with("column\t"), "{}", result);
+        }
+
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+    }
+
+    #[test]
+    fn test_comment_errors() {
+        with_memory_db(|db| {
+            parse_and_execute(db, "CREATE TABLE users (id INT)").unwrap();
+
+            let result = parse_and_execute(db, "COMMENT ON COLUMN users.nope IS 'x'");
+            assert_eq!(result.unwrap_err(), "Column 'nope' does not exist");
+            let result = parse_and_execute(db, "COMMENT ON TABLE nope IS 'x'");
+            assert_eq!(result.unwrap_err(), "Table 'nope' does not exist");
+            parse_and_execute(db, "COMMENT IF EXISTS ON TABLE nope IS 'x'").unwrap();
+            let result = parse_and_execute(db, "COMMENT ON TABLE users IS 'two\nlines'");
+            assert_eq!(result.unwrap_err(), "Comments can't contain line breaks");
+            let result = parse_and_execute(db, "COMMENT ON SCHEMA public IS 'x'");
+            assert_eq!(result.unwrap_err(), "COMMENT ON SCHEMA is not supported");
+        });
+    }
+
+    fn query_stats(db: &mut Database, sql: &str) -> QueryStats {
+        let stmt = Parser::parse_sql(&MatiDialect {}, sql).unwrap().remove(0);
+        execute_with_stats(db, stmt).unwrap().1
+    }
+
+    #[test]
+    fn test_stats_full_scan_with_selective_predicate() {
+        with_memory_db(|db| {
+            parse_and_execute(db, "CREATE TABLE users (id INT, name TEXT)").unwrap();
+            for batch in 0..5 {
+                let values: Vec<String> = (0..100)
+                    .map(|i| format!("({}, 'User{}')", batch * 100 + i, i))
+                    .collect();
+                let sql = format!("INSERT INTO users VALUES {}", values.join(", "));
+                parse_and_execute(db, &sql).unwrap();
+            }
+
+            let stats = query_stats(db, "SELECT * FROM users WHERE id = 5");
+            assert_eq!(stats.rows_scanned, 500);
+            assert_eq!(stats.rows_returned, 1);
+            assert!(stats.pages_read > 1);
+        });
+    }
+
+    #[test]
+    fn test_stats_index_lookup() {
+        with_memory_db(|db| {
+            setup_employees(db);
+            parse_and_execute(db, "CREATE INDEX idx ON employees (dept)").unwrap();
+
+            let stats = query_stats(db, "SELECT * FROM employees WHERE dept = 'ops'");
+            assert_eq!(stats.rows_scanned, 1);
+            assert_eq!(stats.rows_returned, 1);
+        });
+    }
+
+    #[test]
+    fn test_insert_returning_autoincrement_id() {
+        with_memory_db(|db| {
+            parse_and_execute(db, "CREATE TABLE users (id INT AUTOINCREMENT, name TEXT)").unwrap();
+
+            let result = parse_and_execute(
+                db,
+                "INSERT INTO users (name) VALUES ('Alice'), ('Bob') RETURNING id, name",
+            )
+            .unwrap();
+            assert_eq!(
+                result,
+                "id\tname\n--------------------\n1\tAlice\n2\tBob\n(2 rows)"
+            );
+
+            // An explicit id moves the counter past it
+            parse_and_execute(db, "INSERT INTO users VALUES (10, 'Carol')").unwrap();
+            let result =
+                parse_and_execute(db, "INSERT INTO users (name) VALUES ('Dave') RETURNING *")
+                    .unwrap();
+            assert!(result.contains("11\tDave"));
+        });
+    }
+
+    #[test]
+    fn test_insert_on_conflict() {
+        with_memory_db(|db| {
+            parse_and_execute(
+                db,
+                "CREATE TABLE counters (name TEXT PRIMARY KEY, hits INT, note TEXT)",
+            )
+            .unwrap();
+            parse_and_execute(db, "INSERT INTO counters VALUES ('a', 1, 'x')").unwrap();
+
+            // DO NOTHING leaves the existing row alone but inserts the rest
+            let result = parse_and_execute(
+                db,
+                "INSERT INTO counters VALUES ('a', 5, 'y'), ('b', 1, 'y') ON CONFLICT DO NOTHING",
+            )
+            .unwrap();
+            assert_eq!(result, "Inserted 1 row(s)");
+            let result = parse_and_execute(db, "SELECT hits, note FROM counters WHERE name = 'a'");
+            assert!(result.unwrap().contains("1\tx"));
+
+            // DO UPDATE changes the conflicting row, seeing the proposed one as excluded
+            let result = parse_and_execute(
+                db,
+                "INSERT INTO counters VALUES ('a', 5, 'z') ON CONFLICT (name) \
+                 DO UPDATE SET hits = counters.hits + excluded.hits, note = excluded.note",
+            )
+            .unwrap();
+            assert_eq!(result, "Inserted 0 row(s), updated 1 row(s)");
+            let result = parse_and_execute(db, "SELECT * FROM counters ORDER BY name").unwrap();
+            assert!(result.contains("a\t6\tz\nb\t1\ty"));
+
+            // The WHERE condition can skip the update
+            parse_and_execute(
+                db,
+                "INSERT INTO counters VALUES ('b', 9, 'w') ON CONFLICT (name) \
+                 DO UPDATE SET hits = excluded.hits WHERE counters.hits > 5",
+            )
+            .unwrap();
+            let result = parse_and_execute(db, "SELECT hits FROM counters WHERE name = 'b'");
+            assert!(result.unwrap().contains("\n1\n"));
+
+            // The same row can't be updated twice by one statement
+            let result = parse_and_execute(
+                db,
+                "INSERT INTO counters VALUES ('c', 1, NULL), ('c', 2, NULL) \
+                 ON CONFLICT (name) DO UPDATE SET hits = 0",
+            );
+            assert!(matches!(result, Err(e) if e.contains("same row twice")));
+            let result = parse_and_execute(
+                db,
+                "INSERT INTO counters VALUES ('a', 1, NULL) ON CONFLICT (hits) DO NOTHING",
+            );
+            assert!(matches!(result, Err(e) if e.contains("not a PRIMARY KEY or UNIQUE")));
+            let result = parse_and_execute(db, "SELECT * FROM counters").unwrap();
+            assert!(result.ends_with("(2 rows)"));
+        });
+    }
+
+    #[test]
+    fn test_insert_column_list_errors() {
+        with_memory_db(|db| {
+            parse_and_execute(
+                db,
+                "CREATE TABLE users (id INT NOT NULL, name TEXT DEFAULT 'anon')",
+            )
+            .unwrap();
+
+            let result = parse_and_execute(db, "INSERT INTO users (id) VALUES (1) RETURNING name");
+            assert!(result.unwrap().contains("anon"));
+
+            assert!(parse_and_execute(db, "INSERT INTO users (name) VALUES ('x')").is_err());
+            assert!(parse_and_execute(db, "INSERT INTO users (id) VALUES (1, 2)").is_err());
+            assert!(
+                parse_and_execute(db, "INSERT INTO users (id) VALUES (2) RETURNING missing")
+                    .is_err()
+            );
+            assert!(
+                parse_and_execute(db, "SELECT * FROM users")
+                    .unwrap()
+                    .ends_with("(1 rows)")
+            );
+        });
+    }
+
+    #[test]
+    fn test_ragged_insert_names_bad_row() {
+        with_memory_db(|db| {
+            parse_and_execute(db, "CREATE TABLE t (a INT, b INT, c INT)").unwrap();
+
+            let result = parse_and_execute(db, "INSERT INTO t (a, b) VALUES (1, 2), (3), (4, 5)");
+            assert_eq!(
+                result.unwrap_err(),
+                "VALUES row 2 has 1 value(s) but INSERT lists 2 column(s)"
+            );
+            let result = parse_and_execute(db, "INSERT INTO t VALUES (1, 2, 3), (4, 5, 6, 7)");
+            assert_eq!(
+                result.unwrap_err(),
+                "VALUES row 2 has 4 value(s) but table 't' has 3 column(s)"
+            );
+            // A short row no longer slips in without its trailing columns
+            let result = parse_and_execute(db, "INSERT INTO t VALUES (1, 2)");
+            assert_eq!(
+                result.unwrap_err(),
+                "VALUES row 1 has 2 value(s) but table 't' has 3 column(s)"
+            );
+
+            // Nothing from a failed statement is written
+            let result = parse_and_execute(db, "SELECT COUNT(*) FROM t").unwrap();
+            assert!(result.ends_with("\n0\n(1 rows)"), "{}", result);
+        });
+    }
+
+    #[test]
+    fn test_autoincrement_survives_reopen() {
+        let path = "test_executor_autoincrement_reopen.db";
+        let catalog = format!("{}.catalog", path);
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+
+        {
+            let mut db = Database::new(path).unwrap();
+            parse_and_execute(
+                &mut db,
+                "CREATE TABLE users (id INT AUTOINCREMENT, name TEXT)",
+            )
+            .unwrap();
+            parse_and_execute(&mut db, "INSERT INTO users (name) VALUES ('Alice')").unwrap();
+        }
+
+        {
+            let mut db = Database::new(path).unwrap();
+            let result = parse_and_execute(
+                &mut db,
+                "INSERT INTO users (name) VALUES ('Bob') RETURNING id",
+            )
+            .unwrap();
+            assert!(result.contains("\n2\n"));
+        }
+
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+    }
+
+    #[test]
+    fn test_system_columns() {
+        with_memory_db(|db| {
+            let select = |db: &Database, sql: &str| {
+                let stmt = Parser::parse_sql(&MatiDialect {}, sql).unwrap().remove(0);
+                query(db, stmt).unwrap()
+            };
+            parse_and_execute(
+                db,
+                "CREATE TABLE t (id INT, name TEXT) WITH (created_at = true)",
+            )
+            .unwrap();
+            parse_and_execute(db, "INSERT INTO t VALUES (1, 'a'), (2, 'b')").unwrap();
+            parse_and_execute(db, "INSERT INTO t (name) VALUES ('c')").unwrap();
+
+            // Hidden from *, but there when asked for
+            let result = parse_and_execute(db, "SELECT * FROM t").unwrap();
+            assert!(result.starts_with("id\tname\n"));
+            let result = select(db, "SELECT _row_id, * FROM t");
+            assert_eq!(result.columns, ["_row_id", "id", "name"]);
+            let row_ids: Vec<i64> = result
+                .iter()
+                .map(|row| row.get_long("_row_id").unwrap().unwrap())
+                .collect();
+            assert_eq!(row_ids.len(), 3);
+            assert!(row_ids.windows(2).all(|pair| pair[0] < pair[1]));
+
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as i64;
+            let result = select(db, "SELECT _created_at FROM t");
+            for row in result.iter() {
+                let created_at = row.get_long("_created_at").unwrap().unwrap();
+                assert!((now - 60..=now).contains(&created_at));
+            }
+
+            // _row_id picks out exactly one row
+            let result =
+                parse_and_execute(db, &format!("DELETE FROM t WHERE _row_id = {}", row_ids[1]));
+            assert_eq!(result.unwrap(), "Deleted 1 row(s)");
+            let result = select(db, "SELECT id, _row_id FROM t");
+            assert_eq!(result.len(), 2);
+            assert_eq!(result.get_long(0, "_row_id").unwrap(), Some(row_ids[0]));
+            assert_eq!(result.get_long(1, "_row_id").unwrap(), Some(row_ids[2]));
+
+            // The system columns can't be written or shadowed
+            for sql in [
+                "UPDATE t SET _created_at = 0",
+                "INSERT INTO t (id, _row_id) VALUES (3, 7)",
+                "CREATE TABLE u (_row_id INT)",
+                "ALTER TABLE t RENAME COLUMN name TO _created_at",
+            ] {
+                let result = parse_and_execute(db, sql);
+                assert!(
+                    matches!(&result, Err(e) if e.contains("system column") || e.contains("maintained by MatiDB")),
+                    "{}: {:?}",
+                    sql,
+                    result
+                );
+            }
+            let result = parse_and_execute(db, "INSERT INTO t VALUES (3, 'd', 0)");
+            assert!(matches!(result, Err(e) if e.contains("has 2 column(s)")));
+
+            // Without the option there's no _created_at, but _row_id is always there
+            parse_and_execute(db, "CREATE TABLE plain (id INT)").unwrap();
+            parse_and_execute(db, "INSERT INTO plain VALUES (1)").unwrap();
+            assert!(parse_and_execute(db, "SELECT _created_at FROM plain").is_err());
+            let result = parse_and_execute(db, "SELECT _row_id FROM plain").unwrap();
+            assert!(result.ends_with("(1 rows)"));
+        });
+    }
+
+    #[test]
+    fn test_compact_tables_mix_with_tagged() {
+        let path = "test_executor_compact_rows.db";
+        let catalog = format!("{}.catalog", path);
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+
+        let create = "(id INT PRIMARY KEY, active BOOL, score DECIMAL(6, 2), note TEXT)";
+        let rows = "(1, TRUE, 9.5, 'first'), (2, NULL, 7, NULL), (3, FALSE, NULL, 'third')";
+        {
+            let mut db = Database::new(path).unwrap();
+            for (table, options) in [("wide", " WITH (row_format = 'compact')"), ("plain", "")] {
+                parse_and_execute(
+                    &mut db,
+                    &format!("CREATE TABLE {} {}{}", table, create, options),
+                )
+                .unwrap();
+                parse_and_execute(&mut db, &format!("INSERT INTO {} VALUES {}", table, rows))
+                    .unwrap();
+            }
+            let free_space = |table: &str| {
+                let page_id = db.tables[table].first_page_id;
+                let mut page = *db
+                    .buffer_pool
+                    .borrow_mut()
+                    .fetch_page(page_id)
+                    .unwrap()
+                    .borrow();
+                SlottedPage::new(&mut page).free_space()
+            };
+            assert!(free_space("wide") > free_space("plain"));
+
+            parse_and_execute(&mut db, "UPDATE wide SET note = 'second' WHERE id = 2").unwrap();
+            parse_and_execute(&mut db, "UPDATE plain SET note = 'second' WHERE id = 2").unwrap();
+
+            // Compact rows only hold values of their columns' types
+            let result = parse_and_execute(&mut db, "INSERT INTO wide VALUES (4, 'yes', 1, 'x')");
+            assert!(matches!(result, Err(e) if e.contains("can't hold")));
+        }
+
+        // The format is kept in the catalog, so each table decodes its own way
+        let mut db = Database::new(path).unwrap();
+        assert_eq!(db.tables["wide"].format, RowFormat::Compact);
+        assert_eq!(db.tables["plain"].format, RowFormat::Tagged);
+        let wide = parse_and_execute(&mut db, "SELECT * FROM wide ORDER BY id").unwrap();
+        let plain = parse_and_execute(&mut db, "SELECT * FROM plain ORDER BY id").unwrap();
+        assert_eq!(wide, plain);
+        assert!(wide.contains("2\tNULL\t7.00\tsecond"));
+        assert_eq!(db.check_pages().unwrap(), Vec::<String>::new());
+
+        let result = parse_and_execute(
+            &mut db,
+            "CREATE TABLE bad (x FLOAT) WITH (row_format = 'compact')",
+        );
+        assert!(matches!(result, Err(e) if e.contains("unsupported column type 'FLOAT'")));
+        let result = parse_and_execute(&mut db, "CREATE TABLE bad (x INT) WITH (ttl = 70)");
+        assert!(matches!(result, Err(e) if e.contains("Unsupported table option")));
+        drop(db);
+
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+    }
+
+    #[test]
+    fn test_fill_factor() {
+        let path = "test_executor_fill_factor.db";
+        let catalog = format!("{}.catalog", path);
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+
+        // Free space on each of a table's pages
+        let free_space = |db: &mut Database, table: &str| -> Vec<usize> {
+            let result = parse_and_execute(db, &format!("PRAGMA table_pages('{}')", table));
+            let result = result.unwrap();
+            let lines: Vec<&str> = result.lines().collect();
+            lines[2..lines.len() - 1]
+                .iter()
+                .map(|line| line.split('\t').nth(2).unwrap().parse().unwrap())
+                .collect()
+        };
+
+        {
+            let mut db = Database::new(path).unwrap();
+            for (table, options) in [("loose", " WITH (fillfactor = 60)"), ("packed", "")] {
+                parse_and_execute(
+                    &mut db,
+                    &format!("CREATE TABLE {} (id INT, note TEXT){}", table, options),
+                )
+                .unwrap();
+                let values: Vec<String> = (0..200)
+                    .map(|i| format!("({}, '{}')", i, "x".repeat(40)))
+                    .collect();
+                let sql = format!("INSERT INTO {} VALUES {}", table, values.join(", "));
+                parse_and_execute(&mut db, &sql).unwrap();
+            }
+
+            // No page of the loose table is more than 60% full
+            let loose = free_space(&mut db, "loose");
+            let packed = free_space(&mut db, "packed");
+            assert!(loose.len() > packed.len(), "{:?} {:?}", loose, packed);
+            assert!(
+                loose.iter().all(|&free| free * 100 >= PAGE_SIZE * 40),
+                "{:?}",
+                loose
+            );
+
+            // The room left lets a row grow where it is
+            let before = parse_and_execute(&mut db, "SELECT _row_id FROM loose WHERE id = 5");
+            let grow = format!("UPDATE loose SET note = '{}' WHERE id = 5", "y".repeat(400));
+            parse_and_execute(&mut db, &grow).unwrap();
+            let after = parse_and_execute(&mut db, "SELECT _row_id FROM loose WHERE id = 5");
+            assert_eq!(before, after);
+        }
+
+        // The fill factor is kept in the catalog
+        let mut db = Database::new(path).unwrap();
+        assert_eq!(db.tables["loose"].fill_factor, 60);
+        assert_eq!(db.tables["packed"].fill_factor, 100);
+
+        for option in ["fillfactor = 5", "fillfactor = 101", "fillfactor = 'lots'"] {
+            let sql = format!("CREATE TABLE bad (x INT) WITH ({})", option);
+            let result = parse_and_execute(&mut db, &sql);
+            assert!(matches!(result, Err(e) if e.contains("from 10 to 100")));
+        }
+        drop(db);
+
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+    }
+
+    #[test]
+    fn test_primary_key_equality_stops_scan() {
+        with_memory_db(|db| {
+            parse_and_execute(db, "CREATE TABLE users (id INT PRIMARY KEY, name TEXT)").unwrap();
+            let values: Vec<String> = (0..500).map(|i| format!("({}, 'User{}')", i, i)).collect();
+            let sql = format!("INSERT INTO users VALUES {}", values.join(", "));
+            parse_and_execute(db, &sql).unwrap();
+
+            let result = parse_and_execute(db, "SELECT * FROM users WHERE id = 5").unwrap();
+            assert!(result.contains("5\tUser5"));
+            assert!(result.ends_with("(1 rows)"));
+
+            let stats = query_stats(db, "SELECT * FROM users WHERE id = 5");
+            assert_eq!(stats.rows_scanned, 6);
+            assert_eq!(stats.rows_returned, 1);
+
+            // A non-unique column still needs the whole table
+            let stats = query_stats(db, "SELECT * FROM users WHERE name = 'User5'");
+            assert_eq!(stats.rows_scanned, 500);
+        });
+    }
+
+    #[test]
+    fn test_unique_constraint_rejects_duplicates() {
+        with_memory_db(|db| {
+            let dialect = MatiDialect {};
+            let mut run = |sql: &str| {
+                let stmt = Parser::parse_sql(&dialect, sql).unwrap().remove(0);
+                execute(db, stmt)
+            };
+
+            run("CREATE TABLE users (id INT PRIMARY KEY, email TEXT UNIQUE)").unwrap();
+            run("INSERT INTO users VALUES (1, 'a@x')").unwrap();
+
+            assert!(matches!(
+                run("INSERT INTO users VALUES (1, 'b@x')"),
+                Err(ExecError::Constraint(_))
+            ));
+            // Duplicates within one statement are caught and nothing is written
+            assert!(matches!(
+                run("INSERT INTO users VALUES (2, 'c@x'), (3, 'c@x')"),
+                Err(ExecError::Constraint(_))
+            ));
+            assert!(run("SELECT * FROM users").unwrap().ends_with("(1 rows)"));
+        });
+    }
+
+    #[test]
+    fn test_tablesample() {
+        with_memory_db(|db| {
+            parse_and_execute(db, "CREATE TABLE users (id INT, name TEXT)").unwrap();
+            let values: Vec<String> = (1..=200).map(|i| format!("({}, 'u{}')", i, i)).collect();
+            parse_and_execute(
+                db,
+                &format!("INSERT INTO users VALUES {}", values.join(", ")),
+            )
+            .unwrap();
+            let rows = |db: &mut Database, sql: &str| -> Vec<String> {
+                let result = parse_and_execute(db, sql).unwrap();
+                let lines: Vec<&str> = result.lines().collect();
+                lines[2..lines.len() - 1]
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect()
+            };
+
+            let sample = rows(db, "SELECT * FROM users TABLESAMPLE (10 ROWS)");
+            assert_eq!(sample.len(), 10);
+            // Real rows, each once, in table order
+            let ids: Vec<i64> = sample
+                .iter()
+                .map(|row| {
+                    let (id, name) = row.split_once('\t').unwrap();
+                    assert_eq!(name, format!("u{}", id));
+                    id.parse().unwrap()
+                })
+                .collect();
+            assert!(ids.windows(2).all(|w| w[0] < w[1]), "{:?}", ids);
+            assert!(ids.iter().all(|id| (1..=200).contains(id)));
+            assert_eq!(
+                query_stats(db, "SELECT * FROM users TABLESAMPLE (10 ROWS)").rows_scanned,
+                200
+            );
+
+            // The same seed gives the same sample; WHERE filters the sample
+            let seeded = "SELECT id FROM users TABLESAMPLE (20 ROWS) REPEATABLE (42)";
+            assert_eq!(rows(db, seeded), rows(db, seeded));
+            let upper = rows(
+                db,
+                "SELECT id FROM users u TABLESAMPLE (20 ROWS) REPEATABLE (42) WHERE id > 100",
+            );
+            assert!(upper.len() < 20);
+            assert!(upper.iter().all(|id| rows(db, seeded).contains(id)));
+
+            assert_eq!(
+                rows(db, "SELECT id FROM users TABLESAMPLE (500 ROWS)").len(),
+                200
+            );
+            assert!(rows(db, "SELECT id FROM users TABLESAMPLE (0 PERCENT)").is_empty());
+            assert_eq!(
+                rows(db, "SELECT id FROM users TABLESAMPLE BERNOULLI (100)").len(),
+                200
+            );
+            let percent = rows(
+                db,
+                "SELECT id FROM users TABLESAMPLE (50 PERCENT) REPEATABLE (1)",
+            );
+            assert!((50..150).contains(&percent.len()), "{}", percent.len());
+
+            let explain = parse_and_execute(db, "EXPLAIN SELECT * FROM users TABLESAMPLE (5 ROWS)");
+            assert_eq!(explain.unwrap(), "Sample Scan on users (5 ROWS)");
+
+            for (sql, error) in [
+                (
+                    "SELECT id FROM users TABLESAMPLE (150 PERCENT)",
+                    "from 0 to 100",
+                ),
+                (
+                    "SELECT id FROM users TABLESAMPLE (1.5 ROWS)",
+                    "non-negative integer",
+                ),
+                (
+                    "SELECT id FROM users TABLESAMPLE SYSTEM (BUCKET 1 OUT OF 4)",
+                    "Unsupported TABLESAMPLE",
+                ),
+                (
+                    "SELECT * FROM users a TABLESAMPLE (5 ROWS), users b",
+                    "single table",
+                ),
+            ] {
+                let result = parse_and_execute(db, sql).unwrap_err();
+                assert!(result.contains(error), "{}: {}", sql, result);
+            }
+        });
+    }
+
+    #[test]
+    fn test_explain_shows_plan() {
+        with_memory_db(|db| {
+            setup_employees(db);
+
+            let plan = parse_and_execute(db, "EXPLAIN SELECT * FROM employees WHERE dept = 'eng'")
+                .unwrap();
+            assert_eq!(plan, "Seq Scan on employees\n  Filter: dept = 'eng'");
+
+            parse_and_execute(db, "CREATE INDEX idx ON employees (dept)").unwrap();
+            let plan = parse_and_execute(db, "EXPLAIN SELECT * FROM employees WHERE dept = 'eng'")
+                .unwrap();
+            assert!(plan.starts_with("Index Lookup on employees using idx (dept = eng)"));
+        });
+    }
+
+    #[test]
+    fn test_explain_analyze_reports_measurements() {
+        with_memory_db(|db| {
+            parse_and_execute(db, "CREATE TABLE users (id INT, name TEXT)").unwrap();
+            let values: Vec<String> = (0..200).map(|i| format!("({}, 'User{}')", i, i)).collect();
+            let sql = format!("INSERT INTO users VALUES {}", values.join(", "));
+            parse_and_execute(db, &sql).unwrap();
+
+            let sql =
+                "-- how selective is this?\nEXPLAIN ANALYZE SELECT * FROM users WHERE id < 10";
+            let output = parse_and_execute(db, sql).unwrap();
+
+            assert!(output.contains("Rows scanned: 200"));
+            assert!(output.contains("Rows filtered: 190"));
+            assert!(output.contains("Rows returned: 10"));
+
+            let elapsed: f64 = output
+                .lines()
+                .find_map(|l| l.strip_prefix("Elapsed: "))
+                .and_then(|l| l.strip_suffix(" ms"))
+                .unwrap()
+                .parse()
+                .unwrap();
+            assert!(elapsed > 0.0);
+        });
+    }
+
+    #[test]
+    fn test_explain_analyze_more_rows_than_scanned() {
+        with_memory_db(|db| {
+            parse_and_execute(db, "CREATE TABLE a (x INT)").unwrap();
+
+            // An aggregate returns a row even when there's none to read
+            let output = parse_and_execute(db, "EXPLAIN ANALYZE SELECT COUNT(*) FROM a").unwrap();
+            assert!(output.contains("Rows scanned: 0\nRows filtered: 0\nRows returned: 1"));
+
+            parse_and_execute(db, "INSERT INTO a VALUES (1), (2), (3)").unwrap();
+            let output = parse_and_execute(db, "EXPLAIN ANALYZE SELECT * FROM a a1, a a2").unwrap();
+            assert!(output.contains("Rows scanned: 6\nRows filtered: 0\nRows returned: 9"));
+            let output = parse_and_execute(
+                db,
+                "EXPLAIN ANALYZE SELECT * FROM a a1, a a2 WHERE a1.x < a2.x",
+            )
+            .unwrap();
+            assert!(output.contains("Rows scanned: 6\nRows filtered: 6\nRows returned: 3"));
+            let output = parse_and_execute(
+                db,
+                "EXPLAIN ANALYZE SELECT COUNT(*) FROM a WHERE x > 1 HAVING COUNT(*) > 5",
+            )
+            .unwrap();
+            assert!(output.contains("Rows scanned: 3\nRows filtered: 1\nRows returned: 0"));
+        });
+    }
+
+    #[test]
+    fn test_select_concatenation() {
+        with_memory_db(|db| {
+            parse_and_execute(db, "CREATE TABLE people (first TEXT, last TEXT, age INT)").unwrap();
+            parse_and_execute(db, "INSERT INTO people VALUES ('Ada', 'Lovelace', 36)").unwrap();
+
+            let result =
+                parse_and_execute(db, "SELECT first || ' ' || last AS full_name FROM people")
+                    .unwrap();
+            assert_eq!(result, "full_name\n----------\nAda Lovelace\n(1 rows)");
+
+            let result = parse_and_execute(
+                db,
+                "SELECT CONCAT(last, ', ', first, ' (', age, ')') FROM people",
+            )
+            .unwrap();
+            assert!(result.contains("\nLovelace, Ada (36)\n"));
+        });
+    }
+
+    #[test]
+    fn test_query_returns_values() {
+        with_memory_db(|db| {
+            setup_employees(db);
+            let stmt = Parser::parse_sql(
+                &MatiDialect {},
+                "SELECT id, dept AS team FROM employees WHERE dept = 'eng' ORDER BY id",
+            )
+            .unwrap()
+            .remove(0);
+            let result = query(db, stmt).unwrap();
+
+            assert_eq!(result.columns, vec!["id", "team"]);
+            let ids: Vec<i64> = result
+                .iter()
+                .map(|row| row.get_long("id").unwrap().unwrap())
+                .collect();
+            assert_eq!(ids, vec![1, 2, 4]);
+            assert_eq!(result.get_text(0, "team").unwrap(), Some("eng"));
+
+            let stmt = Parser::parse_sql(&MatiDialect {}, "DELETE FROM employees")
+                .unwrap()
+                .remove(0);
+            assert!(matches!(query(db, stmt), Err(ExecError::Unsupported(_))));
+        });
+    }
+
+    #[test]
+    fn test_pragmas() {
+        with_memory_db(|db| {
+            let value = |db: &mut Database, sql: &str| -> i64 {
+                let result = parse_and_execute(db, sql).unwrap();
+                result.lines().nth(2).unwrap().parse().unwrap()
+            };
+
+            parse_and_execute(db, "CREATE TABLE a (id INT, note TEXT)").unwrap();
+            for i in 0..100 {
+                let sql = format!("INSERT INTO a VALUES ({}, '{}')", i, "x".repeat(100));
+                parse_and_execute(db, &sql).unwrap();
+            }
+            parse_and_execute(db, "CREATE TABLE b (id INT)").unwrap();
+            let a_pages = db.table_pages(db.tables["a"].first_page_id).unwrap();
+            assert!(a_pages.len() >= 3);
+            assert_eq!(value(db, "PRAGMA page_count"), a_pages.len() as i64 + 1);
+            assert_eq!(value(db, "PRAGMA free_pages"), 0);
+
+            // One row per page in the chain, holding all 100 rows between them
+            let result = parse_and_execute(db, "PRAGMA table_pages('a')").unwrap();
+            let lines: Vec<&str> = result.lines().collect();
+            assert_eq!(lines[0], "page_id\ttuples\tfree_space");
+            assert_eq!(lines.len(), a_pages.len() + 3);
+            let tuples: i64 = lines[2..lines.len() - 1]
+                .iter()
+                .map(|line| line.split('\t').nth(1).unwrap().parse::<i64>().unwrap())
+                .sum();
+            assert_eq!(tuples, 100);
+            // The table name may be left unquoted
+            assert_eq!(
+                parse_and_execute(db, "pragma TABLE_PAGES(a);").unwrap(),
+                result
+            );
+
+            // Emptying a and vacuuming unlinks its later pages, but b's page
+            // keeps them in the file
+            parse_and_execute(db, "DELETE FROM a").unwrap();
+            parse_and_execute(db, "VACUUM").unwrap();
+            assert_eq!(value(db, "PRAGMA page_count"), a_pages.len() as i64 + 1);
+            assert_eq!(value(db, "PRAGMA free_pages"), a_pages.len() as i64 - 1);
+            let result = parse_and_execute(db, "PRAGMA table_pages('a')").unwrap();
+            assert!(result.ends_with(&format!("{}\t0\t4088\n(1 rows)", a_pages[0])));
+
+            let result = parse_and_execute(db, "PRAGMA buffer_stats").unwrap();
+            assert!(result.starts_with("cached_pages\tcapacity\thits\tmisses\tevictions\n"));
+            assert!(result.ends_with("(1 rows)"));
+
+            let result = parse_and_execute(db, "PRAGMA table_pages('missing')");
+            assert_eq!(result.unwrap_err(), "Table 'missing' does not exist");
+            let result = parse_and_execute(db, "PRAGMA table_pages(missing)");
+            assert_eq!(result.unwrap_err(), "Table 'missing' does not exist");
+            let result = parse_and_execute(db, "PRAGMA journal_mode");
+            assert_eq!(result.unwrap_err(), "Unknown PRAGMA 'journal_mode'");
+        });
+    }
+
+    #[test]
+    fn test_union() {
+        with_memory_db(|db| {
+            setup_employees(db);
+            parse_and_execute(db, "CREATE TABLE grades (level DECIMAL(4, 1), name TEXT)").unwrap();
+            parse_and_execute(
+                db,
+                "INSERT INTO grades VALUES (3.0, 'senior'), (4.5, 'lead')",
+            )
+            .unwrap();
+
+            // Integers and decimals mix; names come from the first query, and
+            // UNION drops the rows both sides have
+            let result = parse_and_execute(
+                db,
+                "SELECT level AS grade, dept FROM employees UNION SELECT level, name FROM grades ORDER BY grade DESC, dept",
+            )
+            .unwrap();
+            assert_eq!(
+                result,
+                "grade\tdept\n--------------------\n4.5\tlead\n3\teng\n3\tops\n3.0\tsenior\n1\teng\n(5 rows)"
+            );
+
+            let result = parse_and_execute(
+                db,
+                "SELECT dept FROM employees UNION ALL SELECT name FROM grades LIMIT 2 OFFSET 3",
+            )
+            .unwrap();
+            assert!(result.contains("eng\nsenior\n(2 rows)"), "{}", result);
+            let result = parse_and_execute(
+                db,
+                "SELECT dept FROM employees UNION SELECT dept FROM employees",
+            )
+            .unwrap();
+            assert!(result.ends_with("(2 rows)"));
+
+            let result = parse_and_execute(
+                db,
+                "SELECT id, dept FROM employees UNION SELECT level FROM grades",
+            );
+            assert_eq!(
+                result.unwrap_err(),
+                "each UNION query must have the same number of columns"
+            );
+            let result = parse_and_execute(
+                db,
+                "SELECT dept FROM employees UNION SELECT level FROM grades",
+            );
+            assert_eq!(
+                result.unwrap_err(),
+                "UNION types text and decimal cannot be matched"
+            );
+        });
+    }
+
+    #[test]
+    fn test_boolean_predicates() {
+        with_memory_db(|db| {
+            parse_and_execute(db, "CREATE TABLE flags (id INT, active BOOL)").unwrap();
+            parse_and_execute(
+                db,
+                "INSERT INTO flags VALUES (1, TRUE), (2, FALSE), (3, NULL)",
+            )
+            .unwrap();
+            let ids = |db: &mut Database, filter: &str| {
+                let sql = format!("SELECT id FROM flags WHERE {}", filter);
+                let result = parse_and_execute(db, &sql).unwrap();
+                result.lines().skip(2).collect::<Vec<_>>().join(",")
+            };
+
+            // A bare boolean column is the same test as comparing it with TRUE
+            assert_eq!(ids(db, "active"), "1,(1 rows)");
+            assert_eq!(ids(db, "active = TRUE"), "1,(1 rows)");
+            assert_eq!(ids(db, "NOT active"), "2,(1 rows)");
+            assert_eq!(ids(db, "TRUE"), "1,2,3,(3 rows)");
+            assert_eq!(ids(db, "FALSE"), "(0 rows)");
+
+            // A constant FALSE or NULL doesn't read the table at all
+            for filter in ["FALSE", "(NULL)"] {
+                let sql = format!("SELECT id FROM flags WHERE {}", filter);
+                assert_eq!(query_stats(db, &sql).rows_scanned, 0);
+            }
+            assert_eq!(
+                query_stats(db, "SELECT id FROM flags WHERE TRUE").rows_returned,
+                3
+            );
+        });
+    }
+
+    #[test]
+    fn test_select_computed_booleans() {
+        with_memory_db(|db| {
+            parse_and_execute(db, "CREATE TABLE people (name TEXT, age INT)").unwrap();
+            parse_and_execute(
+                db,
+                "INSERT INTO people VALUES ('ann', 30), ('bob', 10), ('cy', NULL), ('di', 18)",
+            )
+            .unwrap();
+
+            let result = parse_and_execute(
+                db,
+                "SELECT name, age > 18 AS is_adult, NOT age >= 18 FROM people",
+            )
+            .unwrap();
+            let rows: Vec<&str> = result.lines().skip(2).collect();
+            assert_eq!(
+                rows,
+                vec![
+                    "ann\ttrue\tfalse",
+                    "bob\tfalse\ttrue",
+                    "cy\tNULL\tNULL",
+                    "di\tfalse\tfalse",
+                    "(4 rows)"
+                ]
+            );
+            assert!(result.starts_with("name\tis_adult\tNOT age >= 18\n"));
+
+            // A computed column sorts like any other: false, true, then NULL
+            let result = parse_and_execute(
+                db,
+                "SELECT name, age > 18 AS is_adult FROM people ORDER BY 2",
+            )
+            .unwrap();
+            let rows: Vec<&str> = result.lines().skip(2).collect();
+            assert_eq!(rows[..3], ["bob\tfalse", "di\tfalse", "ann\ttrue"]);
+        });
+    }
+
+    #[test]
+    fn test_blob_round_trip() {
+        with_memory_db(|db| {
+            parse_and_execute(db, "CREATE TABLE messages (id INT, body BLOB)").unwrap();
+            parse_and_execute(
+                db,
+                "INSERT INTO messages VALUES (1, X'0A00FF00'), (2, X''), (3, DECODE('00', 'hex'))",
+            )
+            .unwrap();
+
+            let result = parse_and_execute(db, "SELECT * FROM messages").unwrap();
+            assert!(result.contains("1\t\\x0a00ff00\n"));
+            assert!(result.contains("2\t\\x\n"));
+            assert!(result.contains("3\t\\x00\n"));
+
+            let result = parse_and_execute(
+                db,
+                "SELECT id FROM messages WHERE body = DECODE('0a00ff00', 'hex')",
+            )
+            .unwrap();
+            assert!(result.ends_with("1\n(1 rows)"));
+
+            assert!(parse_and_execute(db, "INSERT INTO messages VALUES (4, X'ABC')").is_err());
+        });
+    }
+
+    #[test]
+    fn test_decimal_columns() {
+        let path = "test_decimal_columns.db";
+        let catalog = format!("{}.catalog", path);
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+
+        {
+            let mut db = Database::new(path).unwrap();
+            let db = &mut db;
+            parse_and_execute(db, "CREATE TABLE items (id INT, price DECIMAL(6, 2))").unwrap();
+            // Stored with two fractional digits, rounding any beyond that
+            parse_and_execute(
+                db,
+                "INSERT INTO items VALUES (1, 0.1), (2, 0.2), (3, 4), (4, 2.345), (5, NULL)",
+            )
+            .unwrap();
+
+            let result = parse_and_execute(db, "SELECT price FROM items").unwrap();
+            let rows: Vec<&str> = result.lines().skip(2).collect();
+            assert_eq!(
+                rows,
+                vec!["0.10", "0.20", "4.00", "2.35", "NULL", "(5 rows)"]
+            );
+
+            let result =
+                parse_and_execute(db, "SELECT id FROM items WHERE price = 0.1 + 0.2").unwrap();
+            assert!(result.ends_with("\n(0 rows)"), "{}", result);
+            parse_and_execute(db, "UPDATE items SET price = price + 0.2 WHERE id = 1").unwrap();
+            let result =
+                parse_and_execute(db, "SELECT id FROM items WHERE price = 0.1 + 0.2").unwrap();
+            assert!(result.ends_with("\n1\n(1 rows)"), "{}", result);
+
+            let result = parse_and_execute(db, "SELECT SUM(price) FROM items").unwrap();
+            assert!(result.contains("\n6.85\n"), "{}", result);
+            let result =
+                parse_and_execute(db, "SELECT id FROM items WHERE price > 2 ORDER BY price")
+                    .unwrap();
+            assert!(result.contains("\n4\n3\n"), "{}", result);
+
+            for sql in [
+                "INSERT INTO items VALUES (6, 12345.6)",
+                "INSERT INTO items VALUES (6, 'free')",
+                "CREATE TABLE bad (n DECIMAL(50, 2))",
+                "CREATE TABLE bad (n DECIMAL(2, 3))",
+            ] {
+                assert!(parse_and_execute(db, sql).is_err(), "{}", sql);
+            }
+        }
+
+        // The column type survives a reopen
+        {
+            let mut db = Database::new(path).unwrap();
+            let db = &mut db;
+            parse_and_execute(db, "INSERT INTO items VALUES (6, 1)").unwrap();
+            let result = parse_and_execute(db, "SELECT price FROM items WHERE id = 6").unwrap();
+            assert!(result.contains("\n1.00\n"), "{}", result);
+        }
+
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+    }
+
+    #[test]
+    fn test_column_references_ignore_case() {
+        with_memory_db(|db| {
+            setup_employees(db);
+            parse_and_execute(db, "CREATE INDEX idx ON employees (DEPT)").unwrap();
+
+            let result =
+                parse_and_execute(db, "SELECT ID, Level FROM employees WHERE Dept = 'ops'")
+                    .unwrap();
+            assert!(result.starts_with("id\tlevel\n"));
+            assert!(result.ends_with("(1 rows)"));
+
+            // The mixed-case predicate still finds the index
+            let stats = query_stats(db, "SELECT * FROM employees WHERE DEPT = 'ops'");
+            assert_eq!(stats.rows_scanned, 1);
+            assert_eq!(db.indexes["idx"].columns, vec!["dept"]);
+        });
+    }
+
+    #[test]
+    fn test_limit_stops_fetching_pages() {
+        with_memory_db(|db| {
+            parse_and_execute(db, "CREATE TABLE users (id INT, name TEXT)").unwrap();
+            let values: Vec<String> = (0..500).map(|i| format!("({}, 'User{}')", i, i)).collect();
+            let sql = format!("INSERT INTO users VALUES {}", values.join(", "));
+            parse_and_execute(db, &sql).unwrap();
+            assert!(db.table_pages(0).unwrap().len() > 2);
+
+            let result = parse_and_execute(db, "SELECT id FROM users LIMIT 5").unwrap();
+            assert_eq!(result, "id\n----------\n0\n1\n2\n3\n4\n(5 rows)");
+
+            let stats = query_stats(db, "SELECT * FROM users LIMIT 5");
+            assert_eq!(stats.pages_read, 1);
+            assert_eq!(stats.rows_scanned, 5);
+
+            let stats = query_stats(db, "SELECT * FROM users LIMIT 0");
+            assert_eq!(stats.pages_read, 0);
+
+            let result =
+                parse_and_execute(db, "SELECT id FROM users WHERE id > 100 LIMIT 2 OFFSET 3")
+                    .unwrap();
+            assert!(result.ends_with("\n104\n105\n(2 rows)"));
+
+            assert!(parse_and_execute(db, "SELECT * FROM users LIMIT -1").is_err());
+        });
+    }
+
+    #[test]
+    fn test_offset_without_limit() {
+        with_memory_db(|db| {
+            setup_employees(db);
+
+            // OFFSET alone skips rows and returns the rest
+            let result = parse_and_execute(db, "SELECT id FROM employees OFFSET 2").unwrap();
+            assert_eq!(result, "id\n----------\n3\n4\n(2 rows)");
+            let result =
+                parse_and_execute(db, "SELECT id FROM employees ORDER BY id DESC OFFSET 1")
+                    .unwrap();
+            assert_eq!(result, "id\n----------\n3\n2\n1\n(3 rows)");
+            let result = parse_and_execute(db, "SELECT id FROM employees OFFSET 9").unwrap();
+            assert!(result.ends_with("(0 rows)"));
+
+            // LIMIT ALL is no limit at all
+            let result = parse_and_execute(db, "SELECT id FROM employees LIMIT ALL").unwrap();
+            assert!(result.ends_with("\n1\n2\n3\n4\n(4 rows)"));
+            let result = parse_and_execute(
+                db,
+                "SELECT id FROM employees WHERE dept = 'eng' LIMIT ALL OFFSET 1",
+            )
+            .unwrap();
+            assert!(result.ends_with("\n2\n4\n(2 rows)"));
+        });
+    }
+
+    #[test]
+    fn test_with_queries() {
+        with_memory_db(|db| {
+            setup_employees(db);
+
+            let result = parse_and_execute(
+                db,
+                "WITH eng AS (SELECT * FROM employees WHERE dept = 'eng') \
+                 SELECT id, level FROM eng WHERE level > 1 ORDER BY id",
+            )
+            .unwrap();
+            assert_eq!(
+                result,
+                "id\tlevel\n--------------------\n2\t3\n4\t3\n(2 rows)"
+            );
+
+            // A later query reads an earlier one, with columns renamed
+            let result = parse_and_execute(
+                db,
+                "WITH senior AS (SELECT id, dept FROM employees WHERE level = 3), \
+                 counts (team, people) AS (SELECT dept, COUNT(*) FROM senior GROUP BY dept) \
+                 SELECT team, people FROM counts WHERE people > 1",
+            )
+            .unwrap();
+            assert_eq!(
+                result,
+                "team\tpeople\n--------------------\neng\t2\n(1 rows)"
+            );
+
+            // Joined with a table, and read by a subquery
+            let result = parse_and_execute(
+                db,
+                "WITH juniors AS (SELECT id FROM employees WHERE level = 1) \
+                 SELECT e.dept FROM employees e JOIN juniors j ON e.id = j.id",
+            )
+            .unwrap();
+            assert_eq!(result, "dept\n----------\neng\n(1 rows)");
+            let result = parse_and_execute(
+                db,
+                "WITH ops AS (SELECT id FROM employees WHERE dept = 'ops') \
+                 SELECT id FROM employees WHERE id IN (SELECT id FROM ops)",
+            )
+            .unwrap();
+            assert_eq!(result, "id\n----------\n3\n(1 rows)");
+
+            // One named like a table hides it
+            let result = parse_and_execute(
+                db,
+                "WITH employees AS (SELECT id FROM employees WHERE id > 3) \
+                 SELECT * FROM employees",
+            )
+            .unwrap();
+            assert_eq!(result, "id\n----------\n4\n(1 rows)");
+
+            for (sql, message) in [
+                (
+                    "WITH RECURSIVE n AS (SELECT 1) SELECT * FROM n",
+                    "WITH RECURSIVE is not supported",
+                ),
+                (
+                    "WITH a AS (SELECT id FROM employees), a AS (SELECT id FROM employees) \
+                     SELECT * FROM a",
+                    "used more than once",
+                ),
+                (
+                    "WITH a (x, y) AS (SELECT id FROM employees) SELECT * FROM a",
+                    "1 column(s) but 2 name(s)",
+                ),
+            ] {
+                let result = parse_and_execute(db, sql);
+                assert!(
+                    matches!(&result, Err(e) if e.contains(message)),
+                    "{:?}",
+                    result
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn test_fetch_first() {
+        with_memory_db(|db| {
+            setup_employees(db);
+
+            // FETCH FIRST gives the same rows as the LIMIT it stands for
+            for (fetch, limit) in [
+                (
+                    "SELECT id FROM employees ORDER BY id FETCH FIRST 2 ROWS ONLY",
+                    "SELECT id FROM employees ORDER BY id LIMIT 2",
+                ),
+                (
+                    "SELECT id FROM employees ORDER BY id OFFSET 1 ROWS FETCH NEXT 2 ROWS ONLY",
+                    "SELECT id FROM employees ORDER BY id LIMIT 2 OFFSET 1",
+                ),
+                (
+                    "SELECT dept FROM employees ORDER BY id DESC FETCH FIRST ROW ONLY",
+                    "SELECT dept FROM employees ORDER BY id DESC LIMIT 1",
+                ),
+            ] {
+                let result = parse_and_execute(db, fetch).unwrap();
+                assert_eq!(result, parse_and_execute(db, limit).unwrap());
+            }
+            let result = parse_and_execute(
+                db,
+                "SELECT id FROM employees ORDER BY id OFFSET 1 ROWS FETCH FIRST 2 ROWS ONLY",
+            )
+            .unwrap();
+            assert_eq!(result, "id\n----------\n2\n3\n(2 rows)");
+
+            for (sql, message) in [
+                (
+                    "SELECT id FROM employees ORDER BY dept FETCH FIRST 1 ROWS WITH TIES",
+                    "WITH TIES is not supported",
+                ),
+                (
+                    "SELECT id FROM employees FETCH FIRST 50 PERCENT ROWS ONLY",
+                    "PERCENT is not supported",
+                ),
+                (
+                    "SELECT id FROM employees LIMIT 1 FETCH FIRST 2 ROWS ONLY",
+                    "both LIMIT and FETCH FIRST",
+                ),
+            ] {
+                let result = parse_and_execute(db, sql);
+                assert!(
+                    matches!(&result, Err(e) if e.contains(message)),
+                    "{:?}",
+                    result
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn test_order_by_spills_to_disk() {
+        with_memory_db(|db| {
+            parse_and_execute(db, "CREATE TABLE readings (id INT, sensor INT, value TEXT)")
+                .unwrap();
+            for batch in 0..5 {
+                let values: Vec<String> = (0..100)
+                    .map(|i| {
+                        let id = batch * 100 + i;
+                        let value = if id % 9 == 0 {
+                            "NULL".to_string()
+                        } else {
+                            format!("'v{}'", (id * 37) % 101)
+                        };
+                        format!("({}, {}, {})", id, (id * 13) % 7, value)
+                    })
+                    .collect();
+                let sql = format!("INSERT INTO readings VALUES {}", values.join(", "));
+                parse_and_execute(db, &sql).unwrap();
+            }
+
+            let queries = [
+                "SELECT * FROM readings ORDER BY value, id DESC",
+                "SELECT id, sensor FROM readings ORDER BY sensor DESC",
+                "SELECT DISTINCT ON (sensor) sensor, id FROM readings ORDER BY sensor, value",
+                "SELECT DISTINCT value FROM readings ORDER BY value DESC NULLS LAST LIMIT 7 OFFSET 3",
+            ];
+            let in_memory: Vec<String> = queries
+                .iter()
+                .map(|sql| parse_and_execute(db, sql).unwrap())
+                .collect();
+
+            // 500 rows through a 32-row buffer makes 15 sorted runs to merge
+            db.sort_buffer_rows = 32;
+            for (sql, expected) in queries.iter().zip(&in_memory) {
+                assert_eq!(&parse_and_execute(db, sql).unwrap(), expected, "{}", sql);
+            }
+
+            let result =
+                parse_and_execute(db, "SELECT id FROM readings ORDER BY sensor, id").unwrap();
+            let ids: Vec<i64> = result
+                .lines()
+                .skip(2)
+                .take(500)
+                .map(|line| line.parse().unwrap())
+                .collect();
+            let mut expected: Vec<i64> = (0..500).collect();
+            expected.sort_by_key(|id| ((id * 13) % 7, *id));
+            assert_eq!(ids, expected);
+
+            // Values in different runs still have to be comparable
+            parse_and_execute(db, "INSERT INTO readings VALUES (500, 0, 1)").unwrap();
+            let result = parse_and_execute(db, "SELECT * FROM readings ORDER BY value");
+            assert_eq!(
+                result.unwrap_err(),
+                "ORDER BY can't sort text and integer values together"
+            );
+        });
+    }
+
+    #[test]
+    fn test_order_by_nulls_first_and_last() {
+        with_memory_db(|db| {
+            parse_and_execute(db, "CREATE TABLE people (id INT, name TEXT)").unwrap();
+            parse_and_execute(
+                db,
+                "INSERT INTO people VALUES (1, 'bob'), (2, NULL), (3, 'alice'), (4, NULL), (5, 'carol')",
+            )
+            .unwrap();
+            let ids = |db: &mut Database, order: &str| {
+                let sql = format!("SELECT id FROM people ORDER BY {}", order);
+                let result = parse_and_execute(db, &sql).unwrap();
+                result.lines().skip(2).take(5).collect::<Vec<_>>().join(",")
+            };
+
+            // By default NULLs are larger than any value
+            assert_eq!(ids(db, "name"), "3,1,5,2,4");
+            assert_eq!(ids(db, "name DESC"), "2,4,5,1,3");
+
+            assert_eq!(ids(db, "name NULLS FIRST"), "2,4,3,1,5");
+            assert_eq!(ids(db, "name ASC NULLS LAST"), "3,1,5,2,4");
+            assert_eq!(ids(db, "name DESC NULLS FIRST"), "2,4,5,1,3");
+            assert_eq!(ids(db, "name DESC NULLS LAST"), "5,1,3,2,4");
+            assert_eq!(ids(db, "name DESC NULLS LAST, id DESC"), "5,1,3,4,2");
+        });
+    }
+
+    #[test]
+    fn test_order_by_expression() {
+        with_memory_db(|db| {
+            parse_and_execute(
+                db,
+                "CREATE TABLE staff (id INT, name TEXT, nickname TEXT, salary INT, bonus INT)",
+            )
+            .unwrap();
+            parse_and_execute(
+                db,
+                "INSERT INTO staff VALUES (1, 'Dana', NULL, 100, 50), (2, 'Ann', 'Zed', 120, 0), \
+                 (3, 'Cy', 'Bo', 90, 40)",
+            )
+            .unwrap();
+            let mut ids = |sql: &str| -> Vec<String> {
+                let result = parse_and_execute(db, sql).unwrap();
+                let lines: Vec<&str> = result.lines().collect();
+                lines[2..lines.len() - 1]
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect()
+            };
+
+            assert_eq!(
+                ids("SELECT id FROM staff ORDER BY salary + bonus DESC"),
+                ["1", "3", "2"]
+            );
+            assert_eq!(
+                ids("SELECT id FROM staff ORDER BY COALESCE(nickname, name)"),
+                ["3", "1", "2"]
+            );
+            // Expressions can be mixed with names and positions
+            assert_eq!(
+                ids("SELECT id FROM staff ORDER BY salary - salary, 1 DESC"),
+                ["3", "2", "1"]
+            );
+
+            let result =
+                parse_and_execute(db, "SELECT id FROM staff ORDER BY COALESCE(nickname, id)");
+            assert_eq!(
+                result.unwrap_err(),
+                "ORDER BY can't sort integer and text values together"
+            );
+            let result = parse_and_execute(db, "SELECT id FROM staff ORDER BY salary + nope");
+            assert!(result.unwrap_err().contains("nope"));
+        });
+    }
+
+    #[test]
+    fn test_distinct_on() {
+        with_memory_db(|db| {
+            parse_and_execute(db, "CREATE TABLE events (user_id INT, ts INT, what TEXT)").unwrap();
+            parse_and_execute(
+                db,
+                "INSERT INTO events VALUES (2, 10, 'login'), (1, 5, 'login'), (2, 30, 'logout'), \
+                 (1, 20, 'click'), (3, 7, 'login'), (2, 20, 'click')",
+            )
+            .unwrap();
+
+            // The latest event per user
+            let result = parse_and_execute(
+                db,
+                "SELECT DISTINCT ON (user_id) * FROM events ORDER BY user_id, ts DESC",
+            )
+            .unwrap();
+            assert!(
+                result.ends_with("\n1\t20\tclick\n2\t30\tlogout\n3\t7\tlogin\n(3 rows)"),
+                "{}",
+                result
+            );
+
+            // Without ORDER BY the first row in storage order is kept
+            let result =
+                parse_and_execute(db, "SELECT DISTINCT ON (what) what, user_id FROM events")
+                    .unwrap();
+            assert!(
+                result.ends_with("\nlogin\t2\nlogout\t2\nclick\t1\n(3 rows)"),
+                "{}",
+                result
+            );
+
+            // Plain DISTINCT compares every output column, before OFFSET and LIMIT
+            let result = parse_and_execute(
+                db,
+                "SELECT DISTINCT what FROM events ORDER BY what LIMIT 2 OFFSET 1",
+            )
+            .unwrap();
+            assert!(result.ends_with("\nlogin\nlogout\n(2 rows)"), "{}", result);
+            let result = parse_and_execute(db, "SELECT DISTINCT user_id FROM events").unwrap();
+            assert!(result.ends_with("\n2\n1\n3\n(3 rows)"), "{}", result);
+
+            let result = parse_and_execute(db, "SELECT DISTINCT ON (nope) * FROM events");
+            assert!(result.unwrap_err().contains("nope"));
+        });
+    }
+
+    #[test]
+    fn test_limit_with_aggregates_and_distinct() {
+        with_memory_db(|db| {
+            parse_and_execute(db, "CREATE TABLE readings (sensor INT, value INT)").unwrap();
+            for batch in 0..5 {
+                let values: Vec<String> = (0..100)
+                    .map(|i| format!("({}, {})", i % 10, batch * 100 + i))
+                    .collect();
+                let sql = format!("INSERT INTO readings VALUES {}", values.join(", "));
+                parse_and_execute(db, &sql).unwrap();
+            }
+
+            // LIMIT applies to the aggregated row, so every row is still counted
+            let result = parse_and_execute(db, "SELECT COUNT(*) FROM readings LIMIT 1").unwrap();
+            assert!(result.ends_with("\n500\n(1 rows)"), "{}", result);
+            let stats = query_stats(db, "SELECT COUNT(*) FROM readings LIMIT 1");
+            assert_eq!(stats.rows_scanned, 500);
+            let result = parse_and_execute(
+                db,
+                "SELECT sensor, COUNT(*) FROM readings GROUP BY sensor ORDER BY sensor LIMIT 2",
+            )
+            .unwrap();
+            assert!(result.ends_with("\n0\t50\n1\t50\n(2 rows)"), "{}", result);
+
+            // Unsorted DISTINCT stops once it has found enough distinct rows
+            let sql = "SELECT DISTINCT sensor FROM readings LIMIT 3 OFFSET 1";
+            let result = parse_and_execute(db, sql).unwrap();
+            assert!(result.ends_with("\n1\n2\n3\n(3 rows)"), "{}", result);
+            let stats = query_stats(db, sql);
+            assert_eq!(stats.rows_scanned, 4);
+            assert_eq!(stats.rows_returned, 3);
+
+            // With ORDER BY every row has to be seen first
+            let sql = "SELECT DISTINCT sensor FROM readings ORDER BY sensor DESC LIMIT 2";
+            let result = parse_and_execute(db, sql).unwrap();
+            assert!(result.ends_with("\n9\n8\n(2 rows)"), "{}", result);
+            assert_eq!(query_stats(db, sql).rows_scanned, 500);
+        });
+    }
+
+    #[test]
+    fn test_in_subquery() {
+        with_memory_db(|db| {
+            parse_and_execute(db, "CREATE TABLE users (id INT, active BOOLEAN)").unwrap();
+            parse_and_execute(db, "CREATE TABLE orders (id INT, user_id INT)").unwrap();
+            parse_and_execute(
+                db,
+                "INSERT INTO users VALUES (1, true), (2, false), (3, true)",
+            )
+            .unwrap();
+            parse_and_execute(
+                db,
+                "INSERT INTO orders VALUES (10, 1), (11, 2), (12, 3), (13, 1)",
+            )
+            .unwrap();
+            let ids = |result: String| -> Vec<String> {
+                let lines: Vec<&str> = result.lines().collect();
+                lines[2..lines.len() - 1]
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect()
+            };
+
+            let result = parse_and_execute(
+                db,
+                "SELECT id FROM orders WHERE user_id IN (SELECT id FROM users WHERE active)",
+            )
+            .unwrap();
+            assert_eq!(ids(result), ["10", "12", "13"]);
+            let result = parse_and_execute(
+                db,
+                "SELECT id FROM orders WHERE user_id NOT IN (SELECT id FROM users WHERE active)",
+            )
+            .unwrap();
+            assert_eq!(ids(result), ["11"]);
+
+            // Nothing is IN an empty subquery, and everything is NOT IN it
+            let empty = "(SELECT id FROM users WHERE id > 5)";
+            let result = parse_and_execute(
+                db,
+                &format!("SELECT id FROM orders WHERE user_id IN {}", empty),
+            )
+            .unwrap();
+            assert!(result.ends_with("(0 rows)"));
+            let result = parse_and_execute(
+                db,
+                &format!("SELECT id FROM orders WHERE user_id NOT IN {}", empty),
+            )
+            .unwrap();
+            assert!(result.ends_with("(4 rows)"));
+
+            parse_and_execute(
+                db,
+                "DELETE FROM orders WHERE user_id IN (SELECT id FROM users WHERE NOT active)",
+            )
+            .unwrap();
+            let result = parse_and_execute(db, "SELECT id FROM orders").unwrap();
+            assert_eq!(ids(result), ["10", "12", "13"]);
+
+            let result = parse_and_execute(
+                db,
+                "SELECT id FROM orders WHERE user_id IN (SELECT id, active FROM users)",
+            );
+            assert_eq!(
+                result.unwrap_err(),
+                "IN subquery must return one column, got 2"
+            );
+        });
+    }
+
+    #[test]
+    fn test_any_all_subquery() {
+        with_memory_db(|db| {
+            parse_and_execute(db, "CREATE TABLE staff (name TEXT, salary INT, dept TEXT)").unwrap();
+            parse_and_execute(db, "CREATE TABLE interns (salary INT)").unwrap();
+            parse_and_execute(db, "CREATE TABLE active_depts (dept TEXT)").unwrap();
+            parse_and_execute(
+                db,
+                "INSERT INTO staff VALUES ('ann', 50, 'eng'), ('bob', 30, 'ops'), ('cy', 20, 'hr')",
+            )
+            .unwrap();
+            parse_and_execute(db, "INSERT INTO interns VALUES (25), (30)").unwrap();
+            parse_and_execute(db, "INSERT INTO active_depts VALUES ('eng'), ('hr')").unwrap();
+            let names = |db: &mut Database, condition: &str| -> Vec<String> {
+                let sql = format!("SELECT name FROM staff WHERE {} ORDER BY name", condition);
+                let result = parse_and_execute(db, &sql).unwrap();
+                let lines: Vec<&str> = result.lines().collect();
+                lines[2..lines.len() - 1]
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect()
+            };
+
+            assert_eq!(
+                names(db, "salary > ALL (SELECT salary FROM interns)"),
+                ["ann"]
+            );
+            assert_eq!(
+                names(db, "salary > ANY (SELECT salary FROM interns)"),
+                ["ann", "bob"]
+            );
+            assert_eq!(
+                names(db, "salary >= SOME (SELECT salary FROM interns)"),
+                ["ann", "bob"]
+            );
+            assert_eq!(
+                names(db, "dept = ANY (SELECT dept FROM active_depts)"),
+                ["ann", "cy"]
+            );
+            assert_eq!(
+                names(db, "dept <> ALL (SELECT dept FROM active_depts)"),
+                ["bob"]
+            );
+
+            // ALL of no rows holds and ANY of none doesn't
+            let empty = "(SELECT salary FROM interns WHERE salary > 100)";
+            assert_eq!(
+                names(db, &format!("salary > ALL {}", empty)),
+                ["ann", "bob", "cy"]
+            );
+            assert!(names(db, &format!("salary > ANY {}", empty)).is_empty());
+
+            // A NULL leaves a comparison that isn't decided otherwise unknown
+            parse_and_execute(db, "INSERT INTO interns VALUES (NULL)").unwrap();
+            assert!(names(db, "salary > ALL (SELECT salary FROM interns)").is_empty());
+            assert_eq!(
+                names(db, "salary < ANY (SELECT salary FROM interns)"),
+                ["cy"]
+            );
+            assert_eq!(
+                names(db, "NOT (salary > ALL (SELECT salary FROM interns))"),
+                ["bob", "cy"]
+            );
+
+            let result = parse_and_execute(
+                db,
+                "SELECT name FROM staff WHERE salary > ALL (SELECT salary, salary FROM interns)",
+            );
+            assert_eq!(
+                result.unwrap_err(),
+                "ANY and ALL subquery must return one column, got 2"
+            );
+        });
+    }
+
+    #[test]
+    fn test_exists_subquery() {
+        with_memory_db(|db| {
+            parse_and_execute(db, "CREATE TABLE users (id INT, name TEXT)").unwrap();
+            parse_and_execute(db, "CREATE TABLE orders (id INT, user_id INT, total INT)").unwrap();
+            parse_and_execute(
+                db,
+                "INSERT INTO users VALUES (1, 'ann'), (2, 'bob'), (3, 'cy'), (NULL, 'dee')",
+            )
+            .unwrap();
+            parse_and_execute(
+                db,
+                "INSERT INTO orders VALUES (1, 1, 5), (2, 3, 50), (3, 1, 20), (4, NULL, 5)",
+            )
+            .unwrap();
+            let names = |db: &mut Database, sql: &str| -> Vec<String> {
+                let result = parse_and_execute(db, sql).unwrap();
+                let lines: Vec<&str> = result.lines().collect();
+                lines[2..lines.len() - 1]
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect()
+            };
 
-        let disk = DiskManager::open(&path).unwrap();
-        let buffer_pool = Rc::new(RefCell::new(BufferPool::new(disk, 100)));
-        let mut db = Database::with_buffer_pool(buffer_pool);
+            // `u.id` is the outer row's, even though orders has an id column too
+            assert_eq!(
+                names(
+                    db,
+                    "SELECT name FROM users u WHERE EXISTS \
+                     (SELECT 1 FROM orders o WHERE o.user_id = u.id)"
+                ),
+                ["ann", "cy"]
+            );
+            assert_eq!(
+                names(
+                    db,
+                    "SELECT name FROM users u WHERE NOT EXISTS \
+                     (SELECT 1 FROM orders o WHERE o.user_id = u.id)"
+                ),
+                ["bob", "dee"]
+            );
+            // Other conditions in the subquery still apply, on either side of the comparison
+            assert_eq!(
+                names(
+                    db,
+                    "SELECT name FROM users WHERE EXISTS \
+                     (SELECT * FROM orders WHERE users.id = orders.user_id AND total > 10)"
+                ),
+                ["ann", "cy"]
+            );
+            assert_eq!(
+                names(
+                    db,
+                    "SELECT name FROM users u WHERE u.name <> 'ann' AND EXISTS \
+                     (SELECT 1 FROM orders o WHERE o.user_id = u.id AND o.total > 10)"
+                ),
+                ["cy"]
+            );
 
-        f(&mut db);
+            // Without a reference to the outer query it's the same for every row
+            assert_eq!(
+                names(
+                    db,
+                    "SELECT name FROM users WHERE EXISTS (SELECT 1 FROM orders WHERE total > 40)"
+                )
+                .len(),
+                4
+            );
+            assert!(
+                names(
+                    db,
+                    "SELECT name FROM users WHERE EXISTS (SELECT 1 FROM orders WHERE total > 99)"
+                )
+                .is_empty()
+            );
 
-        let _ = fs::remove_file(&path);
+            parse_and_execute(
+                db,
+                "DELETE FROM users WHERE NOT EXISTS \
+                 (SELECT 1 FROM orders WHERE orders.user_id = users.id)",
+            )
+            .unwrap();
+            assert_eq!(names(db, "SELECT name FROM users"), ["ann", "cy"]);
+
+            let result = parse_and_execute(
+                db,
+                "SELECT name FROM users u WHERE EXISTS \
+                 (SELECT 1 FROM orders o WHERE o.total > u.id)",
+            );
+            assert!(
+                result
+                    .unwrap_err()
+                    .contains("EXISTS subqueries can refer to the outer query")
+            );
+        });
     }
 
-    fn parse_and_execute(db: &mut Database, sql: &str) -> Result<String, String> {
-        let dialect = GenericDialect {};
-        let stmts = Parser::parse_sql(&dialect, sql).map_err(|e| e.to_string())?;
-        execute(db, stmts.into_iter().next().unwrap())
+    #[test]
+    fn test_cast() {
+        with_memory_db(|db| {
+            parse_and_execute(db, "CREATE TABLE products (id INT, price TEXT)").unwrap();
+            parse_and_execute(
+                db,
+                "INSERT INTO products VALUES (CAST('1' AS INT), CAST(9.5 AS TEXT)), (2, '12.25')",
+            )
+            .unwrap();
+
+            let result = parse_and_execute(
+                db,
+                "SELECT CAST(id AS TEXT) || '#' AS label FROM products WHERE CAST(price AS DECIMAL(5, 2)) > 10",
+            )
+            .unwrap();
+            assert_eq!(result, "label\n----------\n2#\n(1 rows)");
+
+            // The stored text is unchanged
+            let result = parse_and_execute(db, "SELECT price FROM products WHERE id = 1").unwrap();
+            assert!(result.contains("\n9.5\n"), "{}", result);
+
+            let result =
+                parse_and_execute(db, "SELECT id FROM products WHERE CAST(price AS INT) > 10");
+            assert_eq!(result.unwrap_err(), "Cannot cast text '9.5' to INT");
+        });
     }
 
     #[test]
-    fn test_create_table() {
-        with_test_db("create", |db| {
-            let result = parse_and_execute(db, "CREATE TABLE users (id INT, name TEXT)");
+    fn test_case_expressions() {
+        with_memory_db(|db| {
+            parse_and_execute(db, "CREATE TABLE people (name TEXT, age INT)").unwrap();
+            parse_and_execute(
+                db,
+                "INSERT INTO people VALUES ('Ann', 70), ('Bob', 30), ('Cy', 12), ('Di', NULL)",
+            )
+            .unwrap();
 
-            assert!(result.is_ok());
-            assert_eq!(result.unwrap(), "Table 'users' created");
-            assert!(db.tables.contains_key("users"));
+            // The first WHEN that holds wins; a NULL condition doesn't hold
+            let result = parse_and_execute(
+                db,
+                "SELECT name, CASE WHEN age >= 65 THEN 'senior' WHEN age >= 18 THEN 'adult' \
+                 WHEN age < 18 THEN 'minor' END AS category FROM people",
+            )
+            .unwrap();
+            assert!(
+                result.contains("Ann\tsenior\nBob\tadult\nCy\tminor\nDi\tNULL\n"),
+                "{}",
+                result
+            );
+
+            // The simple form compares its operand with each WHEN
+            let result = parse_and_execute(
+                db,
+                "SELECT CASE name WHEN 'Ann' THEN 1 WHEN 'Bob' THEN 2 ELSE 0 END AS n FROM people",
+            )
+            .unwrap();
+            assert_eq!(result, "n\n----------\n1\n2\n0\n0\n(4 rows)");
+
+            let result = parse_and_execute(
+                db,
+                "SELECT name FROM people \
+                 WHERE CASE WHEN age IS NULL THEN 'unknown' ELSE 'known' END = 'known' \
+                 AND CASE age WHEN 12 THEN FALSE ELSE TRUE END",
+            )
+            .unwrap();
+            assert_eq!(result, "name\n----------\nAnn\nBob\n(2 rows)");
+
+            let result = parse_and_execute(
+                db,
+                "SELECT SUM(CASE WHEN age >= 18 THEN 1 ELSE 0 END) AS adults FROM people",
+            )
+            .unwrap();
+            assert!(result.contains("\n2\n"), "{}", result);
+
+            let result = parse_and_execute(
+                db,
+                "SELECT name FROM people WHERE CASE WHEN age > 18 THEN missing END IS NULL",
+            );
+            assert!(result.unwrap_err().contains("missing"));
         });
     }
 
     #[test]
-    fn test_create_table_already_exists() {
-        with_test_db("create_exists", |db| {
-            parse_and_execute(db, "CREATE TABLE users (id INT)").unwrap();
+    fn test_json_extract() {
+        with_memory_db(|db| {
+            parse_and_execute(db, "CREATE TABLE docs (id INT, data TEXT)").unwrap();
+            parse_and_execute(
+                db,
+                r#"INSERT INTO docs VALUES
+                    (1, '{"name": "Ann", "age": 31, "address": {"city": "Oslo"}, "tags": ["a", "b"]}'),
+                    (2, '{"name": "Bob", "address": {"city": null}}'),
+                    (3, NULL)"#,
+            )
+            .unwrap();
 
-            let result = parse_and_execute(db, "CREATE TABLE users (id INT)");
+            let result = parse_and_execute(
+                db,
+                "SELECT id, json_extract(data, '$.name'), json_extract(data, '$.address.city'), \
+                 json_extract(data, 'tags[1]') FROM docs",
+            )
+            .unwrap();
+            let rows: Vec<&str> = result.lines().skip(2).collect();
+            assert_eq!(
+                rows,
+                [
+                    "1\tAnn\tOslo\tb",
+                    "2\tBob\tNULL\tNULL",
+                    "3\tNULL\tNULL\tNULL",
+                    "(3 rows)"
+                ]
+            );
 
-            assert!(result.is_err());
-            assert_eq!(result.unwrap_err(), "Table 'users' already exists");
+            // Numbers come back as their text, objects and arrays as JSON
+            let result = parse_and_execute(
+                db,
+                "SELECT json_extract(data, '$.age'), json_extract(data, '$.tags') FROM docs \
+                 WHERE json_extract(data, '$.address.city') = 'Oslo'",
+            )
+            .unwrap();
+            assert!(
+                result.contains("\n31\t[\"a\",\"b\"]\n(1 rows)"),
+                "{}",
+                result
+            );
+
+            parse_and_execute(db, "INSERT INTO docs VALUES (4, '{name: 1}')").unwrap();
+            let err = parse_and_execute(db, "SELECT json_extract(data, '$.name') FROM docs");
+            assert_eq!(
+                err.unwrap_err(),
+                "JSON_EXTRACT: invalid JSON: expected a key at byte 1, found 'n'"
+            );
+            let err = parse_and_execute(db, "SELECT json_extract(data, 'name.') FROM docs");
+            assert!(err.unwrap_err().contains("Invalid JSON path 'name.'"));
+            let err = parse_and_execute(db, "SELECT json_extract(id, '$') FROM docs");
+            assert!(err.unwrap_err().contains("expects text"));
         });
     }
 
     #[test]
-    fn test_insert_single_row() {
-        with_test_db("insert_single", |db| {
+    fn test_filtered_limit_stops_early() {
+        with_memory_db(|db| {
             parse_and_execute(db, "CREATE TABLE users (id INT, name TEXT)").unwrap();
+            let values: Vec<String> = (0..500).map(|i| format!("({}, 'User{}')", i, i)).collect();
+            let sql = format!("INSERT INTO users VALUES {}", values.join(", "));
+            parse_and_execute(db, &sql).unwrap();
+            let table_pages = db.table_pages(0).unwrap().len();
+            assert!(table_pages > 3);
 
-            let result = parse_and_execute(db, "INSERT INTO users VALUES (1, 'Alice')");
+            let sql = "SELECT id FROM users WHERE id >= 250 AND name <> 'User251' LIMIT 3";
+            let result = parse_and_execute(db, sql).unwrap();
+            assert!(result.ends_with("\n250\n252\n253\n(3 rows)"), "{}", result);
 
-            assert!(result.is_ok());
-            assert_eq!(result.unwrap(), "Inserted 1 row(s)");
+            // The scan stops right after the third match, without reading the
+            // pages that follow
+            let stats = query_stats(db, sql);
+            assert_eq!(stats.rows_scanned, 254);
+            assert_eq!(stats.rows_returned, 3);
+            assert!(stats.pages_read < table_pages, "{:?}", stats);
+
+            // With too few matches to fill the LIMIT, every page is read
+            let stats = query_stats(db, "SELECT id FROM users WHERE id >= 498 LIMIT 3");
+            assert_eq!(stats.rows_returned, 2);
+            assert_eq!(stats.rows_scanned, 500);
+            assert_eq!(stats.pages_read, table_pages);
         });
     }
 
     #[test]
-    fn test_insert_multiple_rows() {
-        with_test_db("insert_multiple", |db| {
-            parse_and_execute(db, "CREATE TABLE users (id INT, name TEXT)").unwrap();
+    fn test_update_and_delete() {
+        with_memory_db(|db| {
+            setup_employees(db);
+            parse_and_execute(db, "CREATE INDEX idx ON employees (dept)").unwrap();
 
-            let result = parse_and_execute(db, "INSERT INTO users VALUES (1, 'Alice'), (2, 'Bob')");
+            let result = parse_and_execute(
+                db,
+                "UPDATE employees SET level = level, dept = 'ops' WHERE id = 1",
+            )
+            .unwrap();
+            assert_eq!(result, "Updated 1 row(s)");
+            let result =
+                parse_and_execute(db, "SELECT id FROM employees WHERE dept = 'ops'").unwrap();
+            assert!(result.ends_with("(2 rows)"));
 
-            assert!(result.is_ok());
-            assert_eq!(result.unwrap(), "Inserted 2 row(s)");
+            let result = parse_and_execute(db, "DELETE FROM employees WHERE dept = 'eng'").unwrap();
+            assert_eq!(result, "Deleted 2 row(s)");
+            let result = parse_and_execute(db, "SELECT * FROM employees").unwrap();
+            assert!(result.ends_with("(2 rows)"));
+            assert!(
+                parse_and_execute(db, "SELECT * FROM employees WHERE dept = 'eng'")
+                    .unwrap()
+                    .ends_with("(0 rows)")
+            );
+
+            assert!(parse_and_execute(db, "UPDATE employees SET missing = 1").is_err());
         });
     }
 
     #[test]
-    fn test_insert_into_nonexistent_table() {
-        with_test_db("insert_nonexistent", |db| {
-            let result = parse_and_execute(db, "INSERT INTO users VALUES (1, 'Alice')");
+    fn test_update_returning() {
+        with_memory_db(|db| {
+            setup_employees(db);
+
+            let result = parse_and_execute(
+                db,
+                "UPDATE employees SET level = level + 10 WHERE dept = 'eng' RETURNING id, level AS new_level",
+            )
+            .unwrap();
+            assert_eq!(
+                result,
+                "id\tnew_level\n--------------------\n1\t11\n2\t13\n4\t13\n(3 rows)"
+            );
+            let result = parse_and_execute(db, "SELECT level FROM employees WHERE id = 4").unwrap();
+            assert!(result.contains("\n13\n"));
 
+            let result = parse_and_execute(
+                db,
+                "UPDATE employees SET level = 0 WHERE id = 9 RETURNING *",
+            )
+            .unwrap();
+            assert_eq!(
+                result,
+                "id\tdept\tlevel\n------------------------------\n(0 rows)"
+            );
+
+            // A bad RETURNING column fails before anything is written
+            let result = parse_and_execute(db, "UPDATE employees SET level = 0 RETURNING missing");
             assert!(result.is_err());
-            assert_eq!(result.unwrap_err(), "Table 'users' does not exist");
+            let result = parse_and_execute(db, "SELECT id FROM employees WHERE level = 0").unwrap();
+            assert!(result.ends_with("(0 rows)"));
         });
     }
 
     #[test]
-    fn test_select_empty_table() {
-        with_test_db("select_empty", |db| {
+    fn test_update_respects_unique_columns() {
+        with_memory_db(|db| {
+            parse_and_execute(db, "CREATE TABLE users (id INT PRIMARY KEY, name TEXT)").unwrap();
+            parse_and_execute(db, "INSERT INTO users VALUES (1, 'a'), (2, 'b')").unwrap();
+
+            assert!(parse_and_execute(db, "UPDATE users SET id = 2 WHERE id = 1").is_err());
+            // Rewriting a row with its own value is not a conflict
+            parse_and_execute(db, "UPDATE users SET id = 1 WHERE id = 1").unwrap();
+            parse_and_execute(db, "UPDATE users SET id = 3 WHERE id = 1").unwrap();
+        });
+    }
+
+    /// Raw bytes of every page of a table, for byte-for-byte comparisons
+    fn table_bytes(db: &Database, table: &str) -> Vec<Vec<u8>> {
+        db.table_pages(db.tables[table].first_page_id)
+            .unwrap()
+            .into_iter()
+            .map(|page_id| {
+                let page_rc = db.buffer_pool.borrow_mut().fetch_page(page_id).unwrap();
+                page_rc.borrow().data.to_vec()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_corrupt_row_error_has_location() {
+        with_memory_db(|db| {
             parse_and_execute(db, "CREATE TABLE users (id INT, name TEXT)").unwrap();
+            let values: Vec<String> = (0..300).map(|i| format!("({}, 'User{}')", i, i)).collect();
+            let sql = format!("INSERT INTO users VALUES {}", values.join(", "));
+            parse_and_execute(db, &sql).unwrap();
 
-            let result = parse_and_execute(db, "SELECT * FROM users");
+            // Overwrite a row on the second page with a Text value that isn't UTF-8
+            let page_id = db.table_pages(db.tables["users"].first_page_id).unwrap()[1];
+            let mut bytes = Row {
+                values: vec![Value::Long(1)],
+            }
+            .to_bytes();
+            bytes.extend_from_slice(&[1, 2, 0, 0, 0, 0xff, 0xfe]);
+            {
+                let page_rc = db.fetch_page_for_write(page_id).unwrap();
+                let mut page = page_rc.borrow_mut();
+                SlottedPage::new(&mut page).update_tuple(1, &bytes).unwrap();
+            }
 
-            assert!(result.is_ok());
-            assert!(result.unwrap().contains("(0 rows)"));
+            let err = parse_and_execute(db, "SELECT * FROM users").unwrap_err();
+            let location = format!("table 'users' at page {} slot 1", page_id);
+            assert!(err.contains(&location), "{}", err);
+            assert!(
+                err.contains("column 2 at byte 14 is not valid UTF-8"),
+                "{}",
+                err
+            );
         });
     }
 
     #[test]
-    fn test_select_with_data() {
-        with_test_db("select_data", |db| {
-            parse_and_execute(db, "CREATE TABLE users (id INT, name TEXT)").unwrap();
-            parse_and_execute(db, "INSERT INTO users VALUES (1, 'Alice')").unwrap();
-            parse_and_execute(db, "INSERT INTO users VALUES (2, 'Bob')").unwrap();
+    fn test_rollback_restores_original_tuples() {
+        with_memory_db(|db| {
+            parse_and_execute(db, "CREATE TABLE users (id INT PRIMARY KEY, name TEXT)").unwrap();
+            let values: Vec<String> = (0..300).map(|i| format!("({}, 'User{}')", i, i)).collect();
+            let sql = format!("INSERT INTO users VALUES {}", values.join(", "));
+            parse_and_execute(db, &sql).unwrap();
+            parse_and_execute(db, "CREATE INDEX by_name ON users (name)").unwrap();
 
-            let result = parse_and_execute(db, "SELECT * FROM users").unwrap();
+            let before_output = parse_and_execute(db, "SELECT * FROM users").unwrap();
+            let before_bytes = table_bytes(db, "users");
+            let before_pages = db.buffer_pool.borrow().num_pages();
 
-            assert!(result.contains("Alice"));
-            assert!(result.contains("Bob"));
-            assert!(result.contains("(2 rows)"));
+            parse_and_execute(db, "BEGIN").unwrap();
+            // In place (shorter), relocated (longer than the page has room for), deleted, inserted
+            parse_and_execute(db, "UPDATE users SET name = 'x' WHERE id < 50").unwrap();
+            parse_and_execute(
+                db,
+                "UPDATE users SET name = name || '-with-a-much-longer-suffix' WHERE id >= 50",
+            )
+            .unwrap();
+            parse_and_execute(db, "DELETE FROM users WHERE id > 250").unwrap();
+            parse_and_execute(db, "INSERT INTO users VALUES (1000, 'New')").unwrap();
+            parse_and_execute(db, "CREATE TABLE scratch (id INT)").unwrap();
+            assert_ne!(
+                parse_and_execute(db, "SELECT * FROM users").unwrap(),
+                before_output
+            );
+            assert!(parse_and_execute(db, "VACUUM").is_err());
+
+            assert_eq!(parse_and_execute(db, "ROLLBACK").unwrap(), "ROLLBACK");
+
+            assert_eq!(
+                parse_and_execute(db, "SELECT * FROM users").unwrap(),
+                before_output
+            );
+            assert_eq!(table_bytes(db, "users"), before_bytes);
+            assert_eq!(db.buffer_pool.borrow().num_pages(), before_pages);
+            assert!(!db.tables.contains_key("scratch"));
+
+            // Indexes point at the restored rows
+            let result =
+                parse_and_execute(db, "SELECT id FROM users WHERE name = 'User7'").unwrap();
+            assert!(result.ends_with("\n7\n(1 rows)"));
         });
     }
 
     #[test]
-    fn test_insert_many_rows_multiple_pages() {
-        with_test_db("insert_many", |db| {
-            parse_and_execute(db, "CREATE TABLE users (id INT, name TEXT)").unwrap();
+    fn test_deferred_unique() {
+        with_memory_db(|db| {
+            parse_and_execute(
+                db,
+                "CREATE TABLE steps (id INT PRIMARY KEY, pos INT UNIQUE DEFERRABLE INITIALLY DEFERRED)",
+            )
+            .unwrap();
+            parse_and_execute(db, "INSERT INTO steps VALUES (1, 1), (2, 2)").unwrap();
+            // Outside a transaction the check is immediate
+            let err = parse_and_execute(db, "INSERT INTO steps VALUES (3, 1)").unwrap_err();
+            assert!(
+                err.contains("Duplicate value 1 for unique column 'pos'"),
+                "{}",
+                err
+            );
 
-            // Insert 500 rows - should span multiple pages
-            for i in 0..500 {
-                let sql = format!("INSERT INTO users VALUES ({}, 'User{}')", i, i);
-                parse_and_execute(db, &sql).unwrap();
+            // Swap the two positions one row at a time
+            parse_and_execute(db, "BEGIN").unwrap();
+            parse_and_execute(db, "UPDATE steps SET pos = 2 WHERE id = 1").unwrap();
+            let output = parse_and_execute(db, "SELECT id FROM steps WHERE pos = 2").unwrap();
+            assert!(output.ends_with("(2 rows)"), "{}", output);
+            parse_and_execute(db, "UPDATE steps SET pos = 1 WHERE id = 2").unwrap();
+            assert_eq!(parse_and_execute(db, "COMMIT").unwrap(), "COMMIT");
+            let output = parse_and_execute(db, "SELECT id, pos FROM steps ORDER BY id").unwrap();
+            assert!(output.contains("1\t2\n2\t1"), "{}", output);
+
+            // A duplicate still there at COMMIT rolls the transaction back
+            parse_and_execute(db, "BEGIN").unwrap();
+            parse_and_execute(db, "INSERT INTO steps VALUES (3, 1)").unwrap();
+            let err = parse_and_execute(db, "COMMIT").unwrap_err();
+            assert!(
+                err.contains("Duplicate value 1 for unique column 'pos'"),
+                "{}",
+                err
+            );
+            assert!(err.contains("rolled back"), "{}", err);
+            assert!(!db.in_transaction());
+            let output = parse_and_execute(db, "SELECT id FROM steps WHERE id = 3").unwrap();
+            assert!(output.ends_with("(0 rows)"), "{}", output);
+
+            // The check follows the table to its new name
+            parse_and_execute(db, "BEGIN").unwrap();
+            parse_and_execute(db, "INSERT INTO steps VALUES (3, 1)").unwrap();
+            parse_and_execute(db, "ALTER TABLE steps RENAME TO moves").unwrap();
+            let err = parse_and_execute(db, "COMMIT").unwrap_err();
+            assert!(
+                err.contains("Duplicate value 1 for unique column 'pos'"),
+                "{}",
+                err
+            );
+            let output = parse_and_execute(db, "SELECT id FROM steps ORDER BY id").unwrap();
+            assert!(output.ends_with("\n1\n2\n(2 rows)"), "{}", output);
+            assert!(parse_and_execute(db, "SELECT id FROM moves").is_err());
+        });
+    }
+
+    #[test]
+    fn test_foreign_keys() {
+        with_memory_db(|db| {
+            parse_and_execute(db, "CREATE TABLE users (id INT PRIMARY KEY, name TEXT)").unwrap();
+            parse_and_execute(
+                db,
+                "INSERT INTO users VALUES (1, 'Ann'), (2, 'Bob'), (3, 'Cy')",
+            )
+            .unwrap();
+            // Looked up in the index rather than by scanning users
+            parse_and_execute(db, "CREATE UNIQUE INDEX users_id ON users (id)").unwrap();
+            parse_and_execute(
+                db,
+                "CREATE TABLE orders (id INT PRIMARY KEY, user_id INT REFERENCES users(id))",
+            )
+            .unwrap();
+
+            let err = parse_and_execute(db, "INSERT INTO orders VALUES (10, 9)").unwrap_err();
+            assert!(
+                err.contains("Value 9 for 'orders.user_id' is not present in 'users.id'"),
+                "{}",
+                err
+            );
+            parse_and_execute(db, "INSERT INTO orders VALUES (10, 1), (11, 2), (12, NULL)")
+                .unwrap();
+            let err =
+                parse_and_execute(db, "UPDATE orders SET user_id = 7 WHERE id = 10").unwrap_err();
+            assert!(err.contains("Value 7"), "{}", err);
+
+            // NO ACTION, the default, keeps referenced rows and values
+            let err = parse_and_execute(db, "DELETE FROM users WHERE id = 1").unwrap_err();
+            assert!(
+                err.contains("Cannot delete 'users.id' = 1: 'orders.user_id' still refers to it"),
+                "{}",
+                err
+            );
+            let err = parse_and_execute(db, "UPDATE users SET id = 5 WHERE id = 2").unwrap_err();
+            assert!(err.contains("Cannot change 'users.id' = 2"), "{}", err);
+            assert_eq!(
+                parse_and_execute(db, "DELETE FROM users WHERE id = 3").unwrap(),
+                "Deleted 1 row(s)"
+            );
+
+            // CASCADE deletes the referring rows, and SET NULL clears them
+            parse_and_execute(
+                db,
+                "CREATE TABLE items (order_id INT, FOREIGN KEY (order_id) REFERENCES orders \
+                 ON DELETE CASCADE)",
+            )
+            .unwrap();
+            parse_and_execute(
+                db,
+                "CREATE TABLE notes (user_id INT REFERENCES users ON DELETE SET NULL, body TEXT)",
+            )
+            .unwrap();
+            parse_and_execute(db, "INSERT INTO items VALUES (10), (10), (11)").unwrap();
+            parse_and_execute(db, "INSERT INTO notes VALUES (2, 'vip'), (1, 'new')").unwrap();
+            parse_and_execute(db, "DELETE FROM orders WHERE id = 10").unwrap();
+            let output = parse_and_execute(db, "SELECT order_id FROM items").unwrap();
+            assert!(output.ends_with("11\n(1 rows)"), "{}", output);
+            parse_and_execute(db, "DELETE FROM orders WHERE user_id = 2").unwrap();
+            parse_and_execute(db, "DELETE FROM users WHERE id = 2").unwrap();
+            let output = parse_and_execute(db, "SELECT user_id, body FROM notes").unwrap();
+            assert!(output.contains("NULL\tvip\n1\tnew"), "{}", output);
+            let output = parse_and_execute(db, "DESCRIBE notes").unwrap();
+            assert!(
+                output.contains("REFERENCES users (id) ON DELETE SET NULL"),
+                "{}",
+                output
+            );
+
+            for (sql, error) in [
+                (
+                    "CREATE TABLE t (a INT REFERENCES nowhere (id))",
+                    "does not exist",
+                ),
+                (
+                    "CREATE TABLE t (a INT REFERENCES users (name))",
+                    "is neither",
+                ),
+                (
+                    "CREATE TABLE t (a INT NOT NULL REFERENCES users ON DELETE SET NULL)",
+                    "NULL",
+                ),
+                (
+                    "CREATE TABLE t (a INT REFERENCES users ON UPDATE CASCADE)",
+                    "not supported",
+                ),
+            ] {
+                let err = parse_and_execute(db, sql).unwrap_err();
+                assert!(err.contains(error), "{}: {}", sql, err);
             }
+        });
+    }
 
-            let result = parse_and_execute(db, "SELECT * FROM users").unwrap();
+    #[test]
+    fn test_self_referencing_foreign_key() {
+        with_memory_db(|db| {
+            parse_and_execute(
+                db,
+                "CREATE TABLE staff (id INT PRIMARY KEY, boss INT REFERENCES staff ON DELETE CASCADE)",
+            )
+            .unwrap();
+            // A row may refer to one inserted alongside it
+            parse_and_execute(db, "INSERT INTO staff VALUES (2, 1), (1, NULL), (3, 2)").unwrap();
+            parse_and_execute(db, "INSERT INTO staff VALUES (4, 1)").unwrap();
+            assert!(parse_and_execute(db, "INSERT INTO staff VALUES (5, 6)").is_err());
 
-            assert!(result.contains("(500 rows)"));
-            assert!(result.contains("User0"));
-            assert!(result.contains("User499"));
+            // The cascade follows the chain down from 2
+            parse_and_execute(db, "DELETE FROM staff WHERE id = 2").unwrap();
+            let output = parse_and_execute(db, "SELECT id FROM staff ORDER BY id").unwrap();
+            assert!(output.ends_with("1\n4\n(2 rows)"), "{}", output);
         });
     }
 
     #[test]
-    fn test_insert_5000_rows() {
-        with_test_db("insert_5000", |db| {
-            parse_and_execute(db, "CREATE TABLE users (id INT, name TEXT)").unwrap();
+    fn test_add_and_rename_foreign_key() {
+        let path = "test_executor_foreign_keys.db";
+        let catalog = format!("{}.catalog", path);
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
 
-            // Build a batch insert
-            let mut values = Vec::new();
-            for i in 0..100 {
-                values.push(format!("({}, 'User{}')", i, i));
+        {
+            let mut db = Database::new(path).unwrap();
+            parse_and_execute(&mut db, "CREATE TABLE users (id INT PRIMARY KEY)").unwrap();
+            parse_and_execute(&mut db, "CREATE TABLE orders (user_id INT)").unwrap();
+            parse_and_execute(&mut db, "INSERT INTO users VALUES (1)").unwrap();
+            parse_and_execute(&mut db, "INSERT INTO orders VALUES (2)").unwrap();
+
+            // Existing rows have to satisfy a foreign key added later
+            let add = "ALTER TABLE orders ADD FOREIGN KEY (user_id) REFERENCES users (id)";
+            assert!(parse_and_execute(&mut db, add).is_err());
+            parse_and_execute(&mut db, "UPDATE orders SET user_id = 1").unwrap();
+            assert_eq!(
+                parse_and_execute(&mut db, add).unwrap(),
+                "Foreign key 'orders.user_id' references 'users.id'"
+            );
+            parse_and_execute(&mut db, "ALTER TABLE users RENAME TO customers").unwrap();
+            parse_and_execute(&mut db, "ALTER TABLE customers RENAME COLUMN id TO cid").unwrap();
+            parse_and_execute(&mut db, "ALTER TABLE orders RENAME COLUMN user_id TO cust").unwrap();
+        }
+
+        {
+            let mut db = Database::new(path).unwrap();
+            assert_eq!(
+                db.tables["orders"].foreign_keys,
+                vec![ForeignKey {
+                    column: "cust".to_string(),
+                    foreign_table: "customers".to_string(),
+                    referred_column: "cid".to_string(),
+                    on_delete: ReferentialAction::NoAction,
+                }]
+            );
+            assert!(parse_and_execute(&mut db, "INSERT INTO orders VALUES (3)").is_err());
+            assert!(parse_and_execute(&mut db, "DELETE FROM customers").is_err());
+        }
+
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+    }
+
+    #[test]
+    fn test_commit_keeps_changes() {
+        with_memory_db(|db| {
+            setup_employees(db);
+
+            assert!(parse_and_execute(db, "COMMIT").is_err());
+            assert!(parse_and_execute(db, "ROLLBACK").is_err());
+
+            parse_and_execute(db, "BEGIN").unwrap();
+            assert!(parse_and_execute(db, "BEGIN").is_err());
+            parse_and_execute(db, "DELETE FROM employees WHERE level = 3").unwrap();
+            assert_eq!(parse_and_execute(db, "COMMIT").unwrap(), "COMMIT");
+
+            let result = parse_and_execute(db, "SELECT * FROM employees").unwrap();
+            assert!(result.ends_with("(1 rows)"));
+        });
+    }
+
+    #[test]
+    fn test_order_by() {
+        with_memory_db(|db| {
+            setup_employees(db);
+
+            let result =
+                parse_and_execute(db, "SELECT id, level FROM employees ORDER BY 2 DESC, id")
+                    .unwrap();
+            let rows: Vec<&str> = result.lines().skip(2).collect();
+            assert_eq!(rows, vec!["2\t3", "3\t3", "4\t3", "1\t1", "(4 rows)"]);
+
+            // By name, including a column that isn't selected, and an alias
+            let result =
+                parse_and_execute(db, "SELECT id FROM employees ORDER BY dept DESC, Level")
+                    .unwrap();
+            assert!(result.contains("\n3\n1\n2\n4\n"));
+            let result = parse_and_execute(
+                db,
+                "SELECT id AS n FROM employees ORDER BY n DESC LIMIT 2 OFFSET 1",
+            )
+            .unwrap();
+            assert!(result.ends_with("\n3\n2\n(2 rows)"));
+
+            assert!(parse_and_execute(db, "SELECT id, level FROM employees ORDER BY 3").is_err());
+            assert!(parse_and_execute(db, "SELECT id FROM employees ORDER BY 0").is_err());
+        });
+    }
+
+    #[test]
+    fn test_where_group_by_having() {
+        with_memory_db(|db| {
+            parse_and_execute(db, "CREATE TABLE emp (dept TEXT, active BOOL, salary INT)").unwrap();
+            parse_and_execute(
+                db,
+                "INSERT INTO emp VALUES ('eng', true, 10), ('eng', true, 20), ('eng', false, 30), \
+                 ('ops', true, 5), ('ops', false, 7), ('ops', false, 9), ('hr', true, 50)",
+            )
+            .unwrap();
+
+            // WHERE drops inactive rows before grouping, so ops keeps only one row
+            // and fails HAVING, though it has three rows in all
+            let result = parse_and_execute(
+                db,
+                "SELECT dept, COUNT(*), SUM(salary) FROM emp WHERE active = true \
+                 GROUP BY dept HAVING COUNT(*) > 1 OR SUM(salary) > 40 ORDER BY dept",
+            )
+            .unwrap();
+            let rows: Vec<&str> = result.lines().skip(2).collect();
+            assert_eq!(rows, vec!["eng\t2\t30", "hr\t1\t50", "(2 rows)"]);
+
+            // HAVING can use an aggregate that isn't selected, and grouped columns
+            let result = parse_and_execute(
+                db,
+                "SELECT dept FROM emp GROUP BY dept HAVING MAX(salary) < 40 AND dept <> 'eng'",
+            )
+            .unwrap();
+            assert!(result.ends_with("ops\n(1 rows)"));
+
+            let result = parse_and_execute(db, "SELECT dept FROM emp WHERE SUM(salary) > 1");
+            assert!(matches!(result, Err(e) if e.contains("not allowed in WHERE")));
+            let result =
+                parse_and_execute(db, "SELECT dept FROM emp GROUP BY dept HAVING salary > 1");
+            assert!(matches!(result, Err(e) if e.contains("must appear in GROUP BY")));
+        });
+    }
+
+    #[test]
+    fn test_group_by_multiple_columns() {
+        with_memory_db(|db| {
+            setup_employees(db);
+            parse_and_execute(db, "INSERT INTO employees VALUES (5, 'ops', 3)").unwrap();
+            parse_and_execute(db, "INSERT INTO employees VALUES (6, 'eng', 1)").unwrap();
+
+            let result = parse_and_execute(
+                db,
+                "SELECT dept, level, COUNT(*), SUM(id), MAX(id) FROM employees \
+                 GROUP BY dept, level",
+            )
+            .unwrap();
+            let rows: Vec<&str> = result.lines().skip(2).collect();
+            assert_eq!(
+                rows,
+                vec![
+                    "eng\t1\t2\t7\t6",
+                    "eng\t3\t2\t6\t4",
+                    "ops\t3\t2\t8\t5",
+                    "(3 rows)"
+                ]
+            );
+
+            // WHERE filters rows before they're grouped; ORDER BY can use an alias
+            let result = parse_and_execute(
+                db,
+                "SELECT level, dept, COUNT(*) AS n FROM employees WHERE id > 1 \
+                 GROUP BY dept, level ORDER BY n, level DESC",
+            )
+            .unwrap();
+            let rows: Vec<&str> = result.lines().skip(2).collect();
+            assert_eq!(
+                rows,
+                vec!["1\teng\t1", "3\teng\t2", "3\tops\t2", "(3 rows)"]
+            );
+
+            // Aggregates without GROUP BY make a single group
+            let result =
+                parse_and_execute(db, "SELECT COUNT(*), MIN(dept) FROM employees").unwrap();
+            assert!(result.contains("\n6\teng\n"), "{}", result);
+
+            for sql in [
+                "SELECT dept, level FROM employees GROUP BY dept",
+                "SELECT dept, COUNT(*) FROM employees GROUP BY dept ORDER BY level",
+                "SELECT id FROM employees WHERE COUNT(*) > 1",
+            ] {
+                assert!(parse_and_execute(db, sql).is_err(), "{}", sql);
             }
+        });
+    }
 
-            // Insert in batches of 100
-            for batch in 0..50 {
-                let mut batch_values = Vec::new();
-                for i in 0..100 {
-                    let id = batch * 100 + i;
-                    batch_values.push(format!("({}, 'User{}')", id, id));
-                }
-                let sql = format!("INSERT INTO users VALUES {}", batch_values.join(", "));
-                parse_and_execute(db, &sql).unwrap();
+    #[test]
+    fn test_aggregates_over_expressions() {
+        with_memory_db(|db| {
+            parse_and_execute(
+                db,
+                "CREATE TABLE emp (name TEXT, dept TEXT, salary INT, bonus DECIMAL(8, 2))",
+            )
+            .unwrap();
+            parse_and_execute(
+                db,
+                "INSERT INTO emp VALUES ('a', 'eng', 100, 1.50), ('b', 'eng', 200, NULL), \
+                 ('c', 'ops', 50, 2.25)",
+            )
+            .unwrap();
+
+            let result = parse_and_execute(
+                db,
+                "SELECT dept, SUM(salary * 12), SUM(salary * 12 + bonus), MAX(salary - 1) \
+                 FROM emp GROUP BY dept",
+            )
+            .unwrap();
+            let rows: Vec<&str> = result.lines().skip(2).collect();
+            assert_eq!(
+                rows,
+                vec![
+                    "eng\t3600\t1201.50\t199",
+                    "ops\t600\t602.25\t49",
+                    "(2 rows)"
+                ]
+            );
+
+            let result =
+                parse_and_execute(db, "SELECT COUNT(bonus * 2), SUM(bonus * 2) FROM emp").unwrap();
+            assert!(result.contains("\n2\t7.50\n"), "{}", result);
+
+            assert_eq!(
+                parse_and_execute(db, "SELECT dept, SUM(name || dept) FROM emp GROUP BY dept")
+                    .unwrap_err(),
+                "SUM expects numbers, but name || dept is aeng"
+            );
+        });
+    }
+
+    #[test]
+    fn test_order_by_collation() {
+        with_memory_db(|db| {
+            parse_and_execute(
+                db,
+                "CREATE TABLE fruit (name TEXT, tag TEXT COLLATE NOCASE)",
+            )
+            .unwrap();
+            parse_and_execute(db, "CREATE INDEX by_tag ON fruit (tag)").unwrap();
+            for name in ["banana", "Apple", "cherry", "apple", "Banana"] {
+                parse_and_execute(
+                    db,
+                    &format!("INSERT INTO fruit VALUES ('{}', '{}')", name, name),
+                )
+                .unwrap();
             }
+            let names = |db: &mut Database, sql: &str| -> Vec<String> {
+                let result = parse_and_execute(db, sql).unwrap();
+                let mut lines: Vec<String> = result.lines().skip(2).map(String::from).collect();
+                lines.pop();
+                lines
+            };
 
-            let result = parse_and_execute(db, "SELECT * FROM users").unwrap();
+            // Binary order puts every capitalized name first
+            assert_eq!(
+                names(db, "SELECT name FROM fruit ORDER BY name"),
+                ["Apple", "Banana", "apple", "banana", "cherry"]
+            );
+            // NOCASE keeps equal names together, in storage order
+            assert_eq!(
+                names(db, "SELECT name FROM fruit ORDER BY name COLLATE NOCASE"),
+                ["Apple", "apple", "banana", "Banana", "cherry"]
+            );
+            // A column declared NOCASE sorts and compares that way by default,
+            // and its index isn't used for lookups that ignore case
+            assert_eq!(
+                names(db, "SELECT tag FROM fruit ORDER BY tag DESC"),
+                ["cherry", "banana", "Banana", "Apple", "apple"]
+            );
+            assert_eq!(
+                names(db, "SELECT tag FROM fruit WHERE tag = 'APPLE'"),
+                ["Apple", "apple"]
+            );
+            assert_eq!(
+                names(
+                    db,
+                    "SELECT name FROM fruit WHERE name = 'APPLE' COLLATE NOCASE"
+                ),
+                ["Apple", "apple"]
+            );
 
-            assert!(result.contains("(5000 rows)"));
+            assert!(
+                parse_and_execute(db, "SELECT name FROM fruit ORDER BY name COLLATE klingon")
+                    .is_err()
+            );
+            assert!(parse_and_execute(db, "CREATE TABLE bad (name TEXT COLLATE klingon)").is_err());
+        });
+    }
+
+    #[test]
+    fn test_unique_with_collation() {
+        with_memory_db(|db| {
+            parse_and_execute(
+                db,
+                "CREATE TABLE tags (name TEXT UNIQUE COLLATE NOCASE, code TEXT COLLATE NOCASE)",
+            )
+            .unwrap();
+            parse_and_execute(db, "INSERT INTO tags VALUES ('A', 'x')").unwrap();
+
+            // 'a' equals 'A' under NOCASE, so the column can't hold both
+            let result = parse_and_execute(db, "INSERT INTO tags VALUES ('a', 'y')");
+            assert_eq!(
+                result.unwrap_err(),
+                "Duplicate value a for unique column 'name'"
+            );
+            let result = parse_and_execute(db, "INSERT INTO tags VALUES ('b', 'y'), ('B', 'z')");
+            assert!(result.is_err());
+            parse_and_execute(
+                db,
+                "INSERT INTO tags VALUES ('a', 'y') ON CONFLICT DO NOTHING",
+            )
+            .unwrap();
+            parse_and_execute(db, "UPDATE tags SET name = 'a' WHERE name = 'A'").unwrap();
+
+            // A unique index compares by its columns' collations too
+            parse_and_execute(db, "INSERT INTO tags VALUES ('b', 'X')").unwrap();
+            let result = parse_and_execute(db, "CREATE UNIQUE INDEX by_code ON tags (code)");
+            assert_eq!(
+                result.unwrap_err(),
+                "Cannot create unique index 'by_code': duplicate value x"
+            );
+            parse_and_execute(db, "UPDATE tags SET code = 'y' WHERE name = 'b'").unwrap();
+            parse_and_execute(db, "CREATE UNIQUE INDEX by_code ON tags (code)").unwrap();
+            let result = parse_and_execute(db, "INSERT INTO tags VALUES ('c', 'Y')");
+            assert_eq!(
+                result.unwrap_err(),
+                "Duplicate value Y for unique index 'by_code'"
+            );
+            parse_and_execute(db, "UPDATE tags SET code = 'Y' WHERE name = 'b'").unwrap();
+
+            let result =
+                parse_and_execute(db, "SELECT name, code FROM tags ORDER BY name").unwrap();
+            assert!(result.ends_with("\na\tx\nb\tY\n(2 rows)"), "{}", result);
+        });
+    }
+
+    #[test]
+    fn test_not_null_checked_after_defaults() {
+        with_memory_db(|db| {
+            parse_and_execute(
+                db,
+                "CREATE TABLE users (id INT NOT NULL, role TEXT NOT NULL DEFAULT 'member', note TEXT)",
+            )
+            .unwrap();
+
+            // The default satisfies NOT NULL; a plain omitted column is NULL
+            let result =
+                parse_and_execute(db, "INSERT INTO users (id) VALUES (1) RETURNING *").unwrap();
+            assert!(result.contains("1\tmember\tNULL"));
+
+            for sql in [
+                "INSERT INTO users (role) VALUES ('admin')",
+                "INSERT INTO users VALUES (2, NULL, 'x')",
+                "UPDATE users SET id = NULL",
+            ] {
+                let err = parse_and_execute(db, sql).unwrap_err();
+                assert!(err.contains("cannot be NULL"), "{}: {}", sql, err);
+            }
+
+            let result = parse_and_execute(db, "SELECT id FROM users WHERE note IS NULL").unwrap();
+            assert!(result.ends_with("\n1\n(1 rows)"));
+            let result = parse_and_execute(db, "SELECT id FROM users WHERE note = NULL").unwrap();
+            assert!(result.ends_with("(0 rows)"));
+        });
+    }
+
+    #[test]
+    fn test_check_constraints() {
+        let path = "test_executor_check_constraints.db";
+        let catalog = format!("{}.catalog", path);
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+
+        {
+            let mut db = Database::new(path).unwrap();
+            parse_and_execute(
+                &mut db,
+                "CREATE TABLE people (age INT CHECK (age >= 18), lo INT, hi INT, \
+                 CONSTRAINT ordered CHECK (lo <= hi))",
+            )
+            .unwrap();
+            parse_and_execute(&mut db, "INSERT INTO people VALUES (30, 1, 2)").unwrap();
+
+            let err =
+                parse_and_execute(&mut db, "INSERT INTO people VALUES (5, 1, 2)").unwrap_err();
+            assert!(
+                err.contains("check constraint violated on 'age'"),
+                "{}",
+                err
+            );
+            let err = parse_and_execute(&mut db, "UPDATE people SET age = 3").unwrap_err();
+            assert!(
+                err.contains("check constraint violated on 'age'"),
+                "{}",
+                err
+            );
+
+            // NULL makes the check unknown, which passes
+            parse_and_execute(&mut db, "INSERT INTO people (lo, hi) VALUES (5, 5)").unwrap();
+
+            assert!(parse_and_execute(&mut db, "CREATE TABLE bad (a INT CHECK (b > 0))").is_err());
+        }
+
+        {
+            // Both kinds of check are kept in the catalog
+            let mut db = Database::new(path).unwrap();
+            let err = parse_and_execute(&mut db, "UPDATE people SET lo = 3").unwrap_err();
+            assert!(
+                err.contains("check constraint violated on 'ordered'"),
+                "{}",
+                err
+            );
+            assert!(parse_and_execute(&mut db, "INSERT INTO people VALUES (10, 0, 0)").is_err());
+            assert!(
+                parse_and_execute(&mut db, "SELECT * FROM people")
+                    .unwrap()
+                    .ends_with("(2 rows)")
+            );
+        }
+
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+    }
+
+    #[test]
+    fn test_numeric_literals() {
+        assert_eq!(parse_number("42").unwrap(), 42);
+        assert_eq!(parse_number("1_000").unwrap(), 1000);
+        assert_eq!(parse_number("1e3").unwrap(), 1000);
+        assert_eq!(parse_number("2.50E1").unwrap(), 25);
+        assert_eq!(parse_number("4.000").unwrap(), 4);
+        assert_eq!(parse_number("9223372036854775807").unwrap(), i64::MAX);
+
+        let err = |literal: &str| parse_number(literal).unwrap_err().to_string();
+        assert!(err("3.14").contains("Fractional numbers are not supported"));
+        assert!(err("15e-1").contains("Fractional numbers are not supported"));
+        assert!(err("1e19").contains("out of range"));
+        assert!(err("1e999999999999").contains("out of range"));
+        assert!(err("1e").contains("Invalid number"));
+
+        // Through SQL too
+        with_memory_db(|db| {
+            parse_and_execute(db, "CREATE TABLE t (n INT)").unwrap();
+            parse_and_execute(db, "INSERT INTO t VALUES (1e3), (12.0)").unwrap();
+            let result = parse_and_execute(db, "SELECT n FROM t WHERE n >= 1.2e1").unwrap();
+            assert!(result.contains("\n1000\n12\n"), "{}", result);
+            assert!(parse_and_execute(db, "INSERT INTO t VALUES (3.14)").is_err());
+
+            parse_and_execute(db, "INSERT INTO t VALUES (1_000_000), (2_5e1)").unwrap();
+            let result = parse_and_execute(db, "SELECT n FROM t WHERE n > 1_000").unwrap();
+            assert!(result.contains("\n1000000\n(1 rows)"), "{}", result);
+            let result = parse_and_execute(db, "SELECT n FROM t WHERE n = 250").unwrap();
+            assert!(result.contains("\n250\n(1 rows)"), "{}", result);
+        });
+    }
+
+    #[test]
+    fn test_signed_numbers() {
+        with_memory_db(|db| {
+            parse_and_execute(db, "CREATE TABLE t (n INT, d DECIMAL(6, 2))").unwrap();
+            parse_and_execute(db, "INSERT INTO t VALUES (-1, -2.5), (+3, 1)").unwrap();
+            let result = parse_and_execute(db, "SELECT n, -n, -d FROM t WHERE n > -1").unwrap();
+            assert!(result.ends_with("\n3\t-3\t-1.00\n(1 rows)"), "{}", result);
+
+            parse_and_execute(db, "UPDATE t SET n = -5 WHERE n = -1").unwrap();
+            let result = parse_and_execute(db, "SELECT n, d FROM t ORDER BY n").unwrap();
+            assert!(result.contains("\n-5\t-2.50\n3\t1.00\n"), "{}", result);
+
+            let result = parse_and_execute(db, "SELECT -9223372036854775808 FROM t").unwrap();
+            assert!(result.contains("\n-9223372036854775808\n"), "{}", result);
+            let err = parse_and_execute(db, "SELECT -(n * 0 - 9223372036854775807 - 1) FROM t")
+                .unwrap_err();
+            assert!(err.contains("Numeric overflow"), "{}", err);
+            let err = parse_and_execute(db, "SELECT -'a' FROM t").unwrap_err();
+            assert!(err.contains("Cannot compute -"), "{}", err);
+        });
+    }
+
+    #[test]
+    fn test_rename_table_and_column() {
+        let path = "test_executor_rename.db";
+        let catalog = format!("{}.catalog", path);
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+
+        {
+            let mut db = Database::new(path).unwrap();
+            parse_and_execute(
+                &mut db,
+                "CREATE TABLE users (id INT, age INT CHECK (age >= 18), CHECK (age < 150))",
+            )
+            .unwrap();
+            parse_and_execute(&mut db, "CREATE TABLE orders (id INT)").unwrap();
+            parse_and_execute(&mut db, "CREATE INDEX by_age ON users (age)").unwrap();
+            parse_and_execute(&mut db, "INSERT INTO users VALUES (1, 30)").unwrap();
+
+            let err = parse_and_execute(&mut db, "ALTER TABLE users RENAME TO orders").unwrap_err();
+            assert!(err.contains("already exists"), "{}", err);
+            assert!(parse_and_execute(&mut db, "ALTER TABLE nobody RENAME TO x").is_err());
+
+            parse_and_execute(&mut db, "ALTER TABLE users RENAME TO customers").unwrap();
+            assert!(parse_and_execute(&mut db, "SELECT * FROM users").is_err());
+            assert_eq!(db.indexes["by_age"].table, "customers");
+            parse_and_execute(&mut db, "RENAME TABLE orders TO purchases").unwrap();
+            assert!(db.tables.contains_key("purchases"));
+
+            let err = parse_and_execute(&mut db, "ALTER TABLE customers RENAME COLUMN age TO id")
+                .unwrap_err();
+            assert!(err.contains("already exists"), "{}", err);
+            parse_and_execute(&mut db, "ALTER TABLE customers RENAME COLUMN AGE TO years").unwrap();
+        }
+
+        {
+            let mut db = Database::new(path).unwrap();
+            let result = parse_and_execute(&mut db, "SELECT * FROM customers").unwrap();
+            assert!(result.starts_with("id\tyears\n"), "{}", result);
+            assert!(result.contains("1\t30"));
+
+            // Checks and indexes follow the column
+            assert_eq!(db.indexes["by_age"].columns, vec!["years".to_string()]);
+            let result =
+                parse_and_execute(&mut db, "EXPLAIN SELECT id FROM customers WHERE years = 30")
+                    .unwrap();
+            assert!(result.contains("by_age"), "{}", result);
+            for sql in [
+                "INSERT INTO customers VALUES (2, 10)",
+                "INSERT INTO customers VALUES (2, 200)",
+            ] {
+                let err = parse_and_execute(&mut db, sql).unwrap_err();
+                assert!(err.contains("check constraint violated"), "{}", err);
+            }
+        }
+
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+    }
+
+    #[test]
+    fn test_analyze_collects_stats() {
+        use crate::stats::ColumnStats;
+
+        with_memory_db(|db| {
+            setup_employees(db);
+            parse_and_execute(db, "CREATE INDEX a_dept ON employees (dept)").unwrap();
+            parse_and_execute(db, "CREATE INDEX b_id ON employees (id)").unwrap();
+            let query = "EXPLAIN SELECT * FROM employees WHERE dept = 'eng' AND id = 1";
+
+            // Without stats the tie between the two indexes goes by name
+            let plan = parse_and_execute(db, query).unwrap();
+            assert!(plan.starts_with("Index Lookup on employees using a_dept"));
+
+            let result = parse_and_execute(db, "ANALYZE employees").unwrap();
+            assert_eq!(result, "Analyzed 'employees': 4 row(s)");
+
+            let stats = db.tables["employees"].stats.clone().unwrap();
+            assert_eq!(stats.row_count, 4);
+            assert_eq!(
+                stats.columns,
+                vec![
+                    ColumnStats {
+                        distinct: 4,
+                        min: Some(1),
+                        max: Some(4),
+                    },
+                    ColumnStats {
+                        distinct: 2,
+                        min: None,
+                        max: None,
+                    },
+                    ColumnStats {
+                        distinct: 2,
+                        min: Some(1),
+                        max: Some(3),
+                    },
+                ]
+            );
+
+            // id has more distinct values, so its index is more selective
+            let plan = parse_and_execute(db, query).unwrap();
+            assert!(plan.starts_with("Index Lookup on employees using b_id"));
+            assert!(plan.contains("Estimated rows: 1"));
+
+            let plan =
+                parse_and_execute(db, "EXPLAIN SELECT * FROM employees WHERE id > 2").unwrap();
+            assert!(plan.contains("Estimated rows: 2"));
+
+            assert!(parse_and_execute(db, "ANALYZE missing").is_err());
+        });
+    }
+
+    #[test]
+    fn test_join_with_compound_condition() {
+        with_memory_db(|db| {
+            setup_employees(db);
+            parse_and_execute(db, "CREATE TABLE depts (name TEXT, floor INT)").unwrap();
+            parse_and_execute(db, "INSERT INTO depts VALUES ('eng', 2), ('ops', 0)").unwrap();
+
+            let result = parse_and_execute(
+                db,
+                "SELECT e.id, d.name, floor FROM employees e \
+                 JOIN depts d ON e.dept = d.name AND e.level > 1 ORDER BY id",
+            )
+            .unwrap();
+            assert_eq!(
+                result,
+                "id\tname\tfloor\n------------------------------\n\
+                 2\teng\t2\n3\tops\t0\n4\teng\t2\n(3 rows)"
+            );
+
+            // WHERE applies to the joined rows
+            let result = parse_and_execute(
+                db,
+                "SELECT id FROM employees INNER JOIN depts ON dept = depts.name WHERE floor = 0",
+            )
+            .unwrap();
+            assert!(result.ends_with("\n3\n(1 rows)"));
+
+            let plan = parse_and_execute(
+                db,
+                "EXPLAIN SELECT * FROM employees e JOIN depts d ON e.dept = d.name",
+            )
+            .unwrap();
+            assert_eq!(
+                plan,
+                "Nested Loop Join\n  Seq Scan on employees\n  Seq Scan on depts\n    Join Filter: e.dept = d.name"
+            );
+        });
+    }
+
+    #[test]
+    fn test_comma_separated_tables() {
+        with_memory_db(|db| {
+            setup_employees(db);
+            parse_and_execute(db, "CREATE TABLE depts (name TEXT, floor INT)").unwrap();
+            parse_and_execute(db, "INSERT INTO depts VALUES ('eng', 2), ('ops', 0)").unwrap();
+
+            // Every employee with every department
+            let result = parse_and_execute(db, "SELECT * FROM employees, depts").unwrap();
+            assert!(
+                result.starts_with("id\tdept\tlevel\tname\tfloor\n"),
+                "{}",
+                result
+            );
+            assert!(result.ends_with("(8 rows)"), "{}", result);
+
+            // WHERE can act as the join condition, with qualified columns
+            let result = parse_and_execute(
+                db,
+                "SELECT employees.id, depts.floor FROM employees, depts \
+                 WHERE employees.dept = depts.name AND floor > 0 ORDER BY id",
+            )
+            .unwrap();
+            let rows: Vec<&str> = result.lines().skip(2).collect();
+            assert_eq!(rows, vec!["1\t2", "2\t2", "4\t2", "(3 rows)"]);
+
+            // More than two tables, mixing commas with JOIN and CROSS JOIN
+            let result = parse_and_execute(
+                db,
+                "SELECT COUNT(*) FROM employees e JOIN depts d ON e.dept = d.name, \
+                 depts d2 CROSS JOIN depts d3",
+            )
+            .unwrap();
+            assert!(result.contains("\n16\n"), "{}", result);
+
+            assert!(parse_and_execute(db, "SELECT * FROM depts, depts").is_err());
+        });
+    }
+
+    #[test]
+    fn test_select_for_update() {
+        with_memory_db(|db| {
+            setup_employees(db);
+
+            parse_and_execute(db, "BEGIN").unwrap();
+            let result = parse_and_execute(
+                db,
+                "SELECT id FROM employees e WHERE dept = 'ops' FOR UPDATE OF e NOWAIT",
+            )
+            .unwrap();
+            assert!(result.ends_with("\n3\n(1 rows)"), "{}", result);
+            parse_and_execute(db, "UPDATE employees SET level = 4 WHERE id = 3").unwrap();
+            parse_and_execute(db, "COMMIT").unwrap();
+
+            let result =
+                parse_and_execute(db, "SELECT level FROM employees WHERE id = 3 FOR SHARE")
+                    .unwrap();
+            assert!(result.ends_with("\n4\n(1 rows)"), "{}", result);
+
+            let err =
+                parse_and_execute(db, "SELECT COUNT(*) FROM employees FOR UPDATE").unwrap_err();
+            assert!(err.contains("not allowed"), "{}", err);
+            let err =
+                parse_and_execute(db, "SELECT * FROM employees FOR UPDATE OF depts").unwrap_err();
+            assert!(err.contains("not in FROM"), "{}", err);
+        });
+    }
+
+    #[test]
+    fn test_range_join() {
+        with_memory_db(|db| {
+            parse_and_execute(db, "CREATE TABLE events (ts INT, what TEXT)").unwrap();
+            parse_and_execute(db, "CREATE TABLE shifts (name TEXT, start INT, finish INT)")
+                .unwrap();
+            parse_and_execute(
+                db,
+                "INSERT INTO events VALUES (5, 'boot'), (15, 'alarm'), (25, 'halt')",
+            )
+            .unwrap();
+            parse_and_execute(
+                db,
+                "INSERT INTO shifts VALUES ('day', 0, 10), ('night', 10, 20), ('late', 15, 30)",
+            )
+            .unwrap();
+
+            let result = parse_and_execute(
+                db,
+                "SELECT what, name FROM events \
+                 JOIN shifts ON events.ts BETWEEN shifts.start AND shifts.finish \
+                 ORDER BY ts, name",
+            )
+            .unwrap();
+            let rows: Vec<&str> = result.lines().skip(2).collect();
+            assert_eq!(
+                rows,
+                vec![
+                    "boot\tday",
+                    "alarm\tlate",
+                    "alarm\tnight",
+                    "halt\tlate",
+                    "(4 rows)"
+                ]
+            );
+
+            // Column names shared by both tables must be qualified
+            parse_and_execute(db, "CREATE TABLE notes (ts INT)").unwrap();
+            let err =
+                parse_and_execute(db, "SELECT ts FROM events JOIN notes ON ts = 1").unwrap_err();
+            assert!(err.contains("ambiguous"), "{}", err);
+            assert!(parse_and_execute(db, "SELECT * FROM events JOIN events ON ts = 1").is_err());
+            assert!(
+                parse_and_execute(db, "SELECT * FROM events e JOIN notes n ON x.ts = n.ts")
+                    .is_err()
+            );
         });
     }
 }
@@ -0,0 +1,1068 @@
+use crate::database::Value;
+use crate::decimal::Decimal;
+use crate::executor::{ExecError, decimal_type, decode_hex, expr_to_value, format_value};
+use crate::json::Json;
+use sqlparser::ast::{
+    BinaryOperator, CaseWhen, CastKind, ColumnDef, ColumnOption, DataType, Expr, Function,
+    FunctionArg, FunctionArgExpr, FunctionArguments, Ident, ObjectName, UnaryOperator,
+};
+use std::cmp::Ordering;
+
+/// How text values are compared and sorted. Chosen per column with
+/// `COLLATE` in CREATE TABLE, or per expression with `expr COLLATE name`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Collation {
+    /// Byte-wise, so `'Z'` sorts before `'a'`
+    #[default]
+    Binary,
+    /// Ignoring ASCII case
+    NoCase,
+}
+
+impl Collation {
+    pub fn from_name(name: &ObjectName) -> Result<Self, ExecError> {
+        match name.to_string().to_uppercase().as_str() {
+            "BINARY" => Ok(Collation::Binary),
+            "NOCASE" => Ok(Collation::NoCase),
+            _ => Err(ExecError::Unsupported(format!(
+                "Unknown collation: {}",
+                name
+            ))),
+        }
+    }
+
+    /// Order two values for sorting. Only text is affected by the collation;
+    /// anything else orders as `Value` does.
+    pub fn sort_order(self, left: &Value, right: &Value) -> Ordering {
+        match (self, left, right) {
+            (Collation::NoCase, Value::Text(a), Value::Text(b)) => a
+                .bytes()
+                .map(|c| c.to_ascii_lowercase())
+                .cmp(b.bytes().map(|c| c.to_ascii_lowercase())),
+            // Integers and decimals order by value
+            (_, Value::Long(a), Value::Decimal(b)) => Decimal::from_i64(*a).cmp(b),
+            (_, Value::Decimal(a), Value::Long(b)) => a.cmp(&Decimal::from_i64(*b)),
+            _ => left.cmp(right),
+        }
+    }
+
+    /// The value as the collation sees it, so that values the collation
+    /// treats as equal hash and compare equal
+    pub fn key(self, value: &Value) -> Value {
+        match (self, value) {
+            (Collation::NoCase, Value::Text(s)) => Value::Text(s.to_ascii_lowercase()),
+            _ => value.clone(),
+        }
+    }
+}
+
+/// The collation a column was declared with
+pub fn column_collation(col: &ColumnDef) -> Result<Collation, ExecError> {
+    col.options
+        .iter()
+        .find_map(|o| match &o.option {
+            ColumnOption::Collation(name) => Some(Collation::from_name(name)),
+            _ => None,
+        })
+        .unwrap_or(Ok(Collation::Binary))
+}
+
+/// The collation an expression's value compares with: an explicit `COLLATE`,
+/// else the collation of the column it refers to
+pub fn expr_collation(expr: &Expr, schema: &[ColumnDef]) -> Result<Collation, ExecError> {
+    if let Some(idx) = column_position(schema, expr) {
+        return column_collation(&schema[idx?]);
+    }
+    match expr {
+        Expr::Collate { collation, .. } => Collation::from_name(collation),
+        Expr::Nested(inner) => expr_collation(inner, schema),
+        _ => Ok(Collation::Binary),
+    }
+}
+
+/// The collation for comparing two expressions; the left side's wins if both have one
+fn comparison_collation(
+    left: &Expr,
+    right: &Expr,
+    schema: &[ColumnDef],
+) -> Result<Collation, ExecError> {
+    match expr_collation(left, schema)? {
+        Collation::Binary => expr_collation(right, schema),
+        collation => Ok(collation),
+    }
+}
+
+/// Find the position of a column in a table schema.
+/// Names match case-insensitively; an exact match wins if two columns differ only in case.
+///
+/// In the combined schema of a join, columns are named `table.column`, and an
+/// unqualified name finds the one column whose last part it is.
+pub fn column_index(schema: &[ColumnDef], name: &str) -> Result<usize, ExecError> {
+    if let Some(idx) = schema
+        .iter()
+        .position(|c| c.name.value == name)
+        .or_else(|| {
+            schema
+                .iter()
+                .position(|c| c.name.value.eq_ignore_ascii_case(name))
+        })
+    {
+        return Ok(idx);
+    }
+
+    if !name.contains('.') {
+        let matches: Vec<usize> = (0..schema.len())
+            .filter(|&idx| unqualified(&schema[idx].name.value).eq_ignore_ascii_case(name))
+            .collect();
+        match matches.as_slice() {
+            [idx] => return Ok(*idx),
+            [] => {}
+            _ => {
+                return Err(ExecError::NotFound(format!(
+                    "Column '{}' is ambiguous; qualify it with a table name",
+                    name
+                )));
+            }
+        }
+    }
+
+    Err(ExecError::NotFound(format!(
+        "Column '{}' does not exist",
+        name
+    )))
+}
+
+/// Resolve a column reference to its position in `schema`, or `None` if the
+/// expression isn't a plain column reference.
+///
+/// Against a joined schema a qualified reference (`users.id`) must match the
+/// table too; against a single table the qualifier is ignored.
+pub fn column_position(schema: &[ColumnDef], expr: &Expr) -> Option<Result<usize, ExecError>> {
+    match expr {
+        Expr::Identifier(ident) => Some(column_index(schema, &ident.value)),
+        Expr::CompoundIdentifier(parts) => {
+            let name = &parts.last()?.value;
+            if parts.len() >= 2 && schema.iter().any(|c| c.name.value.contains('.')) {
+                let qualified = format!("{}.{}", parts[parts.len() - 2].value, name);
+                Some(column_index(schema, &qualified))
+            } else {
+                Some(column_index(schema, name))
+            }
+        }
+        Expr::Nested(inner) => column_position(schema, inner),
+        _ => None,
+    }
+}
+
+/// Call `visit` on an expression and then, as long as it returns true, on
+/// each of its subexpressions, for the expression forms the evaluator supports
+pub fn walk_expr_mut(expr: &mut Expr, visit: &mut dyn FnMut(&mut Expr) -> bool) {
+    if !visit(expr) {
+        return;
+    }
+    match expr {
+        Expr::Nested(inner)
+        | Expr::Collate { expr: inner, .. }
+        | Expr::UnaryOp { expr: inner, .. }
+        | Expr::IsNull(inner)
+        | Expr::IsNotNull(inner) => walk_expr_mut(inner, visit),
+        Expr::Between {
+            expr, low, high, ..
+        } => {
+            for inner in [expr, low, high] {
+                walk_expr_mut(inner, visit);
+            }
+        }
+        Expr::BinaryOp { left, right, .. } => {
+            walk_expr_mut(left, visit);
+            walk_expr_mut(right, visit);
+        }
+        Expr::InList { expr, list, .. } => {
+            walk_expr_mut(expr, visit);
+            for inner in list {
+                walk_expr_mut(inner, visit);
+            }
+        }
+        Expr::AnyOp { left, right, .. } | Expr::AllOp { left, right, .. } => {
+            walk_expr_mut(left, visit);
+            walk_expr_mut(right, visit);
+        }
+        Expr::Array(array) => {
+            for inner in &mut array.elem {
+                walk_expr_mut(inner, visit);
+            }
+        }
+        Expr::Case {
+            operand,
+            conditions,
+            else_result,
+            ..
+        } => {
+            if let Some(operand) = operand {
+                walk_expr_mut(operand, visit);
+            }
+            for when in conditions {
+                walk_expr_mut(&mut when.condition, visit);
+                walk_expr_mut(&mut when.result, visit);
+            }
+            if let Some(else_result) = else_result {
+                walk_expr_mut(else_result, visit);
+            }
+        }
+        Expr::Function(func) => {
+            if let FunctionArguments::List(list) = &mut func.args {
+                for arg in &mut list.args {
+                    if let FunctionArg::Unnamed(FunctionArgExpr::Expr(inner)) = arg {
+                        walk_expr_mut(inner, visit);
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Point every reference to the column at `idx` to `new_name`, for renaming
+/// a column. `schema` is the table's schema before the rename.
+pub fn rename_column_refs(expr: &mut Expr, schema: &[ColumnDef], idx: usize, new_name: &Ident) {
+    let refers = |name: &str| column_index(schema, name).ok() == Some(idx);
+    walk_expr_mut(expr, &mut |expr| {
+        match expr {
+            Expr::Identifier(ident) if refers(&ident.value) => *ident = new_name.clone(),
+            Expr::CompoundIdentifier(parts) => {
+                if let Some(last) = parts.last_mut()
+                    && refers(&last.value)
+                {
+                    *last = new_name.clone();
+                }
+            }
+            _ => {}
+        }
+        true
+    });
+}
+
+/// A column name without the table qualifier it has in a joined schema
+pub fn unqualified(name: &str) -> &str {
+    name.rsplit_once('.').map_or(name, |(_, column)| column)
+}
+
+/// The column name referenced by an expression, if it is a plain column reference.
+/// Qualified references (`users.id`) resolve to their last part.
+pub fn column_name(expr: &Expr) -> Option<&str> {
+    match expr {
+        Expr::Identifier(ident) => Some(&ident.value),
+        Expr::CompoundIdentifier(parts) => parts.last().map(|p| p.value.as_str()),
+        Expr::Nested(inner) => column_name(inner),
+        _ => None,
+    }
+}
+
+/// Evaluate an expression against a row laid out according to `schema`
+pub fn eval_expr(expr: &Expr, schema: &[ColumnDef], values: &[Value]) -> Result<Value, ExecError> {
+    if let Some(idx) = column_position(schema, expr) {
+        let idx = idx?;
+        return values
+            .get(idx)
+            .cloned()
+            .ok_or_else(|| ExecError::Storage(format!("Row has no value for column '{}'", expr)));
+    }
+
+    match expr {
+        Expr::Value(_) => expr_to_value(expr.clone()),
+        Expr::Nested(inner) => eval_expr(inner, schema, values),
+        // The collation only matters where the value is compared
+        Expr::Collate { expr, .. } => eval_expr(expr, schema, values),
+        Expr::UnaryOp {
+            op: UnaryOperator::Not,
+            expr,
+        } => {
+            let value = eval_expr(expr, schema, values)?;
+            Ok(truth_value(expect_truth(&value)?.map(|b| !b)))
+        }
+        Expr::UnaryOp {
+            op: UnaryOperator::Minus,
+            expr,
+        } => negate(eval_expr(expr, schema, values)?),
+        Expr::UnaryOp {
+            op: UnaryOperator::Plus,
+            expr,
+        } => match eval_expr(expr, schema, values)? {
+            value @ (Value::Long(_) | Value::Decimal(_) | Value::Null) => Ok(value),
+            value => Err(ExecError::Type(format!("Cannot compute +{:?}", value))),
+        },
+        Expr::IsNull(inner) => Ok(Value::Bool(
+            eval_expr(inner, schema, values)? == Value::Null,
+        )),
+        Expr::IsNotNull(inner) => Ok(Value::Bool(
+            eval_expr(inner, schema, values)? != Value::Null,
+        )),
+        // `x BETWEEN low AND high` is `low <= x AND x <= high`
+        Expr::Between {
+            expr,
+            negated,
+            low,
+            high,
+        } => {
+            let collation = expr_collation(expr, schema)?;
+            let value = eval_expr(expr, schema, values)?;
+            let low = eval_expr(low, schema, values)?;
+            let high = eval_expr(high, schema, values)?;
+            let above = expect_truth(&eval_comparison(
+                &BinaryOperator::GtEq,
+                &value,
+                &low,
+                collation,
+            )?)?;
+            let below = expect_truth(&eval_comparison(
+                &BinaryOperator::LtEq,
+                &value,
+                &high,
+                collation,
+            )?)?;
+            let within = match (above, below) {
+                (Some(false), _) | (_, Some(false)) => Some(false),
+                (Some(true), Some(true)) => Some(true),
+                _ => None,
+            };
+            Ok(truth_value(within.map(|b| b != *negated)))
+        }
+        // `x IN (a, b)` is `x = a OR x = b`, so a NULL in the list makes a
+        // non-match unknown, and `x IN ()` is false
+        Expr::InList {
+            expr,
+            list,
+            negated,
+        } => {
+            let value = eval_expr(expr, schema, values)?;
+            let mut found = Some(false);
+            for item in list {
+                let collation = comparison_collation(expr, item, schema)?;
+                let item = eval_expr(item, schema, values)?;
+                match expect_truth(&eval_comparison(
+                    &BinaryOperator::Eq,
+                    &value,
+                    &item,
+                    collation,
+                )?)? {
+                    Some(true) => {
+                        found = Some(true);
+                        break;
+                    }
+                    Some(false) => {}
+                    None => found = None,
+                }
+            }
+            Ok(truth_value(found.map(|b| b != *negated)))
+        }
+        // `x > ANY (a, b)` is `x > a OR x > b`, and `x > ALL (a, b)` is
+        // `x > a AND x > b`, so ANY of no values is false and ALL is true.
+        // The values are the rows of a subquery, resolved before evaluation.
+        Expr::AnyOp {
+            left,
+            compare_op,
+            right,
+            ..
+        } => eval_quantified(left, compare_op, right, true, schema, values),
+        Expr::AllOp {
+            left,
+            compare_op,
+            right,
+        } => eval_quantified(left, compare_op, right, false, schema, values),
+        // AND and OR use three-valued logic: NULL is "unknown"
+        Expr::BinaryOp { left, op, right } => match op {
+            BinaryOperator::And => {
+                let left = expect_truth(&eval_expr(left, schema, values)?)?;
+                if left == Some(false) {
+                    return Ok(Value::Bool(false));
+                }
+                let right = expect_truth(&eval_expr(right, schema, values)?)?;
+                Ok(match (left, right) {
+                    (_, Some(false)) => Value::Bool(false),
+                    (Some(true), Some(true)) => Value::Bool(true),
+                    _ => Value::Null,
+                })
+            }
+            BinaryOperator::Or => {
+                let left = expect_truth(&eval_expr(left, schema, values)?)?;
+                if left == Some(true) {
+                    return Ok(Value::Bool(true));
+                }
+                let right = expect_truth(&eval_expr(right, schema, values)?)?;
+                Ok(match (left, right) {
+                    (_, Some(true)) => Value::Bool(true),
+                    (Some(false), Some(false)) => Value::Bool(false),
+                    _ => Value::Null,
+                })
+            }
+            BinaryOperator::Plus | BinaryOperator::Minus | BinaryOperator::Multiply => {
+                let left = eval_expr(left, schema, values)?;
+                let right = eval_expr(right, schema, values)?;
+                eval_arithmetic(op, &left, &right)
+            }
+            BinaryOperator::StringConcat => {
+                let left = eval_expr(left, schema, values)?;
+                let right = eval_expr(right, schema, values)?;
+                if left == Value::Null || right == Value::Null {
+                    return Ok(Value::Null);
+                }
+                Ok(Value::Text(format_value(&left) + &format_value(&right)))
+            }
+            _ => {
+                let collation = comparison_collation(left, right, schema)?;
+                let left = eval_expr(left, schema, values)?;
+                let right = eval_expr(right, schema, values)?;
+                eval_comparison(op, &left, &right, collation)
+            }
+        },
+        Expr::Function(func) => eval_function(func, schema, values),
+        Expr::Case {
+            operand,
+            conditions,
+            else_result,
+            ..
+        } => eval_case(
+            operand.as_deref(),
+            conditions,
+            else_result.as_deref(),
+            schema,
+            values,
+        ),
+        Expr::Cast {
+            kind: CastKind::Cast | CastKind::DoubleColon,
+            expr,
+            data_type,
+            format: None,
+        } => cast_value(eval_expr(expr, schema, values)?, data_type),
+        _ => Err(ExecError::Unsupported(format!(
+            "Unsupported expression: {}",
+            expr
+        ))),
+    }
+}
+
+/// The result of the first `WHEN` that holds, else the `ELSE`, else NULL.
+/// With an operand (`CASE x WHEN 1 THEN ...`), a `WHEN` holds if it equals the
+/// operand, so a NULL operand matches nothing. Results past the one chosen
+/// aren't evaluated.
+fn eval_case(
+    operand: Option<&Expr>,
+    conditions: &[CaseWhen],
+    else_result: Option<&Expr>,
+    schema: &[ColumnDef],
+    values: &[Value],
+) -> Result<Value, ExecError> {
+    let operand_value = operand
+        .map(|operand| eval_expr(operand, schema, values))
+        .transpose()?;
+    for when in conditions {
+        let holds = match (operand, &operand_value) {
+            (Some(operand), Some(operand_value)) => {
+                let collation = comparison_collation(operand, &when.condition, schema)?;
+                let value = eval_expr(&when.condition, schema, values)?;
+                eval_comparison(&BinaryOperator::Eq, operand_value, &value, collation)?
+            }
+            _ => eval_expr(&when.condition, schema, values)?,
+        };
+        // An unknown condition doesn't hold
+        if expect_truth(&holds)? == Some(true) {
+            return eval_expr(&when.result, schema, values);
+        }
+    }
+    match else_result {
+        Some(result) => eval_expr(result, schema, values),
+        None => Ok(Value::Null),
+    }
+}
+
+/// Evaluate a scalar function call
+fn eval_function(
+    func: &Function,
+    schema: &[ColumnDef],
+    values: &[Value],
+) -> Result<Value, ExecError> {
+    let args = function_args(func)?;
+
+    match func.name.to_string().to_uppercase().as_str() {
+        // Non-text arguments are concatenated in their display form; NULLs are skipped
+        "CONCAT" => {
+            let mut result = String::new();
+            for arg in args {
+                match eval_expr(arg, schema, values)? {
+                    Value::Null => {}
+                    value => result.push_str(&format_value(&value)),
+                }
+            }
+            Ok(Value::Text(result))
+        }
+        // The first non-NULL argument; later arguments aren't evaluated
+        "COALESCE" => {
+            for arg in args {
+                let value = eval_expr(arg, schema, values)?;
+                if value != Value::Null {
+                    return Ok(value);
+                }
+            }
+            Ok(Value::Null)
+        }
+        // NULL when the arguments are equal, otherwise the first one
+        "NULLIF" => match args.as_slice() {
+            [left, right] => {
+                let collation = comparison_collation(left, right, schema)?;
+                let left = eval_expr(left, schema, values)?;
+                let right = eval_expr(right, schema, values)?;
+                match eval_comparison(&BinaryOperator::Eq, &left, &right, collation)? {
+                    Value::Bool(true) => Ok(Value::Null),
+                    _ => Ok(left),
+                }
+            }
+            _ => Err(ExecError::Type("NULLIF takes two arguments".to_string())),
+        },
+        // DECODE(text, 'hex') turns a hex string into a blob
+        "DECODE" => match args.as_slice() {
+            [data, format] => {
+                let data = eval_expr(data, schema, values)?;
+                let format = eval_expr(format, schema, values)?;
+                match (data, format) {
+                    (Value::Text(data), Value::Text(format))
+                        if format.eq_ignore_ascii_case("hex") =>
+                    {
+                        decode_hex(&data).map(Value::Blob)
+                    }
+                    (_, format) => Err(ExecError::Type(format!(
+                        "DECODE expects text and 'hex', got format {}",
+                        format_value(&format)
+                    ))),
+                }
+            }
+            _ => Err(ExecError::Type("DECODE takes two arguments".to_string())),
+        },
+        // JSON_EXTRACT(text, '$.a.b[0]') is the value there as text: strings
+        // unquoted, objects and arrays as JSON. NULL if there's nothing there
+        // or it's JSON null; text that isn't JSON is an error.
+        "JSON_EXTRACT" => match args.as_slice() {
+            [document, path] => {
+                let document = eval_expr(document, schema, values)?;
+                let path = eval_expr(path, schema, values)?;
+                let (document, path) = match (document, path) {
+                    (Value::Null, _) | (_, Value::Null) => return Ok(Value::Null),
+                    (Value::Text(document), Value::Text(path)) => (document, path),
+                    (document, path) => {
+                        return Err(ExecError::Type(format!(
+                            "JSON_EXTRACT expects text and a text path, got {} and {}",
+                            document.type_name(),
+                            path.type_name()
+                        )));
+                    }
+                };
+                let json = Json::parse(&document)
+                    .map_err(|e| ExecError::Type(format!("JSON_EXTRACT: invalid JSON: {}", e)))?;
+                Ok(match json.lookup(&path).map_err(ExecError::Type)? {
+                    None | Some(Json::Null) => Value::Null,
+                    Some(Json::String(s)) => Value::Text(s.clone()),
+                    Some(other) => Value::Text(other.to_string()),
+                })
+            }
+            _ => Err(ExecError::Type(
+                "JSON_EXTRACT takes two arguments".to_string(),
+            )),
+        },
+        _ if crate::aggregate::is_aggregate(func) => Err(ExecError::Unsupported(format!(
+            "Aggregate function {} is only allowed in the select list",
+            func.name
+        ))),
+        _ => Err(ExecError::Unsupported(format!(
+            "Unknown function: {}",
+            func.name
+        ))),
+    }
+}
+
+/// Compare `left` with each of the values in `right`, true if `any` of the
+/// comparisons hold, or if all of them do. A NULL comparison makes an
+/// otherwise undecided result NULL.
+fn eval_quantified(
+    left: &Expr,
+    op: &BinaryOperator,
+    right: &Expr,
+    any: bool,
+    schema: &[ColumnDef],
+    values: &[Value],
+) -> Result<Value, ExecError> {
+    let Expr::Array(array) = right else {
+        return Err(ExecError::Unsupported(format!(
+            "ANY and ALL need a subquery, got {}",
+            right
+        )));
+    };
+    let value = eval_expr(left, schema, values)?;
+    // The result when no comparison decides it
+    let mut result = Some(!any);
+    for item in &array.elem {
+        let collation = comparison_collation(left, item, schema)?;
+        let item = eval_expr(item, schema, values)?;
+        match expect_truth(&eval_comparison(op, &value, &item, collation)?)? {
+            Some(holds) if holds == any => return Ok(Value::Bool(any)),
+            Some(_) => {}
+            None => result = None,
+        }
+    }
+    Ok(truth_value(result))
+}
+
+/// Convert a value to `data_type`, giving what a column of that type would
+/// store: `CAST(2.5 AS INT)` is 3 and `CAST(x AS DECIMAL(5, 2))` has two
+/// fractional digits. Text is parsed, erroring if it doesn't hold a value of
+/// the type; anything casts to text in its display form. NULL stays NULL.
+pub fn cast_value(value: Value, data_type: &DataType) -> Result<Value, ExecError> {
+    let invalid = |value: &Value| {
+        ExecError::Type(format!(
+            "Cannot cast {} '{}' to {}",
+            value.type_name(),
+            format_value(value),
+            data_type
+        ))
+    };
+    if value == Value::Null {
+        return Ok(value);
+    }
+
+    if let Some((precision, scale)) = decimal_type(data_type)? {
+        let decimal = match &value {
+            Value::Long(n) => Some(Decimal::from_i64(*n)),
+            Value::Decimal(d) => Some(*d),
+            Value::Text(s) => Decimal::parse(s.trim()),
+            _ => None,
+        };
+        return match decimal.and_then(|d| d.rescale(scale)) {
+            Some(d) if d.precision() <= precision => Ok(Value::Decimal(d)),
+            _ => Err(invalid(&value)),
+        };
+    }
+
+    let cast = match data_type {
+        DataType::Int(_) | DataType::Integer(_) | DataType::BigInt(_) | DataType::SmallInt(_) => {
+            match &value {
+                Value::Long(n) => Some(*n),
+                Value::Decimal(d) => d
+                    .rescale(0)
+                    .and_then(|whole| i64::try_from(whole.mantissa()).ok()),
+                Value::Text(s) => s.trim().parse().ok(),
+                Value::Bool(b) => Some(*b as i64),
+                _ => None,
+            }
+            .map(Value::Long)
+        }
+        DataType::Text | DataType::Varchar(_) | DataType::Char(_) | DataType::String(_) => {
+            Some(Value::Text(format_value(&value)))
+        }
+        DataType::Boolean | DataType::Bool => match &value {
+            Value::Bool(b) => Some(*b),
+            Value::Long(n) => Some(*n != 0),
+            Value::Text(s) => match s.trim().to_ascii_lowercase().as_str() {
+                "true" | "t" | "yes" | "1" => Some(true),
+                "false" | "f" | "no" | "0" => Some(false),
+                _ => None,
+            },
+            _ => None,
+        }
+        .map(Value::Bool),
+        DataType::Blob(_) | DataType::Bytea => match value {
+            Value::Blob(ref data) => Some(Value::Blob(data.clone())),
+            Value::Text(ref s) => Some(Value::Blob(s.as_bytes().to_vec())),
+            _ => None,
+        },
+        _ => {
+            return Err(ExecError::Unsupported(format!(
+                "CAST to {} is not supported",
+                data_type
+            )));
+        }
+    };
+    cast.ok_or_else(|| invalid(&value))
+}
+
+/// The plain positional arguments of a function call
+pub fn function_args(func: &Function) -> Result<Vec<&Expr>, ExecError> {
+    let list = match &func.args {
+        FunctionArguments::None => return Ok(vec![]),
+        FunctionArguments::List(list) => list,
+        FunctionArguments::Subquery(_) => {
+            return Err(ExecError::Unsupported(format!(
+                "Subquery arguments are not supported: {}",
+                func
+            )));
+        }
+    };
+
+    list.args
+        .iter()
+        .map(|arg| match arg {
+            FunctionArg::Unnamed(FunctionArgExpr::Expr(expr)) => Ok(expr),
+            _ => Err(ExecError::Unsupported(format!(
+                "Unsupported function argument: {}",
+                arg
+            ))),
+        })
+        .collect()
+}
+
+/// Evaluate a WHERE-style predicate, requiring a boolean result.
+/// A NULL result doesn't match.
+pub fn eval_predicate(
+    expr: &Expr,
+    schema: &[ColumnDef],
+    values: &[Value],
+) -> Result<bool, ExecError> {
+    Ok(expect_truth(&eval_expr(expr, schema, values)?)? == Some(true))
+}
+
+fn eval_comparison(
+    op: &BinaryOperator,
+    left: &Value,
+    right: &Value,
+    collation: Collation,
+) -> Result<Value, ExecError> {
+    // Comparing with NULL is unknown, even NULL = NULL
+    if *left == Value::Null || *right == Value::Null {
+        return match op {
+            BinaryOperator::Eq
+            | BinaryOperator::NotEq
+            | BinaryOperator::Lt
+            | BinaryOperator::LtEq
+            | BinaryOperator::Gt
+            | BinaryOperator::GtEq => Ok(Value::Null),
+            _ => Err(ExecError::Unsupported(format!(
+                "Unsupported operator: {}",
+                op
+            ))),
+        };
+    }
+
+    let ordering = compare_values(left, right, collation)?;
+
+    let result = match op {
+        BinaryOperator::Eq => ordering == Ordering::Equal,
+        BinaryOperator::NotEq => ordering != Ordering::Equal,
+        BinaryOperator::Lt => ordering == Ordering::Less,
+        BinaryOperator::LtEq => ordering != Ordering::Greater,
+        BinaryOperator::Gt => ordering == Ordering::Greater,
+        BinaryOperator::GtEq => ordering != Ordering::Less,
+        _ => {
+            return Err(ExecError::Unsupported(format!(
+                "Unsupported operator: {}",
+                op
+            )));
+        }
+    };
+
+    Ok(Value::Bool(result))
+}
+
+/// `-value` for a number; NULL stays NULL
+pub fn negate(value: Value) -> Result<Value, ExecError> {
+    let negated = match &value {
+        Value::Null => return Ok(Value::Null),
+        Value::Long(n) => n.checked_neg().map(Value::Long),
+        Value::Decimal(d) => d.checked_neg().map(Value::Decimal),
+        _ => return Err(ExecError::Type(format!("Cannot compute -{:?}", value))),
+    };
+    negated.ok_or_else(|| ExecError::Type(format!("Numeric overflow in -{}", format_value(&value))))
+}
+
+/// `+`, `-` or `*` on two numbers. Integers give an integer; if either side is a
+/// decimal, the result is an exact decimal.
+pub fn eval_arithmetic(
+    op: &BinaryOperator,
+    left: &Value,
+    right: &Value,
+) -> Result<Value, ExecError> {
+    let overflow = || {
+        ExecError::Type(format!(
+            "Numeric overflow in {} {} {}",
+            format_value(left),
+            op,
+            format_value(right)
+        ))
+    };
+
+    let result = match (left, right) {
+        (Value::Null, _) | (_, Value::Null) => return Ok(Value::Null),
+        (Value::Long(a), Value::Long(b)) => match op {
+            BinaryOperator::Plus => a.checked_add(*b),
+            BinaryOperator::Multiply => a.checked_mul(*b),
+            _ => a.checked_sub(*b),
+        }
+        .map(Value::Long),
+        _ => {
+            let (Some(a), Some(b)) = (as_decimal(left), as_decimal(right)) else {
+                return Err(ExecError::Type(format!(
+                    "Cannot compute {:?} {} {:?}",
+                    left, op, right
+                )));
+            };
+            match op {
+                BinaryOperator::Plus => a.checked_add(b),
+                BinaryOperator::Multiply => a.checked_mul(b),
+                _ => a.checked_sub(b),
+            }
+            .map(Value::Decimal)
+        }
+    };
+    result.ok_or_else(overflow)
+}
+
+fn as_decimal(value: &Value) -> Option<Decimal> {
+    match value {
+        Value::Long(n) => Some(Decimal::from_i64(*n)),
+        Value::Decimal(d) => Some(*d),
+        _ => None,
+    }
+}
+
+/// Compare two values of the same type, text according to `collation`.
+/// Integers and decimals compare with each other by value.
+pub fn compare_values(
+    left: &Value,
+    right: &Value,
+    collation: Collation,
+) -> Result<Ordering, ExecError> {
+    match (left, right) {
+        (Value::Long(a), Value::Long(b)) => Ok(a.cmp(b)),
+        (Value::Long(_) | Value::Decimal(_), Value::Long(_) | Value::Decimal(_)) => {
+            Ok(collation.sort_order(left, right))
+        }
+        (Value::Text(_), Value::Text(_)) => Ok(collation.sort_order(left, right)),
+        (Value::Bool(a), Value::Bool(b)) => Ok(a.cmp(b)),
+        (Value::Blob(a), Value::Blob(b)) => Ok(a.cmp(b)),
+        _ => Err(ExecError::Type(format!(
+            "Cannot compare {:?} with {:?}",
+            left, right
+        ))),
+    }
+}
+
+/// A boolean, or `None` for NULL
+fn expect_truth(value: &Value) -> Result<Option<bool>, ExecError> {
+    match value {
+        Value::Bool(b) => Ok(Some(*b)),
+        Value::Null => Ok(None),
+        other => Err(ExecError::Type(format!(
+            "Expected a boolean expression, got {:?}",
+            other
+        ))),
+    }
+}
+
+fn truth_value(truth: Option<bool>) -> Value {
+    truth.map_or(Value::Null, Value::Bool)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dialect::MatiDialect;
+    use sqlparser::ast::{DataType, Ident};
+    use sqlparser::parser::Parser;
+
+    fn schema() -> Vec<ColumnDef> {
+        vec![
+            ColumnDef {
+                name: Ident::new("id"),
+                data_type: DataType::Int(None),
+                options: vec![],
+            },
+            ColumnDef {
+                name: Ident::new("name"),
+                data_type: DataType::Text,
+                options: vec![],
+            },
+        ]
+    }
+
+    fn parse_expr(sql: &str) -> Expr {
+        Parser::new(&MatiDialect {})
+            .try_with_sql(sql)
+            .unwrap()
+            .parse_expr()
+            .unwrap()
+    }
+
+    fn eval(sql: &str, values: &[Value]) -> Result<Value, ExecError> {
+        eval_expr(&parse_expr(sql), &schema(), values)
+    }
+
+    #[test]
+    fn test_eval_comparisons() {
+        let row = [Value::Long(5), Value::Text("Alice".to_string())];
+
+        assert_eq!(eval("id = 5", &row).unwrap(), Value::Bool(true));
+        assert_eq!(eval("id > 5", &row).unwrap(), Value::Bool(false));
+        assert_eq!(eval("name <> 'Bob'", &row).unwrap(), Value::Bool(true));
+        assert_eq!(
+            eval("id >= 5 AND NOT (name = 'Bob')", &row).unwrap(),
+            Value::Bool(true)
+        );
+    }
+
+    #[test]
+    fn test_eval_errors() {
+        let row = [Value::Long(5), Value::Text("Alice".to_string())];
+
+        assert!(matches!(
+            eval("missing = 1", &row),
+            Err(ExecError::NotFound(_))
+        ));
+        assert!(matches!(eval("id = 'five'", &row), Err(ExecError::Type(_))));
+    }
+
+    #[test]
+    fn test_column_lookup_ignores_case() {
+        let row = [Value::Long(5), Value::Text("Alice".to_string())];
+
+        assert_eq!(column_index(&schema(), "NAME").unwrap(), 1);
+        assert_eq!(
+            eval("Id = 5 AND NAME = 'Alice'", &row).unwrap(),
+            Value::Bool(true)
+        );
+    }
+
+    #[test]
+    fn test_eval_null_logic() {
+        let row = [Value::Long(5), Value::Null];
+
+        assert_eq!(eval("name = 'Bob'", &row).unwrap(), Value::Null);
+        assert_eq!(eval("NOT (name = 'Bob')", &row).unwrap(), Value::Null);
+        assert_eq!(
+            eval("name = 'Bob' AND id = 6", &row).unwrap(),
+            Value::Bool(false)
+        );
+        assert_eq!(
+            eval("name = 'Bob' OR id = 5", &row).unwrap(),
+            Value::Bool(true)
+        );
+        assert_eq!(eval("name = 'Bob' OR id = 6", &row).unwrap(), Value::Null);
+        assert_eq!(eval("name IS NULL", &row).unwrap(), Value::Bool(true));
+        assert_eq!(eval("id IS NOT NULL", &row).unwrap(), Value::Bool(true));
+        assert_eq!(eval("'x' || name", &row).unwrap(), Value::Null);
+        assert_eq!(
+            eval("CONCAT('x', name)", &row).unwrap(),
+            Value::Text("x".to_string())
+        );
+        assert!(!eval_predicate(&parse_expr("name <> 'Bob'"), &schema(), &row).unwrap());
+    }
+
+    #[test]
+    fn test_eval_null_functions() {
+        let row = [Value::Long(5), Value::Null];
+        let text = |s: &str| Value::Text(s.to_string());
+
+        assert_eq!(eval("COALESCE(name, id)", &row).unwrap(), Value::Long(5));
+        assert_eq!(
+            eval("COALESCE(name, NULL, 'nobody')", &row).unwrap(),
+            text("nobody")
+        );
+        assert_eq!(eval("COALESCE(NULL, name)", &row).unwrap(), Value::Null);
+        // Arguments after the first non-NULL one are never evaluated
+        assert_eq!(
+            eval("COALESCE(id, NO_SUCH_FN())", &row).unwrap(),
+            Value::Long(5)
+        );
+
+        assert_eq!(eval("NULLIF(id, 5)", &row).unwrap(), Value::Null);
+        assert_eq!(eval("NULLIF(id, 6)", &row).unwrap(), Value::Long(5));
+        assert_eq!(eval("NULLIF(name, 'x')", &row).unwrap(), Value::Null);
+        assert!(matches!(eval("NULLIF(id)", &row), Err(ExecError::Type(_))));
+    }
+
+    #[test]
+    fn test_eval_concatenation() {
+        let row = [Value::Long(5), Value::Text("Alice".to_string())];
+        let text = |s: &str| Value::Text(s.to_string());
+
+        assert_eq!(eval("name || ' #' || id", &row).unwrap(), text("Alice #5"));
+        assert_eq!(
+            eval("CONCAT(name, '-', id, '-', true)", &row).unwrap(),
+            text("Alice-5-true")
+        );
+        assert_eq!(eval("concat()", &row).unwrap(), text(""));
+        assert!(matches!(
+            eval("NO_SUCH_FN(name)", &row),
+            Err(ExecError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn test_eval_in_list() {
+        let row = [Value::Long(5), Value::Null];
+
+        assert_eq!(eval("id IN (1, 5)", &row).unwrap(), Value::Bool(true));
+        assert_eq!(eval("id NOT IN (1, 2)", &row).unwrap(), Value::Bool(true));
+        assert_eq!(eval("id IN (5.0, NULL)", &row).unwrap(), Value::Bool(true));
+        // Without a match, a NULL in the list makes the answer unknown
+        assert_eq!(eval("id IN (1, NULL)", &row).unwrap(), Value::Null);
+        assert_eq!(eval("id NOT IN (1, NULL)", &row).unwrap(), Value::Null);
+        assert_eq!(eval("name IN ('a')", &row).unwrap(), Value::Null);
+    }
+
+    #[test]
+    fn test_eval_casts() {
+        let row = [Value::Long(5), Value::Text(" 42 ".to_string())];
+        let text = |s: &str| Value::Text(s.to_string());
+        let dec = |s: &str| Value::Decimal(Decimal::parse(s).unwrap());
+
+        assert_eq!(eval("CAST(id AS TEXT)", &row).unwrap(), text("5"));
+        assert_eq!(eval("CAST(name AS INT)", &row).unwrap(), Value::Long(42));
+        assert_eq!(eval("name::BIGINT + 1", &row).unwrap(), Value::Long(43));
+        assert_eq!(eval("CAST(2.5 AS INT)", &row).unwrap(), Value::Long(3));
+        assert_eq!(
+            eval("CAST(id AS DECIMAL(4, 2))", &row).unwrap(),
+            dec("5.00")
+        );
+        assert_eq!(
+            eval("CAST('-1.005' AS NUMERIC(5, 2))", &row).unwrap(),
+            Value::Decimal(Decimal::new(-101, 2))
+        );
+        assert_eq!(
+            eval("CAST(true AS VARCHAR(5))", &row).unwrap(),
+            text("true")
+        );
+        assert_eq!(eval("CAST(true AS INT)", &row).unwrap(), Value::Long(1));
+        assert_eq!(
+            eval("CAST('No' AS BOOLEAN)", &row).unwrap(),
+            Value::Bool(false)
+        );
+        assert_eq!(eval("CAST(0 AS BOOL)", &row).unwrap(), Value::Bool(false));
+        assert_eq!(
+            eval("CAST('hi' AS BLOB)", &row).unwrap(),
+            Value::Blob(b"hi".to_vec())
+        );
+        assert_eq!(eval("CAST(NULL AS INT)", &row).unwrap(), Value::Null);
+    }
+
+    #[test]
+    fn test_invalid_casts() {
+        let row = [Value::Long(5), Value::Text("abc".to_string())];
+
+        assert!(matches!(
+            eval("CAST(name AS INT)", &row),
+            Err(ExecError::Type(e)) if e == "Cannot cast text 'abc' to INT"
+        ));
+        assert!(matches!(
+            eval("CAST('1.5' AS INT)", &row),
+            Err(ExecError::Type(_))
+        ));
+        assert!(matches!(
+            eval("CAST(1000 AS DECIMAL(4, 2))", &row),
+            Err(ExecError::Type(_))
+        ));
+        assert!(matches!(
+            eval("CAST(X'01' AS INT)", &row),
+            Err(ExecError::Type(_))
+        ));
+        assert!(matches!(
+            eval("CAST(id AS FLOAT)", &row),
+            Err(ExecError::Unsupported(_))
+        ));
+    }
+}
@@ -0,0 +1,168 @@
+use crate::database::{RowId, Value};
+use std::collections::BTreeMap;
+
+/// An in-memory secondary index over one or more columns of a table.
+///
+/// Keys are the tuple of indexed column values, in index column order.
+/// Because keys are ordered lexicographically, every key sharing a given
+/// prefix is stored contiguously, so a lookup on the leading columns of a
+/// composite index is a single range scan.
+///
+/// Only the definition (name, table, columns, uniqueness) is persisted in the
+/// catalog; the entries are rebuilt from the table data when the database is
+/// opened.
+pub struct Index {
+    pub name: String,
+    pub table: String,
+    pub columns: Vec<String>,
+    /// `CREATE UNIQUE INDEX`: no two rows may share a key, unless it holds a NULL
+    pub unique: bool,
+    entries: BTreeMap<Vec<Value>, Vec<RowId>>,
+}
+
+impl Index {
+    pub fn new(name: String, table: String, columns: Vec<String>) -> Self {
+        Self {
+            name,
+            table,
+            columns,
+            unique: false,
+            entries: BTreeMap::new(),
+        }
+    }
+
+    /// An empty index with the same definition, to be repopulated
+    pub fn definition(&self) -> Self {
+        Self {
+            unique: self.unique,
+            ..Self::new(self.name.clone(), self.table.clone(), self.columns.clone())
+        }
+    }
+
+    /// Add a row's key to the index
+    pub fn insert(&mut self, key: Vec<Value>, row_id: RowId) {
+        self.entries.entry(key).or_default().push(row_id);
+    }
+
+    /// Drop a row's entry from the index
+    pub fn remove(&mut self, key: &[Value], row_id: RowId) {
+        if let Some(row_ids) = self.entries.get_mut(key) {
+            row_ids.retain(|&r| r != row_id);
+            if row_ids.is_empty() {
+                self.entries.remove(key);
+            }
+        }
+    }
+
+    /// Find all rows whose key starts with `prefix`.
+    /// Passing a value for every indexed column performs a full-key lookup.
+    pub fn lookup(&self, prefix: &[Value]) -> Vec<RowId> {
+        if prefix.len() == self.columns.len() {
+            return self.entries.get(prefix).cloned().unwrap_or_default();
+        }
+
+        self.entries
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .flat_map(|(_, row_ids)| row_ids.iter().copied())
+            .collect()
+    }
+
+    /// Every distinct key, in order
+    pub fn keys(&self) -> impl Iterator<Item = &[Value]> {
+        self.entries.keys().map(Vec::as_slice)
+    }
+
+    /// Number of rows in the index
+    pub fn entry_count(&self) -> usize {
+        self.entries.values().map(Vec::len).sum()
+    }
+
+    /// The first key shared by more than one row. Keys holding a NULL don't
+    /// count, since NULLs never equal each other.
+    pub fn duplicate_key(&self) -> Option<&[Value]> {
+        self.entries
+            .iter()
+            .find(|(key, row_ids)| row_ids.len() > 1 && !key.contains(&Value::Null))
+            .map(|(key, _)| key.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rid(slot_id: u16) -> RowId {
+        RowId {
+            page_id: 0,
+            slot_id,
+        }
+    }
+
+    fn key(dept: &str, level: i64) -> Vec<Value> {
+        vec![Value::Text(dept.to_string()), Value::Long(level)]
+    }
+
+    fn sample_index() -> Index {
+        let mut index = Index::new(
+            "idx".to_string(),
+            "employees".to_string(),
+            vec!["dept".to_string(), "level".to_string()],
+        );
+        index.insert(key("eng", 1), rid(0));
+        index.insert(key("eng", 3), rid(1));
+        index.insert(key("ops", 3), rid(2));
+        index.insert(key("eng", 3), rid(3));
+        index
+    }
+
+    #[test]
+    fn test_full_key_lookup() {
+        let index = sample_index();
+
+        assert_eq!(index.lookup(&key("eng", 3)), vec![rid(1), rid(3)]);
+        assert_eq!(index.lookup(&key("ops", 3)), vec![rid(2)]);
+        assert!(index.lookup(&key("ops", 1)).is_empty());
+    }
+
+    #[test]
+    fn test_prefix_lookup() {
+        let index = sample_index();
+
+        let eng = index.lookup(&[Value::Text("eng".to_string())]);
+        assert_eq!(eng, vec![rid(0), rid(1), rid(3)]);
+
+        let missing = index.lookup(&[Value::Text("hr".to_string())]);
+        assert!(missing.is_empty());
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut index = sample_index();
+
+        index.remove(&key("eng", 3), rid(1));
+        assert_eq!(index.lookup(&key("eng", 3)), vec![rid(3)]);
+
+        index.remove(&key("ops", 3), rid(2));
+        assert!(index.lookup(&[Value::Text("ops".to_string())]).is_empty());
+
+        // Removing an entry that isn't there is a no-op
+        index.remove(&key("hr", 1), rid(0));
+        assert_eq!(index.lookup(&[Value::Text("eng".to_string())]).len(), 2);
+    }
+
+    #[test]
+    fn test_duplicate_key() {
+        let mut index = sample_index();
+        assert_eq!(index.duplicate_key(), Some(key("eng", 3).as_slice()));
+
+        index.remove(&key("eng", 3), rid(3));
+        assert_eq!(index.duplicate_key(), None);
+
+        // Keys with a NULL are never duplicates
+        let null_key = vec![Value::Text("hr".to_string()), Value::Null];
+        index.insert(null_key.clone(), rid(4));
+        index.insert(null_key, rid(5));
+        assert_eq!(index.duplicate_key(), None);
+    }
+}
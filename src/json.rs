@@ -0,0 +1,389 @@
+use std::fmt;
+
+/// A parsed JSON document, as read by `json_extract`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    /// Kept as written, so `1.50` comes back out as `1.50`
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    /// Members in document order. A repeated key finds its first value.
+    Object(Vec<(String, Json)>),
+}
+
+/// One step of a path: `.name` or `[index]`
+#[derive(Debug, PartialEq)]
+enum Step {
+    Key(String),
+    Index(usize),
+}
+
+impl Json {
+    pub fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = Parser { text, pos: 0 };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.pos < text.len() {
+            return Err(parser.error("end of the document"));
+        }
+        Ok(value)
+    }
+
+    /// The value at `path`, or `None` if the document has nothing there.
+    /// Paths start at the whole document, `$`, and step into objects with
+    /// `.name` and into arrays with `[index]`: `$.items[0].name`. The `$.` can
+    /// be left off (`items[0].name`).
+    pub fn lookup(&self, path: &str) -> Result<Option<&Json>, String> {
+        let mut value = self;
+        for step in parse_path(path)? {
+            let next = match (&step, value) {
+                (Step::Key(key), Json::Object(members)) => {
+                    members.iter().find(|(name, _)| name == key).map(|(_, v)| v)
+                }
+                (Step::Index(index), Json::Array(items)) => items.get(*index),
+                _ => None,
+            };
+            match next {
+                Some(next) => value = next,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(value))
+    }
+}
+
+fn parse_path(path: &str) -> Result<Vec<Step>, String> {
+    let invalid = |why: &str| format!("Invalid JSON path '{}': {}", path, why);
+    let rest = match path.strip_prefix('$') {
+        Some(rest) => rest,
+        // A bare `name...` reads as `$.name...`
+        None if !path.is_empty() && !path.starts_with(['.', '[']) => {
+            return parse_steps(path, true).map_err(|why| invalid(&why));
+        }
+        None => return Err(invalid("expected it to start with '$'")),
+    };
+    parse_steps(rest, false).map_err(|why| invalid(&why))
+}
+
+/// The steps of a path after its `$`; `leading_key` if it starts with a
+/// key that has no `.` before it
+fn parse_steps(mut rest: &str, leading_key: bool) -> Result<Vec<Step>, String> {
+    let mut steps = Vec::new();
+    let mut expect_key = leading_key;
+    loop {
+        if !expect_key {
+            if let Some(after) = rest.strip_prefix('.') {
+                rest = after;
+                expect_key = true;
+                continue;
+            }
+            let Some(after) = rest.strip_prefix('[') else {
+                if rest.is_empty() {
+                    return Ok(steps);
+                }
+                return Err(format!("unexpected '{}'", rest));
+            };
+            let (index, after) = after
+                .split_once(']')
+                .ok_or_else(|| "'[' without a closing ']'".to_string())?;
+            let index = index
+                .trim()
+                .parse()
+                .map_err(|_| format!("'{}' isn't an array index", index))?;
+            steps.push(Step::Index(index));
+            rest = after;
+            continue;
+        }
+
+        let end = rest.find(['.', '[']).unwrap_or(rest.len());
+        if end == 0 {
+            return Err("expected a key after '.'".to_string());
+        }
+        steps.push(Step::Key(rest[..end].to_string()));
+        rest = &rest[end..];
+        expect_key = false;
+    }
+}
+
+impl fmt::Display for Json {
+    /// Compact JSON text
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(s) => write_string(f, s),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(members) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    /// Byte offset of the next character
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, expected: &str) -> String {
+        match self.peek() {
+            Some(c) => format!("expected {} at byte {}, found '{}'", expected, self.pos, c),
+            None => format!("expected {}, found the end of the text", expected),
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = &self.text[self.pos..];
+        self.pos += rest.len() - rest.trim_start_matches([' ', '\t', '\n', '\r']).len();
+    }
+
+    /// Consume `literal` if the text continues with it
+    fn eat(&mut self, literal: &str) -> bool {
+        if self.text[self.pos..].starts_with(literal) {
+            self.pos += literal.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('{') => self.object(),
+            Some('[') => self.array(),
+            Some('"') => self.string().map(Json::String),
+            Some('-' | '0'..='9') => self.number(),
+            _ if self.eat("true") => Ok(Json::Bool(true)),
+            _ if self.eat("false") => Ok(Json::Bool(false)),
+            _ if self.eat("null") => Ok(Json::Null),
+            _ => Err(self.error("a JSON value")),
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+        self.pos += 1;
+        let mut members = Vec::new();
+        self.skip_whitespace();
+        if self.eat("}") {
+            return Ok(Json::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some('"') {
+                return Err(self.error("a key"));
+            }
+            let key = self.string()?;
+            self.skip_whitespace();
+            if !self.eat(":") {
+                return Err(self.error("':'"));
+            }
+            members.push((key, self.value()?));
+            self.skip_whitespace();
+            if self.eat("}") {
+                return Ok(Json::Object(members));
+            }
+            if !self.eat(",") {
+                return Err(self.error("',' or '}'"));
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, String> {
+        self.pos += 1;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.eat("]") {
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            if self.eat("]") {
+                return Ok(Json::Array(items));
+            }
+            if !self.eat(",") {
+                return Err(self.error("',' or ']'"));
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.pos;
+        let digits = |parser: &mut Self| {
+            let rest = &parser.text[parser.pos..];
+            let len = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+            parser.pos += len;
+            len
+        };
+        self.eat("-");
+        if !self.eat("0") && digits(self) == 0 {
+            return Err(self.error("a digit"));
+        }
+        if self.eat(".") && digits(self) == 0 {
+            return Err(self.error("a digit"));
+        }
+        if (self.eat("e") || self.eat("E")) && {
+            let _ = self.eat("+") || self.eat("-");
+            digits(self) == 0
+        } {
+            return Err(self.error("a digit"));
+        }
+        Ok(Json::Number(self.text[start..self.pos].to_string()))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.pos += 1;
+        let mut result = String::new();
+        loop {
+            let Some(c) = self.peek() else {
+                return Err(self.error("'\"'"));
+            };
+            self.pos += c.len_utf8();
+            match c {
+                '"' => return Ok(result),
+                '\\' => result.push(self.escape()?),
+                c if (c as u32) < 0x20 => {
+                    return Err(format!(
+                        "control character in a string at byte {}",
+                        self.pos - 1
+                    ));
+                }
+                c => result.push(c),
+            }
+        }
+    }
+
+    /// The character a `\` escape stands for, the `\` already consumed
+    fn escape(&mut self) -> Result<char, String> {
+        let Some(c) = self.peek() else {
+            return Err(self.error("an escape"));
+        };
+        self.pos += c.len_utf8();
+        Ok(match c {
+            '"' => '"',
+            '\\' => '\\',
+            '/' => '/',
+            'b' => '\u{8}',
+            'f' => '\u{c}',
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            'u' => {
+                let mut code = self.hex4()?;
+                // Characters outside the BMP are a pair of surrogate escapes
+                if (0xd800..0xdc00).contains(&code) && self.eat("\\u") {
+                    let low = self.hex4()?;
+                    if (0xdc00..0xe000).contains(&low) {
+                        code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                    }
+                }
+                // A lone surrogate isn't a character
+                char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER)
+            }
+            _ => return Err(format!("invalid escape '\\{}' at byte {}", c, self.pos - 2)),
+        })
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let hex = self.text.get(self.pos..self.pos + 4).unwrap_or("");
+        let unit = u32::from_str_radix(hex, 16).map_err(|_| self.error("four hex digits"))?;
+        self.pos += 4;
+        Ok(unit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_display() {
+        let text = r#" {"name": "Ann \"A\"", "tags": ["x", 1.50, -2e3, true, null], "empty": {}} "#;
+        let json = Json::parse(text).unwrap();
+        assert_eq!(
+            json.to_string(),
+            r#"{"name":"Ann \"A\"","tags":["x",1.50,-2e3,true,null],"empty":{}}"#
+        );
+        assert_eq!(
+            Json::parse(r#""caf\u00e9 \ud83d\ude00""#).unwrap(),
+            Json::String("café 😀".to_string())
+        );
+
+        for (text, error) in [
+            ("", "expected a JSON value, found the end"),
+            ("{\"a\" 1}", "expected ':' at byte 5"),
+            ("[1, 2", "expected ',' or ']', found the end"),
+            ("[1] x", "expected end of the document at byte 4"),
+            ("01", "expected end of the document at byte 1"),
+            ("{'a': 1}", "expected a key at byte 1"),
+            ("\"\\x\"", "invalid escape"),
+        ] {
+            let err = Json::parse(text).unwrap_err();
+            assert!(err.contains(error), "{:?}: {}", text, err);
+        }
+    }
+
+    #[test]
+    fn test_lookup() {
+        let json = Json::parse(r#"{"user": {"name": "Ann", "pets": [{"kind": "cat"}]}}"#).unwrap();
+        let lookup = |path| json.lookup(path).unwrap().map(|v| v.to_string());
+
+        assert_eq!(lookup("$.user.name").as_deref(), Some("\"Ann\""));
+        assert_eq!(lookup("user.pets[0].kind").as_deref(), Some("\"cat\""));
+        assert_eq!(
+            lookup("$.user.pets[0]").as_deref(),
+            Some("{\"kind\":\"cat\"}")
+        );
+        assert_eq!(lookup("$").unwrap(), json.to_string());
+        for missing in ["$.age", "$.user.pets[1]", "$.user.name.first", "$[0]"] {
+            assert_eq!(lookup(missing), None, "{}", missing);
+        }
+
+        for path in ["", ".user", "$user", "$.", "$.user[x]", "$.pets[0"] {
+            assert!(json.lookup(path).is_err(), "{}", path);
+        }
+    }
+}
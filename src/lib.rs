@@ -1,6 +1,20 @@
+pub mod aggregate;
 pub mod buffer;
+pub mod client;
+pub mod copy;
 pub mod database;
+pub mod decimal;
+pub mod dialect;
+pub mod dump;
 pub mod executor;
+pub mod expr;
+pub mod index;
+pub mod json;
+pub mod logging;
 pub mod protocol;
+pub mod result;
+pub mod sample;
 pub mod slotted_page;
+pub mod sort;
+pub mod stats;
 pub mod storage;
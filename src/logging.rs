@@ -0,0 +1,151 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How severe a log line is. As the configured level, the least severe kind
+/// of line that's printed: `Info` prints errors, warnings and info lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    /// Something failed
+    Error,
+    /// Something went wrong but was worked around
+    Warn,
+    /// Startup, shutdown and connection events
+    Info,
+    /// Every query as well
+    Debug,
+}
+
+/// The level set with `set_level`, as a `LogLevel` discriminant
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Debug as u8);
+
+impl LogLevel {
+    /// The level named on the command line. `off` is kept from before
+    /// warnings had a level of their own, and only leaves errors.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "error" | "off" => Some(LogLevel::Error),
+            "warn" => Some(LogLevel::Warn),
+            "info" => Some(LogLevel::Info),
+            "debug" => Some(LogLevel::Debug),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LogLevel::Error => "ERROR",
+            LogLevel::Warn => "WARN",
+            LogLevel::Info => "INFO",
+            LogLevel::Debug => "DEBUG",
+        }
+    }
+
+    fn from_u8(level: u8) -> Self {
+        match level {
+            0 => LogLevel::Error,
+            1 => LogLevel::Warn,
+            2 => LogLevel::Info,
+            _ => LogLevel::Debug,
+        }
+    }
+}
+
+/// Set the least severe kind of line the process logs
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Whether a line at `level` is printed at the configured level
+pub fn enabled(level: LogLevel) -> bool {
+    level <= LogLevel::from_u8(LEVEL.load(Ordering::Relaxed))
+}
+
+/// Log a line at `level`: errors and warnings to stderr, the rest to stdout
+pub fn log(level: LogLevel, message: fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+    let line = format_line(SystemTime::now(), level, message);
+    if level <= LogLevel::Warn {
+        eprintln!("{}", line);
+    } else {
+        println!("{}", line);
+    }
+}
+
+/// One log line: the UTC time to the millisecond, the level, and the
+/// message, as in `2024-05-01T12:00:00.250Z INFO Client connected`
+pub fn format_line(time: SystemTime, level: LogLevel, message: fmt::Arguments) -> String {
+    format!("{} {} {}", format_timestamp(time), level.name(), message)
+}
+
+/// An RFC 3339 UTC timestamp with milliseconds. Times before 1970 print as
+/// the epoch.
+fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let seconds_of_day = secs % 86_400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// The (year, month, day) of a count of days since 1970-01-01, in the
+/// proleptic Gregorian calendar (Howard Hinnant's `civil_from_days`)
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_format_line() {
+        // 2024-02-29 is a leap day
+        let time = UNIX_EPOCH + Duration::from_millis(1_709_210_096_250);
+        let line = format_line(time, LogLevel::Warn, format_args!("Disk {} full", "is"));
+        assert_eq!(line, "2024-02-29T12:34:56.250Z WARN Disk is full");
+
+        let line = format_line(UNIX_EPOCH, LogLevel::Info, format_args!("Started"));
+        assert_eq!(line, "1970-01-01T00:00:00.000Z INFO Started");
+        let time = UNIX_EPOCH + Duration::from_secs(951_868_800);
+        assert!(format_line(time, LogLevel::Error, format_args!("x")).starts_with("2000-03-01T"));
+    }
+
+    #[test]
+    fn test_log_levels() {
+        assert_eq!(LogLevel::from_name("DEBUG"), Some(LogLevel::Debug));
+        assert_eq!(LogLevel::from_name("off"), Some(LogLevel::Error));
+        assert_eq!(LogLevel::from_name("verbose"), None);
+        assert!(LogLevel::Error < LogLevel::Warn && LogLevel::Info < LogLevel::Debug);
+        for level in [
+            LogLevel::Error,
+            LogLevel::Warn,
+            LogLevel::Info,
+            LogLevel::Debug,
+        ] {
+            assert_eq!(LogLevel::from_u8(level as u8), level);
+        }
+    }
+}
@@ -1,52 +1,208 @@
 use rustyline::DefaultEditor;
 use rustyline::error::ReadlineError;
-use sqlparser::dialect::GenericDialect;
 use sqlparser::parser::Parser;
 use std::env;
+use std::io::Read;
+use std::time::Duration;
 
+mod aggregate;
 mod buffer;
+mod copy;
 mod database;
+mod decimal;
+mod dialect;
+mod dump;
 mod executor;
+mod expr;
+mod index;
+mod json;
+mod logging;
 mod protocol;
+mod result;
+mod sample;
 mod server;
 mod slotted_page;
+mod sort;
+mod stats;
 mod storage;
 
 use database::Database;
-use executor::execute;
-use server::Server;
+use dialect::MatiDialect;
+use executor::{execute_reindex, execute_with_stats};
+use logging::LogLevel;
+use server::{Heartbeat, Server};
 
 fn main() -> std::io::Result<()> {
     let args: Vec<String> = env::args().collect();
 
     // Check if we should run in server mode
     if args.len() > 1 && args[1] == "--server" {
-        let addr = if args.len() > 2 {
-            args[2].as_str()
-        } else {
-            "127.0.0.1:5432"
+        let mut positional = Vec::new();
+        let mut init_file = None;
+        let mut abort_on_error = false;
+        let mut log_level = LogLevel::Debug;
+        let mut admin_token = None;
+        let mut verify_writes = false;
+        let mut socket_path = None;
+        let mut heartbeat = None;
+        let mut rest = args[2..].iter();
+        while let Some(arg) = rest.next() {
+            match arg.as_str() {
+                "--init-file" => match rest.next() {
+                    Some(path) => init_file = Some(path.as_str()),
+                    None => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            "--init-file needs a path",
+                        ));
+                    }
+                },
+                "--listen-unix" => match rest.next() {
+                    Some(path) => socket_path = Some(path.as_str()),
+                    None => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            "--listen-unix needs a socket path",
+                        ));
+                    }
+                },
+                "--init-abort-on-error" => abort_on_error = true,
+                "--quiet" => log_level = LogLevel::Error,
+                "--verify-writes" => verify_writes = true,
+                "--admin-token" => match rest.next() {
+                    Some(token) => admin_token = Some(token.clone()),
+                    None => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            "--admin-token needs a token",
+                        ));
+                    }
+                },
+                "--heartbeat" => match rest.next().and_then(|s| s.parse().ok()) {
+                    Some(seconds) if seconds > 0 => {
+                        // The client gets as long to answer as it was idle
+                        let interval = Duration::from_secs(seconds);
+                        heartbeat = Some(Heartbeat {
+                            interval,
+                            timeout: interval,
+                        });
+                    }
+                    _ => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            "--heartbeat needs a number of seconds",
+                        ));
+                    }
+                },
+                "--log-level" => match rest.next().and_then(|l| LogLevel::from_name(l)) {
+                    Some(level) => log_level = level,
+                    None => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            "--log-level needs one of error, warn, info or debug",
+                        ));
+                    }
+                },
+                _ => positional.push(arg.as_str()),
+            }
+        }
+
+        let (addr, db_path) = match socket_path {
+            // The socket takes the place of the address
+            Some(_) => (None, positional.first()),
+            None => (positional.first(), positional.get(1)),
         };
+        logging::set_level(log_level);
+        let addr = addr.copied().unwrap_or("127.0.0.1:5432");
+        let db_path = db_path.copied().unwrap_or("mati.db");
 
-        let db_path = if args.len() > 3 {
-            args[3].as_str()
+        let db = if verify_writes {
+            Database::new_verifying_writes(db_path)?
         } else {
-            "mati.db"
+            Database::new(db_path)?
         };
-
-        let server = Server::new(addr, db_path)?;
+        let mut server = match socket_path {
+            #[cfg(unix)]
+            Some(path) => Server::with_database_on_socket(path, db_path, db)?,
+            #[cfg(not(unix))]
+            Some(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "--listen-unix needs a Unix platform",
+                ));
+            }
+            None => Server::with_database(addr, db_path, db)?,
+        };
+        server.set_admin_token(admin_token);
+        server.set_heartbeat(heartbeat);
+        if let Some(path) = init_file {
+            server.run_init_file(path, abort_on_error)?;
+        }
         return server.run();
     }
 
-    // Run in interactive mode
-    run_interactive()
+    // Logical backup: print SQL that recreates the database
+    if args.len() > 2 && args[1] == "dump" {
+        let db = Database::new(&args[2])?;
+        let sql = dump::dump(&db).map_err(std::io::Error::other)?;
+        print!("{}", sql);
+        return Ok(());
+    }
+
+    // Replay a dump from a file (or stdin) into a database
+    if args.len() > 2 && args[1] == "restore" {
+        let sql = match args.get(3) {
+            Some(path) => std::fs::read_to_string(path)?,
+            None => {
+                let mut sql = String::new();
+                std::io::stdin().read_to_string(&mut sql)?;
+                sql
+            }
+        };
+
+        let mut db = Database::new(&args[2])?;
+        let count = dump::restore(&mut db, &sql).map_err(std::io::Error::other)?;
+        db.buffer_pool.borrow_mut().flush_all()?;
+        db.save_catalog()?;
+        println!("Restored {} statement(s)", count);
+        return Ok(());
+    }
+
+    // Recover tables from page chains the catalog doesn't know about
+    if args.len() > 2 && args[1] == "repair" {
+        let mut db = Database::new(&args[2])?;
+        let report = db.repair()?;
+        if report.is_empty() {
+            println!("No unclaimed page chains found");
+        }
+        for line in report {
+            println!("{}", line);
+        }
+        db.save_catalog()?;
+        return Ok(());
+    }
+
+    // Run in interactive mode, optionally without touching disk
+    let in_memory = args.len() > 1 && args[1] == "--memory";
+    let verify_writes = args.len() > 1 && args[1] == "--verify-writes";
+    run_interactive(in_memory, verify_writes)
 }
 
-fn run_interactive() -> std::io::Result<()> {
-    // Initialize database with file storage
-    let mut db = Database::new("mati.db").expect("Failed to initialize database");
+fn run_interactive(in_memory: bool, verify_writes: bool) -> std::io::Result<()> {
+    let mut db = if in_memory {
+        println!("Running in memory: nothing will be saved to disk");
+        Database::in_memory()
+    } else if verify_writes {
+        println!("Verifying writes: every page written is read back and compared");
+        Database::new_verifying_writes("mati.db").expect("Failed to initialize database")
+    } else {
+        // Initialize database with file storage
+        Database::new("mati.db").expect("Failed to initialize database")
+    };
 
     let mut rl = DefaultEditor::new().map_err(std::io::Error::other)?;
-    let dialect = GenericDialect {};
+    let dialect = MatiDialect {};
+    let mut show_stats = false;
 
     println!("MatiDB v0.2.0 - Now with persistent storage!");
     println!("Type 'exit' to quit, 'tables' to list tables\n");
@@ -65,9 +221,28 @@ fn run_interactive() -> std::io::Result<()> {
 
                 match sql.to_lowercase().as_str() {
                     "exit" | "quit" => {
+                        // An unfinished transaction is abandoned
+                        if let Err(e) = db.rollback_transaction() {
+                            logging::log(
+                                LogLevel::Warn,
+                                format_args!("Failed to roll back transaction: {}", e),
+                            );
+                        }
+
+                        // Give back space held by unused trailing pages
+                        if let Err(e) = db.trim_trailing_pages() {
+                            logging::log(
+                                LogLevel::Warn,
+                                format_args!("Failed to trim unused pages: {}", e),
+                            );
+                        }
+
                         // Flush all pages before exit
                         if let Err(e) = db.buffer_pool.borrow_mut().flush_all() {
-                            eprintln!("Warning: Failed to flush pages: {}", e);
+                            logging::log(
+                                LogLevel::Warn,
+                                format_args!("Failed to flush pages: {}", e),
+                            );
                         }
                         println!("Goodbye!");
                         break;
@@ -89,14 +264,70 @@ fn run_interactive() -> std::io::Result<()> {
                         }
                         continue;
                     }
+                    "check" => {
+                        match db.check_pages() {
+                            Ok(problems) if problems.is_empty() => println!("All pages OK"),
+                            Ok(problems) => {
+                                for problem in problems {
+                                    println!("  {}", problem);
+                                }
+                            }
+                            Err(e) => eprintln!("Error checking pages: {}", e),
+                        }
+                        continue;
+                    }
+                    "stats" => {
+                        show_stats = !show_stats;
+                        println!("Query stats {}", if show_stats { "on" } else { "off" });
+                        continue;
+                    }
+                    command if command.starts_with("page ") => {
+                        match command["page ".len()..].trim().parse() {
+                            Ok(page_id) => match db.inspect_page(page_id) {
+                                Ok(description) => println!("{}", description),
+                                Err(e) => eprintln!("Error: {}", e),
+                            },
+                            Err(_) => eprintln!("Usage: page <id>"),
+                        }
+                        continue;
+                    }
                     _ => {}
                 }
 
+                if protocol::is_copy_from_stdin(sql) {
+                    // The rows follow, one CSV line per prompt, up to `\.`
+                    let mut lines = Vec::new();
+                    while let Ok(line) = rl.readline("") {
+                        if line == protocol::END_OF_DATA {
+                            break;
+                        }
+                        lines.push(line);
+                    }
+                    match copy::copy_from_stdin(&mut db, sql, &lines.join("\n")) {
+                        Ok(msg) => println!("{}", msg),
+                        Err(e) => eprintln!("Error: {}", e),
+                    }
+                    continue;
+                }
+
+                if let Some(result) = execute_reindex(&mut db, sql) {
+                    match result {
+                        Ok(msg) => println!("{}", msg),
+                        Err(e) => eprintln!("Error: {}", e),
+                    }
+                    continue;
+                }
+
                 match Parser::parse_sql(&dialect, sql) {
                     Ok(statements) => {
                         for stmt in statements {
-                            match execute(&mut db, stmt) {
-                                Ok(msg) => println!("{}", msg),
+                            match execute_with_stats(&mut db, stmt) {
+                                Ok((msg, stats)) => {
+                                    println!("{}", msg);
+                                    if show_stats {
+                                        println!("{}", stats);
+                                    }
+                                }
                                 Err(e) => eprintln!("Error: {}", e),
                             }
                         }
@@ -110,8 +341,20 @@ fn run_interactive() -> std::io::Result<()> {
                 println!("^C");
             }
             Err(ReadlineError::Eof) => {
+                if let Err(e) = db.rollback_transaction() {
+                    logging::log(
+                        LogLevel::Warn,
+                        format_args!("Failed to roll back transaction: {}", e),
+                    );
+                }
+                if let Err(e) = db.trim_trailing_pages() {
+                    logging::log(
+                        LogLevel::Warn,
+                        format_args!("Failed to trim unused pages: {}", e),
+                    );
+                }
                 if let Err(e) = db.buffer_pool.borrow_mut().flush_all() {
-                    eprintln!("Warning: Failed to flush pages: {}", e);
+                    logging::log(LogLevel::Warn, format_args!("Failed to flush pages: {}", e));
                 }
                 println!("Goodbye!");
                 break;
@@ -1,27 +1,127 @@
+use crate::executor::ExecError;
 use std::io::{BufRead, BufReader, Read, Write};
 
 /// Protocol for client-server communication
 /// Messages are simple newline-delimited text
 ///
 /// Client sends: SQL command (one line)
-/// Server responds: "OK\n<result>\nEND\n" or "ERROR\n<message>\nEND\n"
+/// Server responds: "OK\n<result>\nEND\n" or "ERROR:<CODE>\n<message>\nEND\n"
+///
+/// The error code is optional on the wire: a bare "ERROR" line is still
+/// accepted and parsed as `ErrorCode::Unknown`.
+///
+/// Every request gets exactly one response. A blank query (or one that is
+/// only comments) is answered with `OK` and `EMPTY_QUERY`.
+///
+/// After a `COPY ... FROM STDIN` query, the client sends the rows to copy as
+/// CSV lines, then a line holding just `\.`, and gets one response for the
+/// whole copy.
+///
+/// A server started with a heartbeat sends a "PING" line when a client has
+/// been idle for a while, outside any response. The client answers with a
+/// "PONG" line, which gets no response, and is dropped if it doesn't answer
+/// in time.
+///
+/// A client may start by sending "HANDSHAKE <version>" with the newest
+/// protocol version it speaks. The server answers "OK\nPROTOCOL <version>"
+/// with the version the connection will use, which is lower if the server
+/// is older, or an `UNSUPPORTED` error and closes the connection if it can't
+/// speak that version. A client that skips the handshake gets version 1.
 
 #[derive(Debug)]
 pub enum Response {
     Ok(String),
-    Error(String),
+    Error { code: ErrorCode, message: String },
+}
+
+/// Result message for a query with no statements in it
+pub const EMPTY_QUERY: &str = "Empty query";
+
+/// Newest protocol version this build speaks
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest protocol version this build still speaks, and the one used by
+/// clients that don't send a handshake
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// The line a server sends to check that an idle client is still there
+pub const PING: &str = "PING";
+
+/// A client's answer to `PING`
+pub const PONG: &str = "PONG";
+
+/// The line a client sends to ask for protocol `version`
+#[allow(dead_code)]
+pub fn handshake(version: u32) -> String {
+    format!("HANDSHAKE {}", version)
+}
+
+/// The version asked for by a handshake line, `None` if the line isn't one
+/// and an error if its version isn't a number
+pub fn parse_handshake(line: &str) -> Option<Result<u32, String>> {
+    let (command, version) = line.split_once(' ').unwrap_or((line, ""));
+    if !command.eq_ignore_ascii_case("handshake") {
+        return None;
+    }
+    Some(
+        version
+            .trim()
+            .parse()
+            .map_err(|_| "Usage: HANDSHAKE <version>".to_string()),
+    )
+}
+
+/// The version to use with a client that asked for `requested`: the newest
+/// one both sides speak
+pub fn negotiate_version(requested: u32) -> Result<u32, String> {
+    if requested < MIN_PROTOCOL_VERSION {
+        return Err(format!(
+            "Protocol version {} is not supported; this server speaks versions {} to {}",
+            requested, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        ));
+    }
+    Ok(requested.min(PROTOCOL_VERSION))
+}
+
+/// The version in the server's answer to a handshake
+#[allow(dead_code)]
+pub fn parse_handshake_reply(reply: &str) -> Option<u32> {
+    reply.strip_prefix("PROTOCOL ")?.trim().parse().ok()
 }
 
 #[allow(dead_code)]
 impl Response {
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        Response::Error {
+            code,
+            message: message.into(),
+        }
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         match self {
             Response::Ok(msg) => format!("OK\n{}\nEND\n", msg).into_bytes(),
-            Response::Error(msg) => format!("ERROR\n{}\nEND\n", msg).into_bytes(),
+            Response::Error {
+                code: ErrorCode::Unknown,
+                message,
+            } => format!("ERROR\n{}\nEND\n", message).into_bytes(),
+            Response::Error { code, message } => {
+                format!("ERROR:{}\n{}\nEND\n", code.as_str(), message).into_bytes()
+            }
         }
     }
 
     pub fn from_reader<R: Read>(reader: &mut BufReader<R>) -> std::io::Result<Self> {
+        loop {
+            if let Some(response) = Self::read_frame(reader)? {
+                return Ok(response);
+            }
+        }
+    }
+
+    /// Read what the server sends next: a response, or `None` for a `PING`,
+    /// which the caller answers with `PONG`. `from_reader` skips pings.
+    pub fn read_frame<R: Read>(reader: &mut BufReader<R>) -> std::io::Result<Option<Self>> {
         let mut first_line = String::new();
         let bytes_read = reader.read_line(&mut first_line)?;
 
@@ -33,6 +133,9 @@ impl Response {
         }
 
         let first_line = first_line.trim();
+        if first_line == PING {
+            return Ok(None);
+        }
 
         let mut content = String::new();
         loop {
@@ -59,8 +162,12 @@ impl Response {
         }
 
         match first_line {
-            "OK" => Ok(Response::Ok(content)),
-            "ERROR" => Ok(Response::Error(content)),
+            "OK" => Ok(Some(Response::Ok(content))),
+            "ERROR" => Ok(Some(Response::error(ErrorCode::Unknown, content))),
+            line if line.starts_with("ERROR:") => Ok(Some(Response::error(
+                ErrorCode::parse(&line["ERROR:".len()..]),
+                content,
+            ))),
             _ => Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
                 format!("Invalid response: {}", first_line),
@@ -69,14 +176,142 @@ impl Response {
     }
 }
 
-pub fn read_query<R: Read>(reader: &mut BufReader<R>) -> std::io::Result<String> {
+/// Category of an error response, so clients can branch on the kind of failure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Syntax,
+    NotFound,
+    AlreadyExists,
+    Constraint,
+    Type,
+    Unsupported,
+    Storage,
+    /// The client isn't allowed to do that, e.g. an admin command with a bad token
+    PermissionDenied,
+    Unknown,
+}
+
+#[allow(dead_code)]
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::Syntax => "SYNTAX",
+            ErrorCode::NotFound => "NOT_FOUND",
+            ErrorCode::AlreadyExists => "ALREADY_EXISTS",
+            ErrorCode::Constraint => "CONSTRAINT",
+            ErrorCode::Type => "TYPE",
+            ErrorCode::Unsupported => "UNSUPPORTED",
+            ErrorCode::Storage => "STORAGE",
+            ErrorCode::PermissionDenied => "PERMISSION_DENIED",
+            ErrorCode::Unknown => "UNKNOWN",
+        }
+    }
+
+    /// Parse a code from the wire; unrecognized codes map to `Unknown`
+    pub fn parse(s: &str) -> Self {
+        match s {
+            "SYNTAX" => ErrorCode::Syntax,
+            "NOT_FOUND" => ErrorCode::NotFound,
+            "ALREADY_EXISTS" => ErrorCode::AlreadyExists,
+            "CONSTRAINT" => ErrorCode::Constraint,
+            "TYPE" => ErrorCode::Type,
+            "UNSUPPORTED" => ErrorCode::Unsupported,
+            "STORAGE" => ErrorCode::Storage,
+            "PERMISSION_DENIED" => ErrorCode::PermissionDenied,
+            _ => ErrorCode::Unknown,
+        }
+    }
+
+    /// Whether retrying the same request might succeed.
+    /// Only storage failures can be transient; everything else will fail again.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, ErrorCode::Storage)
+    }
+}
+
+impl From<&ExecError> for ErrorCode {
+    fn from(e: &ExecError) -> Self {
+        match e {
+            ExecError::NotFound(_) => ErrorCode::NotFound,
+            ExecError::AlreadyExists(_) => ErrorCode::AlreadyExists,
+            ExecError::Constraint(_) => ErrorCode::Constraint,
+            ExecError::Type(_) => ErrorCode::Type,
+            ExecError::Unsupported(_) => ErrorCode::Unsupported,
+            ExecError::Storage(_) => ErrorCode::Storage,
+        }
+    }
+}
+
+/// Read one query line, trimmed. Returns `None` once the client has closed
+/// the connection; a blank line is `Some("")`.
+pub fn read_query<R: Read>(reader: &mut BufReader<R>) -> std::io::Result<Option<String>> {
     let mut query = String::new();
-    reader.read_line(&mut query)?;
-    Ok(query.trim().to_string())
+    if reader.read_line(&mut query)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(query.trim().to_string()))
+}
+
+/// The line that ends the data of a `COPY ... FROM STDIN`, as in PostgreSQL
+pub const END_OF_DATA: &str = "\\.";
+
+/// Whether a query is a `COPY ... FROM STDIN`, whose data lines follow it.
+/// This only looks at the words, so the data is read even if the statement
+/// turns out to be invalid and is never mistaken for queries.
+pub fn is_copy_from_stdin(query: &str) -> bool {
+    let words: Vec<String> = query
+        .trim_end_matches(|c: char| c == ';' || c.is_whitespace())
+        .split_whitespace()
+        .map(str::to_lowercase)
+        .collect();
+    words.first().is_some_and(|w| w == "copy")
+        && words
+            .windows(2)
+            .any(|pair| pair[0] == "from" && pair[1] == "stdin")
 }
 
+/// Read the data lines of a `COPY ... FROM STDIN` up to the `\.` line, joined
+/// with newlines. Lines are kept as sent, apart from their line ending.
+/// Returns `None` if the client disconnects before the end of the data.
+pub fn read_copy_data<R: Read>(reader: &mut BufReader<R>) -> std::io::Result<Option<String>> {
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line
+            .strip_suffix('\n')
+            .map_or(line.as_str(), |l| l.strip_suffix('\r').unwrap_or(l));
+        if line == END_OF_DATA {
+            return Ok(Some(lines.join("\n")));
+        }
+        lines.push(line.to_string());
+    }
+}
+
+/// Lines of a result written between flushes. Sending a big result a batch
+/// at a time means a slow client holds the server back, rather than the
+/// whole result being copied into the writer first, and a client that has
+/// gone away is noticed before the rest is written.
+pub const FLUSH_EVERY_LINES: usize = 500;
+
+/// Write a response, the same bytes as `Response::to_bytes`, flushing it
+/// every `FLUSH_EVERY_LINES` lines and at the end
 pub fn write_response<W: Write>(writer: &mut W, response: &Response) -> std::io::Result<()> {
-    writer.write_all(&response.to_bytes())?;
+    let Response::Ok(msg) = response else {
+        writer.write_all(&response.to_bytes())?;
+        return writer.flush();
+    };
+    writer.write_all(b"OK\n")?;
+    for (idx, line) in msg.split('\n').enumerate() {
+        if idx > 0 && idx % FLUSH_EVERY_LINES == 0 {
+            writer.flush()?;
+        }
+        writer.write_all(line.as_bytes())?;
+        writer.write_all(b"\n")?;
+    }
+    writer.write_all(b"END\n")?;
     writer.flush()
 }
 
@@ -95,12 +330,50 @@ mod tests {
 
     #[test]
     fn test_error_response_serialization() {
-        let response = Response::Error("Table not found".to_string());
+        let response = Response::error(ErrorCode::Unknown, "Table not found");
         let bytes = response.to_bytes();
         let expected = b"ERROR\nTable not found\nEND\n";
         assert_eq!(bytes, expected);
     }
 
+    #[test]
+    fn test_coded_error_serialization() {
+        let response = Response::error(ErrorCode::Constraint, "duplicate key");
+        let bytes = response.to_bytes();
+        let expected = b"ERROR:CONSTRAINT\nduplicate key\nEND\n";
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn test_coded_error_round_trip() {
+        let response = Response::error(ErrorCode::NotFound, "Table 'users' does not exist");
+        let cursor = Cursor::new(response.to_bytes());
+        let mut reader = BufReader::new(cursor);
+
+        match Response::from_reader(&mut reader).unwrap() {
+            Response::Error { code, message } => {
+                assert_eq!(code, ErrorCode::NotFound);
+                assert_eq!(message, "Table 'users' does not exist");
+                assert!(!code.is_retryable());
+            }
+            _ => panic!("Expected Error response"),
+        }
+    }
+
+    #[test]
+    fn test_unrecognized_error_code() {
+        let data = b"ERROR:SOMETHING_NEW\nboom\nEND\n";
+        let mut reader = BufReader::new(Cursor::new(data));
+
+        match Response::from_reader(&mut reader).unwrap() {
+            Response::Error { code, message } => {
+                assert_eq!(code, ErrorCode::Unknown);
+                assert_eq!(message, "boom");
+            }
+            _ => panic!("Expected Error response"),
+        }
+    }
+
     #[test]
     fn test_ok_response_deserialization() {
         let data = b"OK\nQuery executed successfully\nEND\n";
@@ -124,7 +397,10 @@ mod tests {
         let response = Response::from_reader(&mut reader).unwrap();
 
         match response {
-            Response::Error(msg) => assert_eq!(msg, "Table not found"),
+            Response::Error { code, message } => {
+                assert_eq!(code, ErrorCode::Unknown);
+                assert_eq!(message, "Table not found");
+            }
             _ => panic!("Expected Error response"),
         }
     }
@@ -145,6 +421,53 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_read_copy_data() {
+        assert!(is_copy_from_stdin("copy users (id, name) FROM stdin;"));
+        assert!(!is_copy_from_stdin("COPY users TO STDOUT"));
+        assert!(!is_copy_from_stdin("SELECT * FROM stdin"));
+
+        let data = b"1,\" padded \"\r\n2,\"two\nlines\"\n\\.\nSELECT 1\n";
+        let mut reader = BufReader::new(Cursor::new(data));
+        assert_eq!(
+            read_copy_data(&mut reader).unwrap().as_deref(),
+            Some("1,\" padded \"\n2,\"two\nlines\"")
+        );
+        // The next query is left for the caller
+        assert_eq!(
+            read_query(&mut reader).unwrap().as_deref(),
+            Some("SELECT 1")
+        );
+
+        let mut reader = BufReader::new(Cursor::new(b"1,a\n"));
+        assert_eq!(read_copy_data(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn test_negotiate_version() {
+        let requested = parse_handshake(&handshake(PROTOCOL_VERSION)).unwrap();
+        assert_eq!(negotiate_version(requested.unwrap()), Ok(PROTOCOL_VERSION));
+        // A newer client is downgraded to what the server speaks
+        assert_eq!(
+            negotiate_version(PROTOCOL_VERSION + 3),
+            Ok(PROTOCOL_VERSION)
+        );
+        assert_eq!(parse_handshake_reply("PROTOCOL 1"), Some(1));
+
+        assert_eq!(parse_handshake("SELECT 1"), None);
+        assert!(parse_handshake("handshake two").unwrap().is_err());
+    }
+
+    #[test]
+    fn test_reject_unsupported_version() {
+        let err = negotiate_version(MIN_PROTOCOL_VERSION - 1).unwrap_err();
+        assert!(
+            err.contains("Protocol version 0 is not supported"),
+            "{}",
+            err
+        );
+    }
+
     #[test]
     fn test_read_query() {
         let data = b"SELECT * FROM users\n";
@@ -152,6 +475,57 @@ mod tests {
         let mut reader = BufReader::new(cursor);
 
         let query = read_query(&mut reader).unwrap();
-        assert_eq!(query, "SELECT * FROM users");
+        assert_eq!(query.as_deref(), Some("SELECT * FROM users"));
+    }
+
+    #[test]
+    fn test_read_blank_query_and_eof() {
+        let data = b"   \t\n\nSELECT 1\n";
+        let mut reader = BufReader::new(Cursor::new(data));
+
+        assert_eq!(read_query(&mut reader).unwrap().as_deref(), Some(""));
+        assert_eq!(read_query(&mut reader).unwrap().as_deref(), Some(""));
+        assert_eq!(
+            read_query(&mut reader).unwrap().as_deref(),
+            Some("SELECT 1")
+        );
+        assert_eq!(read_query(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn test_write_response_flushes_in_batches() {
+        /// Records the bytes written by the time of each flush
+        #[derive(Default)]
+        struct Recorder {
+            data: Vec<u8>,
+            flushed_at: Vec<usize>,
+        }
+        impl Write for Recorder {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.data.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                self.flushed_at.push(self.data.len());
+                Ok(())
+            }
+        }
+
+        let lines: Vec<String> = (0..1200).map(|i| format!("{:04}", i)).collect();
+        let response = Response::Ok(lines.join("\n"));
+        let mut recorder = Recorder::default();
+        write_response(&mut recorder, &response).unwrap();
+        assert_eq!(recorder.data, response.to_bytes());
+        // After "OK" and 500 lines, 1000 lines, then the end
+        assert_eq!(
+            recorder.flushed_at,
+            [3 + 500 * 5, 3 + 1000 * 5, 3 + 1200 * 5 + 4]
+        );
+
+        let error = Response::error(ErrorCode::Type, "bad\nvalue");
+        let mut recorder = Recorder::default();
+        write_response(&mut recorder, &error).unwrap();
+        assert_eq!(recorder.data, error.to_bytes());
+        assert_eq!(recorder.flushed_at.len(), 1);
     }
 }
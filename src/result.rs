@@ -0,0 +1,199 @@
+use crate::database::Value;
+use crate::decimal::Decimal;
+use crate::executor::ExecError;
+
+/// The rows of a SELECT, for code embedding the database rather than
+/// reading its text output
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryResult {
+    /// Result column names, as they head the text output
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+/// One row of a `QueryResult`, whose values can be looked up by column name
+#[derive(Debug, Clone, Copy)]
+pub struct RowView<'a> {
+    columns: &'a [String],
+    values: &'a [Value],
+}
+
+#[allow(dead_code)]
+impl QueryResult {
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// Position of a result column; names match case-insensitively
+    pub fn column_index(&self, name: &str) -> Result<usize, ExecError> {
+        column_index(&self.columns, name)
+    }
+
+    /// The row at `row`, or a `NotFound` error past the end
+    pub fn row(&self, row: usize) -> Result<RowView<'_>, ExecError> {
+        let values = self.rows.get(row).ok_or_else(|| {
+            ExecError::NotFound(format!(
+                "Row {} is out of range for a result of {} row(s)",
+                row,
+                self.rows.len()
+            ))
+        })?;
+        Ok(RowView {
+            columns: &self.columns,
+            values,
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = RowView<'_>> {
+        self.rows.iter().map(|values| RowView {
+            columns: &self.columns,
+            values,
+        })
+    }
+
+    pub fn get(&self, row: usize, column: &str) -> Result<&Value, ExecError> {
+        self.row(row)?.get(column)
+    }
+
+    pub fn get_long(&self, row: usize, column: &str) -> Result<Option<i64>, ExecError> {
+        self.row(row)?.get_long(column)
+    }
+
+    pub fn get_decimal(&self, row: usize, column: &str) -> Result<Option<Decimal>, ExecError> {
+        self.row(row)?.get_decimal(column)
+    }
+
+    pub fn get_text(&self, row: usize, column: &str) -> Result<Option<&str>, ExecError> {
+        self.row(row)?.get_text(column)
+    }
+
+    pub fn get_bool(&self, row: usize, column: &str) -> Result<Option<bool>, ExecError> {
+        self.row(row)?.get_bool(column)
+    }
+}
+
+/// The typed getters return `Ok(None)` for NULL and a `Type` error when the
+/// column holds a different type.
+#[allow(dead_code)]
+impl<'a> RowView<'a> {
+    pub fn values(&self) -> &'a [Value] {
+        self.values
+    }
+
+    pub fn get(&self, column: &str) -> Result<&'a Value, ExecError> {
+        Ok(&self.values[column_index(self.columns, column)?])
+    }
+
+    pub fn get_long(&self, column: &str) -> Result<Option<i64>, ExecError> {
+        match self.get(column)? {
+            Value::Long(n) => Ok(Some(*n)),
+            other => self.mismatch(column, other, "integer"),
+        }
+    }
+
+    /// Integers are widened to decimals
+    pub fn get_decimal(&self, column: &str) -> Result<Option<Decimal>, ExecError> {
+        match self.get(column)? {
+            Value::Decimal(d) => Ok(Some(*d)),
+            Value::Long(n) => Ok(Some(Decimal::from_i64(*n))),
+            other => self.mismatch(column, other, "decimal"),
+        }
+    }
+
+    pub fn get_text(&self, column: &str) -> Result<Option<&'a str>, ExecError> {
+        match self.get(column)? {
+            Value::Text(s) => Ok(Some(s)),
+            other => self.mismatch(column, other, "text"),
+        }
+    }
+
+    pub fn get_bool(&self, column: &str) -> Result<Option<bool>, ExecError> {
+        match self.get(column)? {
+            Value::Bool(b) => Ok(Some(*b)),
+            other => self.mismatch(column, other, "boolean"),
+        }
+    }
+
+    fn mismatch<T>(
+        &self,
+        column: &str,
+        value: &Value,
+        expected: &str,
+    ) -> Result<Option<T>, ExecError> {
+        if *value == Value::Null {
+            return Ok(None);
+        }
+        Err(ExecError::Type(format!(
+            "Column '{}' holds {}, not {}",
+            column,
+            value.type_name(),
+            expected
+        )))
+    }
+}
+
+fn column_index(columns: &[String], name: &str) -> Result<usize, ExecError> {
+    columns
+        .iter()
+        .position(|c| c.eq_ignore_ascii_case(name))
+        .ok_or_else(|| ExecError::NotFound(format!("Column '{}' is not in the result", name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result() -> QueryResult {
+        QueryResult {
+            columns: vec!["id".to_string(), "name".to_string(), "active".to_string()],
+            rows: vec![
+                vec![
+                    Value::Long(1),
+                    Value::Text("Alice".to_string()),
+                    Value::Bool(true),
+                ],
+                vec![Value::Long(2), Value::Null, Value::Bool(false)],
+            ],
+        }
+    }
+
+    #[test]
+    fn test_typed_getters() {
+        let result = result();
+        assert_eq!(result.get_long(0, "id").unwrap(), Some(1));
+        assert_eq!(result.get_text(0, "NAME").unwrap(), Some("Alice"));
+        assert_eq!(result.get_text(1, "name").unwrap(), None);
+        assert_eq!(result.get_bool(1, "active").unwrap(), Some(false));
+        assert_eq!(
+            result.get_decimal(1, "id").unwrap(),
+            Some(Decimal::from_i64(2))
+        );
+
+        let names: Vec<Option<&str>> = result
+            .iter()
+            .map(|row| row.get_text("name").unwrap())
+            .collect();
+        assert_eq!(names, vec![Some("Alice"), None]);
+    }
+
+    #[test]
+    fn test_getter_errors() {
+        let result = result();
+        assert!(matches!(
+            result.get_long(0, "email"),
+            Err(ExecError::NotFound(_))
+        ));
+        assert!(matches!(
+            result.get_long(2, "id"),
+            Err(ExecError::NotFound(_))
+        ));
+        assert!(matches!(
+            result.get_long(0, "name"),
+            Err(ExecError::Type(e)) if e == "Column 'name' holds text, not integer"
+        ));
+    }
+}
@@ -0,0 +1,125 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How much of a table `TABLESAMPLE` keeps
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleSize {
+    /// `(n ROWS)`: exactly `n` rows, or every row of a smaller table
+    Rows(usize),
+    /// `(p PERCENT)` or just `(p)`: each row independently, with probability `p` / 100
+    Percent(f64),
+}
+
+/// Picks a random sample of the items offered to it, in one pass and
+/// without knowing in advance how many there are. `n` rows are kept by
+/// reservoir sampling, so at most `n` are held at a time. The sample comes
+/// back in the order the items were offered.
+pub struct Sampler<T> {
+    size: SampleSize,
+    rng: Rng,
+    offered: usize,
+    /// Picked items, with their position among those offered
+    picked: Vec<(usize, T)>,
+}
+
+impl<T> Sampler<T> {
+    /// A sampler whose choices are the same for the same `seed`, or
+    /// different each time without one
+    pub fn new(size: SampleSize, seed: Option<u64>) -> Self {
+        let seed = seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos() as u64)
+        });
+        Self {
+            size,
+            rng: Rng(seed),
+            offered: 0,
+            picked: Vec::new(),
+        }
+    }
+
+    pub fn offer(&mut self, item: T) {
+        let position = self.offered;
+        self.offered += 1;
+        match self.size {
+            SampleSize::Rows(n) if self.picked.len() < n => self.picked.push((position, item)),
+            SampleSize::Rows(n) => {
+                // Each of the items so far stays in with probability n / offered
+                let slot = self.rng.below(self.offered as u64) as usize;
+                if slot < n {
+                    self.picked[slot] = (position, item);
+                }
+            }
+            SampleSize::Percent(percent) => {
+                if self.rng.unit() * 100.0 < percent {
+                    self.picked.push((position, item));
+                }
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> Vec<T> {
+        self.picked.sort_by_key(|(position, _)| *position);
+        self.picked.into_iter().map(|(_, item)| item).collect()
+    }
+}
+
+/// A small pseudo-random generator (SplitMix64): plenty for picking rows,
+/// but not for anything that needs to be unpredictable
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..bound`
+    fn below(&mut self, bound: u64) -> u64 {
+        ((self.next() as u128 * bound as u128) >> 64) as u64
+    }
+
+    /// A number in `[0, 1)`
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(size: SampleSize, seed: u64, count: usize) -> Vec<usize> {
+        let mut sampler = Sampler::new(size, Some(seed));
+        for i in 0..count {
+            sampler.offer(i);
+        }
+        sampler.finish()
+    }
+
+    #[test]
+    fn test_reservoir_keeps_n_rows_in_order() {
+        let picked = sample(SampleSize::Rows(10), 7, 1000);
+        assert_eq!(picked.len(), 10);
+        assert!(picked.windows(2).all(|w| w[0] < w[1]), "{:?}", picked);
+        // Not just the first rows
+        assert!(picked.iter().any(|&i| i >= 10), "{:?}", picked);
+
+        // The same seed picks the same rows, and a table smaller than the
+        // sample is returned whole
+        assert_eq!(sample(SampleSize::Rows(10), 7, 1000), picked);
+        assert_eq!(sample(SampleSize::Rows(10), 7, 4), [0, 1, 2, 3]);
+        assert!(sample(SampleSize::Rows(0), 7, 4).is_empty());
+    }
+
+    #[test]
+    fn test_percent_sample() {
+        let picked = sample(SampleSize::Percent(25.0), 3, 10_000);
+        assert!((2000..3000).contains(&picked.len()), "{}", picked.len());
+        assert!(sample(SampleSize::Percent(0.0), 3, 100).is_empty());
+        assert_eq!(sample(SampleSize::Percent(100.0), 3, 100).len(), 100);
+    }
+}
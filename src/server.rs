@@ -1,61 +1,180 @@
+use crate::copy::copy_from_stdin;
 use crate::database::Database;
-use crate::executor::execute;
-use crate::protocol::{read_query, write_response, Response};
-use sqlparser::dialect::GenericDialect;
+use crate::dialect::MatiDialect;
+use crate::executor::{execute, execute_reindex, Cursor, ExecError, ROW_ID_COLUMN};
+use crate::logging::{log, LogLevel};
+use crate::protocol::{
+    is_copy_from_stdin, negotiate_version, parse_handshake, read_copy_data, read_query,
+    write_response, ErrorCode, Response, EMPTY_QUERY, MIN_PROTOCOL_VERSION, PING, PONG,
+};
+use sqlparser::ast::{CloseCursor, Declare, Expr, FetchDirection, Ident, Set, Statement, Value};
+use sqlparser::keywords::Keyword;
 use sqlparser::parser::Parser;
-use std::io::{BufReader, BufWriter};
+use sqlparser::tokenizer::{Location, Token, Tokenizer};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
 use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::time::Duration;
 
 pub struct Server {
     db: Database,
-    listener: TcpListener,
+    db_path: String,
+    listener: Listener,
+    /// Token a client must give with `shutdown`; without one, remote
+    /// shutdown is disabled
+    admin_token: Option<String>,
+    /// How idle clients are checked on; without one, they're never pinged
+    heartbeat: Option<Heartbeat>,
+}
+
+/// When to ping an idle client, and how long it has to answer before the
+/// server drops it. A client that has gone away without closing its
+/// connection, e.g. behind a NAT that forgot it, would otherwise hold the
+/// server, which serves one client at a time, forever.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Heartbeat {
+    /// How long a client may send nothing before it's pinged
+    pub interval: Duration,
+    /// How long it has to answer the ping
+    pub timeout: Duration,
 }
 
 impl Server {
+    #[allow(dead_code)]
     pub fn new(addr: &str, db_path: &str) -> std::io::Result<Self> {
-        let db = Database::new(db_path)?;
-        let listener = TcpListener::bind(addr)?;
-        
-        println!("MatiDB Server v0.2.0 listening on {}", addr);
-        println!("Database file: {}", db_path);
-        
-        Ok(Self {
+        Self::with_database(addr, db_path, Database::new(db_path)?)
+    }
+
+    /// Serve a database that's already open, e.g. one opened with
+    /// `Database::new_verifying_writes`. `db_path` is only for logging.
+    pub fn with_database(addr: &str, db_path: &str, db: Database) -> std::io::Result<Self> {
+        let listener = Listener::Tcp(TcpListener::bind(addr)?);
+        Ok(Self::with_listener(listener, db_path, db))
+    }
+
+    /// Serve a database on a Unix domain socket at `socket_path` instead of
+    /// a TCP port. A socket file left there by a server that didn't shut
+    /// down cleanly is replaced; the server removes its own when it stops.
+    #[cfg(unix)]
+    pub fn with_database_on_socket(
+        socket_path: &str,
+        db_path: &str,
+        db: Database,
+    ) -> std::io::Result<Self> {
+        use std::os::unix::fs::FileTypeExt;
+
+        if std::fs::symlink_metadata(socket_path).is_ok_and(|m| m.file_type().is_socket()) {
+            std::fs::remove_file(socket_path)?;
+        }
+        let listener = Listener::Unix(UnixSocket {
+            listener: UnixListener::bind(socket_path)?,
+            path: PathBuf::from(socket_path),
+        });
+        Ok(Self::with_listener(listener, db_path, db))
+    }
+
+    fn with_listener(listener: Listener, db_path: &str, db: Database) -> Self {
+        Self {
             db,
+            db_path: db_path.to_string(),
             listener,
-        })
+            admin_token: None,
+            heartbeat: None,
+        }
+    }
+
+    pub fn set_admin_token(&mut self, token: Option<String>) {
+        self.admin_token = token;
+    }
+
+    pub fn set_heartbeat(&mut self, heartbeat: Option<Heartbeat>) {
+        self.heartbeat = heartbeat;
+    }
+
+    /// Run the SQL statements in a file against the database before
+    /// accepting connections, e.g. to make sure the schema exists.
+    ///
+    /// A failing statement is logged and skipped, unless `abort_on_error`
+    /// is set, in which case it stops the script and is returned.
+    pub fn run_init_file(&mut self, path: &str, abort_on_error: bool) -> std::io::Result<()> {
+        let sql = std::fs::read_to_string(path)?;
+        let statements = Parser::parse_sql(&MatiDialect {}, &sql).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Parse error in {}: {}", path, e),
+            )
+        })?;
+
+        let total = statements.len();
+        let mut failed = 0;
+        for stmt in statements {
+            let description = stmt.to_string();
+            if let Err(e) = execute(&mut self.db, stmt) {
+                if abort_on_error {
+                    return Err(std::io::Error::other(format!(
+                        "{} (in: {})",
+                        e, description
+                    )));
+                }
+                log(LogLevel::Warn, format_args!("Init file: {} (in: {})", e, description));
+                failed += 1;
+            }
+        }
+
+        self.db.buffer_pool.borrow_mut().flush_all()?;
+        self.db.save_catalog()?;
+        log(
+            LogLevel::Info,
+            format_args!("Ran {} statement(s) from {} ({} failed)", total, path, failed),
+        );
+
+        Ok(())
     }
 
     pub fn run(mut self) -> std::io::Result<()> {
-        for stream in self.listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    if let Err(e) = handle_client(stream, &mut self.db) {
-                        eprintln!("Error handling client: {}", e);
-                    }
-                    
-                    // Flush after each client disconnects to ensure data persistence
-                    if let Err(e) = self.db.buffer_pool.borrow_mut().flush_all() {
-                        eprintln!("Warning: Failed to flush buffer pool: {}", e);
-                    }
-                    
-                    // Save catalog after each client session
-                    if let Err(e) = self.db.save_catalog() {
-                        eprintln!("Warning: Failed to save catalog: {}", e);
-                    }
+        log(
+            LogLevel::Info,
+            format_args!(
+                "MatiDB Server v0.2.0 listening on {}",
+                self.listener.local_name()?
+            ),
+        );
+        log(LogLevel::Info, format_args!("Database file: {}", self.db_path));
+
+        loop {
+            let admin_token = self.admin_token.as_deref();
+            let heartbeat = self.heartbeat;
+            let shutdown = match &self.listener {
+                Listener::Tcp(listener) => {
+                    let stream = listener.accept().map(|(stream, _)| stream);
+                    serve_connection(stream, &mut self.db, admin_token, heartbeat)
                 }
-                Err(e) => {
-                    eprintln!("Connection failed: {}", e);
+                #[cfg(unix)]
+                Listener::Unix(socket) => {
+                    let stream = socket.listener.accept().map(|(stream, _)| stream);
+                    serve_connection(stream, &mut self.db, admin_token, heartbeat)
                 }
+            };
+            if shutdown {
+                break;
             }
         }
         
         // Final flush when server stops
-        println!("Server shutting down, flushing data...");
+        log(LogLevel::Info, format_args!("Server shutting down, flushing data..."));
+        if let Err(e) = self.db.trim_trailing_pages() {
+            log(LogLevel::Error, format_args!("Failed to trim unused pages: {}", e));
+        }
         if let Err(e) = self.db.buffer_pool.borrow_mut().flush_all() {
-            eprintln!("Error flushing buffer pool: {}", e);
+            log(LogLevel::Error, format_args!("Failed to flush buffer pool: {}", e));
         }
         if let Err(e) = self.db.save_catalog() {
-            eprintln!("Error saving catalog: {}", e);
+            log(LogLevel::Error, format_args!("Failed to save catalog: {}", e));
         }
         
         Ok(())
@@ -65,100 +184,1364 @@ impl Server {
 impl Drop for Server {
     fn drop(&mut self) {
         // Ensure data is flushed even if server is killed
-        println!("Flushing database before shutdown...");
+        log(LogLevel::Info, format_args!("Flushing database before shutdown..."));
         if let Err(e) = self.db.buffer_pool.borrow_mut().flush_all() {
-            eprintln!("Error flushing buffer pool on drop: {}", e);
+            log(LogLevel::Error, format_args!("Failed to flush buffer pool on drop: {}", e));
         }
         if let Err(e) = self.db.save_catalog() {
-            eprintln!("Error saving catalog on drop: {}", e);
+            log(LogLevel::Error, format_args!("Failed to save catalog on drop: {}", e));
+        }
+    }
+}
+
+/// Where the server accepts connections
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixSocket),
+}
+
+impl Listener {
+    /// The address clients connect to, as `matidb-client` takes it
+    fn local_name(&self) -> std::io::Result<String> {
+        match self {
+            Listener::Tcp(listener) => Ok(listener.local_addr()?.to_string()),
+            #[cfg(unix)]
+            Listener::Unix(socket) => Ok(format!("unix:{}", socket.path.display())),
         }
     }
 }
 
-fn handle_client(stream: TcpStream, db: &mut Database) -> std::io::Result<()> {
-    let peer_addr = stream.peer_addr()?;
-    println!("Client connected: {}", peer_addr);
+/// A Unix domain socket listener, whose socket file is removed when it's dropped
+#[cfg(unix)]
+struct UnixSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// A client connection: TCP, or on Unix a Unix domain socket
+trait ClientStream: Read + Write + Sized {
+    fn try_clone(&self) -> std::io::Result<Self>;
+
+    /// Make reads fail with `WouldBlock` or `TimedOut` after `timeout`
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>;
+
+    /// How the client is named in the log
+    fn peer_name(&self) -> std::io::Result<String>;
+}
+
+impl ClientStream for TcpStream {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn peer_name(&self) -> std::io::Result<String> {
+        Ok(self.peer_addr()?.to_string())
+    }
+}
+
+#[cfg(unix)]
+impl ClientStream for UnixStream {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    /// Clients of a Unix socket have no address of their own, so they're
+    /// named after the socket
+    fn peer_name(&self) -> std::io::Result<String> {
+        let addr = self.local_addr()?;
+        let path = addr.as_pathname().map(|path| path.display().to_string());
+        Ok(format!("unix:{}", path.unwrap_or_default()))
+    }
+}
+
+/// Serve a client the listener accepted, then flush and save the catalog.
+/// Returns whether the client asked the server to shut down.
+fn serve_connection<S: ClientStream>(
+    stream: std::io::Result<S>,
+    db: &mut Database,
+    admin_token: Option<&str>,
+    heartbeat: Option<Heartbeat>,
+) -> bool {
+    let stream = match stream {
+        Ok(stream) => stream,
+        Err(e) => {
+            log(LogLevel::Error, format_args!("Connection failed: {}", e));
+            return false;
+        }
+    };
+    let shutdown = match handle_client(stream, db, admin_token, heartbeat) {
+        Ok(shutdown) => shutdown,
+        Err(e) => {
+            log(LogLevel::Error, format_args!("Error handling client: {}", e));
+            false
+        }
+    };
+
+    // Flush after each client disconnects to ensure data persistence
+    if let Err(e) = db.buffer_pool.borrow_mut().flush_all() {
+        log(LogLevel::Warn, format_args!("Failed to flush buffer pool: {}", e));
+    }
+
+    // Save catalog after each client session
+    if let Err(e) = db.save_catalog() {
+        log(LogLevel::Warn, format_args!("Failed to save catalog: {}", e));
+    }
+
+    shutdown
+}
+
+/// Settings that belong to one client connection, changed with
+/// `SET name = value` and read back with `SHOW name`, and the cursors it has
+/// open. They start from the defaults for every connection and are dropped
+/// when it closes.
+struct Session {
+    /// Protocol version agreed in the handshake, which `SHOW` reports but
+    /// `SET` can't change
+    protocol_version: u32,
+    /// With autocommit off, a statement run outside a transaction starts one,
+    /// and nothing is kept until COMMIT. A transaction still open when the
+    /// client disconnects is rolled back.
+    autocommit: bool,
+    /// With atomic batches on, a request of several statements run outside
+    /// a transaction gets one of its own: all of them are kept or none are
+    atomic_batches: bool,
+    /// Cursors opened with DECLARE, by lowercased name
+    cursors: HashMap<String, Cursor>,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self {
+            protocol_version: MIN_PROTOCOL_VERSION,
+            autocommit: true,
+            atomic_batches: false,
+            cursors: HashMap::new(),
+        }
+    }
+}
+
+impl Session {
+    /// Run a statement for this client, handling the session's own statements
+    fn execute(&mut self, db: &mut Database, stmt: Statement) -> Result<String, ExecError> {
+        match stmt {
+            Statement::Set(Set::SingleAssignment {
+                variable, values, ..
+            }) => match values.as_slice() {
+                [value] => self.set(&variable.to_string(), value),
+                _ => Err(ExecError::Type(format!(
+                    "SET {} takes a single value",
+                    variable
+                ))),
+            },
+            Statement::ShowVariable { variable } => self.show(&variable),
+            Statement::Declare { stmts } => stmts
+                .into_iter()
+                .map(|declare| self.declare(db, declare))
+                .collect::<Result<Vec<_>, _>>()
+                .map(|messages| messages.join("\n")),
+            Statement::Fetch {
+                name,
+                direction,
+                into: None,
+                ..
+            } => self.fetch(db, &name, &direction),
+            Statement::Close { cursor } => self.close(cursor),
+            Statement::StartTransaction { .. }
+            | Statement::Commit { .. }
+            | Statement::Rollback { .. }
+            | Statement::Vacuum(_) => execute(db, stmt),
+            stmt => {
+                if !self.autocommit && !db.in_transaction() {
+                    db.begin_transaction();
+                }
+                execute(db, stmt)
+            }
+        }
+    }
+
+    /// Run a `COPY ... FROM STDIN` with the data lines that followed it,
+    /// inside the session's transaction like any other write
+    fn copy_from_stdin(&mut self, db: &mut Database, query: &str, data: &str) -> Response {
+        if !self.autocommit && !db.in_transaction() {
+            db.begin_transaction();
+        }
+        match copy_from_stdin(db, query, data) {
+            Ok(msg) => Response::Ok(msg),
+            Err(e) => Response::error(ErrorCode::from(&e), e.to_string()),
+        }
+    }
+
+    /// Run the statements of one request in order, stopping at the first
+    /// error. With atomic batches on and no transaction open, several
+    /// statements run in a transaction of their own that's rolled back if
+    /// any of them fails.
+    fn execute_batch(
+        &mut self,
+        db: &mut Database,
+        statements: Vec<Statement>,
+    ) -> Result<Vec<String>, ExecError> {
+        if !self.atomic_batches || statements.len() < 2 || db.in_transaction() {
+            return statements
+                .into_iter()
+                .map(|stmt| self.execute(db, stmt))
+                .collect();
+        }
+        if statements.iter().any(|stmt| {
+            matches!(
+                stmt,
+                Statement::StartTransaction { .. }
+                    | Statement::Commit { .. }
+                    | Statement::Rollback { .. }
+            )
+        }) {
+            return Err(ExecError::Unsupported(
+                "An atomic batch can't contain BEGIN, COMMIT or ROLLBACK".to_string(),
+            ));
+        }
+
+        db.begin_transaction();
+        let results = statements
+            .into_iter()
+            .map(|stmt| self.execute(db, stmt))
+            .collect::<Result<Vec<_>, _>>();
+        match results {
+            Ok(results) => {
+                let commit = Statement::Commit {
+                    chain: false,
+                    end: false,
+                    modifier: None,
+                };
+                execute(db, commit)?;
+                Ok(results)
+            }
+            Err(e) => {
+                db.rollback_transaction()?;
+                Err(e)
+            }
+        }
+    }
+
+    fn set(&mut self, name: &str, value: &Expr) -> Result<String, ExecError> {
+        match name.to_lowercase().as_str() {
+            "autocommit" => {
+                self.autocommit = parse_on_off("autocommit", value)?;
+                Ok("SET".to_string())
+            }
+            "atomic_batches" => {
+                self.atomic_batches = parse_on_off("atomic_batches", value)?;
+                Ok("SET".to_string())
+            }
+            "transaction_isolation" => Err(ExecError::Unsupported(
+                "transaction_isolation is always serializable, since the server runs one connection at a time"
+                    .to_string(),
+            )),
+            _ => Err(ExecError::NotFound(format!("Unknown setting '{}'", name))),
+        }
+    }
+
+    /// `DECLARE name CURSOR FOR query`
+    fn declare(&mut self, db: &Database, declare: Declare) -> Result<String, ExecError> {
+        let (Some(query), [name]) = (declare.for_query, declare.names.as_slice()) else {
+            return Err(ExecError::Unsupported(
+                "Only DECLARE name CURSOR FOR query is supported".to_string(),
+            ));
+        };
+        if declare.scroll == Some(true) {
+            return Err(ExecError::Unsupported(
+                "Cursors only move forward, so SCROLL is not supported".to_string(),
+            ));
+        }
+        let key = name.value.to_lowercase();
+        if self.cursors.contains_key(&key) {
+            return Err(ExecError::AlreadyExists(format!(
+                "Cursor '{}' already exists",
+                name.value
+            )));
+        }
+        let cursor = Cursor::open(db, *query)?;
+        self.cursors.insert(key, cursor);
+        Ok("DECLARE CURSOR".to_string())
+    }
+
+    /// `FETCH [count | NEXT | ALL | FORWARD ...] FROM name`
+    fn fetch(
+        &mut self,
+        db: &Database,
+        name: &Ident,
+        direction: &FetchDirection,
+    ) -> Result<String, ExecError> {
+        let count = match direction {
+            FetchDirection::Next | FetchDirection::Forward { limit: None } => 1,
+            FetchDirection::All | FetchDirection::ForwardAll => usize::MAX,
+            FetchDirection::Count { limit } | FetchDirection::Forward { limit: Some(limit) } => {
+                match limit {
+                    Value::Number(n, _) => n.parse().map_err(|_| {
+                        ExecError::Type(format!("FETCH takes a non-negative count, got {}", n))
+                    })?,
+                    other => {
+                        return Err(ExecError::Type(format!(
+                            "FETCH takes a non-negative count, got {}",
+                            other
+                        )));
+                    }
+                }
+            }
+            other => {
+                return Err(ExecError::Unsupported(format!(
+                    "Cursors only move forward, so FETCH {} is not supported",
+                    other
+                )));
+            }
+        };
+        self.cursor(name)?.fetch(db, count)
+    }
+
+    /// `CLOSE name` or `CLOSE ALL`
+    fn close(&mut self, cursor: CloseCursor) -> Result<String, ExecError> {
+        match cursor {
+            CloseCursor::All => self.cursors.clear(),
+            CloseCursor::Specific { name } => {
+                self.cursor(&name)?;
+                self.cursors.remove(&name.value.to_lowercase());
+            }
+        }
+        Ok("CLOSE CURSOR".to_string())
+    }
+
+    /// Replace each `WHERE CURRENT OF name` in an UPDATE or DELETE with
+    /// `WHERE _row_id = id`, picking out the row the cursor last fetched.
+    /// The parser doesn't know the clause, so this is done on the query text.
+    fn resolve_current_of(&self, query: &str) -> Result<String, ExecError> {
+        let Ok(tokens) = Tokenizer::new(&MatiDialect {}, query).tokenize_with_location() else {
+            // Left for the parser to report
+            return Ok(query.to_string());
+        };
+        let significant: Vec<usize> = (0..tokens.len())
+            .filter(|&idx| !matches!(tokens[idx].token, Token::Whitespace(_)))
+            .collect();
+        let word = |pos: usize| match significant.get(pos).map(|&idx| &tokens[idx].token) {
+            Some(Token::Word(word)) => Some(word),
+            _ => None,
+        };
+        let is_keyword = |pos: usize, keyword: Keyword| word(pos).is_some_and(|w| w.keyword == keyword);
+
+        // Byte ranges of `CURRENT OF name`, in order, and what replaces them
+        let mut replacements = Vec::new();
+        for pos in 0..significant.len() {
+            if !(is_keyword(pos, Keyword::WHERE)
+                && is_keyword(pos + 1, Keyword::CURRENT)
+                && is_keyword(pos + 2, Keyword::OF))
+            {
+                continue;
+            }
+            let Some(name) = word(pos + 3) else {
+                continue;
+            };
+            let cursor = self
+                .cursors
+                .get(&name.value.to_lowercase())
+                .ok_or_else(|| ExecError::NotFound(format!("Cursor '{}' does not exist", name.value)))?;
+
+            // The statement's table: `UPDATE t` or `DELETE FROM t`
+            let start = (0..pos)
+                .rev()
+                .find(|&p| tokens[significant[p]].token == Token::SemiColon)
+                .map_or(0, |p| p + 1);
+            let table = if is_keyword(start, Keyword::UPDATE) {
+                word(start + 1)
+            } else if is_keyword(start, Keyword::DELETE) && is_keyword(start + 1, Keyword::FROM) {
+                word(start + 2)
+            } else {
+                None
+            };
+            let Some(table) = table else {
+                return Err(ExecError::Unsupported(
+                    "WHERE CURRENT OF is only supported in UPDATE and DELETE".to_string(),
+                ));
+            };
+            if !table.value.eq_ignore_ascii_case(cursor.table()) {
+                return Err(ExecError::Unsupported(format!(
+                    "Cursor '{}' reads table '{}', not '{}'",
+                    name.value,
+                    cursor.table(),
+                    table.value
+                )));
+            }
+            let row_id = cursor.current_row_id().ok_or_else(|| {
+                ExecError::NotFound(format!("Cursor '{}' is not positioned on a row", name.value))
+            })?;
+            let start = byte_offset(query, tokens[significant[pos + 1]].span.start);
+            let end = byte_offset(query, tokens[significant[pos + 3]].span.end);
+            replacements.push((start..end, format!("{} = {}", ROW_ID_COLUMN, row_id)));
+        }
+
+        let mut query = query.to_string();
+        for (range, replacement) in replacements.into_iter().rev() {
+            query.replace_range(range, &replacement);
+        }
+        Ok(query)
+    }
+
+    fn cursor(&mut self, name: &Ident) -> Result<&mut Cursor, ExecError> {
+        self.cursors
+            .get_mut(&name.value.to_lowercase())
+            .ok_or_else(|| ExecError::NotFound(format!("Cursor '{}' does not exist", name.value)))
+    }
+
+    fn show(&self, name: &[Ident]) -> Result<String, ExecError> {
+        let name = name
+            .iter()
+            .map(|part| part.value.as_str())
+            .collect::<Vec<_>>()
+            .join(".");
+        match name.to_lowercase().as_str() {
+            "autocommit" => Ok(if self.autocommit { "on" } else { "off" }.to_string()),
+            "atomic_batches" => Ok(if self.atomic_batches { "on" } else { "off" }.to_string()),
+            "protocol_version" => Ok(self.protocol_version.to_string()),
+            "transaction_isolation" => Ok("serializable".to_string()),
+            _ => Err(ExecError::NotFound(format!("Unknown setting '{}'", name))),
+        }
+    }
+}
+
+/// A boolean setting's value: `on`, `true` or `1`, or `off`, `false` or `0`
+fn parse_on_off(name: &str, value: &Expr) -> Result<bool, ExecError> {
+    let text = value.to_string().trim_matches('\'').to_lowercase();
+    match text.as_str() {
+        "on" | "true" | "1" => Ok(true),
+        "off" | "false" | "0" => Ok(false),
+        _ => Err(ExecError::Type(format!(
+            "{} must be on or off, got {}",
+            name, value
+        ))),
+    }
+}
+
+/// Serve one client until it disconnects. Returns whether it asked the
+/// server to shut down.
+///
+/// A transaction is part of the client's session: one the client leaves
+/// open is rolled back when it goes, however the connection ends, so the
+/// next client never sees its uncommitted changes.
+fn handle_client<S: ClientStream>(
+    stream: S,
+    db: &mut Database,
+    admin_token: Option<&str>,
+    heartbeat: Option<Heartbeat>,
+) -> std::io::Result<bool> {
+    let result = serve_client(stream, db, admin_token, heartbeat);
+    if db.in_transaction() {
+        log(
+            LogLevel::Info,
+            format_args!("Rolling back the transaction the client left open"),
+        );
+        if let Err(e) = db.rollback_transaction() {
+            log(LogLevel::Warn, format_args!("Failed to roll back transaction: {}", e));
+        }
+    }
+    result
+}
+
+/// Answer a client's requests until it disconnects
+fn serve_client<S: ClientStream>(
+    stream: S,
+    db: &mut Database,
+    admin_token: Option<&str>,
+    heartbeat: Option<Heartbeat>,
+) -> std::io::Result<bool> {
+    let peer_addr = stream.peer_name()?;
+    log(LogLevel::Info, format_args!("Client connected: {}", peer_addr));
 
     let read_stream = stream.try_clone()?;
     let write_stream = stream;
     
     let mut reader = BufReader::new(read_stream);
     let mut writer = BufWriter::new(write_stream);
-    let dialect = GenericDialect {};
+    let mut session = Session::default();
+    let mut first_message = true;
 
     loop {
+        if let Some(heartbeat) = heartbeat
+            && !await_client(&mut reader, &mut writer, heartbeat)?
+        {
+            log(
+                LogLevel::Warn,
+                format_args!("Client {} didn't answer a ping, dropping it", peer_addr),
+            );
+            break;
+        }
+
         // Read query from client
         let query = match read_query(&mut reader) {
-            Ok(q) if q.is_empty() => {
-                println!("Client {} disconnected", peer_addr);
+            Ok(Some(q)) => q,
+            Ok(None) => {
+                log(LogLevel::Info, format_args!("Client {} disconnected", peer_addr));
                 break;
             }
-            Ok(q) => q,
             Err(e) => {
-                if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                    println!("Client {} disconnected", peer_addr);
+                if e.kind() == std::io::ErrorKind::UnexpectedEof || is_disconnect(&e) {
+                    log(LogLevel::Info, format_args!("Client {} disconnected", peer_addr));
                     break;
                 }
-                eprintln!("Error reading query: {}", e);
+                log(LogLevel::Error, format_args!("Error reading query: {}", e));
                 break;
             }
         };
 
-        println!("Client {}: {}", peer_addr, query);
+        // An answer to a ping only shows the client is still there
+        if query == PONG {
+            continue;
+        }
+
+        log(
+            LogLevel::Debug,
+            format_args!("Client {}: {}", peer_addr, redact_token(&query)),
+        );
 
-        // Handle special commands
-        let response = match query.to_lowercase().as_str() {
-            "exit" | "quit" => {
-                write_response(&mut writer, &Response::Ok("Goodbye".to_string()))?;
-                println!("Client {} disconnected", peer_addr);
+        // The protocol version can only be agreed before anything else is sent
+        if let Some(requested) = parse_handshake(&query) {
+            if !std::mem::replace(&mut first_message, false) {
+                let response =
+                    Response::error(ErrorCode::Syntax, "HANDSHAKE must be the first message");
+                write_response(&mut writer, &response)?;
+                continue;
+            }
+            let version = match requested {
+                Ok(requested) => negotiate_version(requested)
+                    .map_err(|message| Response::error(ErrorCode::Unsupported, message)),
+                Err(message) => Err(Response::error(ErrorCode::Syntax, message)),
+            };
+            match version {
+                Ok(version) => {
+                    session.protocol_version = version;
+                    let response = Response::Ok(format!("PROTOCOL {}", version));
+                    write_response(&mut writer, &response)?;
+                    continue;
+                }
+                Err(response) => {
+                    write_response(&mut writer, &response)?;
+                    log(
+                        LogLevel::Info,
+                        format_args!("Client {} disconnected after a failed handshake", peer_addr),
+                    );
+                    break;
+                }
+            }
+        }
+        first_message = false;
+
+        if matches!(query.to_lowercase().as_str(), "exit" | "quit") {
+            write_response(&mut writer, &Response::Ok("Goodbye".to_string()))?;
+            log(LogLevel::Info, format_args!("Client {} disconnected", peer_addr));
+            break;
+        }
+
+        let (command, token) = query.split_once(' ').unwrap_or((&query, ""));
+        if command.eq_ignore_ascii_case("shutdown") {
+            match authorize_admin(admin_token, token.trim()) {
+                Ok(()) => {
+                    write_response(&mut writer, &Response::Ok("Shutting down".to_string()))?;
+                    log(
+                        LogLevel::Info,
+                        format_args!("Client {} requested shutdown", peer_addr),
+                    );
+                    return Ok(true);
+                }
+                Err(response) => write_response(&mut writer, &response)?,
+            }
+            continue;
+        }
+
+        // The data lines follow the statement, up to a line holding `\.`
+        let response = if is_copy_from_stdin(&query) {
+            match read_copy_data(&mut reader) {
+                Ok(Some(data)) => session.copy_from_stdin(db, &query, &data),
+                Err(e) if !is_disconnect(&e) => return Err(e),
+                _ => {
+                    log(
+                        LogLevel::Info,
+                        format_args!("Client {} disconnected during COPY", peer_addr),
+                    );
+                    break;
+                }
+            }
+        } else {
+            respond(db, &mut session, &query)
+        };
+        match write_response(&mut writer, &response) {
+            Ok(()) => {}
+            // The rest of the result is dropped, not an error for the server
+            Err(e) if is_disconnect(&e) => {
+                log(
+                    LogLevel::Info,
+                    format_args!("Client {} disconnected before reading its result", peer_addr),
+                );
                 break;
             }
-            "tables" => {
-                let msg = if db.tables.is_empty() {
-                    "No tables".to_string()
-                } else {
-                    db.tables.keys().map(|k| k.as_str()).collect::<Vec<_>>().join("\n")
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(false)
+}
+
+/// Wait until the client sends something, or closes the connection. After
+/// `heartbeat.interval` with nothing from it, it's sent a `PING`, and if
+/// nothing comes within `heartbeat.timeout` after that, returns false.
+fn await_client<S: ClientStream>(
+    reader: &mut BufReader<S>,
+    writer: &mut BufWriter<S>,
+    heartbeat: Heartbeat,
+) -> std::io::Result<bool> {
+    for (wait, last) in [(heartbeat.interval, false), (heartbeat.timeout, true)] {
+        if !reader.buffer().is_empty() {
+            return Ok(true);
+        }
+        reader.get_ref().set_read_timeout(Some(wait))?;
+        let ready = reader.fill_buf().map(|_| ());
+        reader.get_ref().set_read_timeout(None)?;
+        match ready {
+            Ok(()) => return Ok(true),
+            Err(e) if is_timeout(&e) && !last => {
+                writeln!(writer, "{}", PING)?;
+                writer.flush()?;
+            }
+            Err(e) if is_timeout(&e) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(false)
+}
+
+/// Whether an I/O error is a read timing out, which is `WouldBlock` on Unix
+fn is_timeout(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+    )
+}
+
+/// Whether an I/O error means the client has gone away
+fn is_disconnect(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::BrokenPipe
+            | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionAborted
+    )
+}
+
+/// Check the token given with an admin command against the server's
+fn authorize_admin(admin_token: Option<&str>, given: &str) -> Result<(), Response> {
+    match admin_token {
+        None => Err(Response::error(
+            ErrorCode::Unsupported,
+            "Admin commands are disabled; start the server with --admin-token to allow them",
+        )),
+        Some(token) if token == given => Ok(()),
+        Some(_) => Err(Response::error(
+            ErrorCode::PermissionDenied,
+            "Invalid admin token",
+        )),
+    }
+}
+
+/// A query as it's logged: the token given with `shutdown` is hidden
+fn redact_token(query: &str) -> Cow<'_, str> {
+    match query.split_once(' ') {
+        Some((command, _)) if command.eq_ignore_ascii_case("shutdown") => {
+            Cow::Owned(format!("{} <redacted>", command))
+        }
+        _ => Cow::Borrowed(query),
+    }
+}
+
+/// The byte offset in `text` of a tokenizer location (1-based line and
+/// character column)
+fn byte_offset(text: &str, location: Location) -> usize {
+    let mut line_start = 0;
+    for _ in 1..location.line {
+        line_start += text[line_start..].find('\n').map_or(0, |idx| idx + 1);
+    }
+    text[line_start..]
+        .char_indices()
+        .nth(location.column.saturating_sub(1) as usize)
+        .map_or(text.len(), |(idx, _)| line_start + idx)
+}
+
+/// Build the response to one query from a client
+fn respond(db: &mut Database, session: &mut Session, query: &str) -> Response {
+    // Handle special commands
+    match query.to_lowercase().as_str() {
+        "" => Response::Ok(EMPTY_QUERY.to_string()),
+        "tables" => {
+            let msg = if db.tables.is_empty() {
+                "No tables".to_string()
+            } else {
+                db.tables.keys().map(|k| k.as_str()).collect::<Vec<_>>().join("\n")
+            };
+            Response::Ok(msg)
+        }
+        command if command.starts_with("page ") => {
+            match command["page ".len()..].trim().parse() {
+                Ok(page_id) => match db.inspect_page(page_id) {
+                    Ok(description) => Response::Ok(description),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                        Response::error(ErrorCode::NotFound, e.to_string())
+                    }
+                    Err(e) => Response::error(ErrorCode::Storage, e.to_string()),
+                },
+                Err(_) => Response::error(ErrorCode::Syntax, "Usage: page <id>"),
+            }
+        }
+        "flush" => {
+            match db.buffer_pool.borrow_mut().flush_all() {
+                Ok(_) => Response::Ok("All pages flushed to disk".to_string()),
+                Err(e) => {
+                    Response::error(ErrorCode::Storage, format!("Failed to flush: {}", e))
+                }
+            }
+        }
+        _ => {
+            if let Some(result) = execute_reindex(db, query) {
+                return match result {
+                    Ok(msg) => Response::Ok(msg),
+                    Err(e) => Response::error(ErrorCode::from(&e), e.to_string()),
                 };
-                Response::Ok(msg)
             }
-            "flush" => {
-                match db.buffer_pool.borrow_mut().flush_all() {
-                    Ok(_) => Response::Ok("All pages flushed to disk".to_string()),
-                    Err(e) => Response::Error(format!("Failed to flush: {}", e)),
+            let query = match session.resolve_current_of(query) {
+                Ok(query) => query,
+                Err(e) => return Response::error(ErrorCode::from(&e), e.to_string()),
+            };
+            // Parse and execute SQL
+            match Parser::parse_sql(&MatiDialect {}, &query) {
+                Ok(statements) if statements.is_empty() => {
+                    // Nothing but comments
+                    Response::Ok(EMPTY_QUERY.to_string())
                 }
+                Ok(statements) => match session.execute_batch(db, statements) {
+                    Ok(results) => Response::Ok(results.join("\n")),
+                    Err(e) => Response::error(ErrorCode::from(&e), e.to_string()),
+                },
+                Err(e) => Response::error(ErrorCode::Syntax, format!("Parse error: {}", e)),
             }
-            _ => {
-                // Parse and execute SQL
-                match Parser::parse_sql(&dialect, &query) {
-                    Ok(statements) => {
-                        let mut results = Vec::new();
-                        let mut has_error = false;
-                        let mut error_msg = String::new();
-                        
-                        for stmt in statements {
-                            match execute(db, stmt) {
-                                Ok(msg) => results.push(msg),
-                                Err(e) => {
-                                    has_error = true;
-                                    error_msg = e;
-                                    break;
-                                }
-                            }
-                        }
-                        
-                        if has_error {
-                            Response::Error(error_msg)
-                        } else {
-                            Response::Ok(results.join("\n"))
-                        }
-                    }
-                    Err(e) => Response::Error(format!("Parse error: {}", e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::net::SocketAddr;
+    use std::sync::mpsc;
+    use std::thread;
+
+    #[test]
+    fn test_empty_query_response() {
+        let mut db = Database::in_memory();
+
+        for query in ["", "-- just a comment", "/* nothing */"] {
+            match respond(&mut db, &mut Session::default(), query) {
+                Response::Ok(msg) => assert_eq!(msg, EMPTY_QUERY),
+                other => panic!("Expected Ok response, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_init_file() {
+        let path = "test_server_init.db";
+        let catalog = format!("{}.catalog", path);
+        let script = "test_server_init.sql";
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+
+        fs::write(
+            script,
+            "CREATE TABLE users (id INT, name TEXT);\n\
+             CREATE TABLE users (id INT);\n\
+             INSERT INTO users VALUES (1, 'alice');\n",
+        )
+        .unwrap();
+
+        {
+            let mut server = Server::new("127.0.0.1:0", path).unwrap();
+            // The duplicate CREATE TABLE fails, but the INSERT still runs
+            server.run_init_file(script, false).unwrap();
+
+            match respond(&mut server.db, &mut Session::default(), "SELECT name FROM users") {
+                Response::Ok(msg) => assert!(msg.contains("alice")),
+                other => panic!("Expected Ok response, got {:?}", other),
+            }
+        }
+
+        {
+            let mut server = Server::new("127.0.0.1:0", path).unwrap();
+            let err = server.run_init_file(script, true).unwrap_err();
+            assert!(err.to_string().contains("already exists"));
+            assert!(server.run_init_file("no_such_script.sql", false).is_err());
+        }
+
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+        let _ = fs::remove_file(script);
+    }
+
+    /// Send queries to `handle_client` over a real connection, returning the
+    /// response text (or error message) for each
+    fn run_client(db: &mut Database, queries: &[&str]) -> Vec<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let queries: Vec<String> = queries.iter().map(|q| q.to_string()).collect();
+
+        let client = thread::spawn(move || send_queries(addr, &queries));
+
+        let (stream, _) = listener.accept().unwrap();
+        handle_client(stream, db, None, None).unwrap();
+        client.join().unwrap()
+    }
+
+    fn send_queries(addr: SocketAddr, queries: &[String]) -> Vec<String> {
+        exchange(TcpStream::connect(addr).unwrap(), queries)
+    }
+
+    fn exchange<S: ClientStream>(stream: S, queries: &[String]) -> Vec<String> {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        queries
+            .iter()
+            .map(|query| {
+                writeln!(writer, "{}", query).unwrap();
+                match Response::from_reader(&mut reader).unwrap() {
+                    Response::Ok(msg) => msg,
+                    Response::Error { message, .. } => message,
                 }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_shutdown_command() {
+        let path = "test_server_shutdown.db";
+        let catalog = format!("{}.catalog", path);
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+
+        let (addr_tx, addr_rx) = mpsc::channel();
+        let server = thread::spawn(move || {
+            let mut server = Server::new("127.0.0.1:0", path).unwrap();
+            server.set_admin_token(Some("secret".to_string()));
+            addr_tx.send(server.listener.local_name().unwrap()).unwrap();
+            server.run()
+        });
+        let addr: SocketAddr = addr_rx.recv().unwrap().parse().unwrap();
+
+        let queries: Vec<String> = [
+            "CREATE TABLE t (id INT)",
+            "INSERT INTO t VALUES (1), (2)",
+            "shutdown",
+            "SHUTDOWN wrong",
+            "shutdown secret",
+        ]
+        .iter()
+        .map(|q| q.to_string())
+        .collect();
+        let responses = send_queries(addr, &queries);
+        assert_eq!(responses[2], "Invalid admin token");
+        assert_eq!(responses[3], "Invalid admin token");
+        assert_eq!(responses[4], "Shutting down");
+
+        // The accept loop stops and the listener is closed
+        server.join().unwrap().unwrap();
+        assert!(TcpStream::connect(addr).is_err());
+
+        // Without a token the command is refused outright
+        assert!(matches!(
+            authorize_admin(None, ""),
+            Err(Response::Error {
+                code: ErrorCode::Unsupported,
+                ..
+            })
+        ));
+
+        // The data was flushed before exiting
+        let mut db = Database::new(path).unwrap();
+        match respond(&mut db, &mut Session::default(), "SELECT id FROM t") {
+            Response::Ok(msg) => assert!(msg.ends_with("(2 rows)"), "{}", msg),
+            other => panic!("Expected Ok response, got {:?}", other),
+        }
+        drop(db);
+
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+    }
+
+    #[test]
+    fn test_shutdown_token_not_logged() {
+        for query in ["shutdown secret", "SHUTDOWN  secret"] {
+            let logged = redact_token(query);
+            assert!(!logged.contains("secret"), "{}", logged);
+            assert!(logged.ends_with(" <redacted>"), "{}", logged);
+        }
+        assert_eq!(redact_token("shutdown"), "shutdown");
+        assert_eq!(
+            redact_token("SELECT 'shutdown secret'"),
+            "SELECT 'shutdown secret'"
+        );
+    }
+
+    #[test]
+    fn test_session_variables() {
+        let mut db = Database::in_memory();
+
+        let responses = run_client(
+            &mut db,
+            &[
+                "SHOW autocommit",
+                "CREATE TABLE t (id INT)",
+                "SET autocommit = off",
+                "SHOW AUTOCOMMIT",
+                "INSERT INTO t VALUES (1)",
+                "SET autocommit = maybe",
+                "SHOW no_such_setting",
+                "exit",
+            ],
+        );
+        assert_eq!(responses[0], "on");
+        assert_eq!(responses[2], "SET");
+        assert_eq!(responses[3], "off");
+        assert!(responses[5].contains("autocommit must be on or off"));
+        assert!(responses[6].contains("Unknown setting"));
+
+        // The INSERT started a transaction that was never committed, so it
+        // ended with the connection. Settings don't carry over either.
+        let responses = run_client(
+            &mut db,
+            &["SHOW autocommit", "SELECT * FROM t", "exit"],
+        );
+        assert_eq!(responses[0], "on");
+        assert!(responses[1].ends_with("(0 rows)"));
+    }
+
+    #[test]
+    fn test_transaction_isolation() {
+        let mut db = Database::in_memory();
+        let responses = run_client(
+            &mut db,
+            &[
+                "SHOW transaction_isolation",
+                "SET transaction_isolation = 'read committed'",
+                "BEGIN ISOLATION LEVEL READ COMMITTED",
+                "CREATE TABLE t (id INT)",
+                "COMMIT",
+                "exit",
+            ],
+        );
+        assert_eq!(responses[0], "serializable");
+        assert!(responses[1].contains("always serializable"), "{}", responses[1]);
+        // Any level can be asked for, since the one given is at least as strict
+        assert_eq!(responses[2], "BEGIN");
+        assert_eq!(responses[4], "COMMIT");
+    }
+
+    #[test]
+    fn test_cursor_fetches_in_batches() {
+        let mut db = Database::in_memory();
+        let mut session = Session::default();
+        let mut run = |query: &str| match respond(&mut db, &mut session, query) {
+            Response::Ok(msg) => Ok(msg),
+            Response::Error { message, .. } => Err(message),
+        };
+
+        // Enough rows to fill several pages
+        run("CREATE TABLE t (id INT, note TEXT)").unwrap();
+        let values: Vec<String> = (1..=300)
+            .map(|i| format!("({}, '{}')", i, "x".repeat(40)))
+            .collect();
+        run(&format!("INSERT INTO t VALUES {}", values.join(", "))).unwrap();
+
+        // Batches of 100 rows, or 7 so batches end mid-page
+        for (batch, query) in [
+            (100, "SELECT id, note FROM t"),
+            (7, "SELECT id FROM t WHERE id > 120 LIMIT 50 OFFSET 5"),
+        ] {
+            let full = run(query).unwrap();
+            run(&format!("DECLARE cur CURSOR FOR {}", query)).unwrap();
+            let mut rows = Vec::new();
+            loop {
+                let result = run(&format!("FETCH {} FROM cur", batch)).unwrap();
+                let lines: Vec<&str> = result.lines().collect();
+                let batch_rows = &lines[2..lines.len() - 1];
+                assert!(batch_rows.len() <= batch);
+                if batch_rows.is_empty() {
+                    break;
+                }
+                rows.extend(batch_rows.iter().map(|row| row.to_string()));
             }
+            assert_eq!(run("CLOSE cur").unwrap(), "CLOSE CURSOR");
+
+            let lines: Vec<&str> = full.lines().collect();
+            assert_eq!(rows, &lines[2..lines.len() - 1]);
+        }
+
+        assert!(run("FETCH 1 FROM cur").unwrap_err().contains("does not exist"));
+        assert!(
+            run("DECLARE c CURSOR FOR SELECT id FROM t ORDER BY id")
+                .unwrap_err()
+                .contains("storage order")
+        );
+        run("DECLARE c CURSOR FOR SELECT id FROM t").unwrap();
+        assert!(
+            run("DECLARE c CURSOR FOR SELECT id FROM t")
+                .unwrap_err()
+                .contains("already exists")
+        );
+        assert!(run("FETCH PRIOR FROM c").unwrap_err().contains("only move forward"));
+        assert!(run("FETCH NEXT FROM c").unwrap().ends_with("(1 rows)"));
+    }
+
+    #[test]
+    fn test_where_current_of() {
+        let mut db = Database::in_memory();
+        let mut session = Session::default();
+        let mut run = |query: &str| match respond(&mut db, &mut session, query) {
+            Response::Ok(msg) => Ok(msg),
+            Response::Error { message, .. } => Err(message),
         };
 
-        write_response(&mut writer, &response)?;
+        run("CREATE TABLE users (id INT, name TEXT)").unwrap();
+        run("CREATE TABLE other (id INT)").unwrap();
+        run("INSERT INTO users VALUES (1, 'a'), (2, 'b'), (3, 'c'), (4, 'd')").unwrap();
+        run("DECLARE cur CURSOR FOR SELECT id FROM users WHERE id > 1").unwrap();
+        assert!(
+            run("UPDATE users SET name = 'x' WHERE CURRENT OF cur")
+                .unwrap_err()
+                .contains("not positioned on a row")
+        );
+
+        // The last row of the batch is the current one
+        assert!(run("FETCH 2 FROM cur").unwrap().contains("\n3\n"));
+        assert_eq!(
+            run("UPDATE users SET name = 'it''s' WHERE CURRENT OF cur").unwrap(),
+            "Updated 1 row(s)"
+        );
+        let result = run("SELECT id, name FROM users").unwrap();
+        let rows: Vec<&str> = result.lines().skip(2).collect();
+        assert_eq!(rows, ["1\ta", "2\tb", "3\tit's", "4\td", "(4 rows)"]);
+
+        run("FETCH NEXT FROM cur").unwrap();
+        assert_eq!(
+            run("delete from users where current of CUR;").unwrap(),
+            "Deleted 1 row(s)"
+        );
+        assert!(run("SELECT * FROM users").unwrap().ends_with("(3 rows)"));
+
+        for (query, error) in [
+            ("DELETE FROM other WHERE CURRENT OF cur", "reads table 'users'"),
+            ("DELETE FROM users WHERE CURRENT OF nope", "does not exist"),
+        ] {
+            let message = run(query).unwrap_err();
+            assert!(message.contains(error), "{}: {}", query, message);
+        }
+
+        // A FETCH past the last row leaves the cursor on none
+        assert!(run("FETCH NEXT FROM cur").unwrap().ends_with("(0 rows)"));
+        assert!(
+            run("DELETE FROM users WHERE CURRENT OF cur")
+                .unwrap_err()
+                .contains("not positioned on a row")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket() {
+        let path = "test_server_unix.db";
+        let catalog = format!("{}.catalog", path);
+        let socket = std::env::temp_dir().join(format!("matidb-test-{}.sock", std::process::id()));
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+        // A socket file left behind by an earlier server is replaced
+        drop(UnixListener::bind(&socket).unwrap());
+        assert!(socket.exists());
+
+        let (name_tx, name_rx) = mpsc::channel();
+        let socket_path = socket.to_str().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let db = Database::new(path).unwrap();
+            let mut server = Server::with_database_on_socket(&socket_path, path, db).unwrap();
+            server.set_admin_token(Some("secret".to_string()));
+            name_tx.send(server.listener.local_name().unwrap()).unwrap();
+            server.run()
+        });
+        assert_eq!(
+            name_rx.recv().unwrap(),
+            format!("unix:{}", socket.display())
+        );
+
+        let queries: Vec<String> = [
+            "CREATE TABLE t (id INT)",
+            "INSERT INTO t VALUES (1), (2)",
+            "SELECT id FROM t",
+            "shutdown secret",
+        ]
+        .iter()
+        .map(|q| q.to_string())
+        .collect();
+        let responses = exchange(UnixStream::connect(&socket).unwrap(), &queries);
+        assert!(responses[2].ends_with("(2 rows)"), "{}", responses[2]);
+        assert_eq!(responses[3], "Shutting down");
+
+        // The server removes its socket file once it's stopped
+        server.join().unwrap().unwrap();
+        assert!(!socket.exists());
+        assert!(UnixStream::connect(&socket).is_err());
+
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(&catalog);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_client_disconnects_mid_result() {
+        use std::io::BufRead;
+
+        let mut db = Database::in_memory();
+        let mut session = Session::default();
+        respond(&mut db, &mut session, "CREATE TABLE big (id INT, note TEXT)");
+        let values: Vec<String> = (0..20_000)
+            .map(|i| format!("({}, '{}')", i, "x".repeat(50)))
+            .collect();
+        let insert = format!("INSERT INTO big VALUES {}", values.join(", "));
+        assert!(matches!(respond(&mut db, &mut session, &insert), Response::Ok(_)));
+
+        // The result is far bigger than a socket buffer, so the server is
+        // still writing it when the client hangs up
+        let (server_end, client_end) = UnixStream::pair().unwrap();
+        let client = thread::spawn(move || {
+            let mut reader = BufReader::new(client_end.try_clone().unwrap());
+            let mut writer = client_end;
+            for query in ["BEGIN", "INSERT INTO big VALUES (20000, 'uncommitted')"] {
+                writeln!(writer, "{}", query).unwrap();
+                assert!(matches!(Response::from_reader(&mut reader), Ok(Response::Ok(_))));
+            }
+            writeln!(writer, "SELECT * FROM big").unwrap();
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, "OK\n");
+        });
+
+        // The server stops writing, and the client's transaction is rolled back
+        let shutdown = handle_client(server_end, &mut db, None, None).unwrap();
+        assert!(!shutdown);
+        client.join().unwrap();
+        assert!(!db.in_transaction());
+
+        let responses = run_client(&mut db, &["SELECT COUNT(*) FROM big", "exit"]);
+        assert!(responses[0].contains("\n20000\n"), "{}", responses[0]);
+    }
+
+    #[test]
+    fn test_protocol_handshake() {
+        use crate::protocol::{handshake, PROTOCOL_VERSION};
+        let mut db = Database::in_memory();
+
+        // A newer client is downgraded to the version the server speaks
+        let newer = handshake(PROTOCOL_VERSION + 1);
+        let responses = run_client(
+            &mut db,
+            &[&newer, "SHOW protocol_version", "HANDSHAKE 1", "exit"],
+        );
+        assert_eq!(responses[0], format!("PROTOCOL {}", PROTOCOL_VERSION));
+        assert_eq!(responses[1], PROTOCOL_VERSION.to_string());
+        assert!(responses[2].contains("must be the first message"));
+
+        // Clients that don't send a handshake get the oldest version
+        let responses = run_client(&mut db, &["SHOW protocol_version", "exit"]);
+        assert_eq!(responses[0], MIN_PROTOCOL_VERSION.to_string());
+
+        // An unsupported version is refused and the connection closed
+        let responses = run_client(&mut db, &["HANDSHAKE 0"]);
+        assert!(responses[0].contains("Protocol version 0 is not supported"));
+    }
+
+    #[test]
+    fn test_transaction_ends_with_session() {
+        let mut db = Database::in_memory();
+        run_client(&mut db, &["CREATE TABLE t (id INT)", "INSERT INTO t VALUES (1)", "exit"]);
+
+        // The first client's uncommitted rows are gone before the second
+        // connects, and the second's go with it in turn
+        let responses = run_client(
+            &mut db,
+            &["BEGIN", "INSERT INTO t VALUES (2)", "SELECT id FROM t"],
+        );
+        assert!(responses[2].ends_with("1\n2\n(2 rows)"), "{}", responses[2]);
+        assert!(!db.in_transaction());
+
+        let responses = run_client(
+            &mut db,
+            &["SELECT id FROM t", "BEGIN", "DELETE FROM t", "quit"],
+        );
+        assert!(responses[0].ends_with("1\n(1 rows)"), "{}", responses[0]);
+        assert!(!db.in_transaction());
+
+        let responses = run_client(&mut db, &["SELECT id FROM t", "exit"]);
+        assert!(responses[0].ends_with("1\n(1 rows)"), "{}", responses[0]);
+    }
+
+    #[test]
+    fn test_heartbeat() {
+        let heartbeat = Heartbeat {
+            interval: Duration::from_millis(50),
+            timeout: Duration::from_millis(100),
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let stream = TcpStream::connect(addr).unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut read_line = || {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                line
+            };
+
+            // Answered pings keep the connection open, and a query is
+            // answered as usual after them
+            for _ in 0..2 {
+                assert_eq!(read_line(), "PING\n");
+                writeln!(writer, "PONG").unwrap();
+            }
+            writeln!(writer, "tables").unwrap();
+            assert_eq!(read_line(), "OK\n");
+            assert_eq!(read_line(), "No tables\n");
+            assert_eq!(read_line(), "END\n");
+
+            // A missed one gets the client dropped
+            assert_eq!(read_line(), "PING\n");
+            assert_eq!(read_line(), "");
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut db = Database::in_memory();
+        let shutdown = handle_client(stream, &mut db, None, Some(heartbeat)).unwrap();
+        assert!(!shutdown);
+        client.join().unwrap();
     }
 
-    Ok(())
+    #[test]
+    fn test_atomic_batches() {
+        let mut db = Database::in_memory();
+        let batch = "INSERT INTO t VALUES (1); CREATE TABLE u (id INT); \
+                     INSERT INTO t VALUES (1); INSERT INTO t VALUES (2)";
+        let responses = run_client(
+            &mut db,
+            &[
+                "CREATE TABLE t (id INT PRIMARY KEY)",
+                "SHOW atomic_batches",
+                "SET atomic_batches = on",
+                // The third statement fails, taking the first two with it
+                batch,
+                "SELECT id FROM t",
+                "SELECT * FROM u",
+                "INSERT INTO t VALUES (5); BEGIN; INSERT INTO t VALUES (6)",
+                "INSERT INTO t VALUES (3); INSERT INTO t VALUES (4)",
+                "SET atomic_batches = off",
+                batch,
+                "SELECT id FROM t ORDER BY id",
+            ],
+        );
+        assert_eq!(responses[1], "off");
+        assert!(responses[3].contains("Duplicate"), "{}", responses[3]);
+        assert!(responses[4].ends_with("(0 rows)"), "{}", responses[4]);
+        assert!(responses[5].contains("does not exist"), "{}", responses[5]);
+        assert!(responses[6].contains("can't contain BEGIN"), "{}", responses[6]);
+        assert_eq!(responses[7], "Inserted 1 row(s)\nInserted 1 row(s)");
+        // Without atomic batches, the statements before the failing one stay
+        assert!(responses[9].contains("Duplicate"), "{}", responses[9]);
+        assert!(responses[10].ends_with("1\n3\n4\n(3 rows)"), "{}", responses[10]);
+        assert!(!db.in_transaction());
+        assert!(db.tables.contains_key("u"));
+    }
+
+    #[test]
+    fn test_copy_to_stdout_and_from_stdin() {
+        let mut db = Database::in_memory();
+        let create = "CREATE TABLE users (id INT PRIMARY KEY, name TEXT, note TEXT, score INT)";
+        let responses = run_client(
+            &mut db,
+            &[
+                create,
+                "INSERT INTO users VALUES (1, 'Smith, Jane', 'says \"hi\"', 10), (2, '', NULL, 20)",
+                // A quoted field may span lines, and `\.` only ends the data
+                // when it's a line of its own
+                "COPY users FROM STDIN\n3,\"two\nlines\",\\.,-7\n\\.",
+                "COPY users TO STDOUT",
+                "SELECT * FROM users ORDER BY id",
+                "COPY users (id, name) TO STDOUT WITH (FORMAT csv, HEADER true)",
+            ],
+        );
+        assert_eq!(responses[2], "Copied 1 row(s)");
+        let csv = &responses[3];
+        assert_eq!(
+            csv,
+            "1,\"Smith, Jane\",\"says \"\"hi\"\"\",10\n2,\"\",,20\n3,\"two\nlines\",\"\\.\",-7"
+        );
+        assert_eq!(responses[5], "id,name\n1,\"Smith, Jane\"\n2,\"\"\n3,\"two\nlines\"");
+
+        // The rows copied out go back in unchanged: empty strings stay empty
+        // and NULLs stay NULL
+        let copy_in = format!("COPY users2 FROM STDIN;\n{}\n\\.", csv);
+        let responses2 = run_client(
+            &mut db,
+            &[
+                &create.replace("users", "users2"),
+                &copy_in,
+                "SELECT * FROM users2 ORDER BY id",
+                "SELECT COUNT(*) FROM users2 WHERE note IS NULL AND name = ''",
+            ],
+        );
+        assert_eq!(responses2[1], "Copied 3 row(s)");
+        assert_eq!(responses2[2], responses[4]);
+        assert!(responses2[3].ends_with("1\n(1 rows)"), "{}", responses2[3]);
+
+        // A bad row copies nothing, and the connection carries on after the
+        // data either way
+        let responses = run_client(
+            &mut db,
+            &[
+                "COPY users2 (id, score) FROM STDIN\n4,40\n5\n\\.",
+                "COPY users2 (id) FROM STDIN\n1\n\\.",
+                "COPY users2 FROM STDIN\n\\.",
+                "COPY users2 TO '/tmp/users.csv'",
+                "COPY users2 TO STDOUT (FORMAT binary)",
+                "SELECT COUNT(*) FROM users2",
+            ],
+        );
+        assert!(responses[0].contains("COPY row 2 has 1 field(s), expected 2"), "{}", responses[0]);
+        assert!(responses[1].contains("Duplicate"), "{}", responses[1]);
+        assert!(responses[2].contains("Copied 0 row(s)"), "{}", responses[2]);
+        assert!(responses[3].contains("only supports TO STDOUT"), "{}", responses[3]);
+        assert!(responses[4].contains("Unsupported COPY option"), "{}", responses[4]);
+        assert!(responses[5].ends_with("3\n(1 rows)"), "{}", responses[5]);
+    }
 }
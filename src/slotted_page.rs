@@ -4,6 +4,9 @@ use crate::storage::{PAGE_SIZE, Page};
 // [0..2]: num_slots (u16)
 // [2..4]: free_space_pointer (u16)
 // [4..8]: next_page_id (u32) - u32::MAX means no next page
+//
+// A deleted slot keeps its place in the slot array (so slot ids stay stable)
+// but has offset 0, which can never hold tuple data because the header is there.
 
 const HEADER_SIZE: usize = 8;
 const SLOT_SIZE: usize = 4; // offset (u16) + length (u16)
@@ -96,6 +99,18 @@ impl<'a> SlottedPage<'a> {
         data_start.saturating_sub(slots_end)
     }
 
+    /// Whether a tuple of `tuple_len` bytes fits and leaves the page no more
+    /// than `fill_factor` percent full. An empty page takes any tuple that
+    /// fits at all, so rows bigger than the fill factor allows still go in.
+    pub fn fits_within(&self, tuple_len: usize, fill_factor: u8) -> bool {
+        let required_space = SLOT_SIZE + tuple_len;
+        if self.free_space() < required_space {
+            return false;
+        }
+        let used = PAGE_SIZE - self.free_space() + required_space;
+        self.num_slots() == 0 || used * 100 <= PAGE_SIZE * fill_factor as usize
+    }
+
     /// Add a tuple to the page
     pub fn add_tuple(&mut self, tuple_data: &[u8]) -> Result<u16, String> {
         let tuple_len = tuple_data.len();
@@ -122,11 +137,19 @@ impl<'a> SlottedPage<'a> {
         self.set_num_slots(slot_id + 1);
         self.set_free_space_pointer(new_data_offset as u16);
 
+        debug_assert_eq!(self.validate(), Ok(()));
         Ok(slot_id)
     }
 
-    /// Get a tuple by slot ID
+    /// Get a copy of a tuple by slot ID. Deleted slots return `None`.
+    #[allow(dead_code)]
     pub fn get_tuple(&self, slot_id: u16) -> Option<Vec<u8>> {
+        self.get_tuple_ref(slot_id).map(<[u8]>::to_vec)
+    }
+
+    /// Borrow a tuple's bytes straight from the page, without copying.
+    /// Deleted slots return `None`.
+    pub fn get_tuple_ref(&self, slot_id: u16) -> Option<&[u8]> {
         if slot_id >= self.num_slots() {
             return None;
         }
@@ -135,7 +158,197 @@ impl<'a> SlottedPage<'a> {
         let data_offset = read_u16(&self.page.data, slot_offset) as usize;
         let data_length = read_u16(&self.page.data, slot_offset + 2) as usize;
 
-        Some(self.page.data[data_offset..data_offset + data_length].to_vec())
+        if data_offset == 0 {
+            return None;
+        }
+
+        Some(&self.page.data[data_offset..data_offset + data_length])
+    }
+
+    /// Mark a tuple as deleted. Its data space is not reclaimed.
+    pub fn delete_tuple(&mut self, slot_id: u16) -> Result<(), String> {
+        if self.get_tuple_ref(slot_id).is_none() {
+            return Err(format!("No tuple in slot {}", slot_id));
+        }
+
+        let slot_offset = HEADER_SIZE + (slot_id as usize * SLOT_SIZE);
+        write_u16(&mut self.page.data, slot_offset, 0);
+        write_u16(&mut self.page.data, slot_offset + 2, 0);
+
+        Ok(())
+    }
+
+    /// Drop deleted slots from the end of the slot array, returning how many
+    /// were removed. Their ids may be handed out again by `add_tuple`.
+    ///
+    /// The free space pointer is also raised to the lowest remaining tuple,
+    /// which gives back the data of deleted tuples that were added last.
+    pub fn trim_deleted_slots(&mut self) -> u16 {
+        let num_slots = self.num_slots();
+        let mut remaining = num_slots;
+        while remaining > 0 && self.get_tuple_ref(remaining - 1).is_none() {
+            remaining -= 1;
+        }
+        if remaining == num_slots {
+            return 0;
+        }
+
+        let data_start = (0..remaining)
+            .filter(|&slot_id| self.get_tuple_ref(slot_id).is_some())
+            .map(|slot_id| read_u16(&self.page.data, HEADER_SIZE + slot_id as usize * SLOT_SIZE))
+            .min()
+            .unwrap_or(PAGE_SIZE as u16);
+        self.set_num_slots(remaining);
+        self.set_free_space_pointer(data_start);
+
+        debug_assert_eq!(self.validate(), Ok(()));
+        num_slots - remaining
+    }
+
+    /// Replace a tuple's data, keeping its slot id.
+    /// Data that fits is overwritten in place; larger data is written into
+    /// the page's free space, failing with "Page full" if there isn't room.
+    pub fn update_tuple(&mut self, slot_id: u16, tuple_data: &[u8]) -> Result<(), String> {
+        let old_len = match self.get_tuple_ref(slot_id) {
+            Some(old) => old.len(),
+            None => return Err(format!("No tuple in slot {}", slot_id)),
+        };
+
+        let slot_offset = HEADER_SIZE + (slot_id as usize * SLOT_SIZE);
+        let tuple_len = tuple_data.len();
+
+        let data_offset = if tuple_len <= old_len {
+            read_u16(&self.page.data, slot_offset) as usize
+        } else {
+            if self.free_space() < tuple_len {
+                return Err("Page full".to_string());
+            }
+            let new_data_offset = self.free_space_pointer() as usize - tuple_len;
+            self.set_free_space_pointer(new_data_offset as u16);
+            new_data_offset
+        };
+
+        self.page.data[data_offset..data_offset + tuple_len].copy_from_slice(tuple_data);
+        write_u16(&mut self.page.data, slot_offset, data_offset as u16);
+        write_u16(&mut self.page.data, slot_offset + 2, tuple_len as u16);
+
+        debug_assert_eq!(self.validate(), Ok(()));
+        Ok(())
+    }
+
+    /// Check the header and slot array for corruption.
+    ///
+    /// The slot array must end before the free space pointer, which must be
+    /// within the page, and every tuple must lie in the data region between
+    /// the free space pointer and the end of the page without overlapping
+    /// another tuple.
+    pub fn validate(&self) -> Result<(), String> {
+        let slots_end = self.slots_end();
+        if slots_end > PAGE_SIZE {
+            return Err(format!(
+                "Slot array for {} slots ends at {}, past the end of the page",
+                self.num_slots(),
+                slots_end
+            ));
+        }
+
+        let free_space_pointer = self.free_space_pointer() as usize;
+        if free_space_pointer > PAGE_SIZE {
+            return Err(format!(
+                "Free space pointer {} is past the end of the page",
+                free_space_pointer
+            ));
+        }
+        if free_space_pointer < slots_end {
+            return Err(format!(
+                "Free space pointer {} is inside the slot array ending at {}",
+                free_space_pointer, slots_end
+            ));
+        }
+
+        let mut ranges = Vec::with_capacity(self.num_slots() as usize);
+        for slot_id in 0..self.num_slots() {
+            let slot_offset = HEADER_SIZE + (slot_id as usize * SLOT_SIZE);
+            let start = read_u16(&self.page.data, slot_offset) as usize;
+            let end = start + read_u16(&self.page.data, slot_offset + 2) as usize;
+            if start == 0 {
+                continue; // Deleted
+            }
+
+            if start < free_space_pointer || end > PAGE_SIZE {
+                return Err(format!(
+                    "Slot {} data [{}..{}) is outside the data region [{}..{})",
+                    slot_id, start, end, free_space_pointer, PAGE_SIZE
+                ));
+            }
+            ranges.push((start, end, slot_id));
+        }
+
+        ranges.sort_unstable();
+        for pair in ranges.windows(2) {
+            let (_, prev_end, prev_slot) = pair[0];
+            let (start, _, slot_id) = pair[1];
+            if start < prev_end {
+                return Err(format!(
+                    "Slot {} data overlaps slot {} data",
+                    slot_id, prev_slot
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Describe the header and every slot, for debugging storage problems.
+    ///
+    /// Reads the raw slot entries without trusting them, so it works on
+    /// corrupted pages too: slots past the end of the page are skipped and
+    /// data outside the page is not shown.
+    pub fn describe(&self) -> String {
+        let next = match self.next_page_id() {
+            NO_NEXT_PAGE => "none".to_string(),
+            page_id => page_id.to_string(),
+        };
+        let mut output = format!(
+            "num_slots: {}\nfree_space_pointer: {}\nnext_page_id: {}\nfree_space: {}",
+            self.num_slots(),
+            self.free_space_pointer(),
+            next,
+            self.free_space()
+        );
+
+        let readable = (PAGE_SIZE - HEADER_SIZE) / SLOT_SIZE;
+        for slot_id in 0..self.num_slots().min(readable as u16) {
+            let slot_offset = HEADER_SIZE + (slot_id as usize * SLOT_SIZE);
+            let start = read_u16(&self.page.data, slot_offset) as usize;
+            let len = read_u16(&self.page.data, slot_offset + 2) as usize;
+            if start == 0 {
+                output.push_str(&format!("\nslot {}: deleted", slot_id));
+                continue;
+            }
+
+            output.push_str(&format!(
+                "\nslot {}: offset {}, length {}",
+                slot_id, start, len
+            ));
+            let preview = self.page.data.get(start..(start + len).min(start + 16));
+            match preview {
+                Some(bytes) if start + len <= PAGE_SIZE => {
+                    let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+                    let more = if len > bytes.len() { " ..." } else { "" };
+                    output.push_str(&format!(" [{}{}]", hex.join(" "), more));
+                }
+                _ => output.push_str(" (outside the page)"),
+            }
+        }
+        if self.num_slots() as usize > readable {
+            output.push_str(&format!(
+                "\n{} slot(s) past the end of the page not shown",
+                self.num_slots() as usize - readable
+            ));
+        }
+
+        output
     }
 }
 
@@ -228,6 +441,27 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_fits_within_fill_factor() {
+        let mut page = Page::new();
+        let mut slotted = SlottedPage::new(&mut page);
+        slotted.init();
+
+        // An empty page takes a tuple bigger than the fill factor allows
+        assert!(slotted.fits_within(3000, 50));
+        assert!(!slotted.fits_within(PAGE_SIZE, 100));
+
+        let tuple_data = [0u8; 100];
+        let mut count = 0;
+        while slotted.fits_within(tuple_data.len(), 50) {
+            slotted.add_tuple(&tuple_data).unwrap();
+            count += 1;
+        }
+        assert_eq!(count, 19);
+        assert!(slotted.free_space() >= PAGE_SIZE / 2);
+        assert!(slotted.fits_within(tuple_data.len(), 100));
+    }
+
     #[test]
     fn test_variable_length_tuples() {
         let mut page = Page::new();
@@ -265,7 +499,139 @@ mod tests {
         assert_eq!(slotted.next_page_id(), NO_NEXT_PAGE);
     }
 
+    #[test]
+    fn test_delete_tuple() {
+        let mut page = Page::new();
+        let mut slotted = SlottedPage::new(&mut page);
+        slotted.init();
+
+        slotted.add_tuple(&[1, 2, 3]).unwrap();
+        slotted.add_tuple(&[4, 5]).unwrap();
+
+        slotted.delete_tuple(0).unwrap();
+        assert!(slotted.get_tuple(0).is_none());
+        assert_eq!(slotted.get_tuple(1).unwrap(), vec![4, 5]);
+        assert_eq!(slotted.num_slots(), 2);
+        assert_eq!(slotted.validate(), Ok(()));
+
+        assert!(slotted.delete_tuple(0).is_err());
+        assert!(slotted.delete_tuple(7).is_err());
+    }
+
+    #[test]
+    fn test_trim_deleted_slots() {
+        let mut page = Page::new();
+        let mut slotted = SlottedPage::new(&mut page);
+        slotted.init();
+
+        for i in 0..5u8 {
+            slotted.add_tuple(&[i; 10]).unwrap();
+        }
+        // A deleted slot before a live one stays
+        slotted.delete_tuple(1).unwrap();
+        assert_eq!(slotted.trim_deleted_slots(), 0);
+
+        let free_before = slotted.free_space();
+        slotted.delete_tuple(4).unwrap();
+        slotted.delete_tuple(3).unwrap();
+        assert_eq!(slotted.trim_deleted_slots(), 2);
+
+        assert_eq!(slotted.num_slots(), 3);
+        // Two slot entries and the two tuples' data are free again
+        assert_eq!(slotted.free_space(), free_before + 2 * (SLOT_SIZE + 10));
+        assert_eq!(slotted.get_tuple(2).unwrap(), vec![2; 10]);
+        assert_eq!(slotted.validate(), Ok(()));
+
+        // Trimming stops at the live tuple in slot 2; the freed ids are reused
+        assert_eq!(slotted.add_tuple(&[9]).unwrap(), 3);
+
+        slotted.delete_tuple(0).unwrap();
+        slotted.delete_tuple(2).unwrap();
+        slotted.delete_tuple(3).unwrap();
+        assert_eq!(slotted.trim_deleted_slots(), 4);
+        assert_eq!(slotted.num_slots(), 0);
+        assert_eq!(slotted.free_space(), PAGE_SIZE - HEADER_SIZE);
+    }
+
+    #[test]
+    fn test_update_tuple() {
+        let mut page = Page::new();
+        let mut slotted = SlottedPage::new(&mut page);
+        slotted.init();
+
+        slotted.add_tuple(&[1, 2, 3]).unwrap();
+        slotted.add_tuple(&[4, 5]).unwrap();
+        let free = slotted.free_space();
+
+        // Shrinking reuses the tuple's own space
+        slotted.update_tuple(0, &[9]).unwrap();
+        assert_eq!(slotted.get_tuple(0).unwrap(), vec![9]);
+        assert_eq!(slotted.free_space(), free);
+
+        // Growing moves the tuple into free space
+        slotted.update_tuple(1, &[7; 10]).unwrap();
+        assert_eq!(slotted.get_tuple(1).unwrap(), vec![7; 10]);
+        assert_eq!(slotted.free_space(), free - 10);
+
+        assert_eq!(
+            slotted.update_tuple(0, &[0; PAGE_SIZE]),
+            Err("Page full".to_string())
+        );
+        assert_eq!(slotted.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_well_formed_page() {
+        let mut page = Page::new();
+        let mut slotted = SlottedPage::new(&mut page);
+        slotted.init();
+        assert_eq!(slotted.validate(), Ok(()));
+
+        slotted.add_tuple(&[1, 2, 3]).unwrap();
+        slotted.add_tuple(&[]).unwrap();
+        slotted.add_tuple(&[4; 200]).unwrap();
+        assert_eq!(slotted.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_corrupted_pages() {
+        let corrupted = |corrupt: fn(&mut SlottedPage)| {
+            let mut page = Page::new();
+            let mut slotted = SlottedPage::new(&mut page);
+            slotted.init();
+            slotted.add_tuple(&[1; 10]).unwrap();
+            slotted.add_tuple(&[2; 10]).unwrap();
+            corrupt(&mut slotted);
+            slotted.validate().unwrap_err()
+        };
 
+        // Slot count so large the slot array runs off the page
+        let err = corrupted(|s| s.set_num_slots(2000));
+        assert_eq!(
+            err,
+            "Slot array for 2000 slots ends at 8008, past the end of the page"
+        );
+
+        let err = corrupted(|s| s.set_free_space_pointer(PAGE_SIZE as u16 + 1));
+        assert_eq!(err, "Free space pointer 4097 is past the end of the page");
+
+        let err = corrupted(|s| s.set_free_space_pointer(10));
+        assert_eq!(
+            err,
+            "Free space pointer 10 is inside the slot array ending at 16"
+        );
+
+        // First slot's length stretches past the end of the page
+        let err = corrupted(|s| write_u16(&mut s.page.data, HEADER_SIZE + 2, 100));
+        assert_eq!(
+            err,
+            "Slot 0 data [4086..4186) is outside the data region [4076..4096)"
+        );
+
+        // Second slot points at the first slot's data
+        let err = corrupted(|s| write_u16(&mut s.page.data, HEADER_SIZE + SLOT_SIZE, 4080));
+        assert_eq!(err, "Slot 0 data overlaps slot 1 data");
+    }
 
     #[test]
     fn test_with_row_serialization() {
@@ -294,4 +660,74 @@ mod tests {
         assert!(matches!(&restored_row.values[1], Value::Text(s) if s == "Alice"));
         assert!(matches!(restored_row.values[2], Value::Bool(true)));
     }
+
+    #[test]
+    fn test_describe_page() {
+        let mut page = Page::new();
+        let mut slotted = SlottedPage::new(&mut page);
+        slotted.init();
+        slotted.add_tuple(&[1, 2, 3]).unwrap();
+        slotted.add_tuple(&[0xab; 20]).unwrap();
+        slotted.add_tuple(&[9]).unwrap();
+        slotted.delete_tuple(2).unwrap();
+        slotted.set_next_page_id(7);
+
+        let description = slotted.describe();
+        let lines: Vec<&str> = description.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "num_slots: 3",
+                "free_space_pointer: 4072",
+                "next_page_id: 7",
+                "free_space: 4052",
+                "slot 0: offset 4093, length 3 [01 02 03]",
+                "slot 1: offset 4073, length 20 [ab ab ab ab ab ab ab ab ab ab ab ab ab ab ab ab ...]",
+                "slot 2: deleted",
+            ]
+        );
+
+        // A corrupted slot array is reported, not trusted
+        write_u16(&mut slotted.page.data, 0, u16::MAX);
+        assert!(
+            slotted
+                .describe()
+                .contains("past the end of the page not shown")
+        );
+    }
+
+    #[test]
+    fn test_borrowed_tuple_matches_copy() {
+        use crate::database::{Row, Value};
+
+        let mut page = Page::new();
+        let mut slotted = SlottedPage::new(&mut page);
+        slotted.init();
+
+        for i in 0..20 {
+            let row = Row {
+                values: vec![Value::Long(i), Value::Text("x".repeat(i as usize))],
+            };
+            slotted.add_tuple(&row.to_bytes()).unwrap();
+        }
+        slotted.delete_tuple(3).unwrap();
+
+        let mut copied = Vec::new();
+        let mut borrowed = Vec::new();
+        for slot_id in 0..slotted.num_slots() + 1 {
+            assert_eq!(
+                slotted.get_tuple_ref(slot_id).map(<[u8]>::to_vec),
+                slotted.get_tuple(slot_id)
+            );
+            if let Some(bytes) = slotted.get_tuple(slot_id) {
+                copied.push(Row::from_bytes(&bytes).unwrap().values);
+            }
+            if let Some(bytes) = slotted.get_tuple_ref(slot_id) {
+                borrowed.push(Row::from_bytes(bytes).unwrap().values);
+            }
+        }
+
+        assert_eq!(borrowed.len(), 19);
+        assert_eq!(borrowed, copied);
+    }
 }
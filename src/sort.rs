@@ -0,0 +1,269 @@
+use crate::database::{Row, Value};
+use std::cmp::Ordering;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+
+/// Rows ORDER BY holds in memory before writing them out as a sorted run
+pub const DEFAULT_SORT_BUFFER_ROWS: usize = 100_000;
+
+/// A row being sorted: its sort key, DISTINCT ON key and output values
+pub type SortRow = (Vec<Value>, Vec<Value>, Vec<Value>);
+
+/// Distinguishes the run files of one process, since sorts can run side by side
+static NEXT_RUN: AtomicUsize = AtomicUsize::new(0);
+
+/// Sorted runs spilled to temporary files, for an external merge sort that
+/// keeps at most `buffer_rows` rows in memory.
+///
+/// Rows are buffered by the caller; once the buffer is full, `spill_if_full` sorts it
+/// and writes it out as a run. `merge` then sorts what's left in memory and
+/// merges it with the runs. Each run is sorted stably and the runs are merged
+/// in the order they were written, so rows with equal keys keep the order they
+/// were added in.
+pub struct SortRuns {
+    buffer_rows: usize,
+    runs: Vec<Run>,
+}
+
+impl SortRuns {
+    pub fn new(buffer_rows: usize) -> Self {
+        Self {
+            buffer_rows: buffer_rows.max(1),
+            runs: Vec::new(),
+        }
+    }
+
+    /// Number of runs written so far
+    #[allow(dead_code)]
+    pub fn run_count(&self) -> usize {
+        self.runs.len()
+    }
+
+    /// Sort `rows` and write them out as a run if the buffer is full,
+    /// leaving `rows` empty
+    pub fn spill_if_full<F>(&mut self, rows: &mut Vec<SortRow>, compare: F) -> std::io::Result<()>
+    where
+        F: Fn(&SortRow, &SortRow) -> Ordering,
+    {
+        if rows.len() < self.buffer_rows {
+            return Ok(());
+        }
+        rows.sort_by(&compare);
+        self.runs.push(Run::write(rows.drain(..))?);
+        Ok(())
+    }
+
+    /// Every row added, in order: the runs merged with `rows`, the ones still
+    /// in memory
+    pub fn merge<F>(self, mut rows: Vec<SortRow>, compare: F) -> std::io::Result<Merge<F>>
+    where
+        F: Fn(&SortRow, &SortRow) -> Ordering,
+    {
+        rows.sort_by(&compare);
+        let mut sources = Vec::with_capacity(self.runs.len() + 1);
+        for run in self.runs {
+            sources.push(Source::Run(run.reader()?));
+        }
+        sources.push(Source::Memory(rows.into_iter()));
+
+        let mut heads = Vec::with_capacity(sources.len());
+        for source in &mut sources {
+            heads.push(source.next_row()?);
+        }
+        Ok(Merge {
+            sources,
+            heads,
+            compare,
+        })
+    }
+}
+
+/// A sorted run in a temporary file, deleted when dropped
+struct Run {
+    path: PathBuf,
+}
+
+impl Run {
+    fn write(rows: impl Iterator<Item = SortRow>) -> std::io::Result<Self> {
+        let path = std::env::temp_dir().join(format!(
+            "matidb-sort-{}-{}.run",
+            std::process::id(),
+            NEXT_RUN.fetch_add(1, AtomicOrdering::Relaxed)
+        ));
+        // Created first, so a failed write still cleans up after itself
+        let run = Run { path };
+        let mut file = BufWriter::new(File::create(&run.path)?);
+        for (key, distinct_key, values) in rows {
+            // Each part is its length, then its tagged values
+            for values in [key, distinct_key, values] {
+                let bytes = Row { values }.to_bytes();
+                file.write_all(&(bytes.len() as u32).to_le_bytes())?;
+                file.write_all(&bytes)?;
+            }
+        }
+        file.flush()?;
+        Ok(run)
+    }
+
+    fn reader(self) -> std::io::Result<RunReader> {
+        Ok(RunReader {
+            file: BufReader::new(File::open(&self.path)?),
+            _run: self,
+        })
+    }
+}
+
+impl Drop for Run {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+struct RunReader {
+    file: BufReader<File>,
+    /// Keeps the file until the reader is done with it
+    _run: Run,
+}
+
+impl RunReader {
+    fn next_row(&mut self) -> std::io::Result<Option<SortRow>> {
+        let Some(key) = self.next_part(true)? else {
+            return Ok(None);
+        };
+        let distinct_key = self.next_part(false)?.unwrap_or_default();
+        let values = self.next_part(false)?.unwrap_or_default();
+        Ok(Some((key, distinct_key, values)))
+    }
+
+    /// The next length-prefixed part of a row. Only the first part of a row
+    /// may find the end of the file.
+    fn next_part(&mut self, may_end: bool) -> std::io::Result<Option<Vec<Value>>> {
+        let mut len = [0; 4];
+        match self.file.read_exact(&mut len) {
+            Err(e) if may_end && e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Ok(None);
+            }
+            result => result?,
+        }
+        let mut bytes = vec![0; u32::from_le_bytes(len) as usize];
+        self.file.read_exact(&mut bytes)?;
+        let row = Row::from_bytes(&bytes).map_err(std::io::Error::other)?;
+        Ok(Some(row.values))
+    }
+}
+
+enum Source {
+    Run(RunReader),
+    Memory(std::vec::IntoIter<SortRow>),
+}
+
+impl Source {
+    fn next_row(&mut self) -> std::io::Result<Option<SortRow>> {
+        match self {
+            Source::Run(reader) => reader.next_row(),
+            Source::Memory(rows) => Ok(rows.next()),
+        }
+    }
+}
+
+/// The rows of several sorted sources in order. Only the first row of each
+/// source is held in memory.
+pub struct Merge<F> {
+    sources: Vec<Source>,
+    heads: Vec<Option<SortRow>>,
+    compare: F,
+}
+
+impl<F> Iterator for Merge<F>
+where
+    F: Fn(&SortRow, &SortRow) -> Ordering,
+{
+    type Item = std::io::Result<SortRow>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // A linear scan is fine for the handful of runs a sort writes. Ties go
+        // to the earlier source, which keeps the sort stable.
+        let mut smallest: Option<usize> = None;
+        for (idx, head) in self.heads.iter().enumerate() {
+            if let Some(row) = head
+                && smallest.is_none_or(|best| {
+                    let best = self.heads[best].as_ref().expect("head is set");
+                    (self.compare)(row, best).is_lt()
+                })
+            {
+                smallest = Some(idx);
+            }
+        }
+
+        let idx = smallest?;
+        match self.sources[idx].next_row() {
+            Ok(next) => std::mem::replace(&mut self.heads[idx], next).map(Ok),
+            Err(e) => {
+                self.heads[idx] = None;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(n: i64, tag: &str) -> SortRow {
+        (
+            vec![Value::Long(n)],
+            vec![],
+            vec![Value::Long(n), Value::Text(tag.to_string())],
+        )
+    }
+
+    fn by_key(a: &SortRow, b: &SortRow) -> Ordering {
+        a.0.cmp(&b.0)
+    }
+
+    #[test]
+    fn test_merge_spilled_runs() {
+        let mut runs = SortRuns::new(4);
+        let mut rows = Vec::new();
+        for i in 0..10 {
+            rows.push(row((i * 7) % 5, &format!("r{}", i)));
+            runs.spill_if_full(&mut rows, by_key).unwrap();
+        }
+        assert_eq!(runs.run_count(), 2);
+        assert_eq!(rows.len(), 2);
+
+        let merged: Vec<SortRow> = runs
+            .merge(rows, by_key)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        // Sorted by key, and rows with equal keys stay in the order they were added
+        let order: Vec<(i64, String)> = merged
+            .into_iter()
+            .map(|(_, _, values)| match (&values[0], &values[1]) {
+                (Value::Long(n), Value::Text(tag)) => (*n, tag.clone()),
+                _ => unreachable!(),
+            })
+            .collect();
+        let mut expected: Vec<(i64, String)> =
+            (0..10).map(|i| ((i * 7) % 5, format!("r{}", i))).collect();
+        expected.sort_by_key(|(n, _)| *n);
+        assert_eq!(order, expected);
+    }
+
+    #[test]
+    fn test_run_files_are_removed() {
+        let mut runs = SortRuns::new(1);
+        let mut rows = vec![row(1, "a")];
+        runs.spill_if_full(&mut rows, by_key).unwrap();
+        let path = runs.runs[0].path.clone();
+        assert!(path.exists());
+
+        let merge = runs.merge(Vec::new(), by_key).unwrap();
+        drop(merge);
+        assert!(!path.exists());
+    }
+}
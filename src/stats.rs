@@ -0,0 +1,214 @@
+use crate::database::Value;
+use std::collections::HashSet;
+
+/// Statistics about a table's contents, gathered by `ANALYZE` for the planner.
+///
+/// They describe the table as it was when last analyzed: later writes don't
+/// update them, so estimates drift until the table is analyzed again.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TableStats {
+    pub row_count: u64,
+    /// One entry per column, in schema order
+    pub columns: Vec<ColumnStats>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ColumnStats {
+    /// Number of distinct non-NULL values
+    pub distinct: u64,
+    /// Smallest and largest value, for integer columns
+    pub min: Option<i64>,
+    pub max: Option<i64>,
+}
+
+impl TableStats {
+    /// Estimated fraction of rows in which the column equals a given value,
+    /// assuming values are spread evenly
+    pub fn equality_selectivity(&self, column: usize) -> f64 {
+        match self.columns.get(column) {
+            Some(stats) if stats.distinct > 0 => 1.0 / stats.distinct as f64,
+            Some(_) => 0.0,
+            None => 1.0,
+        }
+    }
+
+    /// Estimated fraction of rows in which an integer column falls between
+    /// `low` and `high` inclusive, assuming values are spread evenly over
+    /// the column's min..max range
+    pub fn range_selectivity(&self, column: usize, low: i64, high: i64) -> f64 {
+        let Some(ColumnStats {
+            min: Some(min),
+            max: Some(max),
+            ..
+        }) = self.columns.get(column)
+        else {
+            return 1.0;
+        };
+
+        let low = (low as i128).max(*min as i128);
+        let high = (high as i128).min(*max as i128);
+        if low > high {
+            return 0.0;
+        }
+        (high - low + 1) as f64 / (*max as i128 - *min as i128 + 1) as f64
+    }
+
+    /// Number of rows a predicate of the given selectivity is expected to match
+    pub fn estimate_rows(&self, selectivity: f64) -> u64 {
+        (self.row_count as f64 * selectivity).round() as u64
+    }
+
+    /// Serialize for the catalog: `row_count|distinct:min:max,...`,
+    /// leaving min and max empty when the column has none
+    pub fn to_catalog(&self) -> String {
+        let columns: Vec<String> = self
+            .columns
+            .iter()
+            .map(|c| {
+                let bound = |b: Option<i64>| b.map(|n| n.to_string()).unwrap_or_default();
+                format!("{}:{}:{}", c.distinct, bound(c.min), bound(c.max))
+            })
+            .collect();
+        format!("{}|{}", self.row_count, columns.join(","))
+    }
+
+    /// Parse the output of `to_catalog`
+    pub fn from_catalog(s: &str) -> Option<Self> {
+        let (row_count, columns) = s.split_once('|')?;
+        let columns = if columns.is_empty() {
+            Vec::new()
+        } else {
+            columns
+                .split(',')
+                .map(|c| {
+                    let mut parts = c.split(':');
+                    let distinct = parts.next()?.parse().ok()?;
+                    let bound = |p: Option<&str>| match p? {
+                        "" => Some(None),
+                        n => n.parse().ok().map(Some),
+                    };
+                    Some(ColumnStats {
+                        distinct,
+                        min: bound(parts.next())?,
+                        max: bound(parts.next())?,
+                    })
+                })
+                .collect::<Option<Vec<_>>>()?
+        };
+
+        Some(TableStats {
+            row_count: row_count.parse().ok()?,
+            columns,
+        })
+    }
+}
+
+/// Builds `TableStats` one row at a time during a table scan
+pub struct StatsCollector {
+    row_count: u64,
+    distinct: Vec<HashSet<Value>>,
+    min: Vec<Option<i64>>,
+    max: Vec<Option<i64>>,
+}
+
+impl StatsCollector {
+    pub fn new(column_count: usize) -> Self {
+        Self {
+            row_count: 0,
+            distinct: vec![HashSet::new(); column_count],
+            min: vec![None; column_count],
+            max: vec![None; column_count],
+        }
+    }
+
+    pub fn add(&mut self, values: &[Value]) {
+        self.row_count += 1;
+
+        for (idx, value) in values.iter().enumerate().take(self.distinct.len()) {
+            if let Value::Long(n) = value {
+                self.min[idx] = Some(self.min[idx].map_or(*n, |m| m.min(*n)));
+                self.max[idx] = Some(self.max[idx].map_or(*n, |m| m.max(*n)));
+            }
+            if *value != Value::Null {
+                self.distinct[idx].insert(value.clone());
+            }
+        }
+    }
+
+    pub fn finish(self) -> TableStats {
+        let columns = self
+            .distinct
+            .iter()
+            .zip(self.min.iter().zip(&self.max))
+            .map(|(distinct, (min, max))| ColumnStats {
+                distinct: distinct.len() as u64,
+                min: *min,
+                max: *max,
+            })
+            .collect();
+
+        TableStats {
+            row_count: self.row_count,
+            columns,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> TableStats {
+        let mut collector = StatsCollector::new(2);
+        for i in 1..=100 {
+            collector.add(&[Value::Long(i), Value::Text(format!("dept{}", i % 4))]);
+        }
+        collector.add(&[Value::Null, Value::Null]);
+        collector.finish()
+    }
+
+    #[test]
+    fn test_collect_stats() {
+        let stats = sample();
+
+        assert_eq!(stats.row_count, 101);
+        assert_eq!(
+            stats.columns,
+            vec![
+                ColumnStats {
+                    distinct: 100,
+                    min: Some(1),
+                    max: Some(100),
+                },
+                ColumnStats {
+                    distinct: 4,
+                    min: None,
+                    max: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_selectivity_estimates() {
+        let stats = sample();
+
+        assert_eq!(stats.estimate_rows(stats.equality_selectivity(1)), 25);
+        assert_eq!(stats.range_selectivity(0, 1, 10), 0.1);
+        assert_eq!(stats.range_selectivity(0, 91, i64::MAX), 0.1);
+        assert_eq!(stats.range_selectivity(0, 200, 300), 0.0);
+        // No min/max for text columns, so no estimate
+        assert_eq!(stats.range_selectivity(1, 0, 5), 1.0);
+    }
+
+    #[test]
+    fn test_catalog_round_trip() {
+        let stats = sample();
+        let line = stats.to_catalog();
+
+        assert_eq!(line, "101|100:1:100,4::");
+        assert_eq!(TableStats::from_catalog(&line), Some(stats));
+        assert_eq!(TableStats::from_catalog("0|"), Some(TableStats::default()));
+        assert_eq!(TableStats::from_catalog("x|1::"), None);
+    }
+}
@@ -1,10 +1,14 @@
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Result, Seek, SeekFrom, Write};
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
 
 pub const PAGE_SIZE: usize = 4096;
 
 pub type PageId = u32;
 
+/// Most pages `DiskManager::write_pages` gathers into one write, which bounds
+/// the buffer it copies them into
+pub const MAX_WRITE_BATCH: usize = 32;
+
 // A Page is just a raw array of bytes.
 // We derive Clone and Copy because it's just data.
 #[derive(Debug, Clone, Copy)]
@@ -26,9 +30,47 @@ impl Default for Page {
     }
 }
 
+/// A page store the buffer pool reads from and writes back to.
+///
+/// `DiskManager` is the file-backed implementation; `MemoryStorage` keeps
+/// pages in RAM. Anything implementing this trait can back a `BufferPool`.
+pub trait Storage {
+    /// Reads a page into memory
+    fn read_page(&mut self, page_id: PageId) -> Result<Page>;
+
+    /// Writes a page back to the store. It isn't guaranteed to be durable
+    /// until the next `sync`.
+    fn write_page(&mut self, page_id: PageId, page: &Page) -> Result<()>;
+
+    /// Writes several pages, in any order. Stores that can write adjacent
+    /// pages together do so; by default each is written on its own.
+    fn write_pages(&mut self, pages: &[(PageId, &Page)]) -> Result<()> {
+        for (page_id, page) in pages {
+            self.write_page(*page_id, page)?;
+        }
+        Ok(())
+    }
+
+    /// Makes every page written so far durable
+    fn sync(&mut self) -> Result<()>;
+
+    /// Allocates a new page and returns its ID. Until it's written, the
+    /// page reads back as zeros.
+    fn allocate_page(&mut self) -> Result<PageId>;
+
+    /// Number of pages allocated so far
+    fn num_pages(&self) -> PageId;
+
+    /// Discards every page at or beyond `num_pages`.
+    /// Page ids from `num_pages` on will be handed out again by `allocate_page`.
+    fn truncate(&mut self, num_pages: PageId) -> Result<()>;
+}
+
 pub struct DiskManager {
     file: File,
     next_page_id: PageId,
+    /// Number of writes made to the file
+    writes: u64,
 }
 
 impl DiskManager {
@@ -45,11 +87,32 @@ impl DiskManager {
         let file_len = file.metadata()?.len();
         let next_page_id = (file_len / PAGE_SIZE as u64) as PageId;
 
-        Ok(Self { file, next_page_id })
+        Ok(Self {
+            file,
+            next_page_id,
+            writes: 0,
+        })
     }
 
+    /// Number of writes made to the file so far, to see how well flushes
+    /// are batched
+    #[allow(dead_code)]
+    pub fn write_count(&self) -> u64 {
+        self.writes
+    }
+
+    fn write_at(&mut self, page_id: PageId, data: &[u8]) -> Result<()> {
+        self.file
+            .seek(SeekFrom::Start(page_id as u64 * PAGE_SIZE as u64))?;
+        self.file.write_all(data)?;
+        self.writes += 1;
+        Ok(())
+    }
+}
+
+impl Storage for DiskManager {
     /// Reads a page from disk into memory
-    pub fn read_page(&mut self, page_id: PageId) -> Result<Page> {
+    fn read_page(&mut self, page_id: PageId) -> Result<Page> {
         let offset = page_id as u64 * PAGE_SIZE as u64;
         self.file.seek(SeekFrom::Start(offset))?;
 
@@ -60,20 +123,184 @@ impl DiskManager {
     }
 
     /// Writes a page from memory to disk
-    pub fn write_page(&mut self, page_id: PageId, page: &Page) -> Result<()> {
-        let offset = page_id as u64 * PAGE_SIZE as u64;
-        self.file.seek(SeekFrom::Start(offset))?;
-        self.file.write_all(&page.data)?;
-        self.file.flush()?;
+    fn write_page(&mut self, page_id: PageId, page: &Page) -> Result<()> {
+        self.write_at(page_id, &page.data)
+    }
+
+    /// Writes the pages in file order, each run of consecutive pages (up to
+    /// `MAX_WRITE_BATCH` of them) with a single seek and write
+    fn write_pages(&mut self, pages: &[(PageId, &Page)]) -> Result<()> {
+        let mut pages = pages.to_vec();
+        pages.sort_by_key(|(page_id, _)| *page_id);
+
+        let mut run = Vec::with_capacity(MAX_WRITE_BATCH * PAGE_SIZE);
+        let mut run_start = 0;
+        for (i, (page_id, page)) in pages.iter().enumerate() {
+            let adjacent = i > 0 && *page_id == pages[i - 1].0 + 1;
+            if !run.is_empty() && (!adjacent || run.len() == MAX_WRITE_BATCH * PAGE_SIZE) {
+                self.write_at(run_start, &run)?;
+                run.clear();
+            }
+            if run.is_empty() {
+                run_start = *page_id;
+            }
+            run.extend_from_slice(&page.data);
+        }
+        if !run.is_empty() {
+            self.write_at(run_start, &run)?;
+        }
 
         Ok(())
     }
 
-    /// Allocates a new page and returns its ID
-    pub fn allocate_page(&mut self) -> PageId {
+    /// Forces written pages out to the disk itself (fsync)
+    fn sync(&mut self) -> Result<()> {
+        self.file.sync_data()
+    }
+
+    /// Extends the file to cover the new page, so it exists on disk (as
+    /// zeros) even if it's never written
+    fn allocate_page(&mut self) -> Result<PageId> {
         let page_id = self.next_page_id;
+        self.file.set_len((page_id as u64 + 1) * PAGE_SIZE as u64)?;
         self.next_page_id += 1;
-        page_id
+        Ok(page_id)
+    }
+
+    fn num_pages(&self) -> PageId {
+        self.next_page_id
+    }
+
+    /// Shrinks the file, so the space is given back to the filesystem
+    fn truncate(&mut self, num_pages: PageId) -> Result<()> {
+        if num_pages >= self.next_page_id {
+            return Ok(());
+        }
+
+        self.file.set_len(num_pages as u64 * PAGE_SIZE as u64)?;
+        self.file.sync_all()?;
+        self.next_page_id = num_pages;
+
+        Ok(())
+    }
+}
+
+/// Wraps a store so every page written is read back and compared, turning
+/// a write the store got wrong into an error straight away rather than bad
+/// data at some later read. It doubles the I/O of each write.
+pub struct VerifyingStorage<S> {
+    inner: S,
+}
+
+impl<S: Storage> VerifyingStorage<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+
+    fn verify(&mut self, page_id: PageId, page: &Page) -> Result<()> {
+        if self.inner.read_page(page_id)?.data != page.data {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Page {} reads back differently from what was written",
+                    page_id
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl<S: Storage> Storage for VerifyingStorage<S> {
+    fn read_page(&mut self, page_id: PageId) -> Result<Page> {
+        self.inner.read_page(page_id)
+    }
+
+    fn write_page(&mut self, page_id: PageId, page: &Page) -> Result<()> {
+        self.inner.write_page(page_id, page)?;
+        self.verify(page_id, page)
+    }
+
+    fn write_pages(&mut self, pages: &[(PageId, &Page)]) -> Result<()> {
+        self.inner.write_pages(pages)?;
+        for (page_id, page) in pages {
+            self.verify(*page_id, page)?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.inner.sync()
+    }
+
+    fn allocate_page(&mut self) -> Result<PageId> {
+        self.inner.allocate_page()
+    }
+
+    fn num_pages(&self) -> PageId {
+        self.inner.num_pages()
+    }
+
+    fn truncate(&mut self, num_pages: PageId) -> Result<()> {
+        self.inner.truncate(num_pages)
+    }
+}
+
+/// Page store that lives only in RAM; nothing touches the filesystem
+#[derive(Default)]
+pub struct MemoryStorage {
+    pages: Vec<Page>,
+    next_page_id: PageId,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn read_page(&mut self, page_id: PageId) -> Result<Page> {
+        self.pages.get(page_id as usize).copied().ok_or_else(|| {
+            Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("Page {} has not been allocated", page_id),
+            )
+        })
+    }
+
+    fn write_page(&mut self, page_id: PageId, page: &Page) -> Result<()> {
+        let index = page_id as usize;
+        if self.pages.len() <= index {
+            self.pages.resize(index + 1, Page::new());
+        }
+        self.pages[index] = *page;
+
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn allocate_page(&mut self) -> Result<PageId> {
+        let page_id = self.next_page_id;
+        self.pages.resize(page_id as usize + 1, Page::new());
+        self.next_page_id += 1;
+        Ok(page_id)
+    }
+
+    fn num_pages(&self) -> PageId {
+        self.next_page_id
+    }
+
+    fn truncate(&mut self, num_pages: PageId) -> Result<()> {
+        if num_pages < self.next_page_id {
+            self.pages.truncate(num_pages as usize);
+            self.next_page_id = num_pages;
+        }
+
+        Ok(())
     }
 }
 
@@ -97,7 +324,7 @@ mod tests {
         with_test_file("write_read", |path| {
             let mut dm = DiskManager::open(path).unwrap();
 
-            let page_id = dm.allocate_page();
+            let page_id = dm.allocate_page().unwrap();
             let mut page = Page::new();
             page.data[0] = 42;
             page.data[1] = 123;
@@ -118,8 +345,8 @@ mod tests {
         with_test_file("multiple", |path| {
             let mut dm = DiskManager::open(path).unwrap();
 
-            let page_id_0 = dm.allocate_page();
-            let page_id_1 = dm.allocate_page();
+            let page_id_0 = dm.allocate_page().unwrap();
+            let page_id_1 = dm.allocate_page().unwrap();
 
             let mut page0 = Page::new();
             page0.data[0] = 11;
@@ -144,7 +371,7 @@ mod tests {
             // First session: write data
             {
                 let mut dm = DiskManager::open(path).unwrap();
-                let page_id = dm.allocate_page();
+                let page_id = dm.allocate_page().unwrap();
 
                 let mut page = Page::new();
                 page.data[0] = 99;
@@ -169,19 +396,192 @@ mod tests {
         with_test_file("allocate", |path| {
             let mut dm = DiskManager::open(path).unwrap();
 
-            assert_eq!(dm.allocate_page(), 0);
-            assert_eq!(dm.allocate_page(), 1);
-            assert_eq!(dm.allocate_page(), 2);
+            assert_eq!(dm.allocate_page().unwrap(), 0);
+            assert_eq!(dm.allocate_page().unwrap(), 1);
+            assert_eq!(dm.allocate_page().unwrap(), 2);
             assert_eq!(dm.next_page_id, 3);
         });
     }
 
+    #[test]
+    fn test_unwritten_page_survives_reopen() {
+        with_test_file("unwritten", |path| {
+            {
+                let mut dm = DiskManager::open(path).unwrap();
+                let page_id_0 = dm.allocate_page().unwrap();
+                let page_id_1 = dm.allocate_page().unwrap();
+
+                // Page 0 is allocated but never written
+                let mut page = Page::new();
+                page.data[0] = 5;
+                dm.write_page(page_id_1, &page).unwrap();
+                assert_eq!(page_id_0, 0);
+            }
+
+            let mut dm = DiskManager::open(path).unwrap();
+            assert_eq!(dm.num_pages(), 2);
+            assert!(dm.read_page(0).unwrap().data.iter().all(|&b| b == 0));
+            assert_eq!(dm.read_page(1).unwrap().data[0], 5);
+
+            // Likewise for the last page of the file
+            let page_id = dm.allocate_page().unwrap();
+            drop(dm);
+            let mut dm = DiskManager::open(path).unwrap();
+            assert_eq!(dm.num_pages(), 3);
+            assert!(dm.read_page(page_id).unwrap().data.iter().all(|&b| b == 0));
+        });
+    }
+
+    #[test]
+    fn test_truncate_shrinks_file() {
+        with_test_file("truncate", |path| {
+            let mut dm = DiskManager::open(path).unwrap();
+
+            for _ in 0..4 {
+                let page_id = dm.allocate_page().unwrap();
+                dm.write_page(page_id, &Page::new()).unwrap();
+            }
+            assert_eq!(fs::metadata(path).unwrap().len(), 4 * PAGE_SIZE as u64);
+
+            dm.truncate(1).unwrap();
+            assert_eq!(fs::metadata(path).unwrap().len(), PAGE_SIZE as u64);
+            assert_eq!(dm.num_pages(), 1);
+            assert_eq!(dm.allocate_page().unwrap(), 1);
+
+            // Truncating to a larger size is a no-op
+            dm.truncate(10).unwrap();
+            assert_eq!(dm.num_pages(), 2);
+        });
+    }
+
+    #[test]
+    fn test_in_memory_pages() {
+        let mut dm = MemoryStorage::new();
+
+        let page_id_0 = dm.allocate_page().unwrap();
+        let page_id_1 = dm.allocate_page().unwrap();
+
+        let mut page = Page::new();
+        page.data[0] = 7;
+        dm.write_page(page_id_1, &page).unwrap();
+
+        assert_eq!(dm.read_page(page_id_1).unwrap().data[0], 7);
+        // An allocated page reads as zeros until it's written
+        assert_eq!(dm.read_page(page_id_0).unwrap().data[0], 0);
+        assert!(dm.read_page(5).is_err());
+
+        dm.truncate(1).unwrap();
+        assert!(dm.read_page(page_id_1).is_err());
+        assert_eq!(dm.num_pages(), 1);
+    }
+
+    #[test]
+    fn test_write_pages_coalesces_adjacent_pages() {
+        with_test_file("write_pages", |path| {
+            let mut dm = DiskManager::open(path).unwrap();
+            let pages: Vec<(PageId, Page)> = (0..50)
+                .map(|_| {
+                    let page_id = dm.allocate_page().unwrap();
+                    let mut page = Page::new();
+                    page.data[0] = page_id as u8 + 1;
+                    page.data[PAGE_SIZE - 1] = page_id as u8 + 100;
+                    (page_id, page)
+                })
+                .collect();
+
+            // Pages 0-9 one at a time, as a flush used to
+            for (page_id, page) in &pages[..10] {
+                dm.write_page(*page_id, page).unwrap();
+            }
+            assert_eq!(dm.write_count(), 10);
+
+            // Pages 0-9 and 12-49, given out of order, go in three writes:
+            // a run of 10 pages, and 38 pages split at the batch limit
+            let mut batch: Vec<(PageId, &Page)> = pages
+                .iter()
+                .map(|(page_id, page)| (*page_id, page))
+                .collect();
+            batch.retain(|(page_id, _)| !(10..12).contains(page_id));
+            batch.reverse();
+            dm.write_pages(&batch).unwrap();
+            assert_eq!(dm.write_count(), 10 + 3);
+
+            for (page_id, page) in &batch {
+                let read = dm.read_page(*page_id).unwrap();
+                assert_eq!(read.data[..], page.data[..], "page {}", page_id);
+            }
+            assert!(dm.read_page(10).unwrap().data.iter().all(|&b| b == 0));
+        });
+    }
+
+    /// Memory storage whose reads come back with one bit flipped once
+    /// `corrupt` is set, like a disk silently mangling data
+    struct CorruptingStorage {
+        inner: MemoryStorage,
+        corrupt: bool,
+    }
+
+    impl Storage for CorruptingStorage {
+        fn read_page(&mut self, page_id: PageId) -> Result<Page> {
+            let mut page = self.inner.read_page(page_id)?;
+            if self.corrupt {
+                page.data[100] ^= 1;
+            }
+            Ok(page)
+        }
+
+        fn write_page(&mut self, page_id: PageId, page: &Page) -> Result<()> {
+            self.inner.write_page(page_id, page)
+        }
+
+        fn sync(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn allocate_page(&mut self) -> Result<PageId> {
+            self.inner.allocate_page()
+        }
+
+        fn num_pages(&self) -> PageId {
+            self.inner.num_pages()
+        }
+
+        fn truncate(&mut self, num_pages: PageId) -> Result<()> {
+            self.inner.truncate(num_pages)
+        }
+    }
+
+    #[test]
+    fn test_verify_writes_catches_corruption() {
+        let mut storage = VerifyingStorage::new(CorruptingStorage {
+            inner: MemoryStorage::new(),
+            corrupt: false,
+        });
+        let page_ids = [
+            storage.allocate_page().unwrap(),
+            storage.allocate_page().unwrap(),
+        ];
+        let mut page = Page::new();
+        page.data[100] = 7;
+
+        storage.write_page(page_ids[0], &page).unwrap();
+        storage
+            .write_pages(&[(page_ids[0], &page), (page_ids[1], &page)])
+            .unwrap();
+
+        storage.inner.corrupt = true;
+        let err = storage.write_page(page_ids[1], &page).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("Page 1"), "{}", err);
+        assert!(storage.write_pages(&[(page_ids[0], &page)]).is_err());
+    }
+
     #[test]
     fn test_overwrite_page() {
         with_test_file("overwrite", |path| {
             let mut dm = DiskManager::open(path).unwrap();
 
-            let page_id = dm.allocate_page();
+            let page_id = dm.allocate_page().unwrap();
 
             let mut page = Page::new();
             page.data[0] = 1;